@@ -0,0 +1,39 @@
+//! Build script for Taiko Drum Firmware.
+//!
+//! Handles linking of optional external libraries and the defmt linker script, and passes the
+//! bootloader address to the firmware.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=CMSIS_DSP_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=BOOTLOADER_ADDRESS");
+
+    /* CMSIS-DSP shall be provided as a prebuilt static library for Cortex-M3 target. */
+    if env::var_os("CARGO_FEATURE_CMSIS_DSP").is_some() {
+        if let Ok(dir) = env::var("CMSIS_DSP_LIB_DIR") {
+            println!("cargo:rustc-link-search=native={}", dir);
+        }
+        println!("cargo:rustc-link-lib=static=arm_cortexM3l_math");
+    }
+
+    /* Interned defmt strings are placed into a separate non-loaded section by its linker script. */
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    /* Vector table of the bootloader entered by DFU detach, e.g. 0x08000000 for dapboot. */
+    let address = env::var("BOOTLOADER_ADDRESS").ok().map(|value| {
+        let digits = value.trim().trim_start_matches("0x").trim_start_matches("0X").replace('_', "");
+        u32::from_str_radix(&digits, 16).unwrap_or_else(|_| panic!("BOOTLOADER_ADDRESS is not a hex address: {}", value))
+    });
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("bootloader.rs");
+    let value = match address {
+        Some(address) => format!("Some(0x{:08X})", address),
+        None => "None".into(),
+    };
+    fs::write(out, format!("const BOOTLOADER_ADDRESS: Option<u32> = {};\n", value)).unwrap();
+}
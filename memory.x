@@ -1,12 +1,18 @@
 /* Memory region definitions for STM32F103Cx */
 
 MEMORY {
-    FLASH(rx)   : ORIGIN = 0x08000000, LENGTH = 63K 
-    CFG(rw)     : ORIGIN = 0x0800fc00, LENGTH = 1K
+    FLASH(rx)   : ORIGIN = 0x08000000, LENGTH = 62K 
+    CFG(rw)     : ORIGIN = 0x0800f800, LENGTH = 2K
+    /* Firmware update slot within the upper half of 128K parts (most of "64K" ones included). */
+    UPDATE(rw)  : ORIGIN = 0x08010000, LENGTH = 64K
     RAM(rwx)    : ORIGIN = 0x20000000, LENGTH = 20K
 }
 
 SECTIONS {
     __cfg_start = ORIGIN(CFG);
     __cfg_end = ORIGIN(CFG) + LENGTH(CFG);
+    __app_start = ORIGIN(FLASH);
+    __app_end = ORIGIN(FLASH) + LENGTH(FLASH);
+    __update_start = ORIGIN(UPDATE);
+    __update_end = ORIGIN(UPDATE) + LENGTH(UPDATE);
 }
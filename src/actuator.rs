@@ -0,0 +1,71 @@
+//! Haptic actuator driver for hit confirmation feedback.
+//!
+//! Drives a solenoid or a vibration motor via a low-side transistor connected to PB0. Strength is
+//! controlled by PWM duty cycle of TIM3 channel 3, while the pulse duration is timed by the
+//! [`super::app::Haptic`] task.
+
+use super::pac::{RCC, TIM3};
+use super::pins::{ActuatorPin, PinMode};
+
+/// PWM period. TIM3 is clocked from APB1 at 36 MHz, which gives 20 kHz PWM out of audible range.
+const PWM_ARR: u16 = 1799;
+/// Longest allowed pulse. Solenoids will overheat if kept energized for a long time.
+const MAX_PULSE_MS: u16 = 500;
+
+/// Single actuator pulse requested by the host.
+///
+/// # Output Report
+///
+/// Obtained from the vendor-defined HID output report in the following format:
+/// - strength (1 byte, 0 - off, 255 - full power);
+/// - duration in milliseconds (2 bytes, big-endian);
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HapticPulse {
+    pub(crate) strength: u8,
+    pub(crate) duration_ms: u16,
+}
+
+impl HapticPulse {
+    /// Parses the pulse from the report payload. Duration is limited by [`MAX_PULSE_MS`].
+    pub(crate) fn from_bytes(buff: &[u8]) -> Option<Self> {
+        match buff {
+            [strength, hi, lo, ..] => Some(Self {
+                strength: *strength,
+                duration_ms: u16::from_be_bytes([*hi, *lo]).min(MAX_PULSE_MS),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// PWM driven haptic actuator.
+pub(crate) struct Actuator {
+    tim: TIM3,
+}
+
+impl Actuator {
+    /// Configures PB0 as TIM3 channel 3 PWM output. The actuator is left turned off.
+    ///
+    /// Shall be called after the APB1 prescaler was configured by the USB device initialization.
+    pub(crate) fn new(tim: TIM3, mut pin: ActuatorPin, rcc: &mut RCC) -> Self {
+        rcc.apb1enr.modify(|_, w| w.tim3en().set_bit());
+        pin.set_mode(PinMode::AltPushPull);
+
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.arr.write(|w| w.arr().bits(PWM_ARR));
+        tim.ccr3().write(|w| w.ccr().bits(0));
+        tim.ccmr2_output().modify(|_, w| w.oc3m().pwm_mode1().oc3pe().set_bit());
+        tim.ccer.modify(|_, w| w.cc3e().set_bit());
+        tim.egr.write(|w| w.ug().set_bit());                   /* Loading preloaded registers.  */
+        tim.cr1.modify(|_, w| w.arpe().set_bit().cen().set_bit());
+
+        crate::debug!("Haptic actuator is initialized.");
+        Self { tim }
+    }
+
+    /// Sets the actuator strength. Zero turns it off.
+    pub(crate) fn set(&mut self, strength: u8) {
+        let duty = strength as u32 * (PWM_ARR as u32 + 1) / u8::MAX as u32;
+        self.tim.ccr3().write(|w| w.ccr().bits(duty as u16));
+    }
+}
@@ -0,0 +1,170 @@
+//! Runtime state mirror within the backup registers.
+//!
+//! Backup domain registers survive system resets (brown-outs, watchdog and firmware resets), as
+//! well as power loss on boards with a battery on VBAT. The most critical runtime state is mirrored
+//! there on each change, so the drum comes back with the same profile and modes right away, while
+//! runtime only state like menu navigation mode is never written to flash at all.
+//!
+//! Boot counter, cumulative uptime and the cause of the last reset are kept there as well, which
+//! helps to diagnose watchdog resets and flaky power. Those survive power loss only with a battery.
+//! Supply voltage dips detected by [`super::supply`] are counted there, and a dip, which lasted
+//! until the reset, marks the reset as caused by the power supply.
+
+use super::pac::{BKP, PWR, RCC};
+
+/// Marks valid contents. Backup registers are zero after the backup domain reset.
+const BACKUP_MAGIC: u16 = 0xD12A;
+/* Backup data register indexes. */
+const DR_MAGIC: usize = 0;
+const DR_STATE: usize = 1;
+const DR_BOOTS: usize = 2;
+const DR_UPTIME: usize = 4;
+const DR_RESET_CAUSE: usize = 6;
+const DR_SUPPLY_LOW: usize = 7;
+const DR_SUPPLY_DIPS: usize = 8;
+const DR_CRASH_LOCATION: usize = 9;
+/// Menu navigation mode flag within the state register. Low byte holds the profile.
+const FLAG_MENU: u16 = 1 << 8;
+/// Kind of the last crash within the high byte of the reset cause register, which is only written
+/// at boot otherwise.
+const CRASH_KIND_SHIFT: u16 = 8;
+
+/// Runtime state kept across resets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BackupState {
+    /// Active configuration profile.
+    pub(crate) profile: u8,
+    /// Menu navigation mode.
+    pub(crate) menu: bool,
+}
+
+/// Boot statistics kept across resets.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BootStats {
+    /// Amount of boots since the backup domain reset.
+    pub(crate) boots: u32,
+    /// Cumulative uptime of all boots in seconds.
+    pub(crate) uptime_s: u32,
+    /// Reset flags of RCC_CSR (bits 26-31) shifted to the low byte: bit 2 - NRST pin, bit 3 -
+    /// power-on/power-down, bit 4 - software, bit 5 - independent watchdog, bit 6 - window
+    /// watchdog, bit 7 - low-power reset. Bit 0 is set, if the supply voltage was low at the
+    /// moment of reset.
+    pub(crate) reset_cause: u8,
+    /// Supply voltage dips since the backup domain reset.
+    pub(crate) supply_dips: u16,
+}
+
+impl BootStats {
+    /// Big-endian representation sent to the configuration utility.
+    pub(crate) fn to_bytes(self) -> [u8; 11] {
+        let mut bytes = [0u8; 11];
+        bytes[..4].copy_from_slice(&self.boots.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.uptime_s.to_be_bytes());
+        bytes[8] = self.reset_cause;
+        bytes[9..].copy_from_slice(&self.supply_dips.to_be_bytes());
+        bytes
+    }
+}
+
+/// Reset cause flag of a supply voltage dip, which lasted until the reset.
+const CAUSE_SUPPLY_LOW: u8 = 1 << 0;
+
+/// Backup registers owner.
+pub(crate) struct Backup {
+    bkp: BKP,
+}
+
+impl Backup {
+    /// Enables the write access to the backup domain and records the current boot along with its
+    /// reset cause. Mirrored state is left intact.
+    pub(crate) fn new(bkp: BKP, pwr: &mut PWR, rcc: &mut RCC) -> Self {
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit().bkpen().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        // Reset flags are sticky, so those are cleared for the next boot.
+        let reset_cause = (rcc.csr.read().bits() >> 24) as u8 & 0xFC;
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
+        let mut s = Self { bkp };
+        let valid = s.bkp.dr[DR_MAGIC].read().d().bits() == BACKUP_MAGIC;
+        let (boots, uptime_s) = if valid { (s.read32(DR_BOOTS), s.read32(DR_UPTIME)) } else { (0, 0) };
+        let reset_cause = match s.bkp.dr[DR_SUPPLY_LOW].read().d().bits() {
+            0 => reset_cause,
+            _ => reset_cause | CAUSE_SUPPLY_LOW,
+        };
+        s.write32(DR_BOOTS, boots.wrapping_add(1));
+        s.write32(DR_UPTIME, uptime_s);
+        let crash = s.bkp.dr[DR_RESET_CAUSE].read().d().bits() & !0xFF;
+        s.bkp.dr[DR_RESET_CAUSE].write(|w| w.d().bits(crash | reset_cause as u16));
+        s.bkp.dr[DR_SUPPLY_LOW].write(|w| w.d().bits(0));
+        crate::info!("Boot #{} after reset {:#x}, uptime: {} s.", boots.wrapping_add(1), reset_cause, uptime_s);
+        s
+    }
+
+    /// Boot statistics of the drum.
+    pub(crate) fn stats(&self) -> BootStats {
+        BootStats {
+            boots: self.read32(DR_BOOTS),
+            uptime_s: self.read32(DR_UPTIME),
+            reset_cause: self.bkp.dr[DR_RESET_CAUSE].read().d().bits() as u8,
+            supply_dips: self.bkp.dr[DR_SUPPLY_DIPS].read().d().bits(),
+        }
+    }
+
+    /// Adds the provided amount of seconds to the cumulative uptime.
+    pub(crate) fn add_uptime(&mut self, secs: u32) {
+        self.write32(DR_UPTIME, self.read32(DR_UPTIME).wrapping_add(secs));
+    }
+
+    // Backup registers are 16 bits wide, so larger values span two of them.
+    fn read32(&self, dr: usize) -> u32 {
+        self.bkp.dr[dr].read().d().bits() as u32 | (self.bkp.dr[dr + 1].read().d().bits() as u32) << 16
+    }
+
+    fn write32(&mut self, dr: usize, value: u32) {
+        self.bkp.dr[dr].write(|w| w.d().bits(value as u16));
+        self.bkp.dr[dr + 1].write(|w| w.d().bits((value >> 16) as u16));
+    }
+
+    /// State mirrored before the last reset, if any.
+    pub(crate) fn restore(&self) -> Option<BackupState> {
+        (self.bkp.dr[DR_MAGIC].read().d().bits() == BACKUP_MAGIC).then(|| {
+            let state = self.bkp.dr[DR_STATE].read().d().bits();
+            BackupState { profile: state as u8, menu: state & FLAG_MENU != 0 }
+        })
+    }
+
+    /// Mirrors the state into backup registers.
+    pub(crate) fn store(&mut self, state: BackupState) {
+        let flags = if state.menu { FLAG_MENU } else { 0 };
+        self.bkp.dr[DR_STATE].write(|w| w.d().bits(state.profile as u16 | flags));
+        self.bkp.dr[DR_MAGIC].write(|w| w.d().bits(BACKUP_MAGIC));
+    }
+}
+
+/// Records the supply voltage state from the PVD interrupt, which does not own the backup
+/// registers. Supply registers are never written by [`Backup`] after the boot.
+pub(crate) fn record_supply(low: bool) {
+    let bkp = unsafe { &*BKP::ptr() };
+    if low {
+        let dips = bkp.dr[DR_SUPPLY_DIPS].read().d().bits();
+        bkp.dr[DR_SUPPLY_DIPS].write(|w| w.d().bits(dips.wrapping_add(1)));
+    }
+    bkp.dr[DR_SUPPLY_LOW].write(|w| w.d().bits(low as u16));
+}
+
+/// Records the kind and location of a crash from fault and panic handlers, which do not own the
+/// backup registers. Zero kind forgets the crash.
+pub(crate) fn record_crash(kind: u8, location: u16) {
+    let bkp = unsafe { &*BKP::ptr() };
+    let cause = bkp.dr[DR_RESET_CAUSE].read().d().bits() & 0xFF;
+    bkp.dr[DR_CRASH_LOCATION].write(|w| w.d().bits(location));
+    bkp.dr[DR_RESET_CAUSE].write(|w| w.d().bits(cause | (kind as u16) << CRASH_KIND_SHIFT));
+}
+
+/// Kind and location of the last crash, if any.
+pub(crate) fn last_crash() -> Option<(u8, u16)> {
+    let bkp = unsafe { &*BKP::ptr() };
+    let kind = (bkp.dr[DR_RESET_CAUSE].read().d().bits() >> CRASH_KIND_SHIFT) as u8;
+    (kind != 0).then(|| (kind, bkp.dr[DR_CRASH_LOCATION].read().d().bits()))
+}
@@ -0,0 +1,31 @@
+//! Bench mode for analog front-end bring-up.
+//!
+//! With the `bench` feature, the drum runs from the internal oscillator through the PLL at
+//! 64 MHz, so neither a crystal nor a working USB connection is needed. The USB peripheral is
+//! powered down right after it is created, while sampling and the parser run as usual, and each
+//! detected hit is logged over RTT and the UART logger. Detections are logged as warnings, so they
+//! are kept by `log-warn` builds as well.
+
+use cortex_m::peripheral::NVIC;
+
+use super::pac::Interrupt;
+use super::parser::HitRecord;
+use super::usb::UsbTaikoDrum;
+
+/// Keeps the drum off the bus. USB cannot be clocked at 48 MHz from the bench system clock.
+pub(crate) fn init() {
+    UsbTaikoDrum::disconnect();
+    NVIC::mask(Interrupt::USB_HP_CAN_TX);
+    NVIC::mask(Interrupt::USB_LP_CAN_RX0);
+    crate::warn!("Bench mode: running from HSI with USB disabled.");
+}
+
+/// Logs hits detected within the latest sample of the drum, counted from one.
+pub(crate) fn print(player: u8, records: &[HitRecord]) {
+    for record in records {
+        crate::warn!(
+            "Hit: player {}, pad {}, verdict {}, peak {}, threshold {}, deviation {}/{}",
+            player, record.pad, record.verdict as u8, record.peak, record.threshold, record.deviation, record.limit
+        );
+    }
+}
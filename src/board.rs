@@ -0,0 +1,63 @@
+//! Board variants.
+//!
+//! Pin assignments, ADC channels of the sensors and optional peripherals differ between boards,
+//! so those are defined here for each of them and selected by features. The official PCB is the
+//! default one, while `board-bluepill` selects a Blue Pill development board wired by hand. New
+//! hardware is supported by adding a variant module, without touching drivers.
+
+use super::pins::Pin;
+
+#[cfg(not(feature = "board-bluepill"))]
+pub(crate) use official::*;
+#[cfg(feature = "board-bluepill")]
+pub(crate) use bluepill::*;
+
+/// Official Taiko Drum PCB.
+///
+/// Sensors are connected to `PA3`-`PA6` (P1-P4 connectors). Its only LED is the power indicator,
+/// and pins of the second drum, actuator and VBUS sensing are left unconnected, so those features
+/// need wires soldered to the microcontroller pins.
+#[cfg(not(feature = "board-bluepill"))]
+mod official {
+    use super::Pin;
+
+    /// Left kat, left don, right don and right kat sensors of the first drum.
+    pub(crate) type P1SensorPins = (Pin<'A', 3>, Pin<'A', 4>, Pin<'A', 5>, Pin<'A', 6>);
+    /// Sensors of the second drum in the same order.
+    pub(crate) type P2SensorPins = (Pin<'A', 0>, Pin<'A', 1>, Pin<'A', 2>, Pin<'A', 7>);
+    /// ADC channels of [`P1SensorPins`] and [`P2SensorPins`], which are the pin numbers on port A.
+    pub(crate) const SENSOR_CHANNELS: [[u8; 4]; 2] = [[3, 4, 5, 6], [0, 1, 2, 7]];
+    /// Status LED is not mounted, so self-test results are only read with the programmer.
+    pub(crate) const STATUS_LED: bool = false;
+}
+
+/// Blue Pill development board wired by hand.
+///
+/// Sensors are wired the same way as on the official PCB, while the onboard LED on `PC13` shows
+/// self-test results.
+#[cfg(feature = "board-bluepill")]
+mod bluepill {
+    use super::Pin;
+
+    /// Left kat, left don, right don and right kat sensors of the first drum.
+    pub(crate) type P1SensorPins = (Pin<'A', 3>, Pin<'A', 4>, Pin<'A', 5>, Pin<'A', 6>);
+    /// Sensors of the second drum in the same order.
+    pub(crate) type P2SensorPins = (Pin<'A', 0>, Pin<'A', 1>, Pin<'A', 2>, Pin<'A', 7>);
+    /// ADC channels of [`P1SensorPins`] and [`P2SensorPins`], which are the pin numbers on port A.
+    pub(crate) const SENSOR_CHANNELS: [[u8; 4]; 2] = [[3, 4, 5, 6], [0, 1, 2, 7]];
+    /// Onboard LED, which is lit while `PC13` is low.
+    pub(crate) const STATUS_LED: bool = true;
+}
+
+/* Pins fixed by the microcontroller peripherals, which are the same on every board. */
+
+/// USB D+ line, which is pulled low to simulate disconnection.
+pub type UsbDpPin = Pin<'A', 12>;
+/// USART1 TX output of the UART logger.
+pub(crate) type UartTxPin = Pin<'A', 9>;
+/// PWM output (TIM3 channel 3) driving the haptic actuator.
+pub(crate) type ActuatorPin = Pin<'B', 0>;
+/// VBUS sensing input of self-powered builds.
+pub(crate) type VbusPin = Pin<'B', 10>;
+/// Status LED pin, which is only driven if the board has [`STATUS_LED`].
+pub(crate) type StatusLedPin = Pin<'C', 13>;
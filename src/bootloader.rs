@@ -0,0 +1,52 @@
+//! Handoff to the bootloader for firmware updates without reaching the BOOT0 pin.
+//!
+//! Bootloader is never entered directly from the application, since peripherals and interrupts
+//! configured by the firmware would stay active. Instead a magic word is left within the
+//! uninitialized RAM and the system is reset. The word is checked at the very beginning of the
+//! initialization, before any peripheral is touched.
+//!
+//! The STM32F103 system memory bootloader only talks over USART1, so it is useless after a DFU
+//! detach. The vector table of a USB DFU bootloader flashed alongside the firmware is set by the
+//! `BOOTLOADER_ADDRESS` environment variable at build time instead, e.g. `0x08000000` for dapboot,
+//! which requires the firmware to be linked after it. Without it, bootloader requests are refused.
+
+use core::mem::MaybeUninit;
+
+// Vector table of the bootloader, if one is configured.
+include!(concat!(env!("OUT_DIR"), "/bootloader.rs"));
+/// Value which is unlikely to be found in RAM after power on.
+const BOOTLOADER_MAGIC: u32 = 0xB007_10AD;
+
+/// Bootloader request, which survives the system reset.
+#[unsafe(link_section = ".uninit.BOOTLOADER_REQUEST")]
+static mut BOOTLOADER_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Bootloader is configured, so requests to enter it are accepted.
+pub(crate) const fn available() -> bool {
+    BOOTLOADER_ADDRESS.is_some()
+}
+
+/// Requests the bootloader entry and resets the system.
+pub(crate) fn reboot() -> ! {
+    crate::info!("Rebooting into the bootloader...");
+    unsafe {
+        core::ptr::addr_of_mut!(BOOTLOADER_REQUEST).cast::<u32>().write_volatile(BOOTLOADER_MAGIC);
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Jumps to the bootloader if it was requested before the reset.
+///
+/// Shall be called before any peripheral is configured. The request is cleared beforehand, so
+/// the next reset from the bootloader starts the firmware again.
+pub(crate) fn check() {
+    let request = core::ptr::addr_of_mut!(BOOTLOADER_REQUEST).cast::<u32>();
+    unsafe {
+        if request.read_volatile() == BOOTLOADER_MAGIC {
+            request.write_volatile(0);
+            if let Some(address) = BOOTLOADER_ADDRESS {
+                cortex_m::asm::bootload(address as *const u32)
+            }
+        }
+    }
+}
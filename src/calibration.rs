@@ -0,0 +1,103 @@
+//! Noise floor measurement of idle pads.
+//!
+//! Samples of the first drum are accumulated for the requested duration, while nobody hits the
+//! drum. Standard deviation shows the margin of the hit threshold above the noise, e.g. after the
+//! drum is moved next to speakers or a power supply, while the offset shows biasing errors of
+//! the sensors.
+//!
+//! Samples are measured in blocks of [`BLOCK_LEN`], which are averaged afterwards. Sums of a
+//! block fit into 32 bits and are divided with shifts, so no 64-bit or float arithmetic is pulled
+//! into the firmware. Slow drifts between blocks are not counted as noise.
+
+use super::cfg::Calibration;
+use super::parser::MID_RANGE;
+use super::piezo::{PiezoSample, SAMPLE_RATE_HZ};
+
+/// Samples within a single block. Squares of 12-bit samples still fit into 32-bit sums.
+const BLOCK_SHIFT: u32 = 9;
+const BLOCK_LEN: u32 = 1 << BLOCK_SHIFT;
+/// Blocks measured within about 100 ms.
+pub(crate) const BLOCKS_PER_100MS: u32 = (SAMPLE_RATE_HZ / 10 + BLOCK_LEN / 2) / BLOCK_LEN;
+
+/// Sums of a single pad.
+#[derive(Clone, Copy)]
+struct PadSums {
+    /// Samples and their squares within the current block.
+    sum: i32,
+    squares: u32,
+    /// Block means and variances.
+    means: i32,
+    variances: u32,
+}
+
+/// Running noise measurement.
+pub(crate) struct NoiseMeter {
+    /// Blocks left to measure. Idle if zero.
+    remaining: u32,
+    /// Blocks measured so far.
+    blocks: u32,
+    /// Samples of the current block.
+    count: u32,
+    pads: [PadSums; 4],
+}
+
+impl NoiseMeter {
+    pub(crate) const fn new() -> Self {
+        Self { remaining: 0, blocks: 0, count: 0, pads: [PadSums { sum: 0, squares: 0, means: 0, variances: 0 }; 4] }
+    }
+
+    /// Starts a new measurement of the provided amount of blocks.
+    pub(crate) fn start(&mut self, blocks: u32) {
+        *self = Self { remaining: blocks, ..Self::new() };
+    }
+
+    /// Measurement is in progress.
+    pub(crate) fn is_running(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Accumulates the sample. Writes the result and returns true once the measurement is finished.
+    pub(crate) fn update(&mut self, sample: &PiezoSample, result: &mut Calibration) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.count += 1;
+        let block_end = self.count == BLOCK_LEN;
+        if block_end {
+            (self.count, self.blocks, self.remaining) = (0, self.blocks + 1, self.remaining - 1);
+        }
+
+        for (pad, value) in self.pads.iter_mut().zip(sample.0[0]) {
+            let x = value as i32 - MID_RANGE as i32;
+            pad.sum += x;
+            pad.squares += (x * x) as u32;
+            if block_end {
+                let mean = pad.sum >> BLOCK_SHIFT;
+                pad.means += mean;
+                pad.variances = pad.variances.saturating_add((pad.squares >> BLOCK_SHIFT).saturating_sub((mean * mean) as u32));
+                (pad.sum, pad.squares) = (0, 0);
+            }
+        }
+        if !block_end || self.remaining > 0 {
+            return false;
+        }
+
+        for (i, pad) in self.pads.iter().enumerate() {
+            result.sigma[i] = sqrt(pad.variances.checked_div(self.blocks).unwrap_or(0));
+            result.offset[i] = pad.means.checked_div(self.blocks as i32).unwrap_or(0) as i16;
+        }
+        true
+    }
+}
+
+/// Integer square root, calculated bit by bit. Unlike [`u32::isqrt`], needs no lookup table.
+fn sqrt(value: u32) -> u16 {
+    let mut root = 0u32;
+    for bit in (0..16).rev() {
+        let guess = root | 1 << bit;
+        if guess * guess <= value {
+            root = guess;
+        }
+    }
+    root as u16
+}
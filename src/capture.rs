@@ -0,0 +1,119 @@
+//! Triggered capture of raw samples for diagnosing spurious hits.
+//!
+//! Windows of the parser already hold the latest samples of each pad, therefore no separate
+//! circular buffer is kept. Once armed, the next hit detected on the first drum copies samples
+//! preceding its peak out of the windows and keeps recording the requested time after the peak.
+//! Captured samples are frozen until the capture is armed again.
+//!
+//! Hits are only detected at the end of each window, so the capture is aligned at the peak of the
+//! hit instead of the moment of detection.
+
+use heapless::Vec;
+
+use super::parser::{Parser, MID_RANGE, WINDOW_SIZE};
+use super::piezo::{PiezoSample, SAMPLES_PER_MS};
+
+#[cfg(feature = "two-player")]
+compile_error!("`capture` buffer does not fit into RAM along with parsers of the second drum.");
+
+/// Captured samples, which is 12.8 ms at 10 kHz.
+const CAPTURE_LEN: usize = 128;
+/// Serialized sample with big-endian values of each pad.
+const CAPTURE_SAMPLE_LEN: usize = 8;
+
+/// State of the capture reported to the host.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CaptureState {
+    Idle = 0,
+    /// Waiting for the next hit.
+    Armed = 1,
+    /// Recording samples following the peak of the hit.
+    Triggered = 2,
+    /// Captured samples are ready to be read.
+    Frozen = 3,
+}
+
+/// Samples of the first drum around a hit.
+pub(crate) struct Capture {
+    samples: Vec<[u16; 4], CAPTURE_LEN>,
+    state: CaptureState,
+    /// Requested samples before the peak and from the peak onwards.
+    before: usize,
+    after: usize,
+    /// Samples left to record after the trigger.
+    pending: usize,
+    /// Captured samples preceding the peak.
+    lead: usize,
+}
+
+impl Capture {
+    pub(crate) const fn new() -> Self {
+        Self { samples: Vec::new(), state: CaptureState::Idle, before: 0, after: 0, pending: 0, lead: 0 }
+    }
+
+    /// Arms a new capture of the provided time before and after the peak of the next hit. Time
+    /// after the peak takes precedence, when both do not fit into the buffer.
+    pub(crate) fn arm(&mut self, before_ms: u8, after_ms: u8) {
+        self.after = (after_ms as usize * SAMPLES_PER_MS as usize).min(CAPTURE_LEN);
+        self.before = (before_ms as usize * SAMPLES_PER_MS as usize).min(CAPTURE_LEN - self.after);
+        self.samples.clear();
+        self.lead = 0;
+        self.state = CaptureState::Armed;
+    }
+
+    pub(crate) fn state(&self) -> CaptureState {
+        self.state
+    }
+
+    /// Captured samples and samples preceding the peak of the hit.
+    pub(crate) fn len(&self) -> (usize, usize) {
+        (self.samples.len(), self.lead)
+    }
+
+    /// Records the sample of the first drum after the trigger. Otherwise, the first hit detected
+    /// by the parser of the first drum triggers an armed capture.
+    pub(crate) fn update(&mut self, sample: &PiezoSample, parser: &Parser) {
+        match self.state {
+            CaptureState::Triggered => {
+                self.samples.push(sample.0[0]).ok();
+                self.pending -= 1;
+            },
+            CaptureState::Armed => {
+                let Some(record) = parser.records().first() else {
+                    return;
+                };
+                // Extreme sample of the window is the latest one with the peak value.
+                let pad = record.pad as usize;
+                let peak = (0..WINDOW_SIZE)
+                    .find(|&age| parser.history(age)[pad] == record.peak)
+                    .unwrap_or(0);
+                // Samples following the peak up to the latest one might already cover the capture.
+                let oldest = (peak + self.before).min(WINDOW_SIZE - 1);
+                let newest = (peak + 1).saturating_sub(self.after);
+                for age in (newest..=oldest).rev() {
+                    self.samples.push(parser.history(age).map(|value| (value + MID_RANGE) as u16)).ok();
+                }
+                self.lead = oldest - peak;
+                self.pending = self.after.saturating_sub(peak + 1);
+                self.state = CaptureState::Triggered;
+            },
+            CaptureState::Idle | CaptureState::Frozen => return,
+        }
+        if self.pending == 0 {
+            self.state = CaptureState::Frozen;
+        }
+    }
+
+    /// Reads serialized samples at the byte offset into the buffer. Returns the amount of read
+    /// bytes, which is zero until the capture is frozen.
+    pub(crate) fn read(&self, offset: usize, buff: &mut [u8]) -> usize {
+        let len = if self.state == CaptureState::Frozen { self.samples.len() * CAPTURE_SAMPLE_LEN } else { 0 };
+        let size = len.saturating_sub(offset).min(buff.len());
+        for (i, byte) in (offset..offset + size).zip(buff.iter_mut()) {
+            let value = self.samples[i / CAPTURE_SAMPLE_LEN][i % CAPTURE_SAMPLE_LEN / 2];
+            *byte = value.to_be_bytes()[i % 2];
+        }
+        size
+    }
+}
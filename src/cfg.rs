@@ -1,136 +1,951 @@
 //! Module to hold all configurations related to the taiko drum.
 
-use super::pac::FLASH;
+use super::hid::{HidMode, DEFAULT_HID_POLLING_MS};
+use super::midi::MidiMode;
+use super::flash::{CfgFlash, FlashError, ERASED, PAGE_SIZE};
+use super::crash::REPORT_LEN;
 use usbd_hid::descriptor::KeyboardUsage;
 use core::mem;
-use core::ptr;
-
-/* 
- *  Holds start and end addresses of the last kilobyte of flash, used to store drum's configuration.
- * */
-unsafe extern "C" {
-    static __cfg_start: u8;
-    static __cfg_end: u8;
-}
+use core::ops::RangeInclusive;
 
 /// Drum configuration.
 ///
-/// This structure represents a raw set of bytes stored in the flash memory.
+/// Stored in flash as tagged key-value entries after [`CfgHeader`] (see [`CFG_KEYS`]). Entries
+/// missing from older records keep default values, while unknown ones are skipped, so new fields
+/// can be added without breaking stored data. Fields shall only be appended, since version 1
+/// records hold the raw bytes of this structure.
 #[repr(C)]
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct DrumConfig {
     pub hit_mapping: HitMapping,
     pub parse_cfg: SignalParsingConfiguration,
-    _reserved: u32,
+    pub hid_mode: HidMode,
+    pub midi_mode: MidiMode,
+    pub consumer_mapping: ConsumerMapping,
+    /// Polling interval of HID endpoints in milliseconds (bInterval). Applied after restart.
+    pub poll_interval: u8,
+    /// Hit mapping of the second drum. Only used in two-player configuration.
+    pub p2_hit_mapping: HitMapping,
+    /// Delay before held keys start repeating in 10 ms units. Zero disables auto-repeat.
+    pub repeat_delay: u8,
+    /// Amount of key repeats per second.
+    pub repeat_rate: u8,
+    /// Keys emitted by the pad gestures.
+    pub gesture_mapping: GestureMapping,
+    /// Hit strength is reported as gamepad axes. Non-zero by default, applied after restart.
+    pub velocity_axes: u8,
+    /// USB identity overrides. Only changed via the protected programmer command.
+    pub usb_identity: UsbIdentity,
+    /// CDC serial programmer interface is not exposed when non-zero. Applied after restart.
+    pub cdc_disabled: u8,
+    /// Bus current drawn by the drum in 2 mA units (bMaxPower). Zero keeps the default of 100 mA,
+    /// builds with solenoids or LEDs shall report more. Applied after restart.
+    pub max_power: u8,
+    /// Drum is powered from its own supply (e.g. battery builds). Applied after restart.
+    pub self_powered: u8,
+    /// Profile slot the configuration is stored in. The latest stored profile is the active one.
+    pub profile: u8,
+    /// Profile name, UTF-8 and zero terminated if shorter than [`PROFILE_NAME_LEN`].
+    pub name: [u8; PROFILE_NAME_LEN],
+    /// Hit odometer of the first drum in LK, LD, RD, RK order. Shared by all profiles, the latest
+    /// record holds the current counts.
+    pub hits: [u32; 4],
+    /// Configuration changes are rejected while non-zero, until the unlock command is sent. Guards
+    /// against accidental changes only, since the unlock command is not secret.
+    pub locked: u8,
+    /// Noise of the idle pads measured by the calibration command. Shared by all profiles the
+    /// same way as [`Self::hits`].
+    pub calibration: Calibration,
+    /// Seconds without hits, after which flash and SRAM clocks are stopped while the CPU sleeps.
+    /// Zero keeps them running.
+    pub idle_sleep: u8,
+}
+
+/// Reasons to reject a configuration. Sent back to the utility after NAK.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum CfgError {
+    /// Stream ended within a tag-value pair.
+    Truncated = 0x01,
+    /// Unknown tag within the stream.
+    UnknownTag = 0x02,
+    /// Sensitivity is outside of [`SENSITIVITY_RANGE`].
+    Sensitivity = 0x03,
+    /// Hit threshold given by sharpness and sensitivity is outside of [`HIT_THRESHOLD_RANGE`].
+    Sharpness = 0x04,
+    /// Reserved keyboard usage of a pad or gesture.
+    Keycode = 0x05,
+    /// Usage beyond the consumer control report descriptor.
+    ConsumerUsage = 0x06,
+    /// Configuration is locked.
+    Locked = 0x07,
+    /// Configuration is applied, but could not be saved to flash, so it is lost after restart.
+    Flash = 0x08,
+}
+
+/// Header preceding each configuration or crash record stored in flash. Stored in little-endian.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CfgHeader {
+    /// Always [`CFG_MAGIC`] or [`CRASH_MAGIC`], so random page contents are never taken for a
+    /// record.
+    magic: u16,
+    /// Layout version of the stored configuration.
+    version: u8,
+    /// Length of the stored entries in bytes.
+    len: u8,
+    /// CRC32 of the stored entries.
+    crc: u32,
+}
+
+impl CfgHeader {
+    fn from_bytes(b: [u8; HEADER_SIZE]) -> Self {
+        Self {
+            magic: u16::from_le_bytes([b[0], b[1]]),
+            version: b[2],
+            len: b[3],
+            crc: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut b = [0u8; HEADER_SIZE];
+        b[..2].copy_from_slice(&self.magic.to_le_bytes());
+        b[2] = self.version;
+        b[3] = self.len;
+        b[4..].copy_from_slice(&self.crc.to_le_bytes());
+        b
+    }
+}
+
+/// Offset of the latest matching record and the free space range after the last one.
+type Scan = (Option<usize>, Option<(usize, usize)>);
+
+/// State of the configuration found in flash at boot or of the latest written one.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CfgStatus {
+    /// Configuration was loaded from flash.
+    Loaded      = 0x00,
+    /// Nothing was stored, default values are used.
+    Default     = 0x01,
+    /// Stored configuration is corrupted, default values are used.
+    Corrupted   = 0x02,
+    /// Configuration of an older layout version was loaded and converted.
+    Migrated    = 0x03,
+    /// Latest written configuration caused a hit storm and the previous one was restored.
+    RolledBack  = 0x04,
+}
+
+/// Amount of configuration profiles, which all fit into the page at once.
+pub(crate) const PROFILES: usize = 4;
+/// Maximal length of profile names in bytes.
+pub(crate) const PROFILE_NAME_LEN: usize = 12;
+
+/// Auto-repeat rate used when it is enabled without changing the rate.
+const DEFAULT_REPEAT_RATE: u8 = 10;
+/// Idle time before clocks are stopped during sleep.
+const DEFAULT_IDLE_SLEEP_S: u8 = 60;
+/// Bus current drawn by the bare drum.
+const DEFAULT_MAX_POWER_MA: usize = 100;
+
+/// Valid sensitivity in percents.
+const SENSITIVITY_RANGE: RangeInclusive<u8> = 1..=100;
+/// Valid hit threshold in ADC counts (sharpness scaled by sensitivity). Lower thresholds take the
+/// noise for hits, while 12-bit samples never deviate from the midpoint by more than 2048.
+const HIT_THRESHOLD_RANGE: RangeInclusive<u32> = 16..=2000;
+/// Largest usage of the consumer control report descriptor.
+const CONSUMER_USAGE_MAX: u16 = 0x514;
+
+// Builds the table of stored entries from (key, field, field type) triples along with the
+// functions converting each field from and to its entry.
+macro_rules! cfg_keys {
+    ($($key:literal => $field:ident: $ty:ty),* $(,)?) => {
+        /// Stored entries in (key, offset within [`DrumConfig`], size) format. Keys shall never be
+        /// reused for a different field, even when the field is removed.
+        const CFG_KEYS: &[(u8, usize, usize)] = &[
+            $(($key, mem::offset_of!(DrumConfig, $field), mem::size_of::<$ty>())),*
+        ];
+        // Entries keep the layout of their fields, which is the one of raw version 1 records.
+        const _: () = { $(assert!(<$ty as CfgEntry>::LEN == mem::size_of::<$ty>());)* };
+
+        impl DrumConfig {
+            // Writes the entry of the provided key into the buffer of its size.
+            fn __encode_entry(&self, key: u8, buff: &mut [u8]) {
+                match key {
+                    $($key => self.$field.encode(buff),)*
+                    _ => (),
+                }
+            }
+
+            // Reads the entry of the provided key over the current value of its field. Unknown
+            // keys are skipped, shorter values keep current bytes, while longer ones are truncated.
+            fn __decode_entry(&mut self, key: u8, value: &[u8]) {
+                match key {
+                    $($key => {
+                        let mut bytes = [0u8; <$ty as CfgEntry>::LEN];
+                        self.$field.encode(&mut bytes);
+                        let len = value.len().min(bytes.len());
+                        bytes[..len].copy_from_slice(&value[..len]);
+                        self.$field.decode(&bytes);
+                    },)*
+                    _ => (),
+                }
+            }
+        }
+    };
+}
+
+/// Field stored as a configuration entry.
+///
+/// Entries hold little-endian bytes of the field with zero padding, so any stored value is decoded
+/// into a valid field, e.g. unknown modes and keys fall back to the defaults.
+trait CfgEntry {
+    /// Length of the entry in bytes.
+    const LEN: usize;
+    /// Writes the field into the buffer of [`Self::LEN`] bytes.
+    fn encode(&self, buff: &mut [u8]);
+    /// Reads the field from the buffer of [`Self::LEN`] bytes.
+    fn decode(&mut self, buff: &[u8]);
+}
+
+impl CfgEntry for u8 {
+    const LEN: usize = 1;
+
+    fn encode(&self, buff: &mut [u8]) {
+        buff[0] = *self;
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        *self = buff[0];
+    }
+}
+
+impl<const N: usize> CfgEntry for [u8; N] {
+    const LEN: usize = N;
+
+    fn encode(&self, buff: &mut [u8]) {
+        buff.copy_from_slice(self);
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        self.copy_from_slice(buff);
+    }
+}
+
+impl CfgEntry for [u32; 4] {
+    const LEN: usize = 16;
+
+    fn encode(&self, buff: &mut [u8]) {
+        for (bytes, value) in buff.chunks_exact_mut(4).zip(self) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        for (value, bytes) in self.iter_mut().zip(buff.chunks_exact(4)) {
+            *value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+}
+
+impl CfgEntry for HidMode {
+    const LEN: usize = 1;
+
+    fn encode(&self, buff: &mut [u8]) {
+        buff[0] = *self as u8;
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        *self = buff[0].into();
+    }
+}
+
+impl CfgEntry for MidiMode {
+    const LEN: usize = 1;
+
+    fn encode(&self, buff: &mut [u8]) {
+        buff[0] = *self as u8;
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        *self = buff[0].into();
+    }
+}
+
+impl CfgEntry for HitMapping {
+    const LEN: usize = 8;
+
+    fn encode(&self, buff: &mut [u8]) {
+        let keys = [self.left_kat, self.left_don, self.right_don, self.right_kat];
+        for (bytes, key) in buff.chunks_exact_mut(2).zip(keys) {
+            bytes.copy_from_slice(&[key.key as u8, key.modifier]);
+        }
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        for (key, bytes) in self.keys_mut().into_iter().zip(buff.chunks_exact(2)) {
+            *key = KeyMapping { key: bytes[0].into(), modifier: bytes[1] };
+        }
+    }
+}
+
+impl CfgEntry for SignalParsingConfiguration {
+    const LEN: usize = 6;
+
+    fn encode(&self, buff: &mut [u8]) {
+        let [s0, s1] = self.sharpness.to_le_bytes();
+        buff.copy_from_slice(&[self.sensitivity, 0, s0, s1, self._reserved, 0]);
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        self.sensitivity = buff[0];
+        self.sharpness = u16::from_le_bytes([buff[2], buff[3]]);
+        self._reserved = buff[4];
+    }
+}
+
+impl CfgEntry for ConsumerMapping {
+    const LEN: usize = 8;
+
+    fn encode(&self, buff: &mut [u8]) {
+        let usages = [self.left_kat, self.left_don, self.right_don, self.right_kat];
+        for (bytes, usage) in buff.chunks_exact_mut(2).zip(usages) {
+            bytes.copy_from_slice(&usage.to_le_bytes());
+        }
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        let usages = [&mut self.left_kat, &mut self.left_don, &mut self.right_don, &mut self.right_kat];
+        for (usage, bytes) in usages.into_iter().zip(buff.chunks_exact(2)) {
+            *usage = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
 }
 
-const CFG_START: *const u8 = unsafe { &__cfg_start as *const u8 };
-const CFG_END: *const u8 = unsafe { &__cfg_end as *const u8 };
-/// Size of configuration structure.
-const CFG_SIZE: usize = mem::size_of::<DrumConfig>();
-/// Ensures at runtime that the structure does not require additional padding.
-const _: () = assert!(CFG_SIZE.is_power_of_two());
+impl CfgEntry for GestureMapping {
+    const LEN: usize = 3;
+
+    fn encode(&self, buff: &mut [u8]) {
+        buff.copy_from_slice(&[self.both_kats, self.both_dons, self.hold_don]);
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        (self.both_kats, self.both_dons, self.hold_don) = (buff[0], buff[1], buff[2]);
+    }
+}
+
+impl CfgEntry for UsbIdentity {
+    const LEN: usize = 4 + 2 * USB_IDENTITY_STRING_LEN;
+
+    fn encode(&self, buff: &mut [u8]) {
+        let (ids, strings) = buff.split_at_mut(4);
+        let (manufacturer, product) = strings.split_at_mut(USB_IDENTITY_STRING_LEN);
+        ids[..2].copy_from_slice(&{ self.vid }.to_le_bytes());
+        ids[2..].copy_from_slice(&{ self.pid }.to_le_bytes());
+        manufacturer.copy_from_slice(&self.manufacturer);
+        product.copy_from_slice(&self.product);
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        let (ids, strings) = buff.split_at(4);
+        let (manufacturer, product) = strings.split_at(USB_IDENTITY_STRING_LEN);
+        self.vid = u16::from_le_bytes([ids[0], ids[1]]);
+        self.pid = u16::from_le_bytes([ids[2], ids[3]]);
+        self.manufacturer = Self::to_field(manufacturer);
+        self.product = Self::to_field(product);
+    }
+}
+
+impl CfgEntry for Calibration {
+    const LEN: usize = 16;
+
+    fn encode(&self, buff: &mut [u8]) {
+        let values = self.sigma.into_iter().chain(self.offset.map(|offset| offset as u16));
+        for (bytes, value) in buff.chunks_exact_mut(2).zip(values) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn decode(&mut self, buff: &[u8]) {
+        let (sigma, offset) = buff.split_at(8);
+        for (value, bytes) in self.sigma.iter_mut().zip(sigma.chunks_exact(2)) {
+            *value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        for (value, bytes) in self.offset.iter_mut().zip(offset.chunks_exact(2)) {
+            *value = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+}
+
+cfg_keys! {
+    0x01 => hit_mapping: HitMapping,
+    0x02 => parse_cfg: SignalParsingConfiguration,
+    0x03 => hid_mode: HidMode,
+    0x04 => midi_mode: MidiMode,
+    0x05 => consumer_mapping: ConsumerMapping,
+    0x06 => poll_interval: u8,
+    0x07 => p2_hit_mapping: HitMapping,
+    0x08 => repeat_delay: u8,
+    0x09 => repeat_rate: u8,
+    0x0A => gesture_mapping: GestureMapping,
+    0x0B => velocity_axes: u8,
+    0x0C => usb_identity: UsbIdentity,
+    0x0D => cdc_disabled: u8,
+    0x0E => max_power: u8,
+    0x0F => self_powered: u8,
+    0x10 => profile: u8,
+    0x11 => name: [u8; PROFILE_NAME_LEN],
+    0x12 => hits: [u32; 4],
+    0x13 => locked: u8,
+    0x14 => calibration: Calibration,
+    0x15 => idle_sleep: u8,
+}
+
+/// Length of all stored entries, each prefixed by its key and length bytes.
+const CFG_PAYLOAD_LEN: usize = {
+    let (mut len, mut i) = (0, 0);
+    while i < CFG_KEYS.len() {
+        len += 2 + CFG_KEYS[i].2;
+        i += 1;
+    }
+    len
+};
+/// Size of the header, which is written in half-words as well.
+const HEADER_SIZE: usize = mem::size_of::<CfgHeader>();
+const _: () = assert!(HEADER_SIZE % 2 == 0 && CFG_PAYLOAD_LEN <= u8::MAX as usize);
+const CFG_MAGIC: u16 = 0x7A1C;
+/// Size of a single configuration record, which is appended to the page on each save.
+const RECORD_SIZE: usize = HEADER_SIZE + ((CFG_PAYLOAD_LEN + 1) & !1);
+/// Marks a page with a completely written set of records.
+const PAGE_MAGIC: u16 = 0x7A1D;
+/// Page magic followed by the page sequence counter, which are both half-words.
+const PAGE_HEADER_SIZE: usize = 4;
+/// Marks the record of the last crash, which is kept along with the configuration records.
+const CRASH_MAGIC: u16 = 0x7A1E;
+/// Size of the record holding the longest crash report.
+const CRASH_RECORD_SIZE: usize = HEADER_SIZE + ((REPORT_LEN + 1) & !1);
+const _: () = assert!(REPORT_LEN <= u8::MAX as usize);
+/// Compacted page keeps the latest record of each profile and the crash record.
+const _: () = assert!(PAGE_HEADER_SIZE + PROFILES * RECORD_SIZE + CRASH_RECORD_SIZE <= PAGE_SIZE);
+/// Length of the exported configuration: length prefix, version, entries of each profile
+/// prefixed by their length and CRC32.
+pub(crate) const BLOB_LEN: usize = 2 + 1 + PROFILES * (1 + CFG_PAYLOAD_LEN) + 4;
+/// Layout version of stored records: 1 - raw [`DrumConfig`] bytes, 2 - key-value entries. Shall be
+/// incremented whenever the meaning of an existing entry changes and handled within
+/// [`DrumConfig::migrate`]. New entries need no version change.
+const CFG_VERSION: u8 = 2;
+/// Last layout version, which stored the raw bytes of [`DrumConfig`].
+const CFG_RAW_VERSION: u8 = 1;
+
+/// Bitwise CRC32 (IEEE 802.3) of configurations and firmware updates. A lookup table is not worth the flash.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ b as u32, |crc, _| (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg()))
+    })
+}
 
 impl DrumConfig {
-    // Represents the current structure as an array of words.
-    #[inline(always)]
-    fn to_bytes(&self) -> &[u16; CFG_SIZE / 2] {
-        unsafe { &*(self as *const Self as *const [u16; CFG_SIZE / 2]) }
+    /// Bus current reported to the host in milliamperes. USB 2.0 limits it to 500 mA.
+    pub(crate) fn max_power_ma(&self) -> usize {
+        match self.max_power {
+            0 => DEFAULT_MAX_POWER_MA,
+            units => (units as usize * 2).min(500),
+        }
     }
 
-    // Checking all bytes within the flash page that store our data.
-    #[inline(always)]
-    fn __is_erased() -> bool {
-        unsafe {
-            core::slice::from_ptr_range(CFG_START..CFG_END)
-                .iter()
-                .all(|&b| b == 0xFF)
+    /// Auto-repeat delay and period in milliseconds. Returns [`None`] if auto-repeat is disabled.
+    pub(crate) fn typematic(&self) -> Option<(u32, u32)> {
+        (self.repeat_delay != 0 && self.repeat_rate != 0)
+            .then(|| (self.repeat_delay as u32 * 10, 1000 / self.repeat_rate as u32))
+    }
+
+    /// Checks all fields, so a bad write or a corrupted entry never breaks hit detection or reports.
+    ///
+    /// Values with a safe nearest setting are clamped, while meaningless ones are replaced by
+    /// defaults. Returns the first replaced field as an error, so writes can be rejected.
+    pub(crate) fn validate(&mut self) -> Result<(), CfgError> {
+        let mut result = Ok(());
+
+        let parse = self.parse_cfg;
+        if !SENSITIVITY_RANGE.contains(&parse.sensitivity) {
+            result = Err(CfgError::Sensitivity);
+        } else if !HIT_THRESHOLD_RANGE.contains(&(parse.sharpness as u32 * parse.sensitivity as u32 / 100)) {
+            result = Err(CfgError::Sharpness);
+        }
+        if result.is_err() {
+            self.parse_cfg = SignalParsingConfiguration::default();
+        }
+
+        for (keys, mut defaults) in [(&mut self.hit_mapping, HitMapping::default()), (&mut self.p2_hit_mapping, HitMapping::player2())] {
+            for (key, default) in keys.keys_mut().into_iter().zip(defaults.keys_mut()) {
+                if KeyboardUsage::from(key.key as u8) == KeyboardUsage::Reserved {
+                    key.key = default.key;
+                    result = result.and(Err(CfgError::Keycode));
+                }
+            }
         }
+
+        let gestures = &mut self.gesture_mapping;
+        for gesture in [&mut gestures.both_kats, &mut gestures.both_dons, &mut gestures.hold_don] {
+            if !matches!(*gesture, 0 | GESTURE_CDC_ENABLE | GESTURE_MENU_TOGGLE)
+                && KeyboardUsage::from(*gesture) == KeyboardUsage::Reserved
+            {
+                *gesture = 0;
+                result = result.and(Err(CfgError::Keycode));
+            }
+        }
+
+        let cons = &mut self.consumer_mapping;
+        for usage in [&mut cons.left_kat, &mut cons.left_don, &mut cons.right_don, &mut cons.right_kat] {
+            if *usage > CONSUMER_USAGE_MAX {
+                *usage = 0;
+                result = result.and(Err(CfgError::ConsumerUsage));
+            }
+        }
+
+        self.poll_interval = self.poll_interval.max(1);
+        if self.profile as usize >= PROFILES {
+            self.profile = 0;
+        }
+        result
     }
 
-    // All write flash operations must be done while the flash is not busy.
-    #[inline(always)]
-    fn __bsy<F>(flash: &mut FLASH, f: F) where 
-        F: FnOnce(&mut FLASH)
-    {
-        while flash.sr.read().bsy().bit_is_set() {}
-        f(flash);
-        while flash.sr.read().bsy().bit_is_set() {}
+    // Writes all entries of the current configuration into the provided buffer.
+    fn __encode(&self, buff: &mut [u8]) -> usize {
+        CFG_KEYS.iter().fold(0, |idx, &(key, _, len)| {
+            buff[idx] = key;
+            buff[idx + 1] = len as u8;
+            self.__encode_entry(key, &mut buff[idx + 2..idx + 2 + len]);
+            idx + 2 + len
+        })
     }
 
-    // If flash is locked on reboot, it shall be unlocked via two-key sequence.
+    // Reads entries on top of the default configuration. Unknown keys are skipped, shorter values
+    // keep the default value of remaining bytes, while longer ones are truncated.
+    fn __decode(data: &[u8]) -> Self {
+        let mut cfg = Self::default();
+        let mut entries = data;
+        while let [key, len, rest @ ..] = entries {
+            let (value, next) = rest.split_at((*len as usize).min(rest.len()));
+            cfg.__decode_entry(*key, value);
+            entries = next;
+        }
+        cfg
+    }
+
+    /// Converts the configuration stored by a different firmware version.
+    ///
+    /// Missing entries keep default values, while entries unknown to this firmware are dropped.
+    /// Entries, whose meaning was changed, shall be converted here for preceding versions.
+    fn migrate(&mut self, version: u8) {
+        match version {
+            CFG_VERSION => (),
+            v if v > CFG_VERSION => crate::warn!("Configuration was stored by newer firmware (v{}). Unknown fields are dropped.", v),
+            v => crate::info!("Migrating configuration from v{} to v{}.", v, CFG_VERSION),
+        }
+    }
+
+    // Offset of the provided configuration page. Configuration is stored in two pages used in turns.
     #[inline(always)]
-    fn __unlock_flash(flash: &mut FLASH) { 
-        const KEY1: u32 = 0x45670123;
-        const KEY2: u32 = 0xcdef89ab;
+    fn __page(page: usize) -> usize {
+        page * PAGE_SIZE
+    }
 
-        if flash.cr.read().lock().bit_is_set() {
-            log::info!("Flash is locked. Unlocking...");
-            flash.keyr.write(|w| w.key().variant(KEY1));
-            flash.keyr.write(|w| w.key().variant(KEY2));
+    // Sequence counter of a page, which was completely written.
+    fn __seq(page: usize) -> Result<Option<u16>, FlashError> {
+        let magic = CfgFlash::read_u16(Self::__page(page))?;
+        let seq = CfgFlash::read_u16(Self::__page(page) + 2)?;
+        Ok((magic == PAGE_MAGIC).then_some(seq))
+    }
+
+    // Page holding the current records and its sequence counter. Page with the newer sequence
+    // wins, since the older one is only erased when records are moved again. Firmware preceding
+    // the page header kept records from the start of the last page without any sequence counter.
+    fn __active() -> Result<Option<(usize, Option<u16>)>, FlashError> {
+        Ok(match (Self::__seq(0)?, Self::__seq(1)?) {
+            (Some(a), Some(b)) => Some(if (b.wrapping_sub(a) as i16) > 0 { (1, Some(b)) } else { (0, Some(a)) }),
+            (Some(a), None) => Some((0, Some(a))),
+            (None, Some(b)) => Some((1, Some(b))),
+            (None, None) => (CfgFlash::read_u16(Self::__page(1))? == CFG_MAGIC).then_some((1, None)),
+        })
+    }
+
+    // Header of the record at the provided offset.
+    fn __header(offset: usize) -> Result<CfgHeader, FlashError> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        CfgFlash::read(offset, &mut bytes)?;
+        Ok(CfgHeader::from_bytes(bytes))
+    }
+
+    // Data of the record at the provided offset.
+    fn __data<'a>(offset: usize, header: &CfgHeader, buff: &'a mut [u8; u8::MAX as usize]) -> Result<&'a [u8], FlashError> {
+        let data = &mut buff[..header.len as usize];
+        CfgFlash::read(offset + HEADER_SIZE, data)?;
+        Ok(data)
+    }
+
+    // Walks over configuration records appended to the active page, see [`Self::__walk`].
+    fn __scan(profile: Option<u8>) -> Result<Scan, FlashError> {
+        Self::__walk(CFG_MAGIC, profile)
+    }
+
+    // Walks over records appended to the active page. Returns the offset of the latest record
+    // of the provided magic with a valid CRC, which belongs to the provided profile (any if not
+    // provided), and the free space after the last record. Unreadable garbage and pages written
+    // by older firmware leave no free space.
+    fn __walk(magic: u16, profile: Option<u8>) -> Result<Scan, FlashError> {
+        let Some((page, seq)) = Self::__active()? else {
+            return Ok((None, None));
+        };
+        let end = Self::__page(page) + PAGE_SIZE;
+        let mut offset = Self::__page(page) + if seq.is_some() { PAGE_HEADER_SIZE } else { 0 };
+        let (mut latest, mut buff) = (None, [0u8; u8::MAX as usize]);
+        while offset + HEADER_SIZE <= end {
+            let header = Self::__header(offset)?;
+            let next = offset + HEADER_SIZE + ((header.len as usize + 1) & !1);
+            if (header.magic != CFG_MAGIC && header.magic != CRASH_MAGIC) || next > end {
+                if header.magic != ERASED {
+                    offset = end;
+                }
+                break;
+            }
+            let data = Self::__data(offset, &header, &mut buff)?;
+            if header.magic == magic && crc32(data) == header.crc
+                && (magic != CFG_MAGIC || profile.is_none_or(|p| p == Self::__parse(header.version, data).profile))
+            {
+                latest = Some(offset);
+            }
+            offset = next;
+        }
+        Ok((latest, seq.map(|_| (offset, end))))
+    }
+
+    // Parses record data of the provided layout version without migrating it.
+    fn __parse(version: u8, data: &[u8]) -> Self {
+        if version <= CFG_RAW_VERSION {
+            // Fields are only appended, so raw records hold them at the current offsets.
+            let mut cfg = Self::default();
+            for &(key, offset, len) in CFG_KEYS {
+                if let Some(value) = data.get(offset..(offset + len).min(data.len())) {
+                    cfg.__decode_entry(key, value);
+                }
+            }
+            cfg
+        } else {
+            Self::__decode(data)
+        }
+    }
+
+    // Reads the latest valid record of the provided profile (any if not provided).
+    fn __latest(profile: Option<u8>) -> Result<Option<(Self, CfgStatus)>, FlashError> {
+        let Some(offset) = Self::__scan(profile)?.0 else {
+            return Ok(None);
+        };
+        let header = Self::__header(offset)?;
+        let mut buff = [0u8; u8::MAX as usize];
+        let data = Self::__data(offset, &header, &mut buff)?;
+
+        let mut cfg = Self::__parse(header.version, data);
+        cfg.migrate(header.version);
+        if let Err(err) = cfg.validate() {
+            crate::warn!("Stored configuration holds invalid values ({:?}). Defaults are used for them.", err);
         }
+        let status = if header.version == CFG_VERSION { CfgStatus::Loaded } else { CfgStatus::Migrated };
+        Ok(Some((cfg, status)))
+    }
+
+    // Record of the current configuration: header followed by the entries.
+    fn __record(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        let (head, payload) = record.split_at_mut(HEADER_SIZE);
+        let len = self.__encode(payload);
+        let header = CfgHeader {
+            magic: CFG_MAGIC,
+            version: CFG_VERSION,
+            len: len as u8,
+            crc: crc32(&payload[..len]),
+        };
+        head.copy_from_slice(&header.to_bytes());
+        record
     }
 
     /// Generates a new configuration based on contents written to flash memory containing the
     /// configuration. Otherwise the default value will be used.
+    ///
+    /// Stored bytes are only trusted when their CRC matches, since a write might have been
+    /// interrupted by a power loss. The latest valid record wins, so an interrupted save falls
+    /// back to the previous configuration. Returned status tells which configuration is used.
     #[inline(never)]
-    #[unsafe(link_section = ".data")]
-    pub(crate) fn new(flash: &mut FLASH) -> Self {
-        // Unlocking the flash for this function.
-        Self::__unlock_flash(flash);
+    pub(crate) fn new() -> (Self, CfgStatus) {
+        let status = match Self::__active().and_then(|active| active.map(|_| Self::__latest(None)).transpose()) {
+            Ok(Some(Some(loaded))) => {
+                crate::debug!("Reading previous configuration from flash.");
+                return loaded;
+            },
+            Ok(None) => {
+                crate::warn!("Configuration is erased from flash. Using default values.");
+                CfgStatus::Default
+            },
+            Ok(Some(None)) => {
+                crate::error!("Configuration in flash is corrupted. Using default values.");
+                CfgStatus::Corrupted
+            },
+            Err(err) => {
+                crate::error!("Unable to read configuration from flash: {:?}. Using default values.", err);
+                CfgStatus::Corrupted
+            },
+        };
+        (Self::default(), status)
+    }
 
-        if Self::__is_erased() {
-            log::warn!("Configuration is erased from flash. Using default values.");
-            Self::default()
-        } else {
-            log::info!("Reading previous configuration from flash.");
-            unsafe {
-                // Expecting the structure to be written at the very start of the last page.
-                let ptr = CFG_START as *const Self;
-
-                ptr.as_ref()
-                    .expect("Flash memory should contain valid config data.")
-                    .clone()
-            }
+    /// Latest stored configuration of the provided profile, or defaults if it was never saved.
+    pub(crate) fn profile(profile: u8) -> Self {
+        match Self::__latest(Some(profile)) {
+            Ok(Some((cfg, _))) => cfg,
+            _ => Self { profile, ..Self::default() },
         }
     }
 
+    /// Profile has a configuration stored in flash.
+    pub(crate) fn is_stored(profile: u8) -> bool {
+        matches!(Self::__scan(Some(profile)), Ok((Some(_), _)))
+    }
+
+    /// Names of the profiles stored in flash, indexed by profile.
+    pub(crate) fn names() -> [Option<[u8; PROFILE_NAME_LEN]>; PROFILES] {
+        core::array::from_fn(|profile| Self::is_stored(profile as u8).then(|| Self::profile(profile as u8).name))
+    }
+
+    /// Renames the profile. Longer names are truncated.
+    pub(crate) fn set_name(&mut self, name: &[u8]) {
+        let len = name.len().min(PROFILE_NAME_LEN);
+        self.name = [0; PROFILE_NAME_LEN];
+        self.name[..len].copy_from_slice(&name[..len]);
+    }
+
     /// Saves the current configuration to the flash memory region.
+    ///
+    /// Records are appended to the active page and only moved when it is full, so tuning sessions
+    /// with frequent saves do not wear the flash out. Saving an unchanged configuration writes
+    /// nothing.
+    ///
+    /// Full page is never erased in place. The latest records are written to the other page,
+    /// which is only marked as active with a newer sequence counter once all of them are
+    /// written, so a power loss at any point keeps either the previous or the new configuration.
     #[inline(never)]
     #[unsafe(link_section = ".data")]
-    pub(crate) fn save(&mut self, flash: &mut FLASH) {
-        log::info!("Writing new configuration to memory.");
+    pub(crate) fn save(&self, flash: &mut CfgFlash) -> Result<(), FlashError> {
+        crate::info!("Writing new configuration to memory.");
+        let (latest, free) = Self::__scan(None)?;
+        let record = self.__record();
+        let mut stored = [0u8; RECORD_SIZE];
+        if let Some(latest) = latest && CfgFlash::read(latest, &mut stored).is_ok() && stored == record {
+            crate::info!("Configuration is not changed.");
+            return Ok(());
+        }
 
-        // Unlocking the flash for this function.
-        Self::__unlock_flash(flash);
+        match free {
+            Some((free, end)) if free + RECORD_SIZE <= end && CfgFlash::is_erased(free, RECORD_SIZE)? => {
+                Self::__write(flash, free, &record)
+            },
+            _ => self.__flip(flash),
+        }
+    }
 
-        Self::__bsy(flash, |f| {
-            f.cr.modify(|_, w| w.per().set_bit());
-            f.ar.write(|w| w.far().variant(CFG_START as u32));   /* Erasing the page within the provided address. */
-            f.cr.modify(|_, w| w.strt().set_bit());
+    // Latest stored configurations of all profiles except the current one.
+    fn __others(&self) -> [Option<Self>; PROFILES] {
+        core::array::from_fn(|p| {
+            (p as u8 != self.profile && Self::is_stored(p as u8)).then(|| Self::profile(p as u8))
+        })
+    }
+
+    // Moves the latest record of each profile to the inactive page, the current one is written last.
+    #[inline(always)]
+    fn __flip(&self, flash: &mut CfgFlash) -> Result<(), FlashError> {
+        crate::info!("Configuration page is full. Moving records to the other page.");
+        let mut cfgs: heapless::Vec<Self, PROFILES> = self.__others().into_iter().flatten().collect();
+        cfgs.push(*self).ok();
+        Self::__commit(flash, &cfgs, None)
+    }
+
+    // Writes the provided records to the inactive page and makes it the active one. The last
+    // record becomes the active profile. The crash record is written last, the stored one is
+    // moved if none is provided.
+    #[inline(never)]
+    #[unsafe(link_section = ".data")]
+    fn __commit(flash: &mut CfgFlash, cfgs: &[Self], crash: Option<[u8; CRASH_RECORD_SIZE]>) -> Result<(), FlashError> {
+        let crash = match crash {
+            Some(crash) => Some(crash),
+            None => Self::__crash_record()?,
+        };
+        let (page, seq) = match Self::__active()? {
+            Some((page, seq)) => (1 - page, seq.map_or(0, |seq| seq.wrapping_add(1))),
+            None => (0, 0),
+        };
+        crate::info!("Committing configuration page {} (seq {}).", page, seq);
+
+        flash.erase(Self::__page(page))?;
+        let mut free = Self::__page(page) + PAGE_HEADER_SIZE;
+        for cfg in cfgs {
+            Self::__write(flash, free, &cfg.__record())?;
+            free += RECORD_SIZE;
+        }
+        if let Some(crash) = crash {
+            Self::__write(flash, free, &crash[..HEADER_SIZE + ((crash[3] as usize + 1) & !1)])?;
+        }
+
+        // Sequence counter goes first, so the page only becomes valid with its magic.
+        flash.program(Self::__page(page) + 2, seq)?;
+        flash.program(Self::__page(page), PAGE_MAGIC)
+    }
+
+    /// Exports stored configurations of all profiles as a single blob, which can be imported by
+    /// another drum. The current configuration is exported instead of the stored one and goes
+    /// last, so it stays active after import. Returns the length of the blob.
+    ///
+    /// Blob consists of the length of following data, layout version, entries of each profile
+    /// prefixed by their length and CRC32 of the version and entries. Multibyte values are
+    /// big-endian.
+    pub(crate) fn export(&self, buff: &mut [u8; BLOB_LEN]) -> usize {
+        buff[2] = CFG_VERSION;
+        let others = self.__others();
+        let len = others.iter().flatten().chain(core::iter::once(self)).fold(3, |idx, cfg| {
+            let size = cfg.__encode(&mut buff[idx + 1..]);
+            buff[idx] = size as u8;
+            idx + 1 + size
         });
+        let crc = crc32(&buff[2..len]);
+        buff[..2].copy_from_slice(&((len - 2) as u16).to_be_bytes());
+        buff[len..len + 4].copy_from_slice(&crc.to_be_bytes());
+        len + 4
+    }
 
-        if Self::__is_erased() {
-            self.to_bytes()
-                .into_iter()
-                .enumerate()
-                .for_each(|(i, &word)| unsafe {
-                    Self::__unlock_flash(flash);
-                    let ptr = (CFG_START as *mut u16).add(i);
+    /// Replaces all stored profiles with the ones from an exported blob. Returns the configuration
+    /// of the active profile or [`None`] if the blob is malformed, in which case flash is not
+    /// touched.
+    pub(crate) fn import(flash: &mut CfgFlash, blob: &[u8]) -> Option<Self> {
+        let (len, rest) = blob.split_first_chunk::<2>()?;
+        let (data, crc) = rest.split_at_checked(u16::from_be_bytes(*len) as usize)?;
+        if crc != crc32(data).to_be_bytes() {
+            crate::error!("Configuration blob is corrupted.");
+            return None;
+        }
 
-                    flash.cr.modify(|_, w| w.per().clear_bit());
+        let (&version, mut entries) = data.split_first()?;
+        if version <= CFG_RAW_VERSION {
+            crate::error!("Configuration blob of unsupported version v{}.", version);
+            return None;
+        }
+        let mut cfgs = heapless::Vec::<Self, PROFILES>::new();
+        while let [size, rest @ ..] = entries {
+            let (data, next) = rest.split_at_checked(*size as usize)?;
+            let mut cfg = Self::__decode(data);
+            cfg.migrate(version);
+            if let Err(err) = cfg.validate() {
+                crate::error!("Configuration is rejected: {:?}", err);
+                return None;
+            }
+            if cfg.profile as usize >= PROFILES || cfgs.iter().any(|c| c.profile == cfg.profile) {
+                crate::error!("Configuration blob contains invalid profile {}.", cfg.profile);
+                return None;
+            }
+            cfgs.push(cfg).ok()?;
+            entries = next;
+        }
+        let active = *cfgs.last()?;
 
-                    log::info!("Writing: 0x{:x} -> 0x{:X}", ptr as u32, word);
-                    Self::__bsy(flash, |f| {
-                        f.cr.modify(|_, w| w.pg().set_bit());
-                        ptr::write_volatile(ptr, word);
-                    });
+        crate::info!("Importing {} configuration profiles.", cfgs.len());
+        if let Err(err) = Self::__commit(flash, &cfgs, None) {
+            crate::error!("Unable to import configuration: {:?}", err);
+            return None;
+        }
+        Some(active)
+    }
 
-                    assert!(ptr::read_volatile(ptr) == word);
-                });
-        } else {
-            log::error!("Unable to erase flash memory page.");
+    /// Erases all stored profiles, so the default configuration is used after restart.
+    #[inline(never)]
+    #[unsafe(link_section = ".data")]
+    pub(crate) fn erase(flash: &mut CfgFlash) -> Result<(), FlashError> {
+        (0..CfgFlash::len()).step_by(PAGE_SIZE).try_for_each(|page| flash.erase(page))
+    }
+
+    /// Copies the stored report of the last crash, returns zero if there is none.
+    pub(crate) fn stored_crash(buff: &mut [u8; REPORT_LEN]) -> usize {
+        match Self::__crash_record() {
+            Ok(Some(record)) => {
+                let len = record[3] as usize;
+                buff[..len].copy_from_slice(&record[HEADER_SIZE..][..len]);
+                len
+            },
+            _ => 0,
+        }
+    }
+
+    /// Keeps the report of the last crash along with the configuration records, so it survives
+    /// power loss. Empty report clears the stored one. Unchanged report writes nothing.
+    ///
+    /// Nothing is written while no configuration is stored, since a page holding no configuration
+    /// is taken for a corrupted one.
+    #[inline(never)]
+    #[unsafe(link_section = ".data")]
+    pub(crate) fn save_crash(flash: &mut CfgFlash, report: &[u8]) -> Result<(), FlashError> {
+        let record = Self::__crash(report);
+        if Self::__crash_record()?.unwrap_or(Self::__crash(&[])) == record {
+            return Ok(());
+        }
+        let Some((active, _)) = Self::__latest(None)? else {
+            return Ok(());
+        };
+
+        crate::info!("Writing crash report to memory.");
+        match Self::__walk(CRASH_MAGIC, None)?.1 {
+            Some((free, end)) if free + CRASH_RECORD_SIZE <= end && CfgFlash::is_erased(free, CRASH_RECORD_SIZE)? => {
+                Self::__write(flash, free, &record[..HEADER_SIZE + ((record[3] as usize + 1) & !1)])
+            },
+            _ => {
+                let mut cfgs: heapless::Vec<Self, PROFILES> = active.__others().into_iter().flatten().collect();
+                cfgs.push(active).ok();
+                Self::__commit(flash, &cfgs, Some(record))
+            },
+        }
+    }
+
+    // Latest crash record with a valid CRC, bytes past its length are zero.
+    fn __crash_record() -> Result<Option<[u8; CRASH_RECORD_SIZE]>, FlashError> {
+        let Some(offset) = Self::__walk(CRASH_MAGIC, None)?.0 else {
+            return Ok(None);
+        };
+        let len = Self::__header(offset)?.len as usize;
+        if len > REPORT_LEN {
+            return Ok(None);
         }
+        let mut record = [0u8; CRASH_RECORD_SIZE];
+        CfgFlash::read(offset, &mut record[..HEADER_SIZE + len])?;
+        Ok(Some(record))
+    }
+
+    // Crash record of the report, which is truncated to [`REPORT_LEN`]. Record of an empty report
+    // stands for no record at all.
+    fn __crash(report: &[u8]) -> [u8; CRASH_RECORD_SIZE] {
+        let len = report.len().min(REPORT_LEN);
+        let mut record = [0u8; CRASH_RECORD_SIZE];
+        let (head, payload) = record.split_at_mut(HEADER_SIZE);
+        payload[..len].copy_from_slice(&report[..len]);
+        let header = CfgHeader { magic: CRASH_MAGIC, version: 1, len: len as u8, crc: crc32(&payload[..len]) };
+        head.copy_from_slice(&header.to_bytes());
+        record
+    }
+
+    // Writes the record at the provided offset of the erased flash.
+    #[inline(always)]
+    fn __write(flash: &mut CfgFlash, dst: usize, record: &[u8]) -> Result<(), FlashError> {
+        crate::info!("Writing record: {:#x}", dst);
+        record
+            .chunks_exact(2)
+            .enumerate()
+            .try_for_each(|(i, word)| flash.program(dst + 2 * i, u16::from_le_bytes([word[0], word[1]])))
     }
 }
 
@@ -138,10 +953,122 @@ impl DrumConfig {
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct HitMapping {
-    pub left_kat: KeyboardUsage,
-    pub left_don: KeyboardUsage,
-    pub right_don: KeyboardUsage,
-    pub right_kat: KeyboardUsage,
+    pub left_kat: KeyMapping,
+    pub left_don: KeyMapping,
+    pub right_don: KeyMapping,
+    pub right_kat: KeyMapping,
+}
+
+/// Keyboard key with modifier keys pressed alongside it.
+///
+/// Modifier byte uses the same bit layout as the HID keyboard report (bit 0 - left control, bit 1
+/// - left shift, bit 2 - left alt, bit 3 - left GUI, bits 4-7 - the same for right modifiers).
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct KeyMapping {
+    pub key: KeyboardUsage,
+    pub modifier: u8,
+}
+
+impl From<KeyboardUsage> for KeyMapping {
+    fn from(key: KeyboardUsage) -> Self {
+        Self { key, modifier: 0 }
+    }
+}
+
+/// Optional Consumer page usage mapping for each piezoelectric sensor.
+///
+/// Pads with non-zero usage (e.g. 0xE9 for volume up, 0xCD for play/pause) send it over the
+/// consumer control interface instead of their keyboard key. Only used in keyboard HID mode.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConsumerMapping {
+    pub left_kat: u16,
+    pub left_don: u16,
+    pub right_don: u16,
+    pub right_kat: u16,
+}
+
+/// Keyboard keys emitted by recognized gestures. Zero disables the gesture.
+///
+/// Gestures are disabled by default, since simultaneous hits are also used during gameplay.
+/// [`GESTURE_MENU_TOGGLE`] toggles menu navigation mode and [`GESTURE_CDC_ENABLE`] brings back the
+/// serial programmer interface instead of emitting a key.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GestureMapping {
+    pub both_kats: u8,
+    pub both_dons: u8,
+    pub hold_don: u8,
+}
+
+/// Special gesture value, which toggles menu navigation mode. Usages above 0xE7 are reserved.
+pub(crate) const GESTURE_MENU_TOGGLE: u8 = 0xFF;
+/// Special gesture value, which enables the CDC serial programmer interface and restarts the drum.
+pub(crate) const GESTURE_CDC_ENABLE: u8 = 0xFE;
+
+/// Maximal length of USB identity strings in bytes.
+pub(crate) const USB_IDENTITY_STRING_LEN: usize = 28;
+
+/// USB identity overrides.
+///
+/// The default V-USB shared IDs can collide with other hobby devices, therefore power users might
+/// provide their own VID/PID pair and strings. Zero IDs and empty strings keep firmware defaults.
+/// Strings are UTF-8 and zero terminated if shorter than [`USB_IDENTITY_STRING_LEN`].
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: [u8; USB_IDENTITY_STRING_LEN],
+    pub product: [u8; USB_IDENTITY_STRING_LEN],
+}
+
+impl UsbIdentity {
+    /// Identity without any overrides.
+    pub(crate) const fn new() -> Self {
+        Self { vid: 0, pid: 0, manufacturer: [0; USB_IDENTITY_STRING_LEN], product: [0; USB_IDENTITY_STRING_LEN] }
+    }
+
+    /// Overridden VID/PID pair.
+    pub(crate) fn vid_pid(&self) -> Option<(u16, u16)> {
+        let (vid, pid) = (self.vid, self.pid);
+        (vid != 0 && pid != 0).then_some((vid, pid))
+    }
+
+    /// Overridden manufacturer string.
+    pub(crate) fn manufacturer(&self) -> Option<&str> {
+        Self::as_str(&self.manufacturer)
+    }
+
+    /// Overridden product string.
+    pub(crate) fn product(&self) -> Option<&str> {
+        Self::as_str(&self.product)
+    }
+
+    /// Converts the provided bytes into a string field. Longer strings are truncated.
+    pub(crate) fn to_field(data: &[u8]) -> [u8; USB_IDENTITY_STRING_LEN] {
+        let mut field = [0; USB_IDENTITY_STRING_LEN];
+        let len = data.len().min(USB_IDENTITY_STRING_LEN);
+        field[..len].copy_from_slice(&data[..len]);
+        field
+    }
+
+    // Erased flash is treated the same way as zero terminator.
+    fn as_str(field: &[u8]) -> Option<&str> {
+        let len = field.iter().position(|&b| b == 0 || b == 0xFF).unwrap_or(field.len());
+        core::str::from_utf8(&field[..len]).ok().filter(|s| !s.is_empty())
+    }
+}
+
+/// Noise of the idle pads of the first drum in LK, LD, RD, RK order, see [`super::calibration`].
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Calibration {
+    /// Standard deviation of samples in ADC counts.
+    pub sigma: [u16; 4],
+    /// Mean of samples relative to the ADC midpoint.
+    pub offset: [i16; 4],
 }
 
 /// Signal processing related configuration.
@@ -149,7 +1076,7 @@ pub struct HitMapping {
 /// Even piezos from the same batch will provide very different results. Those calibration values
 /// can be handly to calibrate the drum accordingly to inner sensors.
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SignalParsingConfiguration {
     /// Value in percents that define which deviation percentage is actually enough for piezo
     /// sensor to be count as a proper hit.
@@ -158,11 +1085,68 @@ pub struct SignalParsingConfiguration {
     _reserved: u8,
 }
 
+impl Default for DrumConfig {
+    #[inline(never)]
+    fn default() -> Self {
+        Self {
+            hit_mapping: HitMapping::default(),
+            parse_cfg: SignalParsingConfiguration::default(),
+            hid_mode: HidMode::default(),
+            midi_mode: MidiMode::default(),
+            consumer_mapping: ConsumerMapping::default(),
+            poll_interval: DEFAULT_HID_POLLING_MS,
+            p2_hit_mapping: HitMapping::player2(),
+            repeat_delay: 0,
+            repeat_rate: DEFAULT_REPEAT_RATE,
+            gesture_mapping: GestureMapping::default(),
+            velocity_axes: 1,
+            usb_identity: UsbIdentity::new(),
+            cdc_disabled: 0,
+            max_power: 0,
+            self_powered: 0,
+            profile: 0,
+            name: [0; PROFILE_NAME_LEN],
+            hits: [0; 4],
+            locked: 0,
+            calibration: Calibration::default(),
+            idle_sleep: DEFAULT_IDLE_SLEEP_S,
+        }
+    }
+}
+
+/* 
+ *  Compile-time presets of the default configuration, so builders can ship preconfigured units.
+ * */
+#[cfg(any(
+    all(feature = "preset-osu", feature = "preset-dfjk"),
+    all(feature = "preset-osu", feature = "preset-tnt"),
+    all(feature = "preset-dfjk", feature = "preset-tnt"),
+))]
+compile_error!("Only one of `preset-osu`, `preset-dfjk` and `preset-tnt` features can be enabled.");
+
+/// Default keys in LK, LD, RD, RK order: osu! stable bindings, D F J K otherwise.
+#[cfg(not(any(feature = "preset-dfjk", feature = "preset-tnt")))]
+const PRESET_KEYS: [KeyboardUsage; 4] = [
+    KeyboardUsage::KeyboardZz, KeyboardUsage::KeyboardXx, KeyboardUsage::KeyboardCc, KeyboardUsage::KeyboardVv,
+];
+#[cfg(any(feature = "preset-dfjk", feature = "preset-tnt"))]
+const PRESET_KEYS: [KeyboardUsage; 4] = [
+    KeyboardUsage::KeyboardDd, KeyboardUsage::KeyboardFf, KeyboardUsage::KeyboardJj, KeyboardUsage::KeyboardKk,
+];
+/// Default sensitivity and sharpness. osu! streams are played with light hits, while full swings
+/// on a bigger drum need a higher threshold to keep the crosstalk out.
+#[cfg(feature = "preset-osu")]
+const PRESET_PARSING: (u8, u16) = (70, 1500);
+#[cfg(feature = "preset-tnt")]
+const PRESET_PARSING: (u8, u16) = (90, 1500);
+#[cfg(not(any(feature = "preset-osu", feature = "preset-tnt")))]
+const PRESET_PARSING: (u8, u16) = (80, 1500);
+
 impl Default for SignalParsingConfiguration {
     fn default() -> Self {
         Self {
-            sensitivity: 80u8,
-            sharpness: 1500u16,
+            sensitivity: PRESET_PARSING.0,
+            sharpness: PRESET_PARSING.1,
             _reserved: 0u8,
         }
     }
@@ -170,11 +1154,40 @@ impl Default for SignalParsingConfiguration {
 
 impl Default for HitMapping {
     fn default() -> Self {
+        let [left_kat, left_don, right_don, right_kat] = PRESET_KEYS;
+        Self {
+            left_kat: left_kat.into(),
+            left_don: left_don.into(),
+            right_don: right_don.into(),
+            right_kat: right_kat.into(),
+        }
+    }
+}
+
+impl HitMapping {
+    /// Menu navigation mapping, which temporarily replaces the gameplay one: kats move the
+    /// selection, dons confirm it.
+    pub(crate) fn menu() -> Self {
+        Self {
+            left_kat: KeyboardUsage::KeyboardLeftArrow.into(),
+            left_don: KeyboardUsage::KeyboardEnter.into(),
+            right_don: KeyboardUsage::KeyboardEnter.into(),
+            right_kat: KeyboardUsage::KeyboardRightArrow.into(),
+        }
+    }
+
+    /// Mappings in LK, LD, RD, RK order.
+    fn keys_mut(&mut self) -> [&mut KeyMapping; 4] {
+        [&mut self.left_kat, &mut self.left_don, &mut self.right_don, &mut self.right_kat]
+    }
+
+    /// Default mapping of the second drum, which does not overlap with the first one.
+    fn player2() -> Self {
         Self {
-            left_kat: KeyboardUsage::KeyboardZz,
-            left_don: KeyboardUsage::KeyboardXx,
-            right_don: KeyboardUsage::KeyboardCc,
-            right_kat: KeyboardUsage::KeyboardVv,
+            left_kat: KeyboardUsage::KeyboardBb.into(),
+            left_don: KeyboardUsage::KeyboardNn.into(),
+            right_don: KeyboardUsage::KeyboardMm.into(),
+            right_kat: KeyboardUsage::KeyboardCommaLess.into(),
         }
     }
 }
@@ -0,0 +1,50 @@
+//! Detection of clone microcontrollers.
+//!
+//! Many "Blue Pill" boards carry GD32F103 or CH32F103 silicon instead of STM32F103. Clones run
+//! the same firmware, but their oscillators, USB transceiver and flash controller need more time
+//! to settle. Clones are recognized by the Cortex-M3 revision within CPUID register, since the
+//! debug ID code is not readable without a debugger on this line. Clones reporting the original
+//! core revision (e.g. some CH32F103 batches) can be forced with `clone-compat` feature.
+
+use cortex_m::peripheral::CPUID;
+
+/// Cortex-M3 r1p1 of the original STM32F103.
+const CPUID_STM32F103: u32 = 0x411F_C231;
+/// Cortex-M3 r2p1 of GD32F103.
+const CPUID_GD32F103: u32 = 0x412F_C231;
+
+/// Settle time after HSE and PLL are ready, which clones require before switching SYSCLK.
+pub(crate) const CLONE_CLOCK_SETTLE_CYCLES: u32 = 8_000;
+/// Clone flash controllers raise BSY flag a few cycles after the operation is started.
+pub(crate) const CLONE_FLASH_BSY_CYCLES: u32 = 16;
+/// USB transceiver startup time of clones is longer than 1 µs of the original.
+pub(crate) const CLONE_USB_STARTUP_CYCLES: u32 = 720;
+
+/// Microcontroller the firmware runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum Chip {
+    Stm32,
+    Gd32,
+    /// Unknown clone with the provided CPUID.
+    Clone(u32),
+}
+
+impl Chip {
+    /// Detects the microcontroller from the CPUID register.
+    #[inline(always)]
+    pub(crate) fn detect() -> Self {
+        match unsafe { (*CPUID::PTR).base.read() } {
+            CPUID_STM32F103 if cfg!(feature = "clone-compat") => Self::Clone(CPUID_STM32F103),
+            CPUID_STM32F103 => Self::Stm32,
+            CPUID_GD32F103 => Self::Gd32,
+            cpuid => Self::Clone(cpuid),
+        }
+    }
+
+    /// Clone specific initialization paths shall be used.
+    #[inline(always)]
+    pub(crate) fn is_clone(self) -> bool {
+        self != Self::Stm32
+    }
+}
@@ -0,0 +1,136 @@
+//! System clock configuration.
+//!
+//! The system runs from the PLL driven by the crystal oscillator (HSE). Bus prescalers are set
+//! before the switch, so no bus ever exceeds its limit, and each ready flag is only waited for a
+//! defined time. The USB clock is derived from the PLL as well, which must provide exactly 48 MHz,
+//! so that constraint is checked at compile time. Boards with 12 or 16 MHz crystals are supported
+//! by `hse-12mhz` and `hse-16mhz` features, which select the PLL settings for the same 72 MHz.
+//! Bench builds drive the PLL from the internal oscillator instead, which gives 64 MHz at most and
+//! leaves USB without its clock.
+
+use super::chip::{Chip, CLONE_CLOCK_SETTLE_CYCLES};
+use super::css::HSI_HZ;
+use super::pac::{FLASH, RCC};
+
+#[cfg(all(feature = "hse-12mhz", feature = "hse-16mhz"))]
+compile_error!("Only one of `hse-12mhz` and `hse-16mhz` features can be enabled.");
+
+/// Frequency of the crystal, 8 MHz unless the board carries another one.
+#[cfg(not(any(feature = "hse-12mhz", feature = "hse-16mhz")))]
+const HSE_HZ: u32 = 8_000_000;
+#[cfg(feature = "hse-12mhz")]
+const HSE_HZ: u32 = 12_000_000;
+#[cfg(feature = "hse-16mhz")]
+const HSE_HZ: u32 = 16_000_000;
+/// System clock, which is the maximal one on this line.
+#[cfg(not(feature = "bench"))]
+pub(crate) const SYSCLK_HZ: u32 = 72_000_000;
+/// Maximal system clock from the internal oscillator, which is halved before the PLL.
+#[cfg(feature = "bench")]
+pub(crate) const SYSCLK_HZ: u32 = 64_000_000;
+/// Clock of timers on APB1 (TIM2-TIM4). Its bus runs at a quarter of the system clock, and timers
+/// on a divided bus are clocked twice as fast as the bus.
+pub(crate) const APB1_TIMER_HZ: u32 = SYSCLK_HZ / 4 * 2;
+/// Crystals above 8 MHz are halved before the PLL (PLLXTPRE), so a single multiplier fits more of
+/// them.
+const PLL_HSE_DIV2: bool = HSE_HZ > 12_000_000;
+/// PLL input frequency.
+const PLL_IN_HZ: u32 = if cfg!(feature = "bench") { HSI_HZ / 2 } else { HSE_HZ >> PLL_HSE_DIV2 as u32 };
+/// PLL multiplier of the input frequency.
+const PLL_MUL: u32 = SYSCLK_HZ / PLL_IN_HZ;
+const _: () = assert!(
+    PLL_IN_HZ * PLL_MUL == SYSCLK_HZ && (PLL_MUL == 6 || PLL_MUL == 9 || PLL_MUL == 16),
+    "Crystal frequency does not give 72 MHz with a supported PLL multiplier."
+);
+/// Frequency required by the USB peripheral.
+const USB_HZ: u32 = 48_000_000;
+/// Polls of a ready flag before the clock is considered failed, about 100 ms on HSI.
+const READY_POLLS: u32 = 800;
+/// CPU cycles between polls of a ready flag.
+const READY_POLL_CYCLES: u32 = 1_000;
+
+/// USB prescaler of the PLL output: true - divided by 1, false - divided by 1.5. Returns [`None`]
+/// if neither of them gives 48 MHz.
+const fn usb_prescaler(pll_hz: u32) -> Option<bool> {
+    if pll_hz == USB_HZ {
+        Some(true)
+    } else if pll_hz * 2 == USB_HZ * 3 {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+const USB_PRESCALER: bool = match usb_prescaler(SYSCLK_HZ) {
+    Some(div1) => div1,
+    // USB is not used by bench builds.
+    None if cfg!(feature = "bench") => false,
+    None => panic!("PLL output must be 48 or 72 MHz to clock the USB peripheral."),
+};
+
+/// Reasons of clock configuration failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum ClockError {
+    /// Crystal oscillator did not start.
+    HseStartup,
+    /// PLL did not lock.
+    PllLock,
+    /// System clock was not switched to the PLL.
+    Switch,
+    /// Crystal oscillator stopped at runtime.
+    HseFailure,
+}
+
+/// Waits for the flag with the timeout.
+fn wait(error: ClockError, ready: impl Fn() -> bool) -> Result<(), ClockError> {
+    match (0..READY_POLLS).any(|_| {
+        cortex_m::asm::delay(READY_POLL_CYCLES);
+        ready()
+    }) {
+        true => Ok(()),
+        false => Err(error),
+    }
+}
+
+/// Switches the system clock to the PLL at [`SYSCLK_HZ`].
+///
+/// The system keeps running on HSI after a failure. Clocks, which were already started, are left
+/// running, since they are harmless while not selected.
+pub(crate) fn configure(rcc: &mut RCC, flash: &mut FLASH, chip: Chip) -> Result<(), ClockError> {
+    let hse = !cfg!(feature = "bench");
+    if hse {
+        rcc.cr.modify(|_, w| w.hseon().set_bit());
+        wait(ClockError::HseStartup, || rcc.cr.read().hserdy().bit_is_set())?;
+        if chip.is_clone() {
+            cortex_m::asm::delay(CLONE_CLOCK_SETTLE_CYCLES);
+        }
+    }
+
+    rcc.cfgr.modify(|_, w| {
+        /* Multiplying HSE by PLL_MUL to reach a maximal value of 72 MHz */
+        let w = w.pllsrc().bit(hse).pllxtpre().bit(PLL_HSE_DIV2);
+        match PLL_MUL {
+            6 => w.pllmul().mul6(),
+            16 => w.pllmul().mul16(),
+            _ => w.pllmul().mul9(),
+        }
+    });
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    wait(ClockError::PllLock, || rcc.cr.read().pllrdy().bit_is_set())?;
+    if chip.is_clone() {
+        cortex_m::asm::delay(CLONE_CLOCK_SETTLE_CYCLES);
+    }
+
+    flash.acr.modify(|_, w| w.latency().ws2());
+    rcc.cfgr.modify(|_, w|
+        w.ppre1().div4()            // Clock prescaler for low-freq area (18 MHz).
+         .usbpre().bit(USB_PRESCALER)
+        /* USB peripheral requires PCLK1 frequency to be greater than 8MHz. */
+    );
+
+    rcc.cfgr.modify(|_, w| w.sw().pll());
+    wait(ClockError::Switch, || rcc.cfgr.read().sws().is_pll()).inspect_err(|_| {
+        rcc.cfgr.modify(|_, w| w.sw().hsi());
+    })
+}
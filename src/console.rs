@@ -0,0 +1,98 @@
+//! Line-based text console multiplexed on the CDC serial port.
+//!
+//! Serial frames always start with the sync byte, which is never found in text, therefore bytes
+//! preceding it are taken as console input. Users can configure the drum from any terminal
+//! program without the dedicated utility, e.g. `show`, `set sens 80`, `save` or `stats`.
+
+use heapless::Vec;
+
+/// Longest line, which fits any command.
+pub(crate) const LINE_LEN: usize = 48;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// Command typed into the console.
+pub(crate) enum ConsoleCommand {
+    Help,
+    /// Prints current configuration.
+    Show,
+    /// Applies a configuration value without saving it. Holds the tag of the value.
+    Set(u8, u16),
+    /// Saves the configuration to flash.
+    Save,
+    /// Prints USB and sample processing counters.
+    Stats,
+    /// Unknown command or malformed arguments.
+    Invalid,
+}
+
+/// Console input state.
+pub(crate) struct Console {
+    line: Vec<u8, LINE_LEN>,
+}
+
+impl Console {
+    pub(crate) const fn new() -> Self {
+        Self { line: Vec::new() }
+    }
+
+    /// Takes the input byte and writes its echo. Returns true once the line is complete.
+    ///
+    /// Characters beyond the longest line are dropped, the same way as control ones.
+    pub(crate) fn feed(&mut self, byte: u8, echo: &mut impl core::fmt::Write) -> bool {
+        match byte {
+            b'\r' | b'\n' => {
+                echo.write_str("\r\n").ok();
+                return true;
+            },
+            BACKSPACE | DELETE if self.line.pop().is_some() => {
+                echo.write_str("\x08 \x08").ok();
+            },
+            b' '..=b'~' if self.line.push(byte).is_ok() => {
+                echo.write_char(byte as char).ok();
+            },
+            _ => (),
+        }
+        false
+    }
+
+    /// Takes the complete line, so the next one is received meanwhile.
+    pub(crate) fn take(&mut self) -> Vec<u8, LINE_LEN> {
+        core::mem::take(&mut self.line)
+    }
+}
+
+impl ConsoleCommand {
+    /// Parses the complete line. Empty lines are ignored.
+    pub(crate) fn parse(line: &[u8]) -> Option<Self> {
+        let mut words = core::str::from_utf8(line).unwrap_or_default().split_ascii_whitespace();
+        let command = match (words.next()?, words.next(), words.next()) {
+            ("help", None, _) => Self::Help,
+            ("show", None | Some("cfg"), None) => Self::Show,
+            ("save", None, _) => Self::Save,
+            ("stats", None, _) => Self::Stats,
+            ("set", Some(key), Some(value)) => match (super::prog::config_key(key), number(value)) {
+                (Some(tag), Some(value)) => Self::Set(tag, value),
+                _ => Self::Invalid,
+            },
+            _ => Self::Invalid,
+        };
+        Some(command)
+    }
+}
+
+/// Decimal number, which fits into 16 bits.
+fn number(word: &str) -> Option<u16> {
+    word.bytes().try_fold(0u16, |value, b| value.checked_mul(10)?.checked_add((b as char).to_digit(10)? as u16))
+}
+
+/// Text written into the serial queue. Text, which does not fit into it, is dropped.
+pub(crate) struct Text<'a, const N: usize>(pub(crate) &'a mut Vec<u8, N>);
+
+impl<const N: usize> core::fmt::Write for Text<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(N - self.0.len());
+        self.0.extend_from_slice(&s.as_bytes()[..len]).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Reports of panics, hard faults and clock failures kept across resets.
+//!
+//! The message and a minimal register snapshot are left within the uninitialized RAM, which
+//! survives system resets (reset button, watchdog and software resets), but not power loss. The
+//! crashed firmware never writes the flash, since erasing a configuration page from it risks
+//! losing the configuration. Instead, [`super::app::CfgCommit`] task keeps the report along with
+//! the configuration records on the next boot, which is restored into RAM once it is lost with
+//! power. The kind and location of the crash are also kept within the backup registers, which
+//! survive power loss with a battery on VBAT, so those are reported if the drum lost power before
+//! the report was kept in flash: the line of a panic or the flash offset of the instruction, which
+//! caused a hard fault. The report is read with the programmer after the next boot, until it is
+//! cleared or replaced by the next crash.
+
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+
+use super::cfg::DrumConfig;
+use super::flash::{CfgFlash, FlashError};
+use super::logger::Line;
+
+/// Value which is unlikely to be found in RAM after power on.
+const CRASH_MAGIC: u32 = 0xC0DE_DEAD;
+/// Registers of the snapshot.
+const REGS: usize = 6;
+/// Report with the kind, registers and message. Longer messages are truncated.
+pub(crate) const REPORT_LEN: usize = 1 + REGS * 4 + 96;
+
+/// Cause of the crash.
+#[repr(u8)]
+pub(crate) enum CrashKind {
+    Panic = 1,
+    HardFault = 2,
+    /// External clock failure, the drum kept running on HSI without USB.
+    ClockFailure = 3,
+}
+
+/// Report of the last crash: kind, big-endian PC, LR and xPSR of the faulting context, CFSR, HFSR
+/// and BFAR, followed by the message. PC of panics is unknown, LR is the return address of the
+/// panic handler and xPSR only holds the number of the active exception.
+struct Crash {
+    magic: u32,
+    len: usize,
+    report: [u8; REPORT_LEN],
+}
+
+/// Crash report, which survives the system reset.
+#[unsafe(link_section = ".uninit.CRASH_REPORT")]
+static mut CRASH_REPORT: MaybeUninit<Crash> = MaybeUninit::uninit();
+
+/// Start of the flash, which offsets of faulting instructions are relative to.
+const FLASH_BASE: u32 = 0x0800_0000;
+
+/// Keeps the report of a crash for the next boot. Location is the line of a panic or the flash
+/// offset of the faulting instruction in halfwords.
+pub(crate) fn record(kind: CrashKind, location: u16, pc: u32, lr: u32, xpsr: u32, message: core::fmt::Arguments) {
+    let scb = unsafe { &*SCB::PTR };
+    let crash = unsafe { &mut *core::ptr::addr_of_mut!(CRASH_REPORT).cast::<Crash>() };
+    let regs = [pc, lr, xpsr, scb.cfsr.read(), scb.hfsr.read(), scb.bfar.read()];
+    super::backup::record_crash(kind as u8, location);
+    crash.report[0] = kind as u8;
+    for (reg, bytes) in regs.iter().zip(crash.report[1..].chunks_exact_mut(4)) {
+        bytes.copy_from_slice(&reg.to_be_bytes());
+    }
+    let mut line = Line { buff: &mut crash.report, len: 1 + REGS * 4 };
+    line.write_fmt(message).ok();
+    crash.len = line.len;
+    crash.magic = CRASH_MAGIC;
+}
+
+/// Keeps the report of a panic for the next boot.
+pub(crate) fn record_panic(info: &core::panic::PanicInfo) {
+    let active = unsafe { (*SCB::PTR).icsr.read() } & 0x1FF;
+    let line = info.location().map_or(0, |l| l.line().min(u16::MAX as u32) as u16);
+    record(CrashKind::Panic, line, 0, cortex_m::register::lr::read(), active, format_args!("{}", info));
+}
+
+/// Restores the report kept in flash, once the one in RAM is lost with power.
+pub(crate) fn restore() {
+    let crash = unsafe { &mut *core::ptr::addr_of_mut!(CRASH_REPORT).cast::<Crash>() };
+    if crash.magic == CRASH_MAGIC && crash.len <= REPORT_LEN {
+        return;
+    }
+    crash.len = DrumConfig::stored_crash(&mut crash.report);
+    crash.magic = if crash.len > 0 { CRASH_MAGIC } else { 0 };
+}
+
+/// Keeps the report of the last crash in flash, so it survives power loss. Stored report is
+/// cleared along with the one in RAM.
+pub(crate) fn save(flash: &mut CfgFlash) -> Result<(), FlashError> {
+    let crash = unsafe { &*core::ptr::addr_of!(CRASH_REPORT).cast::<Crash>() };
+    let len = if crash.magic == CRASH_MAGIC && crash.len <= REPORT_LEN { crash.len } else { 0 };
+    DrumConfig::save_crash(flash, &crash.report[..len])
+}
+
+/// Copies the report of the last crash, returns zero if there is none.
+///
+/// Once the report is lost with power before it was kept in flash, a shorter one is made up of the
+/// backup registers: PC of hard faults is restored from the offset, other registers are zero and
+/// the message only tells the line of a panic.
+pub(crate) fn read(buff: &mut [u8]) -> usize {
+    let crash = unsafe { &*core::ptr::addr_of!(CRASH_REPORT).cast::<Crash>() };
+    if crash.magic == CRASH_MAGIC && crash.len <= REPORT_LEN {
+        buff[..crash.len].copy_from_slice(&crash.report[..crash.len]);
+        return crash.len;
+    }
+    let Some((kind, location)) = super::backup::last_crash() else {
+        return 0;
+    };
+    let pc = if kind == CrashKind::HardFault as u8 { FLASH_BASE + ((location as u32) << 1) } else { 0 };
+    buff[..1 + REGS * 4].fill(0);
+    buff[0] = kind;
+    buff[1..5].copy_from_slice(&pc.to_be_bytes());
+    let mut line = Line { buff, len: 1 + REGS * 4 };
+    if kind == CrashKind::Panic as u8 {
+        write!(line, "Panic at line {}, details lost with power", location).ok();
+    } else {
+        line.write_str("Details lost with power").ok();
+    }
+    line.len
+}
+
+/// Forgets the report of the last crash.
+pub(crate) fn clear() {
+    unsafe {
+        core::ptr::addr_of_mut!(CRASH_REPORT).cast::<u32>().write_volatile(0);
+    }
+    super::backup::record_crash(0, 0);
+}
+
+/// Nothing is recovered after a hard fault, so the drum restarts right away instead of hanging
+/// with keys held.
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let offset = (frame.pc().wrapping_sub(FLASH_BASE) >> 1).min(u16::MAX as u32) as u16;
+    record(CrashKind::HardFault, offset, frame.pc(), frame.lr(), frame.xpsr(), format_args!("Hard fault"));
+    SCB::sys_reset()
+}
@@ -1,6 +1,59 @@
-use fixed_fft::{fft_radix2_q15, Direction};
 use num_complex::Complex;
 
+/// Neighbourhood around the main correlation peak, which is not considered as a secondary peak.
+const PEAK_EXCLUSION_RADIUS: usize = 2;
+
+/// Result of cross-correlation between two signals.
+#[derive(Debug, Clone, Copy)]
+pub struct Correlation {
+    /// Delay of the signal relative to the reference in samples.
+    pub delay: isize,
+    /// Fractional part of the delay in range [-0.5, 0.5], obtained via parabolic interpolation
+    /// around the correlation peak.
+    pub fraction: f32,
+    /// Magnitude of the main correlation peak.
+    pub peak: i16,
+    /// Ratio between the highest value outside of the main peak and the main peak itself.
+    ///
+    /// Values close to 1.0 mean that there is no distinct delay between both signals and the
+    /// obtained delay is not trustworthy.
+    pub secondary_ratio: f32,
+}
+
+/// Amount of samples in each correlated signal.
+const N: usize = 256;
+
+/// Scratch area for FFT computation.
+///
+/// # RAM Budget
+///
+/// Holds two buffers of [`N`] complex Q15 values, which is 2 KiB in total. Those used to live on the
+/// task stack, which is quite dangerous on a 20 KiB part. The scratch area is a local resource of
+/// the single parser task and is shared by parsers of all drums, therefore it is allocated
+/// statically and accessed mutually exclusively.
+#[derive(Debug)]
+pub struct XcorrScratch {
+    signal: [Complex<i16>; N],
+    reference: [Complex<i16>; N],
+}
+
+impl XcorrScratch {
+    /// Creates a new zeroed scratch area.
+    pub const fn new() -> Self {
+        Self {
+            signal: [Complex { re: 0, im: 0 }; N],
+            reference: [Complex { re: 0, im: 0 }; N],
+        }
+    }
+}
+
+impl Correlation {
+    /// Delay of the signal relative to the reference with sub-sample precision.
+    pub fn precise_delay(&self) -> f32 {
+        self.delay as f32 + self.fraction
+    }
+}
+
 /// FFT-based Cross Correlation implementation
 ///
 /// This function calculates the cross-correlation by using frequency domain of both signals. This
@@ -8,16 +61,16 @@ use num_complex::Complex;
 /// frequency domain.
 ///
 /// Similar signals will cause cross-correlation output to provide bigger numeric values, where the
-/// biggest one shall correspond to the time delay between one signal and another.
+/// biggest one shall correspond to the time delay between one signal and another. Strength of the
+/// correlation is returned alongside the delay, so that weak correlations can be ignored.
 pub fn xcorr(
+    scratch: &mut XcorrScratch,
     signal: &[i16; 256],
     signal_median: i16,
     reference: &[i16; 256],
     reference_median: i16,
-) -> isize {
-    const N: usize = 256;
-    let mut buf_signal = [Complex { re: 0, im: 0 }; N];
-    let mut buf_reference = [Complex { re: 0, im: 0 }; N];
+) -> Correlation {
+    let XcorrScratch { signal: buf_signal, reference: buf_reference } = scratch;
 
     for i in 0..N {
         buf_signal[i].re = signal[i] - signal_median;
@@ -26,21 +79,116 @@ pub fn xcorr(
         buf_reference[i].im = 0;
     }
 
-    fft_radix2_q15(&mut buf_signal, Direction::ForwardScaled).unwrap();
-    fft_radix2_q15(&mut buf_reference, Direction::ForwardScaled).unwrap();
+    fft::forward(buf_signal);
+    fft::forward(buf_reference);
 
     // z1 = z1 x z2*
-    buf_signal.iter_mut()
-        .zip(buf_reference)
-        .for_each(|(z1, z2)| *z1 = *z1 * z2.conj());
+    conj_mul(buf_signal, buf_reference);
 
-    fft_radix2_q15(&mut buf_signal, Direction::Inverse).unwrap();
+    fft::inverse(buf_signal);
     buf_signal.rotate_right(N / 2);
 
-    let (max_idx, _) = buf_signal.iter()    // Maximum shall correspond to the delay value.
+    let (max_idx, peak) = buf_signal.iter()    // Maximum shall correspond to the delay value.
         .enumerate()
-        .max_by_key(|(_, z)| z.re)
+        .map(|(i, z)| (i, z.re))
+        .max_by_key(|&(_, re)| re)
         .unwrap();
 
-    max_idx as isize - (N / 2) as isize
+    let secondary = buf_signal.iter()
+        .enumerate()
+        .filter(|(i, _)| i.abs_diff(max_idx) > PEAK_EXCLUSION_RADIUS)
+        .map(|(_, z)| z.re)
+        .max()
+        .unwrap_or(0);
+
+    Correlation {
+        delay: max_idx as isize - (N / 2) as isize,
+        fraction: parabolic_offset(buf_signal, max_idx),
+        peak,
+        secondary_ratio: if peak > 0 { secondary.max(0) as f32 / peak as f32 } else { 1.0 },
+    }
+}
+
+/// Multiplies each bin of `z1` by the conjugate of the same bin of `z2`.
+///
+/// Done the same way for both FFT backends, so the correlation peak, which is compared against the
+/// fixed thresholds of the parser, has the same scale.
+fn conj_mul(z1: &mut [Complex<i16>; N], z2: &[Complex<i16>; N]) {
+    z1.iter_mut()
+        .zip(z2)
+        .for_each(|(z1, z2)| *z1 = *z1 * z2.conj());
+}
+
+/// Fits a parabola through the peak and its two neighbours and returns the offset of its vertex.
+///
+/// Allows to decide which pad was hit first even when the true delay is below one sample period.
+fn parabolic_offset(buf: &[Complex<i16>; N], idx: usize) -> f32 {
+    if idx == 0 || idx == N - 1 {
+        return 0.0;
+    }
+
+    let (l, c, r) = (buf[idx - 1].re as f32, buf[idx].re as f32, buf[idx + 1].re as f32);
+    let denom = l - 2.0 * c + r;
+
+    if denom == 0.0 {
+        0.0
+    } else {
+        (0.5 * (l - r) / denom).clamp(-0.5, 0.5)
+    }
+}
+
+/// Pure Rust radix-2 FFT backend.
+#[cfg(not(feature = "cmsis-dsp"))]
+mod fft {
+    use fixed_fft::{fft_radix2_q15, Direction};
+    use num_complex::Complex;
+
+    pub(super) fn forward(buf: &mut [Complex<i16>; 256]) {
+        fft_radix2_q15(buf, Direction::ForwardScaled).unwrap();
+    }
+
+    pub(super) fn inverse(buf: &mut [Complex<i16>; 256]) {
+        fft_radix2_q15(buf, Direction::Inverse).unwrap();
+    }
+}
+
+/// CMSIS-DSP (`arm_math`) FFT backend.
+///
+/// Uses the radix-4 Q15 transforms from the prebuilt Cortex-M3 CMSIS-DSP library. [`Complex`] is
+/// `#[repr(C)]`, so the buffers already match the interleaved `re, im` layout expected by the
+/// library.
+///
+/// The forward transform is scaled by 1/N the same way as the pure Rust one. The inverse
+/// transform of the library is scaled by 1/N as well, unlike the pure Rust one, so its output is
+/// scaled back up to keep the correlation peak comparable with the parser thresholds.
+#[cfg(feature = "cmsis-dsp")]
+mod fft {
+    use num_complex::Complex;
+
+    /// Mirror of `arm_cfft_instance_q15`.
+    #[repr(C)]
+    struct ArmCfftInstanceQ15 {
+        fft_len: u16,
+        p_twiddle: *const i16,
+        p_bit_rev_table: *const u16,
+        bit_rev_length: u16,
+    }
+
+    unsafe impl Sync for ArmCfftInstanceQ15 {}
+
+    unsafe extern "C" {
+        static arm_cfft_sR_q15_len256: ArmCfftInstanceQ15;
+
+        fn arm_cfft_q15(s: *const ArmCfftInstanceQ15, p1: *mut i16, ifft_flag: u8, bit_reverse_flag: u8);
+    }
+
+    pub(super) fn forward(buf: &mut [Complex<i16>; 256]) {
+        unsafe { arm_cfft_q15(&arm_cfft_sR_q15_len256, buf.as_mut_ptr() as *mut i16, 0, 1) }
+    }
+
+    pub(super) fn inverse(buf: &mut [Complex<i16>; 256]) {
+        unsafe { arm_cfft_q15(&arm_cfft_sR_q15_len256, buf.as_mut_ptr() as *mut i16, 1, 1) }
+        // Undoes the 1/N scaling of the library. Lowest bits are lost, which is below the noise.
+        buf.iter_mut().for_each(|z| z.re = z.re.saturating_mul(256));
+    }
 }
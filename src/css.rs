@@ -0,0 +1,48 @@
+//! Clock security system and the fallback to the internal oscillator.
+//!
+//! A bad crystal is a common fault of cheap boards. Its failure to start is detected by the
+//! timeouts of [`super::clocks`] at boot, while the clock security system (CSS) detects its
+//! failure at runtime, switches the system clock to HSI and raises NMI. USB cannot run from HSI,
+//! so in both cases the drum drops off the bus, which releases held keys, leaves a crash report
+//! for the next boot and keeps running on HSI with the error logged, instead of hanging.
+
+use cortex_m::peripheral::NVIC;
+use cortex_m_rt::exception;
+
+use super::clocks::ClockError;
+use super::crash::{self, CrashKind};
+use super::pac::{Interrupt, RCC};
+use super::usb::UsbTaikoDrum;
+
+/// Frequency of the internal oscillator, which runs the system after the failure.
+pub(crate) const HSI_HZ: u32 = 8_000_000;
+
+/// Enables the clock security system, once the system runs from the PLL.
+pub(crate) fn enable(rcc: &mut RCC) {
+    rcc.cr.modify(|_, w| w.csson().set_bit());
+}
+
+/// Continues on HSI after the clock failure. The USB device shall be created beforehand, so the
+/// transceiver is powered down for good.
+pub(crate) fn fallback(err: ClockError) {
+    crash::record(CrashKind::ClockFailure, 0, 0, 0, 0, format_args!("{:?}", err));
+    UsbTaikoDrum::disconnect();
+    NVIC::mask(Interrupt::USB_HP_CAN_TX);
+    NVIC::mask(Interrupt::USB_LP_CAN_RX0);
+    #[cfg(feature = "uart-log")]
+    super::logger::set_uart_clock(HSI_HZ);
+    crate::error!("Clock failure: {:?}. Running on HSI with USB disabled.", err);
+}
+
+/// Hardware already switched the system clock to HSI and stopped the PLL. Timings based on the
+/// system clock, such as the monotonic timer and sampling, are slower from now on.
+///
+/// NMI preempts critical sections, so the error line might interleave with another one.
+#[exception]
+unsafe fn NonMaskableInt() {
+    let rcc = unsafe { &*RCC::ptr() };
+    if rcc.cir.read().cssf().bit_is_set() {
+        rcc.cir.write(|w| w.cssc().set_bit());
+        fallback(ClockError::HseFailure);
+    }
+}
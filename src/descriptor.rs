@@ -0,0 +1,189 @@
+//! HID report descriptor generation.
+//!
+//! The drum interface descriptor is built once during initialization from the current
+//! configuration, so the report layout follows the configured mode, amount of players and
+//! optional velocity axes without separate firmware builds.
+
+use super::hid::{HidMode, PLAYER1_REPORT_ID, PLAYER2_REPORT_ID, HORI_REPORT_DESCRIPTOR};
+
+/// Largest generated descriptor, which is two keyboard collections.
+const REPORT_DESCRIPTOR_CAPACITY: usize = 160;
+
+/* Short item prefixes without the size bits. */
+const USAGE_PAGE: u8 = 0x04;
+const USAGE: u8 = 0x08;
+const USAGE_MIN: u8 = 0x18;
+const USAGE_MAX: u8 = 0x28;
+const LOGICAL_MIN: u8 = 0x14;
+const LOGICAL_MAX: u8 = 0x24;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const INPUT: u8 = 0x80;
+const OUTPUT: u8 = 0x90;
+const COLLECTION: u8 = 0xA0;
+const END_COLLECTION: u8 = 0xC0;
+
+/* Usage pages and usages. */
+const PAGE_GENERIC_DESKTOP: u8 = 0x01;
+const PAGE_KEYBOARD: u8 = 0x07;
+const PAGE_LEDS: u8 = 0x08;
+const PAGE_BUTTON: u8 = 0x09;
+const USAGE_GAMEPAD: u8 = 0x05;
+const USAGE_KEYBOARD: u8 = 0x06;
+const USAGE_X: u8 = 0x30;
+const COLLECTION_APPLICATION: u8 = 0x01;
+
+/* Main item flags. */
+const DATA_ARRAY: u8 = 0x00;
+const CONSTANT: u8 = 0x01;
+const DATA_VARIABLE: u8 = 0x02;
+
+/// Parameters that define the layout of the drum reports.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReportLayout {
+    /// Reporting mode.
+    pub(crate) mode: HidMode,
+    /// Amount of drums. Each one gets its own keyboard collection in keyboard mode.
+    pub(crate) players: usize,
+    /// Amount of pads per drum.
+    pub(crate) pads: u8,
+    /// Hit strength of each pad is reported as an axis in gamepad mode.
+    pub(crate) velocity_axes: bool,
+}
+
+impl ReportLayout {
+    /// Input reports are prefixed with report ID.
+    pub(crate) fn report_ids(&self) -> bool {
+        self.mode == HidMode::Keyboard && self.players > 1
+    }
+
+    /// Length of the gamepad input report in bytes.
+    pub(crate) fn gamepad_report_len(&self) -> usize {
+        let buttons = (self.pads as usize).div_ceil(8);
+        buttons + if self.velocity_axes { self.pads as usize } else { 0 }
+    }
+}
+
+/// Report descriptor buffer, which must outlive the USB device.
+pub(crate) struct ReportDescriptor {
+    buff: [u8; REPORT_DESCRIPTOR_CAPACITY],
+    len: usize,
+}
+
+impl ReportDescriptor {
+    /// Creates an empty descriptor.
+    pub(crate) const fn new() -> Self {
+        Self { buff: [0; REPORT_DESCRIPTOR_CAPACITY], len: 0 }
+    }
+
+    /// Generates the descriptor for the provided layout, replacing the previous one.
+    pub(crate) fn build(&mut self, layout: ReportLayout) -> &[u8] {
+        self.len = 0;
+        match layout.mode {
+            HidMode::Keyboard => {
+                for id in [PLAYER1_REPORT_ID, PLAYER2_REPORT_ID].into_iter().take(layout.players) {
+                    self.keyboard(layout.report_ids().then_some(id));
+                }
+            },
+            HidMode::Gamepad => self.gamepad(layout.pads, layout.velocity_axes),
+            HidMode::Hori => { self.bytes(HORI_REPORT_DESCRIPTOR); },
+        }
+
+        crate::info!("Generated report descriptor of {} bytes.", self.len);
+        &self.buff[..self.len]
+    }
+
+    /// Boot compatible keyboard collection with six keys rollover and LEDs.
+    fn keyboard(&mut self, id: Option<u8>) {
+        self.item(USAGE_PAGE, PAGE_GENERIC_DESKTOP)
+            .item(USAGE, USAGE_KEYBOARD)
+            .item(COLLECTION, COLLECTION_APPLICATION);
+        if let Some(id) = id {
+            self.item(REPORT_ID, id);
+        }
+
+        // Modifiers.
+        self.item(USAGE_PAGE, PAGE_KEYBOARD)
+            .item(USAGE_MIN, 0xE0)
+            .item(USAGE_MAX, 0xE7)
+            .item(LOGICAL_MIN, 0)
+            .item(LOGICAL_MAX, 1)
+            .item(REPORT_SIZE, 1)
+            .item(REPORT_COUNT, 8)
+            .item(INPUT, DATA_VARIABLE)
+            // Reserved byte.
+            .item(REPORT_SIZE, 8)
+            .item(REPORT_COUNT, 1)
+            .item(INPUT, CONSTANT)
+            // LEDs are accepted, but not present on the drum.
+            .item(USAGE_PAGE, PAGE_LEDS)
+            .item(USAGE_MIN, 0x01)
+            .item(USAGE_MAX, 0x05)
+            .item(REPORT_SIZE, 1)
+            .item(REPORT_COUNT, 5)
+            .item(OUTPUT, DATA_VARIABLE)
+            .item(REPORT_SIZE, 3)
+            .item(REPORT_COUNT, 1)
+            .item(OUTPUT, CONSTANT)
+            // Keycodes.
+            .item(USAGE_PAGE, PAGE_KEYBOARD)
+            .item(USAGE_MIN, 0x00)
+            .item(USAGE_MAX, 0xDD)
+            .logical_max_u8()
+            .item(REPORT_SIZE, 8)
+            .item(REPORT_COUNT, 6)
+            .item(INPUT, DATA_ARRAY)
+            .end_collection();
+    }
+
+    /// Gamepad collection with a button per pad and optional velocity axes.
+    fn gamepad(&mut self, pads: u8, velocity_axes: bool) {
+        let padding = pads.next_multiple_of(8) - pads;
+        self.item(USAGE_PAGE, PAGE_GENERIC_DESKTOP)
+            .item(USAGE, USAGE_GAMEPAD)
+            .item(COLLECTION, COLLECTION_APPLICATION)
+            .item(USAGE_PAGE, PAGE_BUTTON)
+            .item(USAGE_MIN, 1)
+            .item(USAGE_MAX, pads)
+            .item(LOGICAL_MIN, 0)
+            .item(LOGICAL_MAX, 1)
+            .item(REPORT_SIZE, 1)
+            .item(REPORT_COUNT, pads)
+            .item(INPUT, DATA_VARIABLE);
+        if padding != 0 {
+            self.item(REPORT_COUNT, padding).item(INPUT, CONSTANT);
+        }
+
+        if velocity_axes {
+            self.item(USAGE_PAGE, PAGE_GENERIC_DESKTOP)
+                .item(USAGE_MIN, USAGE_X)
+                .item(USAGE_MAX, USAGE_X + pads - 1)
+                .logical_max_u8()
+                .item(REPORT_SIZE, 8)
+                .item(REPORT_COUNT, pads)
+                .item(INPUT, DATA_VARIABLE);
+        }
+        self.end_collection();
+    }
+
+    /// Appends a short item with a single byte of data.
+    fn item(&mut self, prefix: u8, data: u8) -> &mut Self {
+        self.bytes(&[prefix | 0x01, data])
+    }
+
+    /// Logical maximum of 255, which must be encoded with two bytes, since logical values are signed.
+    fn logical_max_u8(&mut self) -> &mut Self {
+        self.bytes(&[LOGICAL_MAX | 0x02, 0xFF, 0x00])
+    }
+
+    fn end_collection(&mut self) -> &mut Self {
+        self.bytes(&[END_COLLECTION])
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.buff[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+        self
+    }
+}
@@ -0,0 +1,106 @@
+//! USB DFU run-time class, which lets flashing tools switch the drum into the bootloader.
+//!
+//! Implements the run-time part of the DFU 1.1 specification: an interface without endpoints,
+//! which only accepts DFU_DETACH request. After the detach the drum reboots into the bootloader
+//! (see [`super::bootloader`]), so no BOOT0 jumper is required to update the firmware.
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const USB_SUBCLASS_DFU: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+
+const DFU_FUNCTIONAL_DESC_TYPE: u8 = 0x21;
+/// bitWillDetach, bitManifestationTolerant and bitCanDnload.
+const DFU_ATTRIBUTES: u8 = 0x0D;
+/// Time in milliseconds, during which the host shall wait for the device to detach.
+const DFU_DETACH_TIMEOUT_MS: u16 = 255;
+/// Largest transfer accepted by the bootloader.
+const DFU_TRANSFER_SIZE: u16 = 64;
+
+/* DFU class requests. */
+const DFU_DETACH: u8 = 0x00;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_GETSTATE: u8 = 0x05;
+/// Run-time state, in which the application is running normally.
+const DFU_STATE_APP_IDLE: u8 = 0x00;
+
+/// DFU run-time interface.
+pub(crate) struct DfuRuntimeClass {
+    if_num: InterfaceNumber,
+    /// DFU_DETACH was obtained and not handled yet.
+    detach: bool,
+}
+
+impl DfuRuntimeClass {
+    /// Allocates the interface. No endpoints are required.
+    pub(crate) fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        Self { if_num: alloc.interface(), detach: false }
+    }
+
+    /// Takes the detach request. The bootloader shall only be entered after the control transfer
+    /// is completed, so the host does not treat the detach as failed.
+    pub(crate) fn take_detach(&mut self) -> bool {
+        core::mem::take(&mut self.detach)
+    }
+
+    fn is_own_request(&self, req: &control::Request) -> bool {
+        req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.if_num) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntimeClass {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.if_num, USB_CLASS_APPLICATION_SPECIFIC, USB_SUBCLASS_DFU, DFU_PROTOCOL_RUNTIME)?;
+        let [timeout_lo, timeout_hi] = DFU_DETACH_TIMEOUT_MS.to_le_bytes();
+        let [size_lo, size_hi] = DFU_TRANSFER_SIZE.to_le_bytes();
+        writer.write(DFU_FUNCTIONAL_DESC_TYPE, &[
+            DFU_ATTRIBUTES,
+            timeout_lo, timeout_hi,
+            size_lo, size_hi,
+            0x10, 0x01,     // bcdDFUVersion 1.1
+        ])
+    }
+
+    fn reset(&mut self) {
+        self.detach = false;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_own_request(&req) {
+            return;
+        }
+
+        match req.request {
+            // Status OK, no poll timeout, appIDLE state and no status string.
+            DFU_GETSTATUS => xfer.accept_with(&[0x00, 0x00, 0x00, 0x00, DFU_STATE_APP_IDLE, 0x00]).ok(),
+            DFU_GETSTATE => xfer.accept_with(&[DFU_STATE_APP_IDLE]).ok(),
+            _ => xfer.reject().ok(),
+        };
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_own_request(&req) {
+            return;
+        }
+
+        match req.request {
+            // Flashing tools would wait for a DFU device, which never shows up.
+            DFU_DETACH if !super::bootloader::available() => {
+                crate::warn!("DFU detach is refused, no bootloader is configured.");
+                xfer.reject().ok()
+            },
+            DFU_DETACH => {
+                crate::info!("DFU detach was requested.");
+                self.detach = true;
+                xfer.accept().ok()
+            },
+            _ => xfer.reject().ok(),
+        };
+    }
+}
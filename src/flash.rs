@@ -0,0 +1,227 @@
+//! Access to the configuration and firmware update regions of the flash memory.
+//!
+//! Configuration is stored within the `CFG` region of `memory.x`. All reads and writes of that
+//! region go through [`CfgFlash`], which takes offsets from the start of the region, checks them
+//! against its bounds and only touches the flash with volatile accesses. Errors are returned to
+//! the caller instead of panicking, so the unsafe surface of the configuration storage is limited
+//! to this module. Firmware updates are staged within the `UPDATE` region the same way, see
+//! [`super::update`].
+
+use super::pac::FLASH;
+use super::chip::{Chip, CLONE_FLASH_BSY_CYCLES};
+use core::ptr;
+
+/*
+ *  Holds start and end addresses of the last two kilobytes of flash, used to store drum's configuration.
+ * */
+unsafe extern "C" {
+    static __cfg_start: u8;
+    static __cfg_end: u8;
+    static __update_start: u8;
+    static __update_end: u8;
+}
+
+/// Size of a single flash page, which is the smallest erasable unit.
+pub(crate) const PAGE_SIZE: usize = 1024;
+/// Value of the erased flash half-word.
+pub(crate) const ERASED: u16 = 0xFFFF;
+
+/// Configuration flash access errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum FlashError {
+    /// Access beyond the configuration region.
+    OutOfBounds,
+    /// Half-word access at an odd offset or page erase within the page.
+    Unaligned,
+    /// Programmed half-word is not erased.
+    NotErased,
+    /// Value read back differs from the written one.
+    Verify,
+}
+
+/// Owner of the flash controller, which is only used to store the configuration and firmware updates.
+pub(crate) struct CfgFlash {
+    flash: FLASH,
+}
+
+impl CfgFlash {
+    /// Takes the flash controller over. Wait states shall be configured before.
+    pub(crate) fn new(flash: FLASH) -> Self {
+        Self { flash }
+    }
+
+    /// Size of the configuration region in bytes.
+    #[inline(always)]
+    pub(crate) fn len() -> usize {
+        Self::__end() as usize - Self::__start() as usize
+    }
+
+    /// Copies bytes at the provided offset into the buffer.
+    pub(crate) fn read(offset: usize, buff: &mut [u8]) -> Result<(), FlashError> {
+        let src = Self::__ptr(offset, buff.len())?;
+        for (i, b) in buff.iter_mut().enumerate() {
+            *b = unsafe { ptr::read_volatile(src.add(i)) };
+        }
+        Ok(())
+    }
+
+    /// Reads the half-word at the provided offset.
+    #[inline(always)]
+    pub(crate) fn read_u16(offset: usize) -> Result<u16, FlashError> {
+        let src = Self::__aligned(offset)?;
+        Ok(unsafe { ptr::read_volatile(src) })
+    }
+
+    /// All bytes within the provided range are erased.
+    pub(crate) fn is_erased(offset: usize, len: usize) -> Result<bool, FlashError> {
+        let src = Self::__ptr(offset, len)?;
+        Ok((0..len).all(|i| unsafe { ptr::read_volatile(src.add(i)) } == 0xFF))
+    }
+
+    /// Erases the page starting at the provided offset.
+    #[inline(always)]
+    pub(crate) fn erase(&mut self, offset: usize) -> Result<(), FlashError> {
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(FlashError::Unaligned);
+        }
+        self.__erase(Self::__ptr(offset, PAGE_SIZE)?)
+    }
+
+    /// Programs the erased half-word at the provided offset.
+    #[inline(always)]
+    pub(crate) fn program(&mut self, offset: usize, word: u16) -> Result<(), FlashError> {
+        self.__program(Self::__aligned(offset)?, word)
+    }
+
+    /// Size of the firmware update region in bytes.
+    #[inline(always)]
+    pub(crate) fn update_len() -> usize {
+        unsafe { &__update_end as *const u8 as usize - &__update_start as *const u8 as usize }
+    }
+
+    /// Bytes of the firmware update region within the provided range.
+    ///
+    /// Staged firmware is only read back after it is programmed, so it is not read volatile.
+    pub(crate) fn update(offset: usize, len: usize) -> Result<&'static [u8], FlashError> {
+        let src = Self::__update_ptr(offset, len)?;
+        Ok(unsafe { core::slice::from_raw_parts(src, len) })
+    }
+
+    /// Erases the page of the firmware update region starting at the provided offset.
+    pub(crate) fn erase_update(&mut self, offset: usize) -> Result<(), FlashError> {
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(FlashError::Unaligned);
+        }
+        self.__erase(Self::__update_ptr(offset, PAGE_SIZE)?)
+    }
+
+    /// Programs the erased half-word of the firmware update region at the provided offset.
+    pub(crate) fn program_update(&mut self, offset: usize, word: u16) -> Result<(), FlashError> {
+        if !offset.is_multiple_of(2) {
+            return Err(FlashError::Unaligned);
+        }
+        self.__program(Self::__update_ptr(offset, 2)? as *const u16, word)
+    }
+
+    #[inline(never)]
+    #[unsafe(link_section = ".data")]
+    fn __erase(&mut self, page: *const u8) -> Result<(), FlashError> {
+        self.__unlock();
+        self.__bsy(|f| {
+            f.cr.modify(|_, w| w.per().set_bit());
+            f.ar.write(|w| w.far().variant(page as u32));   /* Erasing the page within the provided address. */
+            f.cr.modify(|_, w| w.strt().set_bit());
+        });
+        self.flash.cr.modify(|_, w| w.per().clear_bit());
+
+        match (0..PAGE_SIZE).all(|i| unsafe { ptr::read_volatile(page.add(i)) } == 0xFF) {
+            true => Ok(()),
+            false => Err(FlashError::Verify),
+        }
+    }
+
+    #[inline(never)]
+    #[unsafe(link_section = ".data")]
+    fn __program(&mut self, dst: *const u16, word: u16) -> Result<(), FlashError> {
+        let dst = dst as *mut u16;
+        if unsafe { ptr::read_volatile(dst) } != ERASED {
+            return Err(FlashError::NotErased);
+        }
+
+        self.__unlock();
+        self.__bsy(|f| {
+            f.cr.modify(|_, w| w.pg().set_bit());
+            unsafe { ptr::write_volatile(dst, word) };
+        });
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+
+        match unsafe { ptr::read_volatile(dst) } == word {
+            true => Ok(()),
+            false => Err(FlashError::Verify),
+        }
+    }
+
+    #[inline(always)]
+    fn __start() -> *const u8 {
+        unsafe { &__cfg_start as *const u8 }
+    }
+
+    #[inline(always)]
+    fn __end() -> *const u8 {
+        unsafe { &__cfg_end as *const u8 }
+    }
+
+    // Address of the provided range, which must lie within the configuration region.
+    #[inline(always)]
+    fn __ptr(offset: usize, len: usize) -> Result<*const u8, FlashError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= Self::len() => Ok(Self::__start().wrapping_add(offset)),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    // Address of the provided range, which must lie within the firmware update region.
+    #[inline(always)]
+    fn __update_ptr(offset: usize, len: usize) -> Result<*const u8, FlashError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= Self::update_len() => Ok(unsafe { &__update_start as *const u8 }.wrapping_add(offset)),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    // Address of the half-word at the provided offset.
+    #[inline(always)]
+    fn __aligned(offset: usize) -> Result<*const u16, FlashError> {
+        if !offset.is_multiple_of(2) {
+            return Err(FlashError::Unaligned);
+        }
+        Self::__ptr(offset, 2).map(|ptr| ptr as *const u16)
+    }
+
+    // All write flash operations must be done while the flash is not busy.
+    #[inline(always)]
+    fn __bsy<F>(&mut self, f: F) where
+        F: FnOnce(&mut FLASH)
+    {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+        f(&mut self.flash);
+        if Chip::detect().is_clone() {
+            cortex_m::asm::delay(CLONE_FLASH_BSY_CYCLES);
+        }
+        while self.flash.sr.read().bsy().bit_is_set() {}
+    }
+
+    // If flash is locked on reboot, it shall be unlocked via two-key sequence.
+    #[inline(always)]
+    fn __unlock(&mut self) {
+        const KEY1: u32 = 0x45670123;
+        const KEY2: u32 = 0xcdef89ab;
+
+        if self.flash.cr.read().lock().bit_is_set() {
+            crate::info!("Flash is locked. Unlocking...");
+            self.flash.keyr.write(|w| w.key().variant(KEY1));
+            self.flash.keyr.write(|w| w.key().variant(KEY2));
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Gesture engine, which maps pad patterns to additional keys.
+//!
+//! Allows players to operate game menus without touching the keyboard. Recognized gestures:
+//! - Both kats hit simultaneously;
+//! - Both dons hit simultaneously;
+//! - Any don held for [`HOLD_DURATION_MS`];
+//!
+//! Each gesture emits a single tap of the configured key. Gestures with zero key are disabled,
+//! while [`super::cfg::GESTURE_MENU_TOGGLE`] switches menu navigation mode on and off.
+//!
+//! Both kats held right after boot restore the factory configuration. This gesture is not
+//! configurable, since a broken configuration is the reason to use it.
+
+use super::cfg::GestureMapping;
+
+/// Time after which held don is recognized as a gesture.
+const HOLD_DURATION_MS: u32 = 2000;
+/// Time for which both kats shall be held to restore the factory configuration.
+const FACTORY_RESET_HOLD_MS: u32 = 3000;
+/// Factory reset hold shall start within this time after boot.
+const FACTORY_RESET_WINDOW_MS: u32 = 10_000;
+
+/* Pad indexes in LK, LD, RD, RK order. */
+const LK: usize = 0;
+const LD: usize = 1;
+const RD: usize = 2;
+const RK: usize = 3;
+
+/// Gesture recognition state.
+#[derive(Debug)]
+pub(crate) struct Gestures {
+    /// Pads state from the previous update.
+    previous: [bool; 4],
+    /// Time when dons started being held.
+    hold_start: Option<u32>,
+    /// Hold gesture was already emitted for the current hold.
+    hold_fired: bool,
+    /// Time when kats started being held after boot.
+    kats_start: Option<u32>,
+}
+
+impl Gestures {
+    /// Creates a new gesture engine with all pads released.
+    pub(crate) const fn new() -> Self {
+        Self { previous: [false; 4], hold_start: None, hold_fired: false, kats_start: None }
+    }
+
+    /// Returns true once both kats were held for [`FACTORY_RESET_HOLD_MS`] right after boot.
+    pub(crate) fn factory_reset(&mut self, pads: [bool; 4], now_ms: u32) -> bool {
+        match (pads[LK] && pads[RK], self.kats_start) {
+            (true, None) if now_ms < FACTORY_RESET_WINDOW_MS => {
+                self.kats_start = Some(now_ms);
+                false
+            },
+            (true, Some(start)) if now_ms.wrapping_sub(start) >= FACTORY_RESET_HOLD_MS => {
+                self.kats_start = None;
+                true
+            },
+            (false, _) => {
+                self.kats_start = None;
+                false
+            },
+            _ => false,
+        }
+    }
+
+    /// Updates the state with current pads and returns a keycode of the recognized gesture.
+    pub(crate) fn update(&mut self, pads: [bool; 4], now_ms: u32, mapping: GestureMapping) -> Option<u8> {
+        let previous = core::mem::replace(&mut self.previous, pads);
+        let pressed = |a: usize, b: usize| pads[a] && pads[b] && !(previous[a] && previous[b]);
+
+        let dons_held = pads[LD] || pads[RD];
+        let hold = match (dons_held, self.hold_start) {
+            (true, None) => {
+                self.hold_start = Some(now_ms);
+                false
+            },
+            (true, Some(start)) => !self.hold_fired && now_ms.wrapping_sub(start) >= HOLD_DURATION_MS,
+            (false, _) => {
+                self.hold_start = None;
+                self.hold_fired = false;
+                false
+            },
+        };
+
+        [
+            (pressed(LK, RK), mapping.both_kats),
+            (pressed(LD, RD), mapping.both_dons),
+            (hold, mapping.hold_don),
+        ]
+        .into_iter()
+        .find(|&(recognized, key)| recognized && key != 0)
+        .map(|(_, key)| {
+            self.hold_fired |= hold;
+            key
+        })
+    }
+}
@@ -0,0 +1,92 @@
+//! Pipeline health monitor.
+//!
+//! Samples flow from the ADC interrupt through the sample queue to the parser and from there
+//! through the report queue to the USB endpoints. Each stage records here, when it falls behind:
+//! queue high-water marks, samples parsed slower than they are taken, sampling paused by the full
+//! sample queue and reports, which could not be sent. Paused sampling and failed sends mean
+//! delayed or lost hits, so those raise a warning, which is blinked on the status LED and
+//! returned by the programmer until it is read.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::piezo::SAMPLE_PERIOD_CYCLES;
+
+/// Events of the pipeline falling behind.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Lag {
+    /// Sample queue was full, so the sampling was paused.
+    SamplingPaused,
+    /// Report was not queued or sent to the host.
+    SendFailure,
+}
+
+/// Flashes of the warning pattern and their duration in milliseconds.
+pub(crate) const FLICKER_BLINKS: u32 = 5;
+pub(crate) const FLICKER_MS: u32 = 50;
+
+/// Samples currently waiting in the sample queue.
+static SAMPLES_QUEUED: AtomicU32 = AtomicU32::new(0);
+/// High-water marks of the sample and report queues.
+static SAMPLE_PEAK: AtomicU32 = AtomicU32::new(0);
+static REPORT_PEAK: AtomicU32 = AtomicU32::new(0);
+/// Samples, which took longer than the sampling period to parse.
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static PAUSES: AtomicU32 = AtomicU32::new(0);
+static SEND_FAILURES: AtomicU32 = AtomicU32::new(0);
+/// Pipeline fell behind since the figures were read.
+static WARNING: AtomicBool = AtomicBool::new(false);
+
+/// Records the sample added to the sample queue.
+pub(crate) fn sample_queued() {
+    let queued = SAMPLES_QUEUED.fetch_add(1, Ordering::Relaxed) + 1;
+    SAMPLE_PEAK.fetch_max(queued, Ordering::Relaxed);
+}
+
+/// Records the sample taken from the sample queue by the parser.
+pub(crate) fn sample_taken() {
+    SAMPLES_QUEUED.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Records the length of the report queue after a report is added.
+pub(crate) fn reports_queued(len: usize) {
+    REPORT_PEAK.fetch_max(len as u32, Ordering::Relaxed);
+}
+
+/// Records the time taken by the parser to process a single sample in CPU cycles.
+///
+/// Single overruns are expected during second stage processing of hits and absorbed by the
+/// sample queue, so those are only counted.
+pub(crate) fn parsed(cycles: u32) {
+    if cycles > SAMPLE_PERIOD_CYCLES {
+        OVERRUNS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records the lag and raises the warning.
+pub(crate) fn record(lag: Lag) {
+    match lag {
+        Lag::SamplingPaused => PAUSES.fetch_add(1, Ordering::Relaxed),
+        Lag::SendFailure => SEND_FAILURES.fetch_add(1, Ordering::Relaxed),
+    };
+    if !WARNING.swap(true, Ordering::Relaxed) {
+        crate::warn!("Pipeline fell behind: {:?}", lag);
+    }
+}
+
+/// Warning is raised.
+pub(crate) fn warning() -> bool {
+    WARNING.load(Ordering::Relaxed)
+}
+
+/// Returns the warning flag, sample and report queue high-water marks, parser overruns, sampling
+/// pauses and send failures since boot, and clears the warning.
+pub(crate) fn take() -> [u32; 6] {
+    [
+        WARNING.swap(false, Ordering::Relaxed) as u32,
+        SAMPLE_PEAK.load(Ordering::Relaxed),
+        REPORT_PEAK.load(Ordering::Relaxed),
+        OVERRUNS.load(Ordering::Relaxed),
+        PAUSES.load(Ordering::Relaxed),
+        SEND_FAILURES.load(Ordering::Relaxed),
+    ]
+}
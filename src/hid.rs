@@ -2,55 +2,69 @@
 
 pub(crate) use usbd_hid::descriptor::{generator_prelude::*, *};
 
-pub(crate) const USB_HID_CLASS_POLLING_MS: u8 = 60;
+use usb_device::class_prelude::*;
+
+use super::cfg::KeyMapping;
+use super::midi::MidiNoteEvents;
+
+/// Default polling interval of HID endpoints. Full-speed devices can be polled every millisecond,
+/// anything slower directly adds to the input lag.
+pub(crate) const DEFAULT_HID_POLLING_MS: u8 = 1;
 
 /// Drum Stroke HID Class Report.
 ///
 /// Acts as a keyboard device that sends corresponding keycodes mapped to the corresponding hitstrokes
 /// obtained from the four drum sensors. Allows to play from the Taiko Drum just like from regular
 /// keyboard.
-#[gen_hid_descriptor(
-    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
-        (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
-            #[packed_bits 8] #[item_settings data,variable,absolute] _modifier=input;
-        };
-        (usage_min = 0x00, usage_max = 0xFF) = {
-            #[item_settings constant,variable,absolute] _reserved=input;
-        };
-        (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
-            #[packed_bits 5] #[item_settings data,variable,absolute] _leds=output;
-        };
-        (usage_page = KEYBOARD, usage_min = 0x00, usage_max = 0xDD) = {
-            #[item_settings data,array,absolute] keycode=input;
-        };
-    }
-)]
-#[allow(dead_code)]
-#[derive(Default)]
+#[derive(Debug, Default, Clone, Copy)]
 pub(crate) struct DrumHitStrokeHidReport {
-    _modifier: u8,
-    _reserved: u8,
-    _leds: u8,
+    modifier: u8,
     keycode: [u8; 6],
 }
 
 impl DrumHitStrokeHidReport {
     /// Generates new keystroke HID report from the provided pressed keys.
     ///
+    /// Modifiers of all pressed keys are combined into the single modifier byte.
+    ///
     /// # Iterator
     ///
     /// Input iterator must be an iterator with maximum capacity of 6 elements. More elements will
     /// be ignored.
     pub(crate) fn new<I>(keys: I) -> Self where
-        I: IntoIterator<Item = KeyboardUsage>,
+        I: IntoIterator<Item = KeyMapping>,
     {
-        let mut iter = keys.into_iter().take(6).map(|k| k as u8);
+        let mut modifier = 0;
+        let mut iter = keys.into_iter()
+            .take(6)
+            .inspect(|k| modifier |= k.modifier)
+            .map(|k| k.key as u8);
         Self {
             keycode: core::array::from_fn(|_| iter.next().unwrap_or(0)),
-            ..Default::default()
+            modifier,
         }
     }
 
+    /// Raw input report bytes.
+    pub(crate) fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [self.modifier, 0, 0, 0, 0, 0, 0, 0];
+        bytes[2..].copy_from_slice(&self.keycode);
+        bytes
+    }
+
+    /// Raw input report bytes prefixed with the report ID, used when several drums are reported
+    /// over the same interface.
+    pub(crate) fn to_bytes_with_id(self, id: u8) -> [u8; 9] {
+        let mut bytes = [id, self.modifier, 0, 0, 0, 0, 0, 0, 0];
+        bytes[3..].copy_from_slice(&self.keycode);
+        bytes
+    }
+
+    /// Checks if no keys are pressed within this report.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.modifier == 0 && self.keycode.iter().all(|&k| k == 0)
+    }
+
     /// Constructs an empty HID report.
     ///
     /// Can be used to fully reset the state of HID device (release all keys).
@@ -58,3 +72,478 @@ impl DrumHitStrokeHidReport {
         Self { ..Default::default() }
     }
 }
+
+/// Report ID of player 1 keyboard in two-player configuration.
+pub(crate) const PLAYER1_REPORT_ID: u8 = 0x01;
+/// Report ID of player 2 keyboard in two-player configuration.
+pub(crate) const PLAYER2_REPORT_ID: u8 = 0x02;
+
+/// Drum Consumer Control HID Class Report.
+///
+/// Sent over a separate HID interface alongside the keyboard reports, so that pads can be mapped to
+/// media keys (volume, play/pause, etc.) from the Consumer usage page.
+pub(crate) type DrumConsumerHidReport = MediaKeyboardReport;
+
+/// Drum Gamepad HID Class Report.
+///
+/// Acts as a gamepad device with a button per drum sensor in LK, LD, RD, RK order. Many rhythm
+/// games and emulators handle controllers better than synthetic keyboards.
+///
+/// Hit strength of each pad is reported as an 8-bit axis (X, Y, Z, Rx in the same order), so host
+/// software can display it or apply its own velocity curves. Released pads report zero. Axes are
+/// omitted from the report if disabled within the configuration.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DrumGamepadHidReport {
+    buttons: u8,
+    velocity: [u8; 4],
+}
+
+impl DrumGamepadHidReport {
+    /// Generates new gamepad HID report from the provided pressed buttons and pads velocity.
+    ///
+    /// # Iterator
+    ///
+    /// Each item is a state of the button with the same index. More than 8 elements will be ignored.
+    pub(crate) fn new<I>(buttons: I, velocity: [u8; 4]) -> Self where
+        I: IntoIterator<Item = bool>,
+    {
+        Self {
+            buttons: buttons.into_iter()
+                .take(8)
+                .enumerate()
+                .fold(0, |acc, (i, pressed)| acc | ((pressed as u8) << i)),
+            velocity,
+        }
+    }
+
+    /// Raw input report bytes.
+    pub(crate) fn to_bytes(self) -> [u8; 5] {
+        let [v0, v1, v2, v3] = self.velocity;
+        [self.buttons, v0, v1, v2, v3]
+    }
+}
+
+/// Report descriptor of HORIPAD-compatible controller.
+///
+/// This layout is used by console Taiko controllers and is expected by Switch titles and most USB
+/// adapters: 16 buttons, a hat switch, four 8-bit stick axes and a vendor byte.
+pub(crate) const HORI_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,         // Usage Page (Generic Desktop)
+    0x09, 0x05,         // Usage (Game Pad)
+    0xA1, 0x01,         // Collection (Application)
+    0x15, 0x00,         //   Logical Minimum (0)
+    0x25, 0x01,         //   Logical Maximum (1)
+    0x35, 0x00,         //   Physical Minimum (0)
+    0x45, 0x01,         //   Physical Maximum (1)
+    0x75, 0x01,         //   Report Size (1)
+    0x95, 0x10,         //   Report Count (16)
+    0x05, 0x09,         //   Usage Page (Button)
+    0x19, 0x01,         //   Usage Minimum (1)
+    0x29, 0x10,         //   Usage Maximum (16)
+    0x81, 0x02,         //   Input (Data, Var, Abs)
+    0x05, 0x01,         //   Usage Page (Generic Desktop)
+    0x25, 0x07,         //   Logical Maximum (7)
+    0x46, 0x3B, 0x01,   //   Physical Maximum (315)
+    0x75, 0x04,         //   Report Size (4)
+    0x95, 0x01,         //   Report Count (1)
+    0x65, 0x14,         //   Unit (English Rotation)
+    0x09, 0x39,         //   Usage (Hat Switch)
+    0x81, 0x42,         //   Input (Data, Var, Abs, Null State)
+    0x65, 0x00,         //   Unit (None)
+    0x95, 0x01,         //   Report Count (1)
+    0x81, 0x01,         //   Input (Const)
+    0x26, 0xFF, 0x00,   //   Logical Maximum (255)
+    0x46, 0xFF, 0x00,   //   Physical Maximum (255)
+    0x09, 0x30,         //   Usage (X)
+    0x09, 0x31,         //   Usage (Y)
+    0x09, 0x32,         //   Usage (Z)
+    0x09, 0x35,         //   Usage (Rz)
+    0x75, 0x08,         //   Report Size (8)
+    0x95, 0x04,         //   Report Count (4)
+    0x81, 0x02,         //   Input (Data, Var, Abs)
+    0x06, 0x00, 0xFF,   //   Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x20,         //   Usage (0x20)
+    0x95, 0x01,         //   Report Count (1)
+    0x81, 0x02,         //   Input (Data, Var, Abs)
+    0x0A, 0x21, 0x26,   //   Usage (0x2621)
+    0x95, 0x08,         //   Report Count (8)
+    0x91, 0x02,         //   Output (Data, Var, Abs)
+    0xC0,               // End Collection
+];
+
+/* HORIPAD button bits used by console Taiko controllers. */
+const HORI_BUTTON_L: u16 = 1 << 4;
+const HORI_BUTTON_R: u16 = 1 << 5;
+const HORI_BUTTON_ZL: u16 = 1 << 6;
+const HORI_BUTTON_ZR: u16 = 1 << 7;
+/* Hat switch value for released d-pad and centered stick value. */
+const HORI_HAT_NEUTRAL: u8 = 0x08;
+const HORI_STICK_CENTER: u8 = 0x80;
+
+/// HORIPAD-compatible Taiko controller report.
+///
+/// Pads are mapped the same way as on console Taiko drums: rims to L/R and faces to ZL/ZR.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DrumHoriHidReport {
+    buttons: u16,
+}
+
+impl DrumHoriHidReport {
+    /// Generates new report from the pads state in LK, LD, RD, RK order.
+    pub(crate) fn new(pads: [bool; 4]) -> Self {
+        Self {
+            buttons: pads.into_iter()
+                .zip([HORI_BUTTON_L, HORI_BUTTON_ZL, HORI_BUTTON_ZR, HORI_BUTTON_R])
+                .filter_map(|(hit, button)| if hit { Some(button) } else { None })
+                .fold(0, |acc, button| acc | button),
+        }
+    }
+
+    /// Raw report bytes accordingly to [`HORI_REPORT_DESCRIPTOR`].
+    pub(crate) fn to_bytes(&self) -> [u8; 8] {
+        let [lo, hi] = self.buttons.to_le_bytes();
+        [
+            lo, hi,
+            HORI_HAT_NEUTRAL,
+            HORI_STICK_CENTER, HORI_STICK_CENTER, HORI_STICK_CENTER, HORI_STICK_CENTER,
+            0,
+        ]
+    }
+}
+
+/// Report descriptor of the vendor-defined configuration interface.
+///
+/// Contains a single 64-byte input and output reports without report IDs, which carry the same
+/// commands and responses as the CDC programmer. The 64-byte feature report holds serialized
+/// configuration, so it can be read and written directly via GET_REPORT/SET_REPORT requests.
+pub(crate) const VENDOR_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xFF,   // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,         // Usage (0x01)
+    0xA1, 0x01,         // Collection (Application)
+    0x15, 0x00,         //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,   //   Logical Maximum (255)
+    0x75, 0x08,         //   Report Size (8)
+    0x95, 0x40,         //   Report Count (64)
+    0x09, 0x02,         //   Usage (0x02)
+    0x81, 0x02,         //   Input (Data, Var, Abs)
+    0x09, 0x03,         //   Usage (0x03)
+    0x91, 0x02,         //   Output (Data, Var, Abs)
+    0x09, 0x04,         //   Usage (0x04)
+    0xB1, 0x02,         //   Feature (Data, Var, Abs)
+    0xC0,               // End Collection
+];
+
+/// HID report mode.
+///
+/// Defines the way drum hits are presented to the host machine. The mode is only applied during
+/// USB enumeration, therefore the drum shall be restarted after it was changed.
+#[repr(u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HidMode {
+    /// Pads are mapped to the keyboard keys.
+    #[default]
+    Keyboard = 0,
+    /// Pads are mapped to the gamepad buttons.
+    Gamepad = 1,
+    /// Pads are mapped to the buttons of HORIPAD-compatible console Taiko controller.
+    Hori = 2,
+}
+
+impl From<u8> for HidMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Gamepad,
+            2 => Self::Hori,
+            _ => Self::Keyboard,
+        }
+    }
+}
+
+/// Any report that can be sent by the drum.
+///
+/// All variants except [`DrumReport::Midi`] are sent over the HID interface.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DrumReport {
+    Keyboard(DrumHitStrokeHidReport, DrumConsumerHidReport),
+    /// Keyboard report of the second drum in two-player configuration.
+    #[cfg_attr(not(feature = "two-player"), allow(dead_code))]
+    Player2(DrumHitStrokeHidReport),
+    Gamepad(DrumGamepadHidReport),
+    Hori(DrumHoriHidReport),
+    Midi(MidiNoteEvents),
+}
+
+impl DrumReport {
+    /// Keyboard report with a single pressed key.
+    pub(crate) fn key(key: KeyboardUsage) -> Self {
+        Self::Keyboard(DrumHitStrokeHidReport::new([key.into()]), DrumConsumerHidReport { usage_id: 0 })
+    }
+
+    /// Keyboard report that releases all keys.
+    pub(crate) fn released() -> Self {
+        Self::Keyboard(DrumHitStrokeHidReport::empty(), DrumConsumerHidReport { usage_id: 0 })
+    }
+}
+
+/* HID class requests and descriptor types. */
+const HID_DESC_TYPE: u8 = 0x21;
+const HID_REPORT_DESC_TYPE: u8 = 0x22;
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REPORT_TYPE_INPUT: u8 = 0x01;
+const HID_REPORT_TYPE_OUTPUT: u8 = 0x02;
+const HID_REPORT_TYPE_FEATURE: u8 = 0x03;
+/// Size of every report defined within [`VENDOR_REPORT_DESCRIPTOR`].
+pub(crate) const VENDOR_REPORT_SIZE: usize = 64;
+/// Idle rate recommended by the HID specification for keyboards (500 ms in 4 ms units).
+pub(crate) const KEYBOARD_DEFAULT_IDLE: u8 = 125;
+/// Largest input report, which is tracked for idle repeats and GET_REPORT requests.
+const INPUT_REPORT_SIZE: usize = 9;
+/// Amount of input reports with different IDs, that are tracked.
+const INPUT_REPORT_SLOTS: usize = 2;
+
+/// Last input report sent with a certain report ID.
+#[derive(Debug, Clone, Copy)]
+struct InputReport {
+    data: [u8; INPUT_REPORT_SIZE],
+    len: usize,
+}
+
+/// HID class used by all drum interfaces.
+///
+/// [`usbd_hid::hid_class::HIDClass`] rejects GET_REPORT and GET_IDLE requests and ignores the
+/// negotiated idle rate, therefore HID interfaces are implemented here:
+/// - Input reports pushed via [`DrumHidClass::push_input`] are repeated each time the idle period
+///   passes without a new report, as required by the HID specification. Idle rate is negotiated
+///   by the host via SET_IDLE request;
+/// - GET_REPORT requests for input reports return the last report with the requested ID, so host
+///   tools can poll current pads state on demand;
+/// - Feature reports are exchanged over the control pipe: GET_REPORT returns the last feature
+///   report provided via [`DrumHidClass::set_feature`] and SET_REPORT stores the obtained data
+///   until it is taken by [`DrumHidClass::pull_feature`];
+pub(crate) struct DrumHidClass<'a, B: UsbBus> {
+    if_num: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: Option<EndpointOut<'a, B>>,
+    report_descriptor: &'static [u8],
+    /// Feature report returned to the host. Feature reports are not supported if not set.
+    feature_in: Option<[u8; VENDOR_REPORT_SIZE]>,
+    /// Feature report obtained from the host, which was not handled yet.
+    feature_out: Option<[u8; VENDOR_REPORT_SIZE]>,
+    /// Input reports are prefixed with report ID.
+    report_ids: bool,
+    /// Idle rate in 4 ms units. Zero means that reports are only sent on change.
+    idle: u8,
+    /// Time passed since the last input report.
+    idle_elapsed_ms: u16,
+    /// Last input reports repeated on idle and returned on GET_REPORT requests.
+    input_reports: [Option<InputReport>; INPUT_REPORT_SLOTS],
+}
+
+impl<'a, B: UsbBus> DrumHidClass<'a, B> {
+    /// Allocates the interface with both interrupt endpoints.
+    pub(crate) fn new(alloc: &'a UsbBusAllocator<B>, report_descriptor: &'static [u8], poll_ms: u8) -> Self {
+        let mut s = Self::new_ep_in(alloc, report_descriptor, poll_ms);
+        s.ep_out = Some(alloc.interrupt(VENDOR_REPORT_SIZE as u16, poll_ms));
+        s
+    }
+
+    /// Allocates the interface with a single interrupt IN endpoint. Output reports can still be
+    /// obtained via SET_REPORT requests.
+    pub(crate) fn new_ep_in(alloc: &'a UsbBusAllocator<B>, report_descriptor: &'static [u8], poll_ms: u8) -> Self {
+        Self {
+            if_num: alloc.interface(),
+            ep_in: alloc.interrupt(VENDOR_REPORT_SIZE as u16, poll_ms),
+            ep_out: None,
+            report_descriptor,
+            feature_in: None,
+            feature_out: None,
+            report_ids: false,
+            idle: 0,
+            idle_elapsed_ms: 0,
+            input_reports: [None; INPUT_REPORT_SLOTS],
+        }
+    }
+
+    /// Marks input reports as prefixed with report ID, so each ID is repeated on idle separately.
+    pub(crate) fn with_report_ids(mut self, report_ids: bool) -> Self {
+        self.report_ids = report_ids;
+        self
+    }
+
+    /// Sets idle rate used until the host negotiates its own one.
+    pub(crate) fn with_idle(mut self, idle: u8) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Sends an input report, which will be repeated if the idle rate is set.
+    pub(crate) fn push_input(&mut self, data: &[u8]) -> usb_device::Result<usize> {
+        self.set_input(data);
+        self.idle_elapsed_ms = 0;
+        self.ep_in.write(data)
+    }
+
+    /// Updates the current input report without sending it (e.g. initial released state).
+    pub(crate) fn set_input(&mut self, data: &[u8]) {
+        if !data.is_empty() && data.len() <= INPUT_REPORT_SIZE {
+            let mut report = InputReport { data: [0; INPUT_REPORT_SIZE], len: data.len() };
+            report.data[..data.len()].copy_from_slice(data);
+
+            let slot = if self.report_ids {
+                self.input_reports.iter()
+                    .position(|r| r.is_none_or(|r| r.data[0] == data[0]))
+                    .unwrap_or(0)
+            } else { 0 };
+            self.input_reports[slot] = Some(report);
+        }
+    }
+
+    /// Last input report with the provided report ID. The ID is ignored if reports are not numbered.
+    fn input(&self, id: u8) -> Option<&[u8]> {
+        self.input_reports.iter()
+            .flatten()
+            .find(|r| !self.report_ids || r.data[0] == id)
+            .map(|r| &r.data[..r.len])
+    }
+
+    /// Sends a raw input report, which is never repeated (e.g. command response).
+    pub(crate) fn push_raw_input(&self, data: &[u8]) -> usb_device::Result<usize> {
+        self.ep_in.write(data)
+    }
+
+    /// Reads a raw output report. Returns [`UsbError::WouldBlock`] if none was obtained.
+    pub(crate) fn pull_raw_output(&self, data: &mut [u8]) -> usb_device::Result<usize> {
+        match &self.ep_out {
+            Some(ep) => ep.read(data),
+            None => Err(UsbError::WouldBlock),
+        }
+    }
+
+    /// Updates the feature report returned on GET_REPORT requests. Data is zero padded.
+    pub(crate) fn set_feature(&mut self, data: &[u8]) {
+        let len = data.len().min(VENDOR_REPORT_SIZE);
+        let mut report = [0; VENDOR_REPORT_SIZE];
+        report[..len].copy_from_slice(&data[..len]);
+        self.feature_in = Some(report);
+    }
+
+    /// Takes the feature report obtained via SET_REPORT request, if any.
+    pub(crate) fn pull_feature(&mut self) -> Option<[u8; VENDOR_REPORT_SIZE]> {
+        self.feature_out.take()
+    }
+
+    /// Advances idle timer and repeats last input reports when the idle period passes.
+    pub(crate) fn tick_idle(&mut self, elapsed_ms: u16) {
+        if self.idle == 0 {
+            return;
+        }
+
+        self.idle_elapsed_ms = self.idle_elapsed_ms.saturating_add(elapsed_ms);
+        if self.idle_elapsed_ms >= self.idle as u16 * 4 {
+            self.idle_elapsed_ms = 0;
+            for report in self.input_reports.iter().flatten() {
+                // Repeated reports are not critical and simply skipped when endpoint is busy.
+                self.ep_in.write(&report.data[..report.len]).ok();
+            }
+        }
+    }
+
+    /// HID descriptor body without length and type fields.
+    fn hid_descriptor(&self) -> [u8; 7] {
+        let [lo, hi] = (self.report_descriptor.len() as u16).to_le_bytes();
+        [
+            0x11, 0x01,     // bcdHID 1.11
+            0x00,           // bCountryCode
+            0x01,           // bNumDescriptors
+            HID_REPORT_DESC_TYPE, lo, hi,
+        ]
+    }
+
+    fn is_own_request(&self, req: &control::Request) -> bool {
+        req.recipient == control::Recipient::Interface && req.index == u8::from(self.if_num) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DrumHidClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.if_num, 0x03, 0x00, 0x00)?;
+        writer.write(HID_DESC_TYPE, &self.hid_descriptor())?;
+        writer.endpoint(&self.ep_in)?;
+        if let Some(ep_out) = &self.ep_out {
+            writer.endpoint(ep_out)?;
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        // Last input reports are kept, since they still reflect current pads state.
+        self.idle_elapsed_ms = 0;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_own_request(&req) {
+            return;
+        }
+
+        match (req.request_type, req.request) {
+            (control::RequestType::Standard, control::Request::GET_DESCRIPTOR) => {
+                match (req.value >> 8) as u8 {
+                    HID_REPORT_DESC_TYPE => xfer.accept_with_static(self.report_descriptor).ok(),
+                    HID_DESC_TYPE => {
+                        let mut desc = [9, HID_DESC_TYPE, 0, 0, 0, 0, 0, 0, 0];
+                        desc[2..].copy_from_slice(&self.hid_descriptor());
+                        xfer.accept_with(&desc).ok()
+                    },
+                    _ => xfer.reject().ok(),
+                };
+            },
+            (control::RequestType::Class, HID_REQ_GET_REPORT) => {
+                match ((req.value >> 8) as u8, self.feature_in.as_ref()) {
+                    (HID_REPORT_TYPE_FEATURE, Some(feature)) => xfer.accept_with(feature).ok(),
+                    (HID_REPORT_TYPE_INPUT, _) => match self.input(req.value as u8) {
+                        Some(report) => xfer.accept_with(report).ok(),
+                        None => xfer.reject().ok(),
+                    },
+                    _ => xfer.reject().ok(),
+                };
+            },
+            (control::RequestType::Class, HID_REQ_GET_IDLE) => {
+                xfer.accept_with(&[self.idle]).ok();
+            },
+            _ => (),
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_own_request(&req) || req.request_type != control::RequestType::Class {
+            return;
+        }
+
+        match (req.request, (req.value >> 8) as u8) {
+            (HID_REQ_SET_REPORT, HID_REPORT_TYPE_FEATURE) if self.feature_in.is_some() => {
+                let data = xfer.data();
+                let len = data.len().min(VENDOR_REPORT_SIZE);
+                let mut report = [0; VENDOR_REPORT_SIZE];
+                report[..len].copy_from_slice(&data[..len]);
+                self.feature_out = Some(report);
+                xfer.accept().ok();
+            },
+            // Keyboard LEDs are not present on the drum.
+            (HID_REQ_SET_REPORT, HID_REPORT_TYPE_OUTPUT) => { xfer.accept().ok(); },
+            // Idle rate is applied to all report IDs at once.
+            (HID_REQ_SET_IDLE, duration) => {
+                self.idle = duration;
+                self.idle_elapsed_ms = 0;
+                xfer.accept().ok();
+            },
+            _ => { xfer.reject().ok(); },
+        }
+    }
+}
@@ -1,7 +1,9 @@
 //! Library space for Taiko Drum Firmware.
+//!
+//! Holds the only RTIC application ([`app`]) along with all drivers and signal processing modules,
+//! while the binary merely links it in.
 #![no_std]
 #![no_main]
-#![feature(slice_from_ptr_range)]
 
 use stm32f1::stm32f103 as pac;
 
@@ -15,30 +17,111 @@ mod parser;
 mod usb;
 /// HID class implementations for drum controller.
 mod hid;
+/// USB MIDI class implementation for drum controller.
+mod midi;
 /// Firmware configuration (Non-volatile).
 mod cfg;
 /// Runtime programmer.
 mod prog;
+/// Programmer protocol definitions.
+mod protocol;
 /// Cross-correlation signal processing.
 mod cross_correlation;
+/// Haptic feedback actuator driver.
+mod actuator;
+/// Auto-repeat of held keys.
+mod typematic;
+/// Pad gestures recognition.
+mod gesture;
+/// HID report descriptor generation.
+mod descriptor;
+/// Bootloader entry.
+mod bootloader;
+/// Panic and hard fault reports.
+mod crash;
+/// System clock configuration.
+mod clocks;
+/// Clock security system.
+mod css;
+/// USB DFU run-time class implementation.
+mod dfu;
+/// WebUSB capability and configurator interface.
+mod webusb;
+/// USB frame timing.
+mod timing;
+/// Clone microcontrollers detection.
+mod chip;
+/// GPIO pin ownership.
+mod pins;
+/// Board variants.
+mod board;
+/// Runtime state mirror within the backup registers.
+mod backup;
+/// Configuration flash region access.
+mod flash;
+/// Firmware updates over the programmer.
+mod update;
+/// Noise floor calibration.
+mod calibration;
+/// CPU load accounting.
+mod load;
+/// Pipeline health monitor.
+mod health;
+/// Static RAM budget.
+mod ram;
+/// Idle power reduction.
+mod power;
+/// Supply voltage monitoring.
+mod supply;
+/// Power-on self-test.
+mod selftest;
+/// Bench mode without USB.
+#[cfg(feature = "bench")]
+mod bench;
+/// Text console on the serial port.
+#[cfg(feature = "console")]
+mod console;
+/// Triggered capture of raw samples.
+#[cfg(feature = "capture")]
+mod capture;
+/// VBUS sensing.
+#[cfg_attr(not(feature = "vbus-sense"), allow(dead_code))]
+mod vbus;
 
 #[rtic::app(
     device = stm32f1::stm32f103,
-    dispatchers = [SDIO, RTC],
+    dispatchers = [SDIO, RTC, SPI3],
     peripherals = true,
 )]
 mod app {
-    use usb_device::UsbError;
     use rtic_monotonics::systick::prelude::*;
     use rtic_sync::make_channel;
-
-    use crate::hid::DrumHitStrokeHidReport;
-
-    use super::cfg::DrumConfig;
-    use super::piezo::{PiezoSample, PIEZO_SENSOR_QUEUE_CAPACITY, PiezoSensorHandler, Receiver};
-    use super::usb::{UsbTaikoDrum, UsbAllocator, UsbBus};
-    use super::parser::Parser as P;
-    use super::prog::Programmer;
+    use core::mem::MaybeUninit;
+
+    use crate::hid::DrumReport;
+
+    use super::cfg::{CfgStatus, DrumConfig, GESTURE_MENU_TOGGLE, GESTURE_CDC_ENABLE};
+    use super::piezo::{PiezoSample, PIEZO_SENSOR_QUEUE_CAPACITY, PLAYERS, PiezoSensorHandler, PiezoSensorSampleMode, Receiver};
+    use super::usb::{UsbTaikoDrum, UsbAllocator, UsbBus, UsbDescriptors};
+    use super::parser::{Parser as P, Player};
+    use super::cross_correlation::XcorrScratch;
+    use super::actuator::{Actuator, HapticPulse};
+    use super::typematic::{self, TypematicSender, TypematicReceiver, TYPEMATIC_QUEUE_CAPACITY};
+    use super::gesture::Gestures;
+    use super::hid::HidMode;
+    use super::chip::Chip;
+    use super::clocks::SYSCLK_HZ;
+    use super::midi::MidiMode;
+    use super::prog::{Programmer, ProgBuffers, Request, RequestSender, RequestReceiver, REQUEST_QUEUE_CAPACITY};
+    use super::prog::{Storage, CommitReceiver, COMMIT_QUEUE_CAPACITY};
+    use super::cfg::BLOB_LEN;
+    use super::pins::{Pins, UsbDpPin};
+    use super::backup::Backup;
+    use super::flash::CfgFlash;
+    use super::load::{self, Task as LoadTask};
+    use super::css;
+    use super::selftest::{self, Check, SensorCheck};
+    use super::pins::StatusLedPin;
 
     /* Firmware clocks. */
     systick_monotonic!(Systick);
@@ -46,18 +129,26 @@ mod app {
     #[shared]
     struct Shared {
         reset_pend: bool,
-        /// Shared pins of GPIOA port.
-        gpioa: super::pac::GPIOA,
+        /// USB D+ line used for re-enumeration.
+        usb_dp: UsbDpPin,
         /// USB device wrapper is used across interrupt handlers and tasks to communicate withhost.
-        usb_dev: UsbTaikoDrum<'static>,
+        usb_dev: &'static mut UsbTaikoDrum<'static>,
+        /// Used by ADC1_2 interrupt handler, which reads the state of current hits periodically.
+        /// USB interrupt handlers stop the sampling while the bus is suspended, while the parser
+        /// resumes the sampling paused by the full queue.
+        piezo_handler: PiezoSensorHandler,
+        /// Flash controller of the programmer tasks, which write the flash outside of the device lock.
+        storage: Storage,
     }
     
     #[local]
     struct Local {
-        /// Local to ADC1_2 interrupt handler, which reads the state of current hits periodically.
-        piezo_handler: PiezoSensorHandler,
-        /// Sensor samples parser.
-        parser: P,
+        /// Haptic actuator, only driven by the host requests.
+        actuator: Actuator,
+        /* Programmer commands queued by each USB interrupt. */
+        rx_requests: RequestSender,
+        tx_requests: RequestSender,
+        poll_requests: RequestSender,
     }
 
     /// Performs a software system reset.
@@ -66,11 +157,20 @@ mod app {
         ctx.shared.reset_pend.lock(|pend| *pend = true);
 
         let timeout = *ctx.local.timeout;
-        log::info!("A system reset was called. Restarting in {} seconds...", timeout);
+        crate::info!("A system reset was called. Restarting in {} seconds...", timeout);
         Systick::delay(timeout.secs()).await;
         rtic::export::SCB::sys_reset();
     }
 
+    /// Reboots into the bootloader after the DFU detach, serial port touch or programmer command.
+    ///
+    /// Waits for the status stage of the detach request or the command response to be completed first.
+    #[task(priority = 1)]
+    async fn BootloaderEntry(_: BootloaderEntry::Context) {
+        Systick::delay(DFU_DETACH_DELAY_MS.millis()).await;
+        super::bootloader::reboot();
+    }
+
     /// Initialization function for drum functionality.
     ///
     /// # Init
@@ -82,115 +182,428 @@ mod app {
     /// simultaneous mode;
     /// - Prepares communication channel between [`app::SensorHandling`] and [`app::UsbHidSender`] tasks.
     #[init(
-        local = [usb_alloc: Option<UsbAllocator> = None]
+        local = [
+            usb_alloc: Option<UsbAllocator> = None,
+            usb_drum: MaybeUninit<UsbTaikoDrum<'static>> = MaybeUninit::uninit(),
+            prog_buffers: ProgBuffers = ProgBuffers::new(),
+            import_buffer: heapless::Vec<u8, BLOB_LEN> = heapless::Vec::new(),
+            descriptors: UsbDescriptors = UsbDescriptors::new(),
+        ]
     )]
     fn Init(ctx: Init::Context) -> (Shared, Local) {
-        let (core, mut dev, alloc) = (ctx.core, ctx.device, ctx.local.usb_alloc);
+        // Bootloader must be entered and staged firmware installed before any peripheral is configured.
+        super::bootloader::check();
+        super::update::check();
+
+        let (mut core, mut dev, alloc) = (ctx.core, ctx.device, ctx.local.usb_alloc);
         let (s, r) = make_channel!(PiezoSample, PIEZO_SENSOR_QUEUE_CAPACITY);
+        let (ts, tr) = make_channel!(DrumReport, TYPEMATIC_QUEUE_CAPACITY);
+        let (ps, pr) = make_channel!(Request, REQUEST_QUEUE_CAPACITY);
+        let (cs, cr) = make_channel!((), COMMIT_QUEUE_CAPACITY);
 
         /* Logging initialization. */
         if let Err(log_set_err) = super::logger::init() {
             unimplemented!()
         }  
-        log::info!("Booting taiko firmware version: [{}]", super::version::TAIKO_HID_FIRMWARE_VERSION);
-
-
-        /* Setting SYSCLK source to PLL (72 MHz on this line.) */
-        let (rcc, flash) = (&mut dev.RCC, &mut dev.FLASH);
-
-        // Enabling internal high speed clock
-        rcc.cr.modify(|_, w| w.hseon().set_bit());
-        while rcc.cr.read().hserdy().bit_is_clear() {}
-
-        rcc.cfgr.modify(|_, w|
-            w   /* Multiplying HSE to reach a maximal value of 72 MHz */
-             .pllsrc().set_bit()
-             .pllxtpre().clear_bit()
-             .pllmul().mul9()
-        );
-
-        // Enabling PLL.
-        rcc.cr.modify(|_, w| w.pllon().set_bit());
-        while rcc.cr.read().pllrdy().bit_is_clear() {}
-
-        flash.acr.modify(|_, w| w.latency().ws2());
+        crate::info!("Booting taiko firmware version: [{}]", super::version::TAIKO_HID_FIRMWARE_VERSION);
+        let chip = Chip::detect();
+        crate::info!("Running on {:?} microcontroller.", chip);
+        super::ram::report();
+
+        /* Setting SYSCLK source to PLL (72 MHz on this line, 64 MHz from HSI in bench builds.) */
+        // The drum stays on HSI with USB disabled, if the crystal or PLL do not start.
+        let clocks = super::clocks::configure(&mut dev.RCC, &mut dev.FLASH, chip);
+        let sysclk_hz = match clocks {
+            Ok(()) => {
+                css::enable(&mut dev.RCC);
+                SYSCLK_HZ
+            },
+            Err(_) => css::HSI_HZ,
+        };
+        selftest::report(Check::Clock, clocks.is_ok());
 
         // Architecture specific USB bus allocator.
         alloc.replace(UsbBus::new(super::usb::UsbControllerSTM32F103));
 
-        // Sys clock switch.
-        rcc.cfgr.modify(|_, w| w.sw().pll());
-        while !rcc.cfgr.read().sws().is_pll() {}
-
         /* Monotonics. */
-        log::debug!("Enabling Systick monotonic...");
-        Systick::start(core.SYST, ARM_SYSTICK_HZ);
-        log::info!("Internal clocks enabled");
+        crate::debug!("Enabling Systick monotonic...");
+        Systick::start(core.SYST, sysclk_hz);
+        super::timing::init(&mut core.DCB, &mut core.DWT);
+        crate::debug!("Internal clocks enabled");
 
         // Runtime firmware and configuration programmer.
-        let programmer = Programmer::new(
-            alloc,
-            //DrumConfig::new(&mut dev.FLASH),
-            DrumConfig::default(),
-            dev.FLASH,
-        );
-
-        let usb_dev = UsbTaikoDrum::new(alloc, programmer, dev.USB, &mut dev.GPIOA, &mut dev.RCC);
+        // Stored records are only trusted after CRC check and validation, otherwise defaults are used.
+        let (cfg, cfg_status) = DrumConfig::new();
+        selftest::report(Check::Config, cfg_status != CfgStatus::Corrupted);
+        // Report of a crash before the last power loss is only left in flash.
+        super::crash::restore();
+        let backup = Backup::new(dev.BKP, &mut dev.PWR, &mut dev.RCC);
+        super::supply::init(&mut dev.PWR, &mut dev.EXTI);
+        let programmer = Programmer::new(alloc, ctx.local.prog_buffers, cfg, cfg_status, backup, cs);
+        let storage = Storage::new(CfgFlash::new(dev.FLASH), ctx.local.import_buffer);
+
+        let mut pins = Pins::new(dev.GPIOA, dev.GPIOB, dev.GPIOC, &mut dev.RCC);
+        if super::board::STATUS_LED {
+            selftest::init_led(&mut pins.status_led);
+        }
+        #[cfg(feature = "uart-log")]
+        super::logger::init_uart(dev.USART1, pins.uart_tx, &mut dev.RCC, sysclk_hz);
+        // Device and programmer buffers are kept in static memory, since each copy of them on the
+        // stack of the initialization takes several kilobytes of RAM.
+        let usb_dev = ctx.local.usb_drum.write(UsbTaikoDrum::new(
+            alloc, ctx.local.descriptors, programmer, dev.USB, &mut pins.usb_dp
+        ));
+        if let Err(err) = clocks {
+            css::fallback(err);
+        }
+        #[cfg(feature = "bench")]
+        super::bench::init();
         let piezo_handler = PiezoSensorHandler::new(
-            (dev.ADC1, dev.ADC2), &mut dev.GPIOA, &mut dev.RCC, dev.TIM4, s.clone()
+            (dev.ADC1, dev.ADC2), pins.sensors, &mut dev.RCC, dev.TIM4, s.clone()
         );
         let cfg = &usb_dev.programmer.cfg;
+        let actuator = Actuator::new(dev.TIM3, pins.actuator, &mut dev.RCC);
 
         /* Tasks */ 
-        Parser::spawn(r).expect("First parser initialization.");
+        // Channel endpoints are not formatted on failure, which would link in the whole `Debug` machinery.
+        Parser::spawn(r, ts).unwrap_or_else(|_| panic!("First parser initialization."));
+        Typematic::spawn(tr).unwrap_or_else(|_| panic!("First typematic initialization."));
+        HidIdle::spawn().expect("First HID idle timer initialization.");
+        #[cfg(not(feature = "bench"))]
+        UsbPollTimer::spawn().expect("First USB poll timer initialization.");
+        Uptime::spawn().expect("First uptime counter initialization.");
+        if super::board::STATUS_LED {
+            StatusLed::spawn(pins.status_led).expect("First status LED initialization.");
+        }
+        UsbConfigManager::spawn(pr).unwrap_or_else(|_| panic!("First programmer initialization."));
+        CfgCommit::spawn(cr).unwrap_or_else(|_| panic!("First configuration commit initialization."));
+        #[cfg(feature = "vbus-sense")]
+        VbusMonitor::spawn(super::vbus::VbusSense::new(pins.vbus))
+            .expect("First VBUS monitor initialization.");
 
         (
-            Shared { usb_dev, gpioa: dev.GPIOA, reset_pend: false }, 
-            Local { piezo_handler, parser: P::default() },
+            Shared { usb_dev, usb_dp: pins.usb_dp, reset_pend: false, piezo_handler, storage }, 
+            Local { 
+                actuator,
+                rx_requests: ps.clone(),
+                tx_requests: ps.clone(),
+                poll_requests: ps,
+            },
         )    
     }
 
+    /// Sleeps until the next interrupt, whenever no task is running.
+    ///
+    /// Wakes up within a few cycles, so samples are still handled in time.
+    #[idle]
+    fn Idle(_: Idle::Context) -> ! {
+        super::power::idle()
+    }
+
     /// Parses upcoming samples to detect proper hits and ignore spurious ones.
     ///
     /// Obtained samples are being parsed to detect a proper drum hit and it's location. Based on
     /// the current hits, HID reports are being sent to the host machine, simulating a keyboard
     /// device that presses the corresponding keystrokes.
-    #[task(local = [parser], shared = [usb_dev])]
-    async fn Parser(mut ctx: Parser::Context, mut r: Receiver) {
-        let parser = ctx.local.parser;
-        log::info!("Parser task spawned. Waiting for samples.");
+    ///
+    /// Runs above USB interrupts, which only hold it off while the device is locked. Parsing
+    /// itself is done outside of that lock, so long USB transactions never delay hit detection.
+    #[task(
+        priority = 3,
+        local = [
+            parsers: [P; PLAYERS] = [const { P::new() }; PLAYERS],
+            scratch: XcorrScratch = XcorrScratch::new(),
+            gestures: Gestures = Gestures::new(),
+            sensor_check: SensorCheck = SensorCheck::new(),
+        ], 
+        shared = [usb_dev, piezo_handler]
+    )]
+    async fn Parser(mut ctx: Parser::Context, mut r: Receiver, mut repeater: TypematicSender) {
+        let (parsers, scratch, gestures) = (ctx.local.parsers, ctx.local.scratch, ctx.local.gestures);
+        let sensor_check = ctx.local.sensor_check;
+        crate::debug!("Parser task spawned. Waiting for samples.");
+        // Parsers of each connected drum are zeroed in static memory as the ones of the first drum.
+        parsers.iter_mut().zip([Player::One, Player::Two]).for_each(|(parser, player)| parser.assign(player));
+        // Reports are not generated while the bus is suspended or the device is not configured.
+        let mut gated = false;
 
         /* Handling samples obtained from the piezoelectric sensor */
         while let Ok(sample) = r.recv().await {
-            ctx.shared.usb_dev.lock(|dev| {
-                parser.parse(&dev.programmer.cfg, sample).map(|report|
-                    UsbHidSender::spawn(report).expect("Higher priority task spawn condition.")
+            super::health::sample_taken();
+            let start = super::timing::now();
+            sensor_check.update(&sample);
+            let mut reports: [Option<DrumReport>; PLAYERS] = Default::default();
+            let (ready, gesture) = load::measure(LoadTask::Parser, || {
+                // Settings are copied, so USB interrupts are only held off for the bookkeeping.
+                let (cfg, mode, midi, menu) = ctx.shared.usb_dev.lock(|dev|
+                    (dev.programmer.cfg, dev.layout.mode, dev.midi_mode(), dev.programmer.menu)
                 );
+                let held = parsers.each_ref().map(|parser| parser.pads());
+                for ((parser, pads), report) in parsers.iter_mut().zip(sample.0).zip(&mut reports) {
+                    *report = parser.parse(scratch, &cfg, mode, midi, menu, pads);
+                }
+                let now = Systick::now().duration_since_epoch().to_millis();
+                let hit = parsers.iter().any(|parser| parser.pads().contains(&true));
+                #[cfg(feature = "bench")]
+                parsers.iter().zip(1..).for_each(|(parser, player)| super::bench::print(player, parser.records()));
+                super::power::track(hit, now, cfg.idle_sleep);
+
+                ctx.shared.usb_dev.lock(|dev| {
+                    let ready = dev.accepts_input();
+                    dev.programmer.count_hits(held, parsers.each_ref().map(|parser| parser.pads()), now, &mut dev.stats);
+                    dev.programmer.stream(&sample, &mut dev.stats);
+                    dev.programmer.telemetry(parsers[0].records(), &mut dev.stats);
+                    #[cfg(feature = "capture")]
+                    dev.programmer.capture(&sample, &parsers[0]);
+                    dev.programmer.calibrate(&sample);
+
+                    // Gestures are only recognized on the first drum in keyboard mode.
+                    let gesture = gestures.update(parsers[0].pads(), now, cfg.gesture_mapping)
+                        .filter(|_| ready && mode == HidMode::Keyboard && midi == MidiMode::Off);
+                    match gesture {
+                        Some(GESTURE_MENU_TOGGLE) => {
+                            dev.programmer.set_menu(!dev.programmer.menu);
+                        },
+                        Some(GESTURE_CDC_ENABLE) => dev.programmer.enable_cdc(),
+                        _ => (),
+                    }
+                    if gestures.factory_reset(parsers[0].pads(), now) {
+                        dev.programmer.factory_reset();
+                    }
+
+                    dev.stats.crosstalk = parsers.iter().fold(0, |sum, parser| sum.wrapping_add(parser.crosstalk()));
+                    dev.stats.latency = dev.stats.latency.max(super::timing::now().wrapping_sub(start));
+                    (ready, gesture)
+                })
             });
+            super::health::parsed(super::timing::now().wrapping_sub(start));
 
-            super::int_enable!(ADC1_2); // TODO! do not enable on each loop.
+            if !ready {
+                // Repeats are stopped, while held keys are released by the device on resume.
+                if !gated {
+                    repeater.try_send(DrumReport::released()).ok();
+                }
+                reports = Default::default();
+            }
+            gated = !ready;
+
+            for report in reports.into_iter().flatten() {
+                if let DrumReport::Keyboard(..) = report {
+                    repeater.try_send(report).ok();
+                }
+                send_report(report).await;
+            }
+
+            // Keys of the previous mapping might still be held, when the mode is toggled.
+            match gesture {
+                Some(GESTURE_MENU_TOGGLE) => {
+                    repeater.try_send(DrumReport::released()).ok();
+                    send_report(DrumReport::released()).await;
+                },
+                Some(GESTURE_CDC_ENABLE) | None => (),
+                Some(key) => {
+                    // Gesture key is tapped once.
+                    send_report(DrumReport::key(key.into())).await;
+                    send_report(DrumReport::released()).await;
+                },
+            }
+
+            // Sampling paused by the full queue is resumed, once every queued sample is parsed.
+            if r.is_empty() {
+                ctx.shared.piezo_handler.lock(|piezo| piezo.drained());
+            }
+        }
+    }
+
+    /// Spawns [`UsbHidSender`] with the provided report.
+    ///
+    /// Previous report might still be pending when several reports are generated at once.
+    async fn send_report(mut report: DrumReport) {
+        while let Err(pending) = UsbHidSender::spawn(report) {
+            report = pending;
             Systick::delay(500.nanos()).await;
         }
     }
 
+    /// Repeats keyboard reports while pads are being held.
+    ///
+    /// Held keys are released and pressed again with the configured rate after the initial delay.
+    #[task(priority = 1, shared = [usb_dev])]
+    async fn Typematic(mut ctx: Typematic::Context, mut r: TypematicReceiver) {
+        let mut held: Option<DrumReport> = None;
+        let mut deadline = Systick::now();
+
+        loop {
+            let received = match held {
+                Some(_) => Systick::timeout_at(deadline, r.recv()).await.ok(),
+                None => Some(r.recv().await),
+            };
+            let timings = ctx.shared.usb_dev.lock(|dev| dev.programmer.cfg.typematic());
+
+            match (received, timings) {
+                (Some(Ok(report)), Some((delay, _))) => {
+                    held = typematic::held(report);
+                    deadline = Systick::now() + delay.millis();
+                },
+                (Some(Err(_)), _) => return,
+                (None, Some((_, period))) => if let Some(report) = held {
+                    send_report(DrumReport::released()).await;
+                    send_report(report).await;
+                    deadline += period.millis();
+                },
+                (_, None) => held = None,
+            }
+        }
+    }
+
     /// Sends USB HID reports to the host machine.
+    ///
+    /// Reports are queued while endpoints are busy and flushed on next USB interrupts. When the
+    /// queue is full, the task waits for the free space, so hits are never dropped.
     #[task(priority = 1, shared = [usb_dev])]
-    async fn UsbHidSender(mut ctx: UsbHidSender::Context, report: DrumHitStrokeHidReport) {
-        ctx.shared.usb_dev.lock(|dev| {
-           
+    async fn UsbHidSender(mut ctx: UsbHidSender::Context, mut report: DrumReport) {
+        while let Err(full) = ctx.shared.usb_dev.lock(|dev| {
             dev.poll();
-            match dev.hid_keyboard.push_input(&report) {
-                Ok(report_length) => {
-                    log::debug!("Bytes send: {}", report_length);
+            // Checking if device is properly initialized at that point.
+            dev.init_poll();
+            dev.queue_report(report)
+        }) {
+            report = full;
+            Systick::delay(1.millis()).await;
+        }
+    }
+
+    /// Tracks the cable state of self-powered drums.
+    ///
+    /// Sampling is stopped while the cable is detached and the connect sequence is performed
+    /// again on re-attach.
+    #[task(priority = 1, shared = [usb_dev, piezo_handler, usb_dp])]
+    async fn VbusMonitor(ctx: VbusMonitor::Context, mut vbus: super::vbus::VbusSense) {
+        let (mut usb_dev, mut piezo_handler, mut usb_dp) = (ctx.shared.usb_dev, ctx.shared.piezo_handler, ctx.shared.usb_dp);
+        loop {
+            Systick::delay(super::vbus::VBUS_POLL_MS.millis()).await;
+            if let Some(attached) = vbus.changed() {
+                (&mut usb_dev, &mut piezo_handler, &mut usb_dp).lock(|dev, piezo, usb_dp| {
+                    if attached { dev.attach(usb_dp) } else { dev.detach() }
+                    __update_sampling(dev, piezo);
+                });
+            }
+        }
+    }
+
+    /// Re-enumerates the device after persistent USB errors.
+    #[task(priority = 1, shared = [usb_dev, usb_dp])]
+    async fn UsbReenumeration(ctx: UsbReenumeration::Context) {
+        (ctx.shared.usb_dev, ctx.shared.usb_dp).lock(|dev, usb_dp| dev.reenumerate(usb_dp));
+    }
+
+    /// Polls the USB device periodically besides USB interrupts.
+    ///
+    /// An event, which interrupt was missed while the interrupts were masked, would otherwise stall
+    /// the enumeration or the endpoints until the next bus event.
+    #[task(priority = 1, local = [poll_requests], shared = [usb_dev, piezo_handler])]
+    async fn UsbPollTimer(ctx: UsbPollTimer::Context) {
+        let (mut usb_dev, mut piezo) = (ctx.shared.usb_dev, ctx.shared.piezo_handler);
+        loop {
+            Systick::delay(USB_SOFT_POLL_MS.millis()).await;
+            usb_dev.lock(|dev| crate::app::__usb_poll(dev, &mut piezo, ctx.local.poll_requests));
+        }
+    }
+
+    /// Repeats HID reports accordingly to the idle rate negotiated by the host.
+    #[task(priority = 1, shared = [usb_dev])]
+    async fn HidIdle(mut ctx: HidIdle::Context) {
+        loop {
+            Systick::delay(HID_IDLE_TICK_MS.millis()).await;
+            ctx.shared.usb_dev.lock(|dev| dev.tick_idle(HID_IDLE_TICK_MS as u16));
+        }
+    }
+
+    /// Accumulates the uptime within backup registers, so it is kept across resets. Saves of hit
+    /// counters are requested from here as well, while a firmware update installed at boot is
+    /// confirmed after the first tick.
+    #[task(priority = 1, shared = [usb_dev, storage])]
+    async fn Uptime(mut ctx: Uptime::Context) {
+        let mut confirmed = false;
+        loop {
+            Systick::delay(UPTIME_TICK_S.secs()).await;
+            if !core::mem::replace(&mut confirmed, true) {
+                ctx.shared.storage.lock(|storage| storage.confirm_update());
+            }
+            ctx.shared.usb_dev.lock(|dev| {
+                dev.programmer.add_uptime(UPTIME_TICK_S);
+                dev.programmer.flush_hits(UPTIME_TICK_S);
+            });
+        }
+    }
+
+    /// Blinks the code of the first failed self-test check, once sensors are checked as well.
+    /// Otherwise flickers every two seconds while the pipeline health warning is raised.
+    #[task(priority = 1)]
+    async fn StatusLed(_: StatusLed::Context, mut led: StatusLedPin) {
+        loop {
+            Systick::delay(selftest::BLINK_PAUSE_MS.millis()).await;
+            let (blinks, on_ms, off_ms) = match selftest::blinks() {
+                Some(blinks) => (blinks, selftest::BLINK_ON_MS, selftest::BLINK_OFF_MS),
+                None if super::health::warning() => {
+                    (super::health::FLICKER_BLINKS, super::health::FLICKER_MS, super::health::FLICKER_MS)
                 },
-                Err(usb_err) => match usb_err {
-                    // Checking if device is properly initialized at that point.
-                    UsbError::WouldBlock => dev.init_poll(),
-                    UsbError::Unsupported => (),
-                    _ => panic!("{:?}", usb_err),
-                }
+                None => continue,
+            };
+            for _ in 0..blinks {
+                led.set_low();
+                Systick::delay(on_ms.millis()).await;
+                led.set_high();
+                Systick::delay(off_ms.millis()).await;
             }
-        });
+        }
+    }
+
+    /// Executes programmer commands queued by USB interrupts.
+    ///
+    /// Flash erases and writes take milliseconds, so those are kept out of interrupts and done
+    /// outside of the device lock. USB interrupts and the parser are only held off while the
+    /// command is executed and while its response is sent after the flash write.
+    #[task(priority = 1, shared = [usb_dev, storage])]
+    async fn UsbConfigManager(ctx: UsbConfigManager::Context, mut r: RequestReceiver) {
+        let (mut usb_dev, mut storage) = (ctx.shared.usb_dev, ctx.shared.storage);
+        while let Ok(request) = r.recv().await {
+            load::measure(LoadTask::Programmer, || {
+                if let Some(pending) = usb_dev.lock(|dev| dev.execute(request)) {
+                    let result = storage.lock(|storage| storage.run(&pending.job));
+                    usb_dev.lock(|dev| dev.complete(pending, result));
+                }
+            });
+        }
+    }
+
+    /// Performs flash writes deferred by the programmer, e.g. saves of calibration results, hit
+    /// counters and rolled back configurations, the same way as [`UsbConfigManager`].
+    #[task(priority = 1, shared = [usb_dev, storage])]
+    async fn CfgCommit(ctx: CfgCommit::Context, mut r: CommitReceiver) {
+        let (mut usb_dev, mut storage) = (ctx.shared.usb_dev, ctx.shared.storage);
+        while let Ok(()) = r.recv().await {
+            load::measure(LoadTask::Programmer, || {
+                while let Some(job) = usb_dev.lock(|dev| dev.programmer.take_deferred()) {
+                    let result = storage.lock(|storage| storage.run(&job));
+                    usb_dev.lock(|dev| dev.programmer.deferred_done(&job, result));
+                }
+            });
+        }
+    }
+
+    /// Pulses the haptic actuator on host request.
+    #[task(priority = 1, local = [actuator])]
+    async fn Haptic(ctx: Haptic::Context, pulse: HapticPulse) {
+        ctx.local.actuator.set(pulse.strength);
+        Systick::delay((pulse.duration_ms as u32).millis()).await;
+        ctx.local.actuator.set(0);
+    }
+
+    /// Restarts the sampling in the mode requested by the programmer.
+    #[task(priority = 1, shared = [piezo_handler])]
+    async fn SamplerControl(mut ctx: SamplerControl::Context, mode: PiezoSensorSampleMode) {
+        ctx.shared.piezo_handler.lock(|piezo| piezo.restart(mode));
     }
 
     /// Piezoelectric sensor handling hardware task.
@@ -201,60 +614,107 @@ mod app {
     ///
     /// The underlying sensor handling structure is queuing next injected sample from the ADC pin
     /// to the [`super::app::UsbHidSender`] task.
-    #[task(binds = ADC1_2, priority = 2, local = [piezo_handler])]
-    fn SensorHandling(ctx: SensorHandling::Context) {
-        ctx.local.piezo_handler.send();
+    ///
+    /// Runs above the parser and USB interrupts, so the sampling is never delayed by either of them.
+    #[task(binds = ADC1_2, priority = 4, shared = [piezo_handler])]
+    fn SensorHandling(mut ctx: SensorHandling::Context) {
+        load::measure(LoadTask::Sampling, || ctx.shared.piezo_handler.lock(|piezo| piezo.send()));
+    }
+
+    /// Records supply voltage dips.
+    #[task(binds = PVD, priority = 3)]
+    fn SupplyMonitor(_: SupplyMonitor::Context) {
+        super::supply::on_pvd();
+    }
+
+    /// Sends the next byte of log lines over the UART.
+    #[cfg(feature = "uart-log")]
+    #[task(binds = USART1, priority = 1)]
+    fn UartLog(_: UartLog::Context) {
+        super::logger::send_uart();
     }
 
     /// USB TX Polling.
-    #[task(binds = USB_HP_CAN_TX, priority = 2, shared = [usb_dev])]
-    fn UsbPollTx(mut ctx: UsbPollTx::Context) {
-        log::debug!("USB_EVENT_Tx");
-        ctx.shared.usb_dev.lock(|dev| {
-            crate::app::__usb_poll(dev);
-        });
+    #[task(binds = USB_HP_CAN_TX, priority = 2, local = [tx_requests], shared = [usb_dev, piezo_handler])]
+    fn UsbPollTx(ctx: UsbPollTx::Context) {
+        crate::debug!("USB_EVENT_Tx");
+        let (mut usb_dev, mut piezo) = (ctx.shared.usb_dev, ctx.shared.piezo_handler);
+        load::measure(LoadTask::UsbTx, || usb_dev.lock(|dev| crate::app::__usb_poll(dev, &mut piezo, ctx.local.tx_requests)));
     }
 
     /// USB RX Polling.
-    #[task(binds = USB_LP_CAN_RX0, priority = 2, shared = [usb_dev])]
-    fn UsbPollRx(mut ctx: UsbPollRx::Context) {
-        log::debug!("USB_EVENT_Rx");
-        ctx.shared.usb_dev.lock(|dev| {
+    ///
+    /// Programmer commands are only taken here and executed by [`UsbConfigManager`] task, so live
+    /// tuning commands take effect once the parser is done with the current sample.
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, local = [rx_requests], shared = [usb_dev, piezo_handler])]
+    fn UsbPollRx(ctx: UsbPollRx::Context) {
+        crate::debug!("USB_EVENT_Rx");
+        let (mut usb_dev, mut piezo) = (ctx.shared.usb_dev, ctx.shared.piezo_handler);
+        load::measure(LoadTask::UsbRx, || usb_dev.lock(|dev| {
             dev.init_poll();   /* Low priority interrupts include enumeration requests and error handling. */
-            crate::app::__usb_poll(dev);
-        });
+            crate::app::__usb_poll(dev, &mut piezo, ctx.local.rx_requests);
+        }));
     }
 
-    fn __usb_poll(dev: &mut UsbTaikoDrum) {
+    /// Sensor handler is only locked for a moment, since it is shared with the sampling interrupt.
+    fn __usb_poll(
+        dev: &mut UsbTaikoDrum, piezo: &mut impl rtic::Mutex<T = PiezoSensorHandler>, requests: &mut RequestSender
+    ) {
         dev.poll();
-        dev.programmer.program();
+        piezo.lock(|piezo| {
+            dev.stats.sample_overflows = piezo.overflows;
+            __update_sampling(dev, piezo);
+        });
+        dev.flush_reports();
+        if dev.dfu.take_detach() {
+            BootloaderEntry::spawn().ok();
+        }
+        if let Err(usb_err) = dev.program(requests) {
+            dev.handle_error(usb_err);
+        }
+        if dev.take_reenumerate() {
+            UsbReenumeration::spawn().ok();
+        }
+    }
+
+    /// Stops sampling while the bus is suspended to fit into the suspend current.
+    fn __update_sampling(dev: &mut UsbTaikoDrum, piezo: &mut PiezoSensorHandler) {
+        match dev.suspend_changed() {
+            Some(true) => piezo.suspend(),
+            Some(false) => piezo.resume(),
+            None => (),
+        }
     }
 
     // Panic handler.
     //
-    // Performs a full system reset after a several second timeout.
-    // TODO! Perform a better panic restart procedure.
+    // Nothing is preempted from here on. The drum is dropped off the bus, so the host releases keys
+    // held at the moment of panic, and the system is reset once the host noticed the detach.
     panic_custom::define_panic!(|info| {
-        log::error!("System panic occured: {}", info);
+        cortex_m::interrupt::disable();
+        super::crash::record_panic(info);
+        #[cfg(not(feature = "defmt"))]
+        crate::error!("System panic occured: {}", info);
+        #[cfg(feature = "defmt")]
+        crate::error!("System panic occured: {}", defmt::Display2Format(info));
+        UsbTaikoDrum::disconnect();
+        #[cfg(feature = "uart-log")]
+        super::logger::flush_uart();
+        cortex_m::asm::delay(PANIC_RESET_DELAY_MS * (SYSCLK_HZ / 1_000));
+        rtic::export::SCB::sys_reset()
     });
 
-    const ARM_SYSTICK_HZ: u32 = 72_000_000;
-}
-
-#[macro_export]
-macro_rules! int_enable {
-    ($name:ident) => {
-        unsafe {
-        cortex_m::peripheral::NVIC::unmask(super::pac::Interrupt::$name); 
-        }
-    };
-}
-
-#[macro_export]
-macro_rules! int_disable {
-    ($name:ident) => {
-        cortex_m::peripheral::NVIC::mask(super::pac::Interrupt::$name); 
-    };
+    /// Delay between the DFU detach request and bootloader entry.
+    const DFU_DETACH_DELAY_MS: u32 = 50;
+    /// Delay between the panic and system reset, which is long enough for the host to notice the
+    /// detach. Restarting right away would look like a glitch, which keeps keys held on some hosts.
+    const PANIC_RESET_DELAY_MS: u32 = 1_000;
+    /// Period of USB polls besides interrupts, which is long enough to cost nothing.
+    const USB_SOFT_POLL_MS: u32 = 10;
+    /// Idle rate is defined in 4 ms units.
+    const HID_IDLE_TICK_MS: u32 = 4;
+    /// Period of cumulative uptime updates. Uptime between the last update and a reset is lost.
+    const UPTIME_TICK_S: u32 = 10;
 }
 
 /// Module containing all information about current firmware version.
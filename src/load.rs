@@ -0,0 +1,123 @@
+//! CPU load accounting.
+//!
+//! Synchronous parts of RTIC tasks are timed with the CPU cycle counter. Those are always nested
+//! properly, since a preempting task finishes before the preempted one continues, therefore the
+//! CPU is busy while at least one of them runs and idles otherwise. Times of each task include
+//! preemption by higher priority tasks. Figures are taken over a window, which starts anew each
+//! time they are read by the programmer.
+
+use rtic_monotonics::systick::prelude::*;
+
+use super::app::Systick;
+use super::timing::{self, CYCLES_PER_FRAME};
+
+/// Instrumented tasks in the order of reported figures.
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+pub(crate) enum Task {
+    /// ADC interrupt, which queues samples.
+    Sampling,
+    /// Processing of a sample by the parser.
+    Parser,
+    /// USB interrupts.
+    UsbTx,
+    UsbRx,
+    /// Execution of programmer commands.
+    Programmer,
+}
+
+/// Amount of [`Task`] variants.
+const TASKS: usize = 5;
+/// Figures are in 0.01% units.
+const LOAD_SCALE: u64 = 10_000;
+
+/// Busy time of a single task within the window.
+#[derive(Clone, Copy)]
+struct TaskTime {
+    cycles: u64,
+    /// Longest single run in CPU cycles.
+    max: u32,
+}
+
+/// Load within the current window.
+struct Load {
+    /// Start of the window in milliseconds.
+    start_ms: u32,
+    /// Instrumented parts currently running, including preempted ones.
+    depth: u32,
+    /// Timestamp of the moment the CPU became busy.
+    busy_since: u32,
+    busy: u64,
+    tasks: [TaskTime; TASKS],
+}
+
+/// Load accounting, which is only accessed within a critical section.
+static mut LOAD: Load = Load { start_ms: 0, depth: 0, busy_since: 0, busy: 0, tasks: [TaskTime { cycles: 0, max: 0 }; TASKS] };
+
+/// Runs the synchronous part of the task and accounts its execution time.
+#[inline(always)]
+pub(crate) fn measure<R>(task: Task, f: impl FnOnce() -> R) -> R {
+    let start = enter();
+    let result = f();
+    leave(task, start);
+    result
+}
+
+/// Marks the CPU busy and returns the start timestamp.
+fn enter() -> u32 {
+    cortex_m::interrupt::free(|_| {
+        let load = unsafe { &mut *core::ptr::addr_of_mut!(LOAD) };
+        let now = timing::now();
+        if load.depth == 0 {
+            load.busy_since = now;
+        }
+        load.depth += 1;
+        now
+    })
+}
+
+/// Accounts the run of the task started at the provided timestamp.
+fn leave(task: Task, start: u32) {
+    cortex_m::interrupt::free(|_| {
+        let load = unsafe { &mut *core::ptr::addr_of_mut!(LOAD) };
+        let now = timing::now();
+        let time = &mut load.tasks[task as usize];
+        let cycles = now.wrapping_sub(start);
+        time.cycles += cycles as u64;
+        time.max = time.max.max(cycles);
+
+        load.depth -= 1;
+        if load.depth == 0 {
+            load.busy += now.wrapping_sub(load.busy_since) as u64;
+        }
+    })
+}
+
+/// Takes figures of the current window and starts the next one: window length in milliseconds,
+/// idle time in 0.01% units, followed by the share of each task in 0.01% units and its longest
+/// run in CPU cycles.
+pub(crate) fn take() -> [u32; 2 + TASKS * 2] {
+    let now_ms = Systick::now().duration_since_epoch().to_millis();
+    cortex_m::interrupt::free(|_| {
+        let load = unsafe { &mut *core::ptr::addr_of_mut!(LOAD) };
+        let window_ms = now_ms.wrapping_sub(load.start_ms);
+        // Frames are 1 ms long, so those are the CPU cycles of a millisecond.
+        let window = (window_ms as u64 * CYCLES_PER_FRAME as u64).max(1);
+        let share = |cycles: u64| (cycles.min(window) * LOAD_SCALE / window) as u32;
+
+        let mut figures = [0; 2 + TASKS * 2];
+        figures[0] = window_ms;
+        figures[1] = LOAD_SCALE as u32 - share(load.busy);
+        for (pair, time) in figures[2..].chunks_exact_mut(2).zip(&mut load.tasks) {
+            pair.copy_from_slice(&[share(time.cycles), time.max]);
+            *time = TaskTime { cycles: 0, max: 0 };
+        }
+        load.start_ms = now_ms;
+        load.busy = 0;
+        // The programmer itself is running, so the busy time is only counted from now on.
+        if load.depth > 0 {
+            load.busy_since = timing::now();
+        }
+        figures
+    })
+}
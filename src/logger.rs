@@ -1,13 +1,244 @@
 //! Custom semihosting logger.
+//!
+//! Messages are logged with the crate-level [`info!`](crate::info) and alike macros, which forward
+//! them to the `log` facade printed by [`TaikoLogger`] over RTT, or to `defmt` with the `defmt`
+//! feature. Defmt only sends interned format string indices and raw arguments, so strings are not
+//! kept in flash and nothing is formatted on the target. Its level is selected at compile time
+//! with `DEFMT_LOG`, which is set to info in `.cargo/config.toml`.
+//!
+//! Both backends use RTT in non-blocking mode: when no debugger is attached or the host reads too
+//! slowly, messages, which do not fit into the channel, are skipped instead of stalling the caller.
+//! [`TaikoLogger`] formats each record into a [`Line`] on the stack first, so the critical section
+//! only copies the finished line into RTT and never masks sampling interrupts for formatting.
+//!
+//! With the `itm` feature, lines are also sent to ITM stimulus port 0, which ST-Link probes read
+//! over SWO, e.g. with `openocd` (see `openocd/stlink.cfg`) or the SWV viewer of ST tools. The
+//! port is selected at runtime: lines are only sent while the debugger enables tracing and the
+//! port, so the drum never waits for the FIFO without a probe. ITM has no buffer to skip lines
+//! with, therefore lines of preempting tasks may interleave.
+//!
+//! A message repeating within a second, e.g. a warning of a starving queue, is only logged once
+//! and the amount of its suppressed repeats is appended to the next logged line, so logging never
+//! worsens the very condition it reports. Defmt messages are cheap to send and are not limited.
+//!
+//! With the `uart-log` feature, lines are also queued for USART1 TX on PA9, which is sent byte by
+//! byte from the lowest priority interrupt. Lines, which do not fit into the queue, are skipped the
+//! same way as with RTT.
+//!
+//! Records of both backends are stamped with the Systick monotonic time in milliseconds, so USB,
+//! ADC and parser events logged from different tasks can be correlated.
+//!
+//! With the `log-ring` feature, the latest lines are also kept in RAM and read back with the
+//! programmer, so events leading up to a bug are retrieved without a debugger attached. Defmt
+//! arguments are only formatted on the host, therefore its lines in the ring only carry the level
+//! and format string of info messages and above, the same as the default `DEFMT_LOG`.
 
-use rtt_target::rprintln;
-use log::{Log, Level, SetLoggerError};
+#[cfg(not(feature = "defmt"))]
+use rtt_target::UpChannel;
+#[cfg(not(feature = "defmt"))]
+use log::{Log, Level};
+use core::fmt::Write;
+use log::SetLoggerError;
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+use rtic_monotonics::systick::prelude::*;
+
+use super::app::Systick;
+
+#[cfg(feature = "defmt")]
+defmt::timestamp!("{=u32:ms}", Systick::now().duration_since_epoch().to_millis());
+
+/// Logs a trace message with the selected backend.
+#[macro_export]
+macro_rules! trace {
+    ($fmt:literal $($arg:tt)*) => {{
+        #[cfg(feature = "defmt")] ::defmt::trace!($fmt $($arg)*);
+        #[cfg(not(feature = "defmt"))] ::log::trace!($fmt $($arg)*);
+    }};
+}
+
+/// Logs a debug message with the selected backend.
+#[macro_export]
+macro_rules! debug {
+    ($fmt:literal $($arg:tt)*) => {{
+        #[cfg(feature = "defmt")] ::defmt::debug!($fmt $($arg)*);
+        #[cfg(not(feature = "defmt"))] ::log::debug!($fmt $($arg)*);
+    }};
+}
+
+/// Logs an info message with the selected backend.
+#[macro_export]
+macro_rules! info {
+    ($fmt:literal $($arg:tt)*) => {{
+        #[cfg(all(feature = "defmt", feature = "log-ring"))]
+        $crate::logger::push_ring(concat!("[INFO] ", $fmt, "\n").as_bytes());
+        #[cfg(feature = "defmt")] ::defmt::info!($fmt $($arg)*);
+        #[cfg(not(feature = "defmt"))] ::log::info!($fmt $($arg)*);
+    }};
+}
+
+/// Logs a warning with the selected backend.
+#[macro_export]
+macro_rules! warn {
+    ($fmt:literal $($arg:tt)*) => {{
+        #[cfg(all(feature = "defmt", feature = "log-ring"))]
+        $crate::logger::push_ring(concat!("[WARN] ", $fmt, "\n").as_bytes());
+        #[cfg(feature = "defmt")] ::defmt::warn!($fmt $($arg)*);
+        #[cfg(not(feature = "defmt"))] ::log::warn!($fmt $($arg)*);
+    }};
+}
+
+/// Logs an error with the selected backend.
+#[macro_export]
+macro_rules! error {
+    ($fmt:literal $($arg:tt)*) => {{
+        #[cfg(all(feature = "defmt", feature = "log-ring"))]
+        $crate::logger::push_ring(concat!("[ERROR] ", $fmt, "\n").as_bytes());
+        #[cfg(feature = "defmt")] ::defmt::error!($fmt $($arg)*);
+        #[cfg(not(feature = "defmt"))] ::log::error!($fmt $($arg)*);
+    }};
+}
+
+#[cfg(all(any(feature = "itm", feature = "uart-log"), feature = "defmt"))]
+compile_error!("`defmt` frames are only sent over RTT, `itm` and `uart-log` require the `log` backend.");
+
+#[cfg(all(feature = "log-ring", any(feature = "two-player", feature = "capture")))]
+compile_error!("`log-ring` buffer does not fit into RAM along with the second drum or `capture` buffer.");
+
+/// Bytes of the latest log lines kept in RAM.
+#[cfg(feature = "log-ring")]
+pub(crate) const RING_LEN: usize = 1024;
+
+/// Latest log lines, which overwrite the oldest ones.
+#[cfg(feature = "log-ring")]
+struct Ring {
+    buff: [u8; RING_LEN],
+    /// Bytes written since boot.
+    written: usize,
+    /// Bytes of the ring latched by the first chunk of the dump.
+    start: usize,
+    end: usize,
+}
+
+/// Log ring, which is only accessed within a critical section.
+#[cfg(feature = "log-ring")]
+static mut RING: Ring = Ring { buff: [0; RING_LEN], written: 0, start: 0, end: 0 };
+
+/// Appends the log line to the ring.
+#[cfg(feature = "log-ring")]
+pub(crate) fn push_ring(line: &[u8]) {
+    cortex_m::interrupt::free(|_| {
+        let ring = unsafe { &mut *core::ptr::addr_of_mut!(RING) };
+        for &byte in line {
+            ring.buff[ring.written % RING_LEN] = byte;
+            ring.written = ring.written.wrapping_add(1);
+        }
+    })
+}
+
+/// Reads a chunk of the ring from its oldest line. The first chunk latches the lines to read, so
+/// lines logged meanwhile, e.g. by the programmer itself, never shift the following chunks.
+#[cfg(feature = "log-ring")]
+pub(crate) fn read_ring(offset: usize, buff: &mut [u8]) -> usize {
+    cortex_m::interrupt::free(|_| {
+        let ring = unsafe { &mut *core::ptr::addr_of_mut!(RING) };
+        if offset == 0 {
+            ring.end = ring.written;
+            ring.start = ring.written.saturating_sub(RING_LEN);
+        }
+        let size = (ring.end - ring.start).saturating_sub(offset).min(buff.len());
+        for (i, byte) in (ring.start + offset..).zip(&mut buff[..size]) {
+            *byte = ring.buff[i % RING_LEN];
+        }
+        size
+    })
+}
 
 /// Semihosting debug logger for taiko drum board.
+#[cfg(not(feature = "defmt"))]
 struct TaikoLogger;
 
+#[cfg(not(feature = "defmt"))]
 const APP_LOGGER: TaikoLogger = TaikoLogger; 
 
+/// Longest log line. Longer messages are truncated.
+#[cfg(not(feature = "defmt"))]
+const LINE_LEN: usize = 96;
+
+/// RTT up channel, which is only accessed within a critical section.
+#[cfg(not(feature = "defmt"))]
+static mut CHANNEL: Option<UpChannel> = None;
+
+/// Time, within which a message from the same call site is only logged once.
+#[cfg(not(feature = "defmt"))]
+const REPEAT_WINDOW_MS: u32 = 1000;
+
+/// Amount of call sites, whose repeats are tracked at the same time.
+#[cfg(not(feature = "defmt"))]
+const REPEAT_SITES: usize = 8;
+
+/// Call site, which logged recently, and its repeats suppressed within [`REPEAT_WINDOW_MS`].
+#[cfg(not(feature = "defmt"))]
+#[derive(Clone, Copy)]
+struct Site {
+    hash: u32,
+    stamp: u32,
+    count: u32,
+}
+
+/// Recently logging call sites. Sites are told apart regardless of the formatted arguments, so
+/// e.g. a warning with a changing value is still suppressed, and interleaved warnings from
+/// different sites do not reset each other.
+#[cfg(not(feature = "defmt"))]
+struct Repeats([Site; REPEAT_SITES]);
+
+#[cfg(not(feature = "defmt"))]
+impl Repeats {
+    /// Counts the message, if its site logged within the window. Otherwise, the message is logged
+    /// along with the amount of suppressed repeats from the same site.
+    ///
+    /// A new site takes the slot of the site, which logged the longest time ago. Its suppressed
+    /// repeats are forgotten.
+    fn update(&mut self, hash: u32, ms: u32) -> Option<u32> {
+        let site = match self.0.iter_mut().find(|site| site.hash == hash) {
+            Some(site) => site,
+            None => {
+                let site = self.0.iter_mut().max_by_key(|site| ms.wrapping_sub(site.stamp)).unwrap();
+                *site = Site { hash, stamp: ms.wrapping_sub(REPEAT_WINDOW_MS), count: 0 };
+                site
+            }
+        };
+        if ms.wrapping_sub(site.stamp) < REPEAT_WINDOW_MS {
+            site.count += 1;
+            return None;
+        }
+        let count = site.count;
+        *site = Site { hash, stamp: ms, count: 0 };
+        Some(count)
+    }
+}
+
+/// Repeats of recently logging call sites, which are only accessed within a critical section.
+#[cfg(not(feature = "defmt"))]
+static mut REPEATS: Repeats = Repeats([Site { hash: 0, stamp: 0, count: 0 }; REPEAT_SITES]);
+
+/// Text formatted into a buffer, e.g. a log line before it is written to RTT. Text, which does not
+/// fit into the buffer, is dropped.
+pub(crate) struct Line<'a> {
+    pub(crate) buff: &'a mut [u8],
+    pub(crate) len: usize,
+}
+
+impl Write for Line<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(self.buff.len() - self.len);
+        self.buff[self.len..][..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "defmt"))]
 impl TaikoLogger {
     /// Initializes global [`TaikoLogger`] structure for the application.
     ///
@@ -17,18 +248,29 @@ impl TaikoLogger {
     fn init() -> Result<(), SetLoggerError> {
         log::set_logger(&APP_LOGGER)
             .map(|_l| {
+                let channels = rtt_target::rtt_init! {
+                    up: {
+                        0: {
+                            size: 1024
+                            mode: NoBlockSkip
+                            name: "Terminal"
+                        }
+                    }
+                };
+                cortex_m::interrupt::free(|_| unsafe {
+                    *core::ptr::addr_of_mut!(CHANNEL) = Some(channels.up.0);
+                });
                 #[cfg(debug_assertions)] {
-                    rtt_target::debug_rtt_init_print!();
                     log::set_max_level(log::LevelFilter::Trace);
                 } 
                 #[cfg(not(debug_assertions))] {
-                    rtt_target::rtt_init_print!();
                     log::set_max_level(log::LevelFilter::Info);
                 } 
             })
     }
 }
 
+#[cfg(not(feature = "defmt"))]
 impl Log for TaikoLogger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
         #[cfg(debug_assertions)] {
@@ -41,22 +283,159 @@ impl Log for TaikoLogger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            rprintln!("{{{}}}, [{}], {}", 
+            // Last byte is kept for the newline.
+            let mut buff = [0; LINE_LEN];
+            let mut line = Line { buff: &mut buff[..LINE_LEN - 1], len: 0 };
+            let ms = Systick::now().duration_since_epoch().to_millis();
+            write!(line, "{} ms ", ms).ok();
+            write!(line, "{{{}}}, [{}], {}", 
                 record.target(), 
                 record.level(), 
                 record.args()
-            ); 
+            ).ok(); 
+
+            // Call sites are told apart by the FNV-1a hash of their file and line.
+            let site = record.file().unwrap_or(record.target()).bytes()
+                .chain(record.line().unwrap_or(0).to_le_bytes());
+            let hash = site.fold(0x811C_9DC5, |hash: u32, b| (hash ^ b as u32).wrapping_mul(0x0100_0193));
+            let Some(repeats) = cortex_m::interrupt::free(|_| unsafe { (*core::ptr::addr_of_mut!(REPEATS)).update(hash, ms) }) else {
+                return;
+            };
+            if repeats > 0 {
+                write!(line, " ({} repeats suppressed)", repeats).ok();
+            }
+            let len = line.len;
+            buff[len] = b'\n';
+            cortex_m::interrupt::free(|_| unsafe {
+                if let Some(channel) = (*core::ptr::addr_of_mut!(CHANNEL)).as_mut() {
+                    channel.write(&buff[..=len]);
+                }
+            });
+            #[cfg(feature = "itm")]
+            write_itm(&buff[..=len]);
+            #[cfg(feature = "uart-log")]
+            push_uart(&buff[..=len]);
+            #[cfg(feature = "log-ring")]
+            push_ring(&buff[..=len]);
         }
     }
 
     fn flush(&self) {}
 }
 
+/// Writes the line to ITM stimulus port 0, if the debugger enabled it.
+#[cfg(feature = "itm")]
+fn write_itm(line: &[u8]) {
+    let itm = unsafe { &mut *cortex_m::peripheral::ITM::PTR };
+    // ITMENA of the trace control and the first bit of the trace enable registers.
+    if itm.tcr.read() & 1 != 0 && itm.ter[0].read() & 1 != 0 {
+        for &byte in line {
+            while !itm.stim[0].is_fifo_ready() {}
+            itm.stim[0].write_u8(byte);
+        }
+    }
+}
+
+/// Baud rate of the UART logger.
+#[cfg(feature = "uart-log")]
+const UART_BAUD: u32 = 115_200;
+/// Bytes of log lines waiting for the UART, which is several lines.
+#[cfg(feature = "uart-log")]
+pub(crate) const UART_QUEUE_LEN: usize = 512;
+
+/// Log lines waiting for the UART.
+#[cfg(feature = "uart-log")]
+struct UartQueue {
+    buff: [u8; UART_QUEUE_LEN],
+    /// Bytes queued and sent since boot.
+    queued: usize,
+    sent: usize,
+}
+
+/// UART queue, which is only accessed within a critical section.
+#[cfg(feature = "uart-log")]
+static mut UART_QUEUE: UartQueue = UartQueue { buff: [0; UART_QUEUE_LEN], queued: 0, sent: 0 };
+
+/// Configures USART1 to send log lines on PA9. Lines logged before are sent right away.
+#[cfg(feature = "uart-log")]
+pub(crate) fn init_uart(
+    usart: super::pac::USART1, mut pin: super::pins::UartTxPin, rcc: &mut super::pac::RCC, sysclk_hz: u32
+) {
+    rcc.apb2enr.modify(|_, w| w.usart1en().set_bit());
+    pin.set_mode(super::pins::PinMode::AltPushPull);
+    set_uart_clock(sysclk_hz);
+    usart.cr1.write(|w| w.ue().set_bit().te().set_bit().txeie().set_bit());
+}
+
+/// Keeps the baud rate after the system clock is changed. USART1 is clocked by APB2, which runs at
+/// the full system clock.
+#[cfg(feature = "uart-log")]
+pub(crate) fn set_uart_clock(sysclk_hz: u32) {
+    let usart = unsafe { &*super::pac::USART1::ptr() };
+    usart.brr.write(|w| unsafe { w.bits(sysclk_hz / UART_BAUD) });
+}
+
+/// Queues the line for the UART. The line is skipped, if it does not fit into the queue.
+#[cfg(feature = "uart-log")]
+fn push_uart(line: &[u8]) {
+    cortex_m::interrupt::free(|_| {
+        let queue = unsafe { &mut *core::ptr::addr_of_mut!(UART_QUEUE) };
+        if UART_QUEUE_LEN - queue.queued.wrapping_sub(queue.sent) < line.len() {
+            return;
+        }
+        for &byte in line {
+            queue.buff[queue.queued % UART_QUEUE_LEN] = byte;
+            queue.queued = queue.queued.wrapping_add(1);
+        }
+        let usart = unsafe { &*super::pac::USART1::ptr() };
+        usart.cr1.modify(|_, w| w.txeie().set_bit());
+    })
+}
+
+/// Sends the next queued byte on the TXE interrupt. The interrupt is disabled when the queue is
+/// empty.
+#[cfg(feature = "uart-log")]
+pub(crate) fn send_uart() {
+    cortex_m::interrupt::free(|_| {
+        let queue = unsafe { &mut *core::ptr::addr_of_mut!(UART_QUEUE) };
+        let usart = unsafe { &*super::pac::USART1::ptr() };
+        if queue.sent == queue.queued {
+            usart.cr1.modify(|_, w| w.txeie().clear_bit());
+        } else {
+            usart.dr.write(|w| w.dr().bits(queue.buff[queue.sent % UART_QUEUE_LEN] as u16));
+            queue.sent = queue.sent.wrapping_add(1);
+        }
+    })
+}
+
+/// Sends queued lines right away from the panic handler, which runs with interrupts disabled.
+#[cfg(feature = "uart-log")]
+pub(crate) fn flush_uart() {
+    let queue = unsafe { &mut *core::ptr::addr_of_mut!(UART_QUEUE) };
+    let usart = unsafe { &*super::pac::USART1::ptr() };
+    // Lines logged before the UART is configured are never sent.
+    if usart.cr1.read().ue().bit_is_clear() {
+        return;
+    }
+    while queue.sent != queue.queued {
+        while usart.sr.read().txe().bit_is_clear() {}
+        usart.dr.write(|w| w.dr().bits(queue.buff[queue.sent % UART_QUEUE_LEN] as u16));
+        queue.sent = queue.sent.wrapping_add(1);
+    }
+}
+
 /// Initializes global [`TaikoLogger`] structure for the application.
 ///
 /// # Debug
 ///
 /// While in debug build, uses Trace logging level.
+#[cfg(not(feature = "defmt"))]
 pub fn init() -> Result<(), SetLoggerError> {
     TaikoLogger::init()
 }
+
+/// Defmt RTT channel is set up by its first message, so there is nothing to initialize.
+#[cfg(feature = "defmt")]
+pub fn init() -> Result<(), SetLoggerError> {
+    Ok(())
+}
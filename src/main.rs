@@ -1,6 +1,7 @@
 //! Main binary entry stub.
 //!
-//! All application definitions and functions are implemented within the library.
+//! All application definitions and functions are implemented within the library. The RTIC
+//! application is only defined there, so this binary never diverges from it.
 #![allow(unused_imports)]
 #![no_main]
 #![no_std]
@@ -0,0 +1,171 @@
+//! USB MIDI device class, which allows to use the drum as an electronic percussion trigger.
+//!
+//! Implements a minimal USB MIDI 1.0 function with a single embedded OUT jack and a bulk IN
+//! endpoint. Each pad emits Note On/Off messages on the General MIDI percussion channel.
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const USB_SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const USB_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+/* Class-specific descriptor types and subtypes. */
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const HEADER: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+const JACK_EMBEDDED: u8 = 0x01;
+const JACK_EXTERNAL: u8 = 0x02;
+
+const EXTERNAL_IN_JACK_ID: u8 = 0x01;
+const EMBEDDED_OUT_JACK_ID: u8 = 0x02;
+/// Total length of class-specific MIDI streaming descriptors (header, two jacks and endpoints).
+const MS_TOTAL_LENGTH: u16 = 7 + 6 + 9 + 9 + 5;
+
+/// Endpoint packet size. Kept small, since USB packet memory is shared with HID and CDC classes.
+const MIDI_PACKET_SIZE: u16 = 16;
+/// General MIDI percussion channel (channel 10).
+const PERCUSSION_CHANNEL: u8 = 9;
+/// Note velocity used for all hits.
+const NOTE_VELOCITY: u8 = 100;
+/// General MIDI percussion notes for LK, LD, RD, RK pads.
+///
+/// Kats are mapped to side stick and closed hi-hat, dons to bass drum and acoustic snare.
+const PAD_NOTES: [u8; 4] = [37, 36, 38, 42];
+
+/* Code index numbers of USB MIDI event packets. */
+const CIN_NOTE_OFF: u8 = 0x08;
+const CIN_NOTE_ON: u8 = 0x09;
+
+/// MIDI output mode.
+#[repr(u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MidiMode {
+    /// MIDI interface is not present. Hits are sent as HID reports.
+    #[default]
+    Off = 0,
+    /// Hits are sent as percussion notes instead of HID reports.
+    Percussion = 1,
+}
+
+impl From<u8> for MidiMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Percussion,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// Note On/Off events for pads that changed their state.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MidiNoteEvents {
+    /// Current state of all pads in LK, LD, RD, RK order.
+    pressed: [bool; 4],
+    /// Pads which state has changed since the last event.
+    changed: [bool; 4],
+}
+
+impl MidiNoteEvents {
+    /// Generates events from the previous and current pads state.
+    pub(crate) fn new(previous: [bool; 4], current: [bool; 4]) -> Self {
+        Self {
+            pressed: current,
+            changed: core::array::from_fn(|i| previous[i] != current[i]),
+        }
+    }
+
+    /// Serializes events into USB MIDI event packets. Returns the buffer and amount of used bytes.
+    fn packets(&self) -> ([u8; 16], usize) {
+        let mut buf = [0u8; 16];
+        let mut len = 0;
+
+        for ((pressed, _), note) in self.pressed.into_iter()
+            .zip(self.changed)
+            .zip(PAD_NOTES)
+            .filter(|((_, changed), _)| *changed)
+        {
+            let (cin, status, velocity) = if pressed {
+                (CIN_NOTE_ON, 0x90 | PERCUSSION_CHANNEL, NOTE_VELOCITY)
+            } else {
+                (CIN_NOTE_OFF, 0x80 | PERCUSSION_CHANNEL, 0)
+            };
+            buf[len..len + 4].copy_from_slice(&[cin, status, note, velocity]);
+            len += 4;
+        }
+
+        (buf, len)
+    }
+}
+
+/// USB MIDI class.
+pub(crate) struct MidiClass<'a, B: UsbBus> {
+    ac_if: InterfaceNumber,
+    ms_if: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+}
+
+impl<'a, B: UsbBus> MidiClass<'a, B> {
+    /// Allocates interfaces and endpoint for a new MIDI function.
+    pub(crate) fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            ac_if: alloc.interface(),
+            ms_if: alloc.interface(),
+            ep_in: alloc.bulk(MIDI_PACKET_SIZE),
+        }
+    }
+
+    /// Sends note events to the host.
+    pub(crate) fn send(&self, events: &MidiNoteEvents) -> Result<usize> {
+        let (buf, len) = events.packets();
+        if len == 0 {
+            return Ok(0);
+        }
+        self.ep_in.write(&buf[..len])
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.iad(self.ac_if, 2, USB_CLASS_AUDIO, USB_SUBCLASS_AUDIOCONTROL, 0x00, None)?;
+
+        /* Audio control interface with a single MIDI streaming interface in collection. */
+        writer.interface(self.ac_if, USB_CLASS_AUDIO, USB_SUBCLASS_AUDIOCONTROL, 0x00)?;
+        writer.write(CS_INTERFACE, &[
+            HEADER,
+            0x00, 0x01,     // bcdADC 1.0
+            0x09, 0x00,     // wTotalLength
+            0x01,           // bInCollection
+            self.ms_if.into(),
+        ])?;
+
+        /* MIDI streaming interface. */
+        writer.interface(self.ms_if, USB_CLASS_AUDIO, USB_SUBCLASS_MIDISTREAMING, 0x00)?;
+        writer.write(CS_INTERFACE, &[
+            HEADER,
+            0x00, 0x01,     // bcdMSC 1.0
+            MS_TOTAL_LENGTH as u8, (MS_TOTAL_LENGTH >> 8) as u8,
+        ])?;
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, JACK_EXTERNAL, EXTERNAL_IN_JACK_ID, 0x00])?;
+        writer.write(CS_INTERFACE, &[
+            MIDI_OUT_JACK, JACK_EMBEDDED, EMBEDDED_OUT_JACK_ID,
+            0x01,                   // bNrInputPins
+            EXTERNAL_IN_JACK_ID,    // baSourceID
+            0x01,                   // baSourcePin
+            0x00,
+        ])?;
+
+        /* Audio endpoints use 9-byte standard descriptor with bRefresh and bSynchAddress. */
+        writer.endpoint_ex(&self.ep_in, |buf| {
+            buf[..2].copy_from_slice(&[0x00, 0x00]);
+            Ok(2)
+        })?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 0x01, EMBEDDED_OUT_JACK_ID])?;
+
+        Ok(())
+    }
+}
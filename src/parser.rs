@@ -2,60 +2,177 @@
 //! piezoelectric sensors and pushes further information about true and spurious hits.
 
 use crate::{
-    cfg::{DrumConfig, HitMapping}, 
-    hid::DrumHitStrokeHidReport, 
-    piezo::PiezoSample,
-    cross_correlation::xcorr,
+    cfg::{DrumConfig, HitMapping, ConsumerMapping}, 
+    hid::{DrumReport, DrumHitStrokeHidReport, DrumConsumerHidReport, DrumGamepadHidReport, DrumHoriHidReport, HidMode}, 
+    midi::{MidiMode, MidiNoteEvents},
+    cross_correlation::{xcorr, XcorrScratch},
 };
 use heapless::Vec;
 
-const MID_RANGE: i16 = 4096 / 2;
-const WINDOW_SIZE: usize = 256;
+/// Midpoint of the 12-bit ADC range, which is the idle level of the biased sensors.
+pub(crate) const MID_RANGE: i16 = 4096 / 2;
+/// Samples within the window of each pad.
+pub(crate) const WINDOW_SIZE: usize = 256;
+/* Correlations weaker than this are not trusted to detect sensor cross-talk. */
+const XCORR_MIN_PEAK: i16 = 8;
+const XCORR_MAX_SECONDARY_RATIO: f32 = 0.8;
+
+/// Decision on a detected hit.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// No other pad was hit at the same time.
+    Accepted = 0,
+    /// Correlation with another pad is too weak, so both pads were hit at once.
+    Simultaneous = 1,
+    /// Rejected as cross-talk of another pad.
+    Crosstalk = 2,
+}
+
+/// Detection details of a single hit, which are streamed for tuning of hit detection.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HitRecord {
+    pub(crate) pad: u8,
+    pub(crate) verdict: Verdict,
+    /// Pad, which correlation with this one decided the verdict.
+    pub(crate) other: u8,
+    /// Extreme sample within the window relative to the ADC midpoint.
+    pub(crate) peak: i16,
+    /// Adaptive threshold (median) of the window.
+    pub(crate) threshold: i16,
+    /// Distance between the peak and threshold, and the distance required for a hit.
+    pub(crate) deviation: u16,
+    pub(crate) limit: u16,
+}
+
+/// Length of serialized [`HitRecord`].
+pub(crate) const HIT_RECORD_LEN: usize = 11;
+
+impl HitRecord {
+    /// Pad, verdict and other pad bytes, followed by big-endian values.
+    pub(crate) fn to_bytes(self) -> [u8; HIT_RECORD_LEN] {
+        let mut bytes = [self.pad, self.verdict as u8, self.other, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes[3..5].copy_from_slice(&self.peak.to_be_bytes());
+        bytes[5..7].copy_from_slice(&self.threshold.to_be_bytes());
+        bytes[7..9].copy_from_slice(&self.deviation.to_be_bytes());
+        bytes[9..].copy_from_slice(&self.limit.to_be_bytes());
+        bytes
+    }
+}
+
+/// Drum which samples are being parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Player {
+    One,
+    /// Second drum in two-player configuration. Only reported in keyboard HID mode.
+    #[cfg_attr(not(feature = "two-player"), allow(dead_code))]
+    Two,
+}
 
 #[derive(Debug)]
 pub struct Parser { 
     /// Sliding windows of samples. It's length is based on the fact that each piezo signal will
-    /// likely last for around 1-2ms and 20 kHz sampling rate of ADC. Each sensor has it's own window.
+    /// likely last for around 1-2ms and 10 kHz sampling rate of ADC. Each sensor has it's own window.
     windows: [SampleWindow<i16, WINDOW_SIZE>; 4],
     /// Four booleans representing the current state of four hit spots.
     states: [bool; 4],
+    /// Pads state from the last generated report.
+    reported: [bool; 4],
+    /// Hit strength of currently pressed pads, measured when the hit was detected.
+    velocities: [u8; 4],
+    /// Drum handled by this parser.
+    player: Player,
+    /// Hits rejected as cross-talk of another pad since boot.
+    crosstalk: u32,
+    /// Hits detected within the latest sample.
+    records: Vec<HitRecord, 4>,
 }
 
 impl Default for Parser {
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    /// Creates a new parser for the first drum. Being zeroed, it is placed into static memory
+    /// without being copied through the stack of the initialization.
+    pub(crate) const fn new() -> Self {
         Self {
             states: [false; 4],
-            windows: core::array::from_fn(|_| SampleWindow::new(0i16)),
+            windows: [const { SampleWindow::new(0i16) }; 4],
+            reported: [false; 4],
+            velocities: [0; 4],
+            player: Player::One,
+            crosstalk: 0,
+            records: Vec::new(),
         }
     }
-}
 
-impl Parser {
+    /// Assigns the drum handled by this parser.
+    pub(crate) fn assign(&mut self, player: Player) {
+        self.player = player;
+    }
+
+    /// Hits rejected as cross-talk of another pad since boot.
+    pub(crate) fn crosstalk(&self) -> u32 {
+        self.crosstalk
+    }
+
+    /// Hits detected within the latest sample along with the reasons to accept or reject them.
+    pub(crate) fn records(&self) -> &[HitRecord] {
+        &self.records
+    }
+
+    /// Samples of each pad relative to the ADC midpoint, where zero age is the latest sample.
+    #[cfg_attr(not(feature = "capture"), allow(dead_code))]
+    pub(crate) fn history(&self, age: usize) -> [i16; 4] {
+        self.windows.each_ref().map(|w| w.fifo[(w.index_fifo + WINDOW_SIZE - 1 - age) % WINDOW_SIZE])
+    }
+
+    /// Current state of pads in LK, LD, RD, RK order.
+    pub(crate) fn pads(&self) -> [bool; 4] {
+        self.states
+    }
+
     /// Parses upcoming samples and returns a boolean according to the current change of state.
+    ///
+    /// Scratch area is shared between parsers of all drums, since they are never running at once.
+    /// In menu navigation mode gameplay mapping is replaced with [`HitMapping::menu`].
     pub(crate) fn parse(
         &mut self, 
+        scratch: &mut XcorrScratch,
         cfg: &DrumConfig, 
-        sample: PiezoSample
-    ) -> Option<DrumHitStrokeHidReport> {
+        mode: HidMode,
+        midi: MidiMode,
+        menu: bool,
+        sample: [u16; 4]
+    ) -> Option<DrumReport> {
         let (sharp, sens) = (cfg.parse_cfg.sharpness, cfg.parse_cfg.sensitivity);
         let (mut state_change, mut second_stage) = (false, false);
+        let records = &mut self.records;
+        records.clear();
 
         self.windows.iter_mut()
-            .zip(sample.0)
-            .zip(&mut self.states)
-            .map(|((a, b), c)| (a, b, c))
-            .for_each(|(w, s, b)| {
+            .zip(sample)
+            .zip(self.states.iter_mut().zip(&mut self.velocities))
+            .zip(0u8..)
+            .map(|(((a, b), (c, d)), pad)| (a, b, c, d, pad))
+            .for_each(|(w, s, b, v, pad)| {
                 w.store(s as i16 - MID_RANGE);
                 if w.index_fifo == 0 {
                     // If deviation is too large, calculating performing second stage signal processing.
                     if check_deviation(w.threshold(), w.min(), w.max(), sharp, sens) {
                         if *b != true {
                             *b = true;
+                            *v = velocity(w.threshold(), w.min(), w.max());
+                            records.push(record(pad, w.threshold(), w.min(), w.max(), sharp, sens)).ok();
                             second_stage = true;
                             state_change = true;
                         }
                     } else {
                         *b = false;
+                        *v = 0;
                         state_change = true;
                     }
                 }
@@ -69,43 +186,91 @@ impl Parser {
                 for j in (i + 1)..4 {
                     if !self.states[j] { continue }
                     let occurance = &self.windows[j];
-                    let delay = xcorr(
+                    let corr = xcorr(
+                        scratch,
                         &occurance.fifo, 
                         occurance.threshold(), 
                         &reference.fifo, 
                         reference.threshold()
                     );
 
-                    log::info!("piezo{} ~ piezo{} = {}", i, j, delay);
+                    crate::info!("piezo{} ~ piezo{} = {}/100 (peak: {}, ratio: {}%)", 
+                        i, j, (corr.precise_delay() * 100.0) as i32, corr.peak, (corr.secondary_ratio * 100.0) as u8
+                    );
 
-                    match delay {
-                        ..0 => self.states[i] = false,
-                        0.. => self.states[j] = false,
+                    // Weak correlation means that both pads were most likely hit at once.
+                    if corr.peak < XCORR_MIN_PEAK || corr.secondary_ratio > XCORR_MAX_SECONDARY_RATIO {
+                        judge(&mut self.records, i, j, Verdict::Simultaneous);
+                        judge(&mut self.records, j, i, Verdict::Simultaneous);
+                        continue
                     }
+
+                    let (crosstalk, source) = if corr.precise_delay() < 0.0 { (i, j) } else { (j, i) };
+                    judge(&mut self.records, crosstalk, source, Verdict::Crosstalk);
+                    self.crosstalk = self.crosstalk.wrapping_add(self.states[crosstalk] as u32);
+                    self.states[crosstalk] = false;
+                    self.velocities[crosstalk] = 0;
                 }
             }
         }
 
         if state_change {
-            return Some(self.current(cfg.hit_mapping));
+            return self.current(cfg, mode, midi, menu);
         }
 
         None
     }
 
-    /// Currently pressed keys mapped into a HID report.
-    fn current(&self, hit_mapping: HitMapping) -> DrumHitStrokeHidReport {
-        DrumHitStrokeHidReport::new(
-            cortex_m::interrupt::free(|_| {
-                self.states.into_iter().zip([
-                    hit_mapping.left_kat,
-                    hit_mapping.left_don,
-                    hit_mapping.right_don,
-                    hit_mapping.right_kat,
+    /// Currently pressed keys mapped into a report of the requested mode.
+    ///
+    /// When MIDI output is enabled, note events are generated instead of HID reports. In keyboard
+    /// mode pads with consumer usage mapping are reported over the consumer control interface.
+    /// Second drum is only reported as the player 2 keyboard.
+    fn current(&mut self, cfg: &DrumConfig, mode: HidMode, midi: MidiMode, menu: bool) -> Option<DrumReport> {
+        let previous = core::mem::replace(&mut self.reported, self.states);
+        let (hit_mapping, consumer_mapping) = if menu {
+            (HitMapping::menu(), ConsumerMapping::default())
+        } else {
+            (cfg.hit_mapping, cfg.consumer_mapping)
+        };
+
+        if self.player == Player::Two {
+            let p2 = cfg.p2_hit_mapping;
+            return (mode == HidMode::Keyboard && midi == MidiMode::Off).then(|| DrumReport::Player2(
+                DrumHitStrokeHidReport::new(
+                    self.states.into_iter()
+                        .zip([p2.left_kat, p2.left_don, p2.right_don, p2.right_kat])
+                        .filter_map(|(hit, key)| if hit { Some(key) } else { None })
+                )
+            ));
+        }
+
+        if midi != MidiMode::Off {
+            return Some(DrumReport::Midi(MidiNoteEvents::new(previous, self.states)));
+        }
+
+        Some(match mode {
+            HidMode::Keyboard => cortex_m::interrupt::free(|_| {
+                let pads = self.states.into_iter().zip([
+                    (hit_mapping.left_kat, consumer_mapping.left_kat),
+                    (hit_mapping.left_don, consumer_mapping.left_don),
+                    (hit_mapping.right_don, consumer_mapping.right_don),
+                    (hit_mapping.right_kat, consumer_mapping.right_kat),
                 ])
-                .filter_map(|(hit, key)| if hit { Some(key) } else { None })
+                .filter_map(|(hit, mapping)| if hit { Some(mapping) } else { None });
+
+                DrumReport::Keyboard(
+                    DrumHitStrokeHidReport::new(
+                        pads.clone().filter_map(|(key, usage)| if usage == 0 { Some(key) } else { None })
+                    ),
+                    DrumConsumerHidReport {
+                        usage_id: pads.map(|(_, usage)| usage).find(|&usage| usage != 0).unwrap_or(0),
+                    },
+                )
             }),
-        )
+            HidMode::Gamepad => DrumReport::Gamepad(DrumGamepadHidReport::new(self.states, self.velocities)),
+            HidMode::Hori => DrumReport::Hori(DrumHoriHidReport::new(self.states)),
+        })
     }
 }
 
@@ -116,7 +281,7 @@ impl Parser {
 /// which detects when piezoelectric sensor is being hit (or spurious hit).
 #[derive(Debug)]
 struct SampleWindow<T: Ord + Copy + core::fmt::Debug, const N: usize> {
-    /// This window is guaranteed to be always sorted.
+    /// This window is guaranteed to be always sorted. It is filled by the first N samples.
     sorted: Vec<T, N>,
     /// FIFO buffer of N last samples.
     fifo: [T; N],
@@ -124,11 +289,11 @@ struct SampleWindow<T: Ord + Copy + core::fmt::Debug, const N: usize> {
 }
 
 impl<T: Ord + Copy + core::fmt::Debug, const N: usize> SampleWindow<T, N> {
-    /// Creates a new instance of [`SampleWindow`] with initial sorted window filled with copied argument value.
-    fn new(filler: T) -> Self {
+    /// Creates a new instance of [`SampleWindow`] with FIFO buffer filled with copied argument value.
+    const fn new(filler: T) -> Self {
         debug_assert!(N.is_power_of_two(), "Current implementation only works for power of two N.");
         Self {
-            sorted: Vec::from_array([filler; N]),
+            sorted: Vec::new(),
             fifo: [filler; N],
             index_fifo: 0,
         }
@@ -138,17 +303,19 @@ impl<T: Ord + Copy + core::fmt::Debug, const N: usize> SampleWindow<T, N> {
     fn store(&mut self, new: T) {
         let old = self.fifo[self.index_fifo];
 
-        // Removes old element from the array.
-        if let Ok(i) = self.sorted.binary_search(&old) {
+        // Removes old element from the array, unless the window is still being filled.
+        if self.sorted.is_full() {
+            let Ok(i) = self.sorted.binary_search(&old) else {
+                panic!("Implementation error. Both fifo array and sorted vector must be synchronized.");
+            };
             self.sorted.remove(i); 
-        } else {
-            panic!("Implementation error. Both fifo array and sorted vector must be synchronized.");
         }
 
         // Inserts new one. Both operation shall proceed to not overflow the vector.
         let (Ok(i) | Err(i)) = self.sorted.binary_search(&new);
+        // Rejected value is not formatted on failure, which would link in `Debug` of samples.
         self.sorted.insert(i, new)
-            .expect("Implementation error. Vector shall always have place for one more element at that point.");
+            .unwrap_or_else(|_| panic!("Implementation error. Vector shall always have place for one more element at that point."));
 
         self.fifo[self.index_fifo] = new;
         self.index_fifo = (self.index_fifo + 1) & (N - 1);  // This is only fine if N is a power of two. 
@@ -175,6 +342,33 @@ impl<T: Ord + Copy + core::fmt::Debug, const N: usize> SampleWindow<T, N> {
     }
 }
 
+/// Hit strength as the largest deviation from the median, scaled to the full 8-bit range.
+fn velocity(median: i16, min_val: i16, max_val: i16) -> u8 {
+    let dev = (max_val - median).unsigned_abs().max((min_val - median).unsigned_abs()) as u32;
+    (dev * u8::MAX as u32 / MID_RANGE as u32).min(u8::MAX as u32) as u8
+}
+
+/// Updates the verdict on a hit detected within the latest sample. Rejection is never overridden.
+fn judge(records: &mut [HitRecord], pad: usize, other: usize, verdict: Verdict) {
+    if let Some(record) = records.iter_mut().find(|r| r.pad as usize == pad && r.verdict != Verdict::Crosstalk) {
+        (record.verdict, record.other) = (verdict, other as u8);
+    }
+}
+
+/// Detection details of a hit on the provided pad.
+fn record(pad: u8, median: i16, min_val: i16, max_val: i16, scale: u16, percent: u8) -> HitRecord {
+    let peak = if max_val - median >= median - min_val { max_val } else { min_val };
+    HitRecord {
+        pad,
+        verdict: Verdict::Accepted,
+        other: pad,
+        peak,
+        threshold: median,
+        deviation: (peak - median).unsigned_abs(),
+        limit: (percent as u32 * scale as u32 / 100).min(u16::MAX as u32) as u16,
+    }
+}
+
 fn relative_deviation(median: i16, value: i16, scale: u16) -> f32 {
     ( (value - median).abs() as f32 ) / scale as f32
 }
@@ -1,36 +1,62 @@
 //! Defines a piezoelectric sensor driver to detect precise hits for Taiko Drum.
 
-use super::pac::{RCC, ADC1, ADC2, GPIOA, TIM4};
+use super::pac::{RCC, ADC1, ADC2, TIM4};
+use super::board::SENSOR_CHANNELS;
+use super::clocks::{APB1_TIMER_HZ, SYSCLK_HZ};
+use super::health::{self, Lag};
+use super::pins::{PinMode, SensorPins};
 use rtic_sync::channel::TrySendError;
 
 /* Constant sampler configuration values. TODO! swap to configurable values saved in flash */
-const INTERRUPT_SAMPLER_TIMER_CC: u16 = 1000;
 /* 12-bit ADC will obtain this value when the voltage will spike to >=0,3V */
 const WATCHDOG_THRESHOLD_HALT_MODE_VALUE: u16 = 500;
 
-/* Sensor position to channel mapping, */
-const LEFT_KAT_PIEZO: u8 = 3;
-const LEFT_DON_PIEZO: u8 = 4;
-const RIGHT_DON_PIEZO: u8 = 5;
-const RIGHT_KAT_PIEZO: u8 = 6;
+/* Sensor position to channel mapping, which is defined by the board. */
+const LEFT_KAT_PIEZO: u8 = SENSOR_CHANNELS[0][0];
+const LEFT_DON_PIEZO: u8 = SENSOR_CHANNELS[0][1];
+const RIGHT_DON_PIEZO: u8 = SENSOR_CHANNELS[0][2];
+const RIGHT_KAT_PIEZO: u8 = SENSOR_CHANNELS[0][3];
+/* Second drum sensors, only sampled in two-player configuration. */
+#[cfg(feature = "two-player")]
+const P2_LEFT_KAT_PIEZO: u8 = SENSOR_CHANNELS[1][0];
+#[cfg(feature = "two-player")]
+const P2_LEFT_DON_PIEZO: u8 = SENSOR_CHANNELS[1][1];
+#[cfg(feature = "two-player")]
+const P2_RIGHT_DON_PIEZO: u8 = SENSOR_CHANNELS[1][2];
+#[cfg(feature = "two-player")]
+const P2_RIGHT_KAT_PIEZO: u8 = SENSOR_CHANNELS[1][3];
+
+/// Amount of drums connected to the board.
+pub(crate) const PLAYERS: usize = if cfg!(feature = "two-player") { 2 } else { 1 };
 
 /// Communication queue capacity.
 pub(crate) const PIEZO_SENSOR_QUEUE_CAPACITY: usize = 32;
+/// Sampling rate of the timer mode, which hit detection is tuned for.
+pub(crate) const SAMPLE_RATE_HZ: u32 = 10_000;
+/// Samples taken within a millisecond.
+pub(crate) const SAMPLES_PER_MS: u32 = SAMPLE_RATE_HZ / 1_000;
+/// Sampling period in ticks of TIM4, which runs at half the system clock. 36 MHz / 3600 = 10 kHz.
+const SAMPLE_PERIOD_TICKS: u16 = (APB1_TIMER_HZ / SAMPLE_RATE_HZ) as u16;
+/// Sampling period in CPU cycles.
+pub(crate) const SAMPLE_PERIOD_CYCLES: u32 = SYSCLK_HZ / SAMPLE_RATE_HZ;
+/// Shortest sampling period accepted from the programmer, 20 kHz. Shorter periods leave no time
+/// for the parser between sampling interrupts.
+const MIN_SAMPLE_PERIOD_TICKS: u16 = (APB1_TIMER_HZ / 20_000) as u16;
 /// Type alias for 32-bit analog value from ADC.
 ///
 /// Sensor handler samples central and edge sensors simultaneously in one such value. Samples are
-/// written in the following order:
+/// written in the following order for each of [`PLAYERS`] drums:
 /// - LK, LD, RD, RK;
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-pub(crate) struct PiezoSample(pub [u16; 4]);
+pub(crate) struct PiezoSample(pub [[u16; 4]; PLAYERS]);
 
 /// Defines sampling mode for [`PiezoSensorHandler`].
 ///
 /// Different modes are used to improve power efficiency and utilize different peripherals for
 /// their needs.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PiezoSensorSampleMode {
+pub(crate) enum PiezoSensorSampleMode {
     /// Halt Sample Mode.
     ///
     /// Default sensor sampling behavior. Performs no sensor sampling at all, until ADC's internal
@@ -40,8 +66,8 @@ enum PiezoSensorSampleMode {
     /// Timer Sample Mode.
     ///
     /// Default sampling mode to analyze peaks from any of four drum's sensor during singular taps
-    /// and bursts. When timer counts to the provided compare value, two ADCs will sample upcoming
-    /// data simultaneously on injected channels.
+    /// and bursts. Each time the timer counts through the provided period in timer ticks, two ADCs
+    /// will sample upcoming data simultaneously on injected channels.
     ///
     /// Sensor handler will be set to [`PiezoSensorSampleMode::HALT`] mode, when no peaks are seen
     /// on all four sensors (communication queue will be sending zeroed data). It will then halt
@@ -49,17 +75,32 @@ enum PiezoSensorSampleMode {
     TIMER(u16),
 }
 
+impl PiezoSensorSampleMode {
+    /// Parses the mode from the programmer payload: `0` - halt, `1` followed by big-endian u16
+    /// sampling period - timer. Periods shorter than [`MIN_SAMPLE_PERIOD_TICKS`] are refused.
+    pub(crate) fn from_bytes(buff: &[u8]) -> Option<Self> {
+        match buff {
+            [0, ..] => Some(Self::HALT),
+            [1, hi, lo, ..] => Some(u16::from_be_bytes([*hi, *lo]))
+                .filter(|&period| period >= MIN_SAMPLE_PERIOD_TICKS)
+                .map(Self::TIMER),
+            _ => None,
+        }
+    }
+}
+
 type Sender = rtic_sync::channel::Sender<'static, PiezoSample, PIEZO_SENSOR_QUEUE_CAPACITY>;
 pub(crate) type Receiver = rtic_sync::channel::Receiver<'static, PiezoSample, PIEZO_SENSOR_QUEUE_CAPACITY>;
 
 /// Handler structure which collects new injected ADC samples on each interrupt.
 ///
-/// This structure is local to [`super::pac::Interrupt::ADC1_2`] interrupt handler hardware task and used to sample and
-/// transfer data to the [`super::app::UsbHidSender`] task. Structure handles both ADC's and four
+/// This structure is used by [`super::pac::Interrupt::ADC1_2`] interrupt handler hardware task to sample and
+/// transfer data to the [`super::app::UsbHidSender`] task. USB interrupt handlers only use it to
+/// suspend and resume the sampling. Structure handles both ADC's and four
 /// analog channels from GPIOA.
 ///
 /// Handler configures two ADCs (ADC1, ADC2) to work in dual injected simultaneous mode.
-pub(crate) struct PiezoSensorHandler {
+pub struct PiezoSensorHandler {
     /// Holds ownership for both ADCs, since they are always used by this structure during interrupts.
     adcs: (ADC1, ADC2),
     /// Timer that causes injected ADC channels to perform the conversion.
@@ -68,6 +109,13 @@ pub(crate) struct PiezoSensorHandler {
     sender: Sender,
     /// Currently used sample mode.
     mode: PiezoSensorSampleMode,
+    /// Sampling period of the timer mode, which is kept across suspends.
+    period: u16,
+    /// Sample taken while the queue was full. Sampling is paused until the parser drains the queue
+    /// and this sample is sent.
+    pending: Option<PiezoSample>,
+    /// Sampling pauses since boot, because the queue was full.
+    pub(crate) overflows: u32,
 }
 
 impl PiezoSensorHandler {
@@ -80,12 +128,12 @@ impl PiezoSensorHandler {
     /// external interrupt for both of them. Two ADCs sample center and edge hits of the drum simultaneously.
     pub(crate) fn new(
         adcs: (ADC1, ADC2), 
-        gpios: &mut GPIOA,
+        pins: SensorPins,
         rcc: &mut RCC, 
         tim: TIM4,
         sender: Sender, 
     ) -> Self {
-        log::debug!("Configuring piezoelectric sensor handler.");
+        crate::debug!("Configuring piezoelectric sensor handler.");
         /* Enabling clocking for ADC1, ADC2 from APB2 high frequency domain. */
         rcc.cfgr.modify(|_, w| 
             w
@@ -102,7 +150,7 @@ impl PiezoSensorHandler {
              .adc2en().set_bit()
         );
 
-        Self::__sensor_gpios_conf(gpios);   // GPIO configuration. 
+        Self::__sensor_gpios_conf(pins);    // GPIO configuration. 
 
         /* Enabling both ADC's */
         adcs.0.cr2.modify(|_, w|
@@ -150,17 +198,39 @@ impl PiezoSensorHandler {
          * ADC1: LEFT_KAT -> LEFT_DON -> JEOC 
          * ADC2: RIGHT_KAT -> RIGHT_DON -> JEOC 
          * */
-        adcs.0.jsqr.modify(|_, w|
-            w.jl().variant(1)
-             .jsq3().variant(LEFT_KAT_PIEZO)
-             .jsq4().variant(LEFT_DON_PIEZO)
-        );
+        #[cfg(not(feature = "two-player"))]
+        {
+            adcs.0.jsqr.modify(|_, w|
+                w.jl().variant(1)
+                 .jsq3().variant(LEFT_KAT_PIEZO)
+                 .jsq4().variant(LEFT_DON_PIEZO)
+            );
 
-        adcs.1.jsqr.modify(|_, w|
-            w.jl().variant(1)
-             .jsq3().variant(RIGHT_KAT_PIEZO)
-             .jsq4().variant(RIGHT_DON_PIEZO)
-        );
+            adcs.1.jsqr.modify(|_, w|
+                w.jl().variant(1)
+                 .jsq3().variant(RIGHT_KAT_PIEZO)
+                 .jsq4().variant(RIGHT_DON_PIEZO)
+            );
+        }
+        /* Second drum sensors are appended to the same sequences, which makes four injected conversions on each ADC. */
+        #[cfg(feature = "two-player")]
+        {
+            adcs.0.jsqr.modify(|_, w|
+                w.jl().variant(3)
+                 .jsq1().variant(LEFT_KAT_PIEZO)
+                 .jsq2().variant(LEFT_DON_PIEZO)
+                 .jsq3().variant(P2_LEFT_KAT_PIEZO)
+                 .jsq4().variant(P2_LEFT_DON_PIEZO)
+            );
+
+            adcs.1.jsqr.modify(|_, w|
+                w.jl().variant(3)
+                 .jsq1().variant(RIGHT_KAT_PIEZO)
+                 .jsq2().variant(RIGHT_DON_PIEZO)
+                 .jsq3().variant(P2_RIGHT_KAT_PIEZO)
+                 .jsq4().variant(P2_RIGHT_DON_PIEZO)
+            );
+        }
         
         // Configure watchdog thresholds
         adcs.0.htr.modify(|_, w| w.ht().bits(WATCHDOG_THRESHOLD_HALT_MODE_VALUE));
@@ -174,16 +244,16 @@ impl PiezoSensorHandler {
         adcs.1.cr2.modify(|_, w| w.adon().set_bit());
 
         tim.psc.write(|w| w.psc().bits(0));                    /* Prescaler value for timer.            */
-        tim.arr.write(|w| w.arr().bits(3599));                 /* 72 Mhz / (3599 + 1) = 20 kHz clock    */
+        tim.arr.write(|w| w.arr().bits(SAMPLE_PERIOD_TICKS - 1)); /* 36 Mhz / (3599 + 1) = 10 kHz clock */
         tim.ccmr1_output().modify(|_, w| w.oc1m().frozen());   /* Don't generate PWM signal on channel  */
         tim.cr1.modify(|_, w| w.opm().clear_bit());            /* Continuous mode.                      */
-        tim.cr2.modify(|_, w| w.mms().update());               /* Generate TRGO when hitting CC         */
+        tim.cr2.modify(|_, w| w.mms().update());               /* Generate TRGO on each update event    */
 
-        log::info!("ADC sampling subsystem is initialized. Waiting for global interrupt unmask.");
+        crate::debug!("ADC sampling subsystem is initialized. Waiting for global interrupt unmask.");
 
-        let mut s = Self { adcs, sender, tim, mode: PiezoSensorSampleMode::HALT };
+        let mut s = Self { adcs, sender, tim, mode: PiezoSensorSampleMode::HALT, period: SAMPLE_PERIOD_TICKS, pending: None, overflows: 0 };
         s.__set_pssm_halt();
-        s.set_interrupt_mode(PiezoSensorSampleMode::TIMER(INTERRUPT_SAMPLER_TIMER_CC));
+        s.set_interrupt_mode(PiezoSensorSampleMode::TIMER(s.period));
         s
     }
 
@@ -195,52 +265,133 @@ impl PiezoSensorHandler {
 
         match mode {
             PiezoSensorSampleMode::HALT => self.__set_pssm_halt(),
-            PiezoSensorSampleMode::TIMER(cc) => self.__set_pssm_timer(cc),
+            PiezoSensorSampleMode::TIMER(period) => self.__set_pssm_timer(period),
         }
         self.mode = mode;
     }
 
+    /// Stops the sampling timer and powers down both ADCs.
+    ///
+    /// Used while USB bus is suspended, since the whole device must fit into the suspend current.
+    pub(crate) fn suspend(&mut self) {
+        crate::info!("Suspending sensor sampling.");
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+        self.adcs.0.cr1.modify(|_, w|
+            w
+             .jeocie().clear_bit()
+             .jawden().clear_bit()
+             .awdie().clear_bit()
+        );
+        self.adcs.0.cr2.modify(|_, w| w.adon().clear_bit());
+        self.adcs.1.cr2.modify(|_, w| w.adon().clear_bit());
+        self.mode = PiezoSensorSampleMode::HALT;
+        // Samples taken before the suspend are stale on resume.
+        self.pending = None;
+    }
+
+    /// Powers up both ADCs and restarts the sampling the same way as after initialization.
+    pub(crate) fn resume(&mut self) {
+        crate::info!("Resuming sensor sampling.");
+        self.adcs.0.cr2.modify(|_, w| w.adon().set_bit());
+        self.adcs.1.cr2.modify(|_, w| w.adon().set_bit());
+        self.__set_pssm_halt();
+        self.set_interrupt_mode(PiezoSensorSampleMode::TIMER(self.period));
+    }
+
+    /// Powers both ADCs down and restarts the sampling in the provided mode.
+    ///
+    /// Used by the programmer to experiment with sampling settings without reflashing. Sampling
+    /// period of the timer mode is kept until the next restart or reset.
+    pub(crate) fn restart(&mut self, mode: PiezoSensorSampleMode) {
+        self.suspend();
+        if let PiezoSensorSampleMode::TIMER(period) = mode {
+            self.period = period;
+        }
+        self.resume();
+        self.set_interrupt_mode(mode);
+    }
+
     /// Sends next sample over communication queue.
+    ///
+    /// Once the queue is full, the sample is kept and the timer trigger is paused, so no conversion
+    /// is started until [`Self::drained`] resumes it. Hits are delayed rather than silently skipped.
     pub(crate) fn send(&mut self) {
         if self.adcs.0.sr.read().jeoc().bit_is_clear() {
-            log::warn!("Unable to read from ADC's that haven't ended their conversion");
+            crate::warn!("Unable to read from ADC's that haven't ended their conversion");
             return
         }
+        let sample = self.read();
+        self.adcs.0.sr.modify(|_, w| w.jeoc().clear_bit());
 
-        if let Err(err) = self.sender.try_send(self.read()) {
-            match err {
+        match self.sender.try_send(sample) {
+            Ok(()) => health::sample_queued(),
+            Err(err) => match err {
                 /* 
                  * This shall not happen at all in this application, since that means loosing
                  * connection with the host machine. 
                  * */
                 TrySendError::NoReceiver(_) => {
-                    log::warn!("Tried to send without a receiver. Loosing data.");
-                    crate::int_disable!(ADC1_2);
+                    crate::warn!("Tried to send without a receiver. Stopping the sampling.");
+                    self.__pause();
                 },
                 /*  
-                 * This means that [`super::app::UsbHidSender`] task is starving. Might cause huge
-                 * input lag spike
+                 * This means that [`super::app::Parser`] task is starving. Sampling is resumed
+                 * once it catches up.
                  * */
-                TrySendError::Full(_) => {
-/*                     log::warn!("FIFO queue is full. Loosing data."); */
-                    crate::int_disable!(ADC1_2);    // Stopping the transmition for some time.
+                TrySendError::Full(sample) => {
+                    crate::warn!("FIFO queue is full. Pausing the sampling.");
+                    self.overflows = self.overflows.wrapping_add(1);
+                    self.pending = Some(sample);
+                    self.__pause();
+                    health::record(Lag::SamplingPaused);
                 }
-            }
+            },
+        }
+    }
+
+    /// Resumes the sampling paused by [`Self::send`], once the parser has drained the queue.
+    ///
+    /// Called by the parser after each sample, so it returns right away unless sampling is paused.
+    pub(crate) fn drained(&mut self) {
+        let Some(sample) = self.pending.take() else { return };
+        if let Err(TrySendError::Full(sample)) = self.sender.try_send(sample) {
+            self.pending = Some(sample);
+            return
+        }
+        health::sample_queued();
+        crate::debug!("Queue is drained. Resuming the sampling.");
+        if let PiezoSensorSampleMode::TIMER(_) = self.mode {
+            self.tim.cr1.modify(|_, w| w.cen().set_bit());
         }
     }
 
     /// Reads ADC conversion result from all sensors.
     fn read(&self) -> PiezoSample {
         PiezoSample([
-            self.adcs.0.jdr1().read().jdata().bits(),
-            self.adcs.0.jdr2().read().jdata().bits(),
-            self.adcs.1.jdr1().read().jdata().bits(),
-            self.adcs.1.jdr2().read().jdata().bits(),
+            [
+                self.adcs.0.jdr1().read().jdata().bits(),
+                self.adcs.0.jdr2().read().jdata().bits(),
+                self.adcs.1.jdr1().read().jdata().bits(),
+                self.adcs.1.jdr2().read().jdata().bits(),
+            ],
+            #[cfg(feature = "two-player")]
+            [
+                self.adcs.0.jdr3().read().jdata().bits(),
+                self.adcs.0.jdr4().read().jdata().bits(),
+                self.adcs.1.jdr3().read().jdata().bits(),
+                self.adcs.1.jdr4().read().jdata().bits(),
+            ],
         ])
     }
 
+    /// Stops the timer trigger without changing the sampling mode. Counter value is kept, so the
+    /// sampling period is not disturbed on resume.
+    fn __pause(&mut self) {
+        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+    }
+
     fn __set_pssm_halt(&mut self) {
-        log::info!("PSSM: Entering HALT mode.");
+        crate::info!("PSSM: Entering HALT mode.");
 
         // Stops the timer if running.
         self.tim.cr1.modify(|r, w| 
@@ -256,8 +407,8 @@ impl PiezoSensorHandler {
         );
     }
 
-    fn __set_pssm_timer(&mut self, cc: u16) {
-        log::info!("PSSM: Entering TIMER mode with CC={}.", cc);
+    fn __set_pssm_timer(&mut self, period: u16) {
+        crate::info!("PSSM: Entering TIMER mode with period={}.", period);
 
         // Disable watchdog, enable JEOC interrupt
         self.adcs.0.cr1.modify(|_, w| {
@@ -267,34 +418,30 @@ impl PiezoSensorHandler {
              .jeocie().set_bit()
         });
 
-        /* CC setup */
-        self.tim.ccr1().write(|w| w.ccr().bits(cc));
+        /* Period setup, conversions are triggered on each update event */
+        self.tim.arr.write(|w| w.arr().bits(period - 1));
         self.tim.cr1.modify(|r, w| 
             if r.cen().bit_is_clear() { w.cen().set_bit() } else { w }
         );
     }
 
-    fn __sensor_gpios_conf(gpios: &mut GPIOA) {
-        // Gpio pins configuration.
-        gpios.crl.modify(|_, w|         /* Configuring required pins as ADC analog input            */
-            w                           /* `push_pull()` method is equal to set analog input mode   */
-             .mode3().input() 
-             .cnf3().push_pull()
-             .mode4().input()
-             .cnf4().push_pull()
-             .mode5().input() 
-             .cnf5().push_pull()
-             .mode6().input()
-             .cnf6().push_pull()
-        );
+    /// Configures sensor pins as ADC analog inputs. Pins are consumed, so nothing else can
+    /// reconfigure them afterwards.
+    fn __sensor_gpios_conf(pins: SensorPins) {
+        let SensorPins { p1: (mut lk, mut ld, mut rd, mut rk), p2 } = pins;
+        lk.set_mode(PinMode::Analog);
+        ld.set_mode(PinMode::Analog);
+        rd.set_mode(PinMode::Analog);
+        rk.set_mode(PinMode::Analog);
 
-        gpios.lckr.modify(|_, w|       /* Locking gpio configuration for used pins. This allows to      */ 
-            w                          /* remove the ownership of [`GPIOA`] for [`PiezoSensorHandler`]  */
-             .lck3().set_bit()
-             .lck4().set_bit()
-             .lck5().set_bit()
-             .lck6().set_bit()
-             .lckk().set_bit()
-        );
+        #[cfg(feature = "two-player")] {
+            let (mut lk, mut ld, mut rd, mut rk) = p2;
+            lk.set_mode(PinMode::Analog);
+            ld.set_mode(PinMode::Analog);
+            rd.set_mode(PinMode::Analog);
+            rk.set_mode(PinMode::Analog);
+        }
+        #[cfg(not(feature = "two-player"))]
+        let _ = p2;
     }
 }
@@ -0,0 +1,125 @@
+//! GPIO pin ownership shared across subsystems.
+//!
+//! GPIO ports are split into typed pin handles during initialization, so each subsystem only
+//! touches its own pins. Configuration registers are always read-modify-written within a critical
+//! section, therefore adding LEDs or buttons can never break USB or ADC pins. Pin numbers are
+//! assigned by [`super::board`].
+
+use core::marker::PhantomData;
+use super::pac::{gpioa::RegisterBlock, GPIOA, GPIOB, GPIOC, RCC};
+
+/// Pin configuration in CNF and MODE bits format of CRL and CRH registers. Outputs are limited
+/// to 2 MHz, which keeps the edges slow and quiet.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub(crate) enum PinMode {
+    Analog          = 0b0000,
+    FloatingInput   = 0b0100,
+    PushPull        = 0b0010,
+    AltPushPull     = 0b1010,
+}
+
+/// Handle of a single pin of the provided port, which is only obtained from [`Pins`].
+#[derive(Debug)]
+pub struct Pin<const PORT: char, const N: u8> {
+    _marker: PhantomData<*const ()>,
+}
+
+/* Pins are only accessed via atomic or critical section protected register operations. */
+unsafe impl<const PORT: char, const N: u8> Send for Pin<PORT, N> {}
+
+impl<const PORT: char, const N: u8> Pin<PORT, N> {
+    const fn new() -> Self {
+        Self { _marker: PhantomData }
+    }
+
+    /// Takes the pin outside of [`Pins`].
+    ///
+    /// # Safety
+    ///
+    /// The current owner of the pin must never run again, e.g. after a panic.
+    pub(crate) unsafe fn steal() -> Self {
+        Self::new()
+    }
+
+    fn port() -> &'static RegisterBlock {
+        match PORT {
+            'A' => unsafe { &*GPIOA::ptr() },
+            'B' => unsafe { &*GPIOB::ptr() },
+            'C' => unsafe { &*GPIOC::ptr() },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Changes the pin configuration without affecting other pins of the port.
+    pub(crate) fn set_mode(&mut self, mode: PinMode) {
+        let (port, shift) = (Self::port(), (N as u32 % 8) * 4);
+        let update = |bits: u32| bits & !(0xF << shift) | (mode as u32) << shift;
+
+        cortex_m::interrupt::free(|_| {
+            if N < 8 {
+                port.crl.modify(|r, w| unsafe { w.bits(update(r.bits())) });
+            } else {
+                port.crh.modify(|r, w| unsafe { w.bits(update(r.bits())) });
+            }
+        });
+    }
+
+    /// Drives the output low. Bit reset register is atomic, so no critical section is required.
+    pub(crate) fn set_low(&mut self) {
+        Self::port().bsrr.write(|w| unsafe { w.bits(1 << (N + 16)) });
+    }
+
+    /// Drives the output high. Bit set register is atomic as well.
+    pub(crate) fn set_high(&mut self) {
+        Self::port().bsrr.write(|w| unsafe { w.bits(1 << N) });
+    }
+
+    /// Reads the input level.
+    pub(crate) fn is_high(&self) -> bool {
+        Self::port().idr.read().bits() & (1 << N) != 0
+    }
+}
+
+pub use super::board::UsbDpPin;
+pub(crate) use super::board::{ActuatorPin, StatusLedPin, UartTxPin, VbusPin};
+use super::board::{P1SensorPins, P2SensorPins};
+
+/// Analog inputs of the piezoelectric sensors, which are assigned by [`super::board`].
+pub(crate) struct SensorPins {
+    /// First drum: left kat, left don, right don and right kat.
+    pub(crate) p1: P1SensorPins,
+    /// Second drum, which is only sampled by two-player firmware.
+    pub(crate) p2: P2SensorPins,
+}
+
+/// All pins used by the drum. Pins, which are not listed here, are left unconfigured.
+pub(crate) struct Pins {
+    pub(crate) sensors: SensorPins,
+    pub(crate) usb_dp: UsbDpPin,
+    pub(crate) actuator: ActuatorPin,
+    #[cfg_attr(not(feature = "vbus-sense"), allow(dead_code))]
+    pub(crate) vbus: VbusPin,
+    #[cfg_attr(not(feature = "uart-log"), allow(dead_code))]
+    pub(crate) uart_tx: UartTxPin,
+    pub(crate) status_led: StatusLedPin,
+}
+
+impl Pins {
+    /// Takes the ownership of GPIO ports and enables their clocks.
+    pub(crate) fn new(_gpioa: GPIOA, _gpiob: GPIOB, _gpioc: GPIOC, rcc: &mut RCC) -> Self {
+        rcc.apb2enr.modify(|_, w| w.iopaen().set_bit().iopben().set_bit().iopcen().set_bit());
+
+        Self {
+            sensors: SensorPins {
+                p1: (Pin::new(), Pin::new(), Pin::new(), Pin::new()),
+                p2: (Pin::new(), Pin::new(), Pin::new(), Pin::new()),
+            },
+            usb_dp: Pin::new(),
+            actuator: Pin::new(),
+            vbus: Pin::new(),
+            uart_tx: Pin::new(),
+            status_led: Pin::new(),
+        }
+    }
+}
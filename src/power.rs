@@ -0,0 +1,39 @@
+//! Idle power reduction.
+//!
+//! The idle task sleeps with WFI between interrupts. Once no hits were detected for the
+//! configured time, flash and SRAM interface clocks are stopped during sleep as well. Those are
+//! started again by the hardware within a few cycles of the wake up, so the next sample is taken in
+//! time and the first hit is never delayed.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::pac::RCC;
+
+/// Time of the last hit in milliseconds.
+static LAST_HIT_MS: AtomicU32 = AtomicU32::new(0);
+/// Interface clocks are stopped during sleep.
+static GATED: AtomicBool = AtomicBool::new(false);
+
+/// Tracks hits of each sample and gates the interface clocks after `idle_sleep_s` seconds
+/// without them. Zero timeout never gates clocks.
+pub(crate) fn track(hit: bool, now_ms: u32, idle_sleep_s: u8) {
+    if hit {
+        LAST_HIT_MS.store(now_ms, Ordering::Relaxed);
+    }
+    let idle_ms = now_ms.wrapping_sub(LAST_HIT_MS.load(Ordering::Relaxed));
+    let gated = idle_sleep_s != 0 && idle_ms >= idle_sleep_s as u32 * 1000;
+    if GATED.swap(gated, Ordering::Relaxed) != gated {
+        crate::debug!("Interface clocks during sleep: {}", if gated { "stopped" } else { "running" });
+        let rcc = unsafe { &*RCC::ptr() };
+        cortex_m::interrupt::free(|_| {
+            rcc.ahbenr.modify(|_, w| w.flitfen().bit(!gated).sramen().bit(!gated))
+        });
+    }
+}
+
+/// Sleeps between interrupts.
+pub(crate) fn idle() -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
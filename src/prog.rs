@@ -1,235 +1,1758 @@
 //! Runtime programmer for configuration and firmware.
 
 use usbd_hid::UsbError;
-use usbd_serial::embedded_io::{Read, ReadReady, Write};
+use usb_device::class::UsbClass;
+use usbd_serial::embedded_io::{Read, Write};
+use heapless::Vec;
 use usbd_serial::SerialPort;
 
-use super::pac::FLASH;
-use super::cfg::DrumConfig;
-use super::usb::{UsbBus, UsbAllocator};
+use super::flash::{CfgFlash, FlashError};
+use super::cfg::{CfgError, CfgStatus, DrumConfig, UsbIdentity, BLOB_LEN, PROFILES, PROFILE_NAME_LEN};
+use super::usb::{UsbBus, UsbAllocator, UsbStats};
+use super::hid::{DrumHidClass, VENDOR_REPORT_DESCRIPTOR, VENDOR_REPORT_SIZE};
+use super::actuator::HapticPulse;
+use super::webusb::WebUsbClass;
+use super::backup::{Backup, BackupState};
+use super::update::{Staging, UpdateError};
+use super::piezo::{PiezoSample, PiezoSensorSampleMode, PLAYERS};
+use super::calibration::{NoiseMeter, BLOCKS_PER_100MS};
+use super::parser::{HitRecord, HIT_RECORD_LEN};
+use super::timing::{self, CYCLES_PER_FRAME};
+use super::protocol::*;
+#[cfg(feature = "console")]
+use super::console::{Console, ConsoleCommand, Text, LINE_LEN};
+#[cfg(feature = "capture")]
+use super::{capture::Capture, parser::Parser};
 
 const COMM_IF_NAME: &'static str = "Taiko Drum CDC Control";
 const DATA_IF_NAME: &'static str = "Taiko Drum CDC Data";
-const BUFF_LEN: usize = 16;
-const ACK: u8 = 0x06;
+/// Equal to the maximal packet size of CDC data endpoints.
+const BUFF_LEN: usize = 64;
+/// Serial frames may span several packets, e.g. large configuration streams. Packets are only
+/// pulled while a whole one fits, so the longest frame always fits along with a partial packet.
+const CDC_RX_LEN: usize = u8::MAX as usize + FRAME_OVERHEAD + BUFF_LEN;
+/// Frames waiting for the serial port buffer. The longest response always fits along with stream
+/// frames queued before it, while the console needs more for the configuration listing.
+const CDC_TX_LEN: usize = if cfg!(feature = "console") { 1024 } else { u8::MAX as usize + FRAME_OVERHEAD + BUFF_LEN };
+/// Longest response of a command, which fits into a single serial frame. The echoed command byte
+/// is inserted after the status byte. Vendor HID and WebUSB interfaces only carry the first
+/// [`BUFF_LEN`] bytes of the whole response, so longer responses are only meant for the serial port.
+const RESP_LEN: usize = u8::MAX as usize - 1;
+/// Chunks of the exported blob, raw pages and captured samples, which fill a single vendor HID
+/// report along with the status and command bytes.
+const CHUNK_LEN: usize = BUFF_LEN - 2;
+/// Partial serial frame is dropped when no further bytes arrive within 100 ms, e.g. after the host
+/// lost a packet of it, so frames sent afterwards are not taken for its remainder. It is only
+/// checked when the device is polled, so a stalled frame is dropped by the periodic USB poll even
+/// when the host sends nothing more.
+const FRAME_TIMEOUT_CYCLES: u32 = 100 * CYCLES_PER_FRAME;
+/// Configuration traffic is not latency critical.
+const VENDOR_HID_POLLING_MS: u8 = 10;
+/// Hit counters are saved to flash at most once per this period of play. Each save appends a
+/// record to the configuration page, so saving on every hit would wear the flash out in weeks.
+const HITS_FLUSH_S: u32 = 30 * 60;
+/// Hits within [`STORM_WINDOW_MS`] after a change of hit detection, which are treated as a hit
+/// storm caused by the new values. Even the fastest streams stay far below.
+const STORM_HITS: u32 = 40;
+/// Period after a change of hit detection, during which hits are checked for a storm.
+const STORM_WINDOW_MS: u32 = 1000;
+/// Decimated samples of all sensors sent within a single stream frame after the [`Command::Scope`] tag.
+const SCOPE_BATCH: usize = (BUFF_LEN - 1) / (size_of::<PiezoSample>());
+/// Commands taken by USB interrupts, which wait for the programmer task. Each one holds up to a
+/// whole serial frame, so the queue is kept short.
+pub(crate) const REQUEST_QUEUE_CAPACITY: usize = 2;
+
+pub(crate) type RequestSender = rtic_sync::channel::Sender<'static, Request, REQUEST_QUEUE_CAPACITY>;
+pub(crate) type RequestReceiver = rtic_sync::channel::Receiver<'static, Request, REQUEST_QUEUE_CAPACITY>;
+/// Wakes [`super::app::CfgCommit`] up. A single signal is enough, since the task takes every
+/// deferred write at once.
+pub(crate) const COMMIT_QUEUE_CAPACITY: usize = 1;
+
+pub(crate) type CommitSender = rtic_sync::channel::Sender<'static, (), COMMIT_QUEUE_CAPACITY>;
+pub(crate) type CommitReceiver = rtic_sync::channel::Receiver<'static, (), COMMIT_QUEUE_CAPACITY>;
+/// Command byte and arguments of a serial frame.
+type Frame = Vec<u8, { u8::MAX as usize }>;
 
 /// Local serializer implementation used to communicate with taiko drum utility.
 trait ProgrammerSerializer: Sized {
     type Error: Sized;
-    /// Serializes a structure in a proper format for utility read.
-    fn serialize(&self, buff: &mut [u8; BUFF_LEN]);
+    /// Serializes a structure in a proper format for utility read. Returns amount of written bytes.
+    fn serialize(&self, buff: &mut [u8]) -> usize;
     /// Deserializes upcoming stream of bytes from the utility into a structure of corresponding type.
     fn deserialize(&self, buff: &[u8]) -> Result<Self, Self::Error>;
 }
 
-#[repr(u8)]
-enum Command {
-    /// Unknown state.
-    Unknown = 0x00,
-    /// Read current configuration.
-    Read    = 0x01,
-    /// Write new configuration.
-    Write   = 0x02,
+/// Programmer interface, which takes the response back.
+#[derive(Clone, Copy)]
+enum Interface {
+    Serial,
+    Hid,
+    WebUsb,
+    /// Configuration stream of the vendor HID feature report, which is answered over the vendor HID
+    /// interface as [`Command::Write`].
+    Feature,
+    /// Complete line of the text console, which is answered with text.
+    #[cfg(feature = "console")]
+    Console,
+}
 
-    /// Reset the firmware.
-    Reset   = 0xff,
+/// Command taken from one of the programmer interfaces, which is executed by the programmer task.
+pub(crate) struct Request {
+    interface: Interface,
+    /// Command byte and arguments, or the reason to reject a serial frame.
+    body: Result<Frame, FrameError>,
 }
 
-impl TryFrom<u8> for Command {
-    type Error = u8;
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        use Command::*;
-        Ok(match value {
-            0x00 => Unknown,
-            0x01 => Read,
-            0x02 => Write,
+impl Request {
+    fn new(interface: Interface, body: &[u8]) -> Self {
+        Self { interface, body: Ok(Vec::from_slice(body).unwrap_or_default()) }
+    }
+}
 
-            0xff => Reset,
-            _ => return Err(value)
-        })
+/// Names of stored profiles indexed by profile.
+pub(crate) type ProfileNames = [Option<[u8; PROFILE_NAME_LEN]>; PROFILES];
+
+/// Flash write of a command, which is performed by the programmer task outside of the device lock.
+pub(crate) enum FlashJob {
+    /// Saves the configuration, so it becomes the latest record of its profile.
+    Save(DrumConfig),
+    /// Saves the configuration of an inactive profile followed by the stored one of the active
+    /// profile, since the latest record is the active one.
+    SaveProfile(DrumConfig, u8),
+    /// Erases all stored profiles.
+    Erase,
+    /// Appends the chunk at the offset of the imported configuration blob.
+    Import(usize, Frame),
+    /// Writes the chunk at the offset of the firmware update.
+    FwWrite(usize, Frame),
+    /// Verifies the firmware update of the length and CRC and marks it for installation.
+    FwCommit(usize, u32),
+    /// Keeps the report of the last crash in flash or clears the stored one.
+    SaveCrash,
+}
+
+/// Outcome of a [`FlashJob`].
+pub(crate) enum JobResult {
+    /// Configuration was saved or erased.
+    Saved(Result<(), FlashError>),
+    /// Chunk was appended, while the rest of the blob is still missing.
+    ImportPending,
+    /// Whole blob was imported, along with the configuration of its active profile and names of
+    /// all profiles.
+    Imported(DrumConfig, ProfileNames),
+    /// Chunk is out of order or the blob is malformed.
+    ImportRejected,
+    /// Firmware update was written or committed.
+    Update(Result<(), UpdateError>),
+}
+
+/// Command, which waits for its flash write before the response is sent.
+pub(crate) struct Pending {
+    pub(crate) job: FlashJob,
+    interface: Interface,
+    command: u8,
+    /// Status byte and the only argument of the response, which is all commands writing the
+    /// flash send back.
+    resp: [u8; 2],
+    len: usize,
+}
+
+/// Flash write requested by the programmer itself, which is performed by
+/// [`super::app::CfgCommit`] task. Later variants include the earlier ones.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Deferred {
+    /// Saves current configuration.
+    Save,
+    /// Saves current configuration and restarts the firmware.
+    SaveReset,
+    /// Erases all stored profiles and restarts the firmware.
+    EraseReset,
+}
+
+/// Flash controller along with the state of writes spanning several commands.
+///
+/// Kept out of the [`Programmer`] as a separate resource of the programmer tasks, so erases and
+/// writes, which stall for milliseconds, never hold off USB interrupts or the parser.
+pub(crate) struct Storage {
+    flash: CfgFlash,
+    /// Configuration blob, which is being imported.
+    import: &'static mut Vec<u8, BLOB_LEN>,
+    /// Firmware update, which is being received.
+    update: Staging,
+}
+
+impl Storage {
+    pub(crate) fn new(flash: CfgFlash, import: &'static mut Vec<u8, BLOB_LEN>) -> Self {
+        Self { flash, import, update: Staging::new() }
     }
+
+    /// Performs the flash write.
+    #[inline(never)]
+    pub(crate) fn run(&mut self, job: &FlashJob) -> JobResult {
+        match job {
+            FlashJob::Save(cfg) => JobResult::Saved(cfg.save(&mut self.flash)),
+            FlashJob::SaveProfile(cfg, active) => JobResult::Saved(
+                cfg.save(&mut self.flash).and_then(|_| DrumConfig::profile(*active).save(&mut self.flash))
+            ),
+            FlashJob::Erase => JobResult::Saved(DrumConfig::erase(&mut self.flash)),
+            FlashJob::Import(offset, chunk) => self.import_chunk(*offset, chunk),
+            FlashJob::FwWrite(offset, chunk) => JobResult::Update(self.update.write(&mut self.flash, *offset, chunk)),
+            FlashJob::FwCommit(len, crc) => JobResult::Update(self.update.commit(&mut self.flash, *len, *crc)),
+            FlashJob::SaveCrash => JobResult::Saved(super::crash::save(&mut self.flash)),
+        }
+    }
+
+    /// Keeps the firmware update installed at boot, see [`super::update::confirm`].
+    pub(crate) fn confirm_update(&mut self) {
+        super::update::confirm(&mut self.flash);
+    }
+
+    /// Appends a chunk of the configuration blob and imports it once the whole blob is obtained.
+    ///
+    /// Chunks shall be sent in order, the one at zero offset starts a new import.
+    fn import_chunk(&mut self, offset: usize, chunk: &[u8]) -> JobResult {
+        if offset == 0 {
+            self.import.clear();
+        }
+        if offset != self.import.len() || self.import.extend_from_slice(chunk).is_err() {
+            self.import.clear();
+            return JobResult::ImportRejected;
+        }
+
+        let [l0, l1, ..] = self.import[..] else { return JobResult::ImportPending };
+        if self.import.len() < 2 + u16::from_be_bytes([l0, l1]) as usize + 4 {
+            return JobResult::ImportPending;
+        }
+
+        let imported = DrumConfig::import(&mut self.flash, self.import);
+        self.import.clear();
+        imported.map_or(JobResult::ImportRejected, |cfg| JobResult::Imported(cfg, DrumConfig::names()))
+    }
+}
+
+/// Configuration replaced by the latest write, kept until the new one proves to be sane.
+struct Rollback {
+    cfg: DrumConfig,
+    /// New configuration was saved to flash, so the previous one shall be saved back.
+    saved: bool,
+    /// Start of the check window, which is set by the first sample after the change.
+    since: Option<u32>,
+    /// Hits counted within the check window.
+    hits: u32,
 }
 
 /// Runtime Programmer.
 ///
-/// Utilizes the serial port in order to perform basic tasks obtained from the host machine via
-/// application specific utility. Below is the list of currently available features of this
+/// Utilizes the serial port, vendor-defined HID or WebUSB interface in order to perform basic tasks
+/// obtained from the host machine via application specific utility. Below is the list of currently available features of this
 /// programmer:
 /// - Configuration Management (reading the configuration from flash and saving new one.);
 /// - Reset the firmware;
+/// - Haptic feedback pulses;
+/// - Menu navigation mode toggling;
+/// - USB identity overrides;
+/// - Live tuning of the configuration without flash writes;
+/// - USB traffic and error counters;
+/// - Configuration corruption status;
+/// - Named configuration profiles;
+/// - Factory reset;
+/// - Configuration export and import;
+/// - Hit odometer of each pad;
+/// - Configuration write-lock;
 pub(crate) struct Programmer<'a> {
-    /// Serial port interface for straight communication between host and firmware.
-    pub(crate) serial: SerialPort<'a, UsbBus>,
+    /// Serial port interface for straight communication between host and firmware. Not exposed
+    /// when disabled by the configuration or when the firmware is built without `cdc` feature.
+    pub(crate) serial: Option<SerialPort<'a, UsbBus>>,
+    /// Vendor-defined HID interface, which carries the same commands for hosts where CDC drivers
+    /// or permissions are painful to deal with. Its feature report mirrors current configuration.
+    pub(crate) hid: DrumHidClass<'a, UsbBus>,
+    /// Vendor interface used by browser-based configurator via WebUSB.
+    pub(crate) webusb: WebUsbClass,
     /// Holds current drum configuration.
     pub(crate) cfg: DrumConfig,
-    /// Flash is only controller by [`UsbConfigManager`] task to save new configurations on runtime.
-    pub(crate) flash: super::pac::FLASH,
+    /// State of the configuration found in flash at boot.
+    cfg_status: CfgStatus,
+    /// Menu navigation mode, in which pads emit [`super::cfg::HitMapping::menu`] keys instead of
+    /// gameplay ones. Runtime only state, which is never saved to flash.
+    pub(crate) menu: bool,
+    /// Mirror of the active profile and menu mode, which survives resets.
+    backup: Backup,
+    /// DTR state of the serial port at the last check.
+    dtr: bool,
+    /// Commands rewriting the flash are allowed by [`Command::Unlock`].
+    unlocked: bool,
+    /// Text console input preceding serial frames.
+    #[cfg(feature = "console")]
+    console: Console,
+    /// Serial frames, which are being received.
+    rx: &'a mut Vec<u8, CDC_RX_LEN>,
+    /// Timestamp of the latest bytes received over the serial port.
+    rx_stamp: u32,
+    /// Serial frames, which are being sent.
+    tx: &'a mut Vec<u8, CDC_TX_LEN>,
+    /// Seconds elapsed since the oldest hit, which is not saved to flash yet.
+    hits_age: Option<u32>,
+    /// Previous configuration, which is restored if the new one causes a hit storm.
+    rollback: Option<Rollback>,
+    /// Only each this sample is streamed, while streaming is off if zero.
+    scope: u8,
+    /// Peak values within the current decimation window and amount of samples within it.
+    scope_peak: (PiezoSample, u8),
+    /// Decimated samples of the next stream frame.
+    scope_batch: Vec<u8, { BUFF_LEN - 1 }>,
+    /// Detection details of hits are streamed.
+    telemetry: bool,
+    /// Noise measurement requested by [`Command::Calibrate`].
+    noise: NoiseMeter,
+    /// Raw samples around a hit requested by [`Command::Capture`].
+    #[cfg(feature = "capture")]
+    capture: &'a mut Capture,
+    /// Flash write of the command being executed.
+    job: Option<FlashJob>,
+    /// Flash write requested by the programmer itself, e.g. once the calibration is finished.
+    deferred: Option<Deferred>,
+    /// Firmware is restarted once the deferred write being performed is done.
+    reset_after: bool,
+    /// Crash report shall be kept in flash or cleared from it, which is deferred after other writes.
+    crash_sync: bool,
+    /// Wakes [`super::app::CfgCommit`] task up to perform the deferred write.
+    commits: CommitSender,
+    /// Names of stored profiles, which are kept in line with flash writes, so listing them never
+    /// scans the flash within the device lock.
+    names: ProfileNames,
+}
+
+/// Buffers of the [`Programmer`], which are kept in static memory instead of being moved along
+/// with it through the stack of the initialization.
+pub(crate) struct ProgBuffers {
+    rx: Vec<u8, CDC_RX_LEN>,
+    tx: Vec<u8, CDC_TX_LEN>,
+    #[cfg(feature = "capture")]
+    capture: Capture,
+}
+
+impl ProgBuffers {
+    pub(crate) const fn new() -> Self {
+        Self {
+            rx: Vec::new(),
+            tx: Vec::new(),
+            #[cfg(feature = "capture")]
+            capture: Capture::new(),
+        }
+    }
 }
 
 impl<'a> Programmer<'a> {
     /// Initializes new instance of [`Programmer`]
-    pub(crate) fn new(alloc: &'a Option<UsbAllocator>, cfg: DrumConfig, flash: FLASH) -> Self {
-        let serial = SerialPort::new_with_interface_names(
-            alloc.as_ref().expect("Won't panic if this function is only called once."),
-            Some(COMM_IF_NAME),
-            Some(DATA_IF_NAME),
-        );
-        Self { serial, cfg, flash }
+    pub(crate) fn new(
+        alloc: &'a Option<UsbAllocator>,
+        buffers: &'a mut ProgBuffers,
+        cfg: DrumConfig,
+        cfg_status: CfgStatus,
+        backup: Backup,
+        commits: CommitSender
+    ) -> Self {
+        let alloc = alloc.as_ref().expect("Won't panic if this function is only called once.");
+        let ProgBuffers {
+            rx,
+            tx,
+            #[cfg(feature = "capture")]
+            capture,
+        } = buffers;
+        let serial = (cfg!(feature = "cdc") && cfg.cdc_disabled == 0)
+            .then(|| SerialPort::new_with_interface_names(alloc, Some(COMM_IF_NAME), Some(DATA_IF_NAME)));
+        if serial.is_none() {
+            crate::info!("CDC serial programmer interface is disabled.");
+        }
+        let hid = DrumHidClass::new(alloc, VENDOR_REPORT_DESCRIPTOR, VENDOR_HID_POLLING_MS);
+        let webusb = WebUsbClass::new(alloc);
+        let mut s = Self {
+            #[cfg(feature = "console")]
+            console: Console::new(),
+            #[cfg(feature = "capture")]
+            capture,
+            serial, hid, webusb, cfg, cfg_status, menu: false, backup, dtr: false, unlocked: false, rx, rx_stamp: 0, tx, hits_age: None, rollback: None, scope: 0, scope_peak: (PiezoSample::default(), 0), scope_batch: Vec::new(), telemetry: false, noise: NoiseMeter::new(), job: None, deferred: None, reset_after: false, crash_sync: true, commits, names: DrumConfig::names()
+        };
+        // Report of the crash before the restart is kept in flash, once the programmer task runs.
+        s.commits.try_send(()).ok();
+
+        // State left before a brown-out or watchdog reset is applied instantly.
+        if let Some(state) = s.backup.restore() {
+            crate::info!("Restoring runtime state: profile {}, menu {}.", state.profile, state.menu);
+            if state.profile != s.cfg.profile && (state.profile as usize) < PROFILES {
+                s.cfg = s.load_profile(state.profile);
+            }
+            s.menu = state.menu;
+        }
+        s.mirror();
+        s.update_feature();
+        s
     }
 }
 
 impl Programmer<'_> {
     pub(crate) fn info(&self) {
-        let lc = self.serial.line_coding();
-        log::info!("Runtime programmer configured with: {:?}, {:?}, {}", 
-            lc.data_rate(), lc.data_bits(), lc.stop_bits() as u8
-        )
-    }
-
-    /// Command parsing and execution function.
-    pub(crate) fn program(&mut self) {
-        let mut buff = [0u8; BUFF_LEN];
-
-        rtic::export::interrupt::free(|_| {
-            // Perform a non-blocking read.
-            if let Ok(true) = self.serial.read_ready() {
-                match self.serial.read(&mut buff) {
-                    Ok(rsize) => if rsize > 0 {
-                        // Performing only properly parsed CMDs.
-                        match buff[0].try_into() {
-                            Ok(cmd) => match cmd {
-                                Command::Reset => {
-                                    self.ack();
-                                    super::app::FirmwareReset::spawn().expect("Reset function cannot be called more than once.");
-                                },
-                                Command::Read => {
-                                    self.ack();
-                                    self.cfg.serialize(&mut buff);
-                                    // Sending current configuration back.
-                                    match self.serial.write(&buff) {
-                                        Ok(wsize) => log::info!("Current configuration was send [{}] bytes", wsize),
-                                        Err(err) => todo!(),
-                                    }
-                                    self.serial.flush().ok();
-                                }
-                                Command::Write => {
-                                    self.ack();
-
-                                    // Mutates current configuration based on obtained data.
-                                    match self.cfg.deserialize(&buff) {
-                                        Ok(new_cfg) => {
-                                            self.cfg = new_cfg;
-                                            self.cfg.save(&mut self.flash);
-                                            log::info!("Writing new configuration:\n{:#?}", new_cfg);
-                                        },
-                                        Err(byte) => if byte != 0 { 
-                                            log::error!("Unexpected byte value obtained: {}", byte) 
-                                        },
-                                    }
-                                }
-                                _ => (),
-                            }
-                            Err(err) => log::warn!("Unknown command byte received: {:#x}, ignoring...", err),
-                        }
-                    },
-                    Err(usb_err) => match usb_err {
-                        UsbError::WouldBlock | UsbError::Unsupported => (),
-                        _ => panic!("{:?}", usb_err),
-                    }
+        if let Some(serial) = &self.serial {
+            let lc = serial.line_coding();
+            crate::info!("Runtime programmer configured with: {:?}, {:?}, {}", 
+                lc.data_rate(), lc.data_bits(), lc.stop_bits() as u8
+            )
+        }
+    }
+
+    /// Enables CDC serial programmer interface and restarts the firmware, so the host enumerates it.
+    ///
+    /// Used by the gesture, since the serial interface cannot be enabled over itself. Vendor HID
+    /// and WebUSB interfaces can enable it with a regular configuration write as well.
+    pub(crate) fn enable_cdc(&mut self) {
+        if self.cfg.cdc_disabled == 0 {
+            return;
+        }
+
+        self.cfg.cdc_disabled = 0;
+        self.defer(Deferred::SaveReset);
+        self.update_feature();
+        crate::info!("CDC serial programmer interface will be enabled after restart.");
+    }
+
+    /// Enters or leaves menu navigation mode.
+    pub(crate) fn set_menu(&mut self, menu: bool) {
+        self.menu = menu;
+        self.mirror();
+        crate::info!("Menu navigation mode: {}", self.menu);
+    }
+
+    /// Adds the provided amount of seconds to the cumulative uptime.
+    pub(crate) fn add_uptime(&mut self, secs: u32) {
+        self.backup.add_uptime(secs);
+    }
+
+    /// Streams raw samples over the serial port, if requested by [`Command::Scope`].
+    ///
+    /// Each sensor is decimated to the peak value within the window, so short hit spikes are never
+    /// missed. Frames, which do not fit into the serial buffer, are dropped.
+    #[inline(never)]
+    pub(crate) fn stream(&mut self, sample: &PiezoSample, stats: &mut UsbStats) {
+        if self.scope == 0 {
+            return;
+        }
+        let (peak, count) = &mut self.scope_peak;
+        for (peak, value) in peak.0.as_flattened_mut().iter_mut().zip(sample.0.as_flattened()) {
+            *peak = (*peak).max(*value);
+        }
+        *count += 1;
+        if *count < self.scope {
+            return;
+        }
+
+        let (peak, _) = core::mem::take(&mut self.scope_peak);
+        for value in peak.0.as_flattened() {
+            self.scope_batch.extend_from_slice(&value.to_be_bytes()).ok();
+        }
+        if self.scope_batch.len() == SCOPE_BATCH * size_of::<PiezoSample>() {
+            let mut frame = [0u8; BUFF_LEN];
+            frame[0] = Command::Scope as u8;
+            frame[1..=self.scope_batch.len()].copy_from_slice(&self.scope_batch);
+            self.scope_batch.clear();
+            self.send(&frame[..=SCOPE_BATCH * size_of::<PiezoSample>()], stats).ok();
+        }
+    }
+
+    /// Streams detection details of hits over the serial port, if requested by [`Command::Telemetry`].
+    ///
+    /// All hits detected within a single sample are sent within a single frame.
+    #[inline(never)]
+    pub(crate) fn telemetry(&mut self, records: &[HitRecord], stats: &mut UsbStats) {
+        if !self.telemetry || records.is_empty() {
+            return;
+        }
+        let mut frame = [0u8; BUFF_LEN];
+        frame[0] = Command::Telemetry as u8;
+        let mut len = 1;
+        for record in records {
+            frame[len..len + HIT_RECORD_LEN].copy_from_slice(&record.to_bytes());
+            len += HIT_RECORD_LEN;
+        }
+        self.send(&frame[..len], stats).ok();
+    }
+
+    /// Feeds the capture armed by [`Command::Capture`] with the sample of the first drum.
+    #[cfg(feature = "capture")]
+    pub(crate) fn capture(&mut self, sample: &PiezoSample, parser: &Parser) {
+        self.capture.update(sample, parser);
+    }
+
+    /// Feeds the noise measurement requested by [`Command::Calibrate`] and saves its result.
+    #[inline(never)]
+    pub(crate) fn calibrate(&mut self, sample: &PiezoSample) {
+        if self.noise.update(sample, &mut self.cfg.calibration) {
+            self.defer(Deferred::Save);
+            crate::debug!("Calibration is finished.");
+        }
+    }
+
+    /// Counts pads of each drum, which were hit since the previous sample, and rolls the latest
+    /// change of hit detection back if it causes a hit storm.
+    #[inline(never)]
+    pub(crate) fn count_hits(&mut self, held: [[bool; 4]; PLAYERS], pads: [[bool; 4]; PLAYERS], now_ms: u32, stats: &mut UsbStats) {
+        let mut hits = 0;
+        for (((count, session), held), pad) in self.cfg.hits.iter_mut().zip(&mut stats.hits).zip(held[0]).zip(pads[0]) {
+            if pad && !held {
+                *count = count.wrapping_add(1);
+                *session = session.wrapping_add(1);
+                self.hits_age.get_or_insert(0);
+                hits += 1;
+            }
+        }
+        // Odometer only tracks the first drum, while a storm is caused by the sensors of either one.
+        for (held, pads) in held.iter().zip(&pads).skip(1) {
+            hits += held.iter().zip(pads).filter(|&(held, pad)| *pad && !*held).count() as u32;
+        }
+
+        if let Some(rollback) = &mut self.rollback {
+            let since = *rollback.since.get_or_insert(now_ms);
+            rollback.hits += hits;
+            if rollback.hits > STORM_HITS {
+                self.roll_back();
+            } else if now_ms.wrapping_sub(since) >= STORM_WINDOW_MS {
+                self.rollback = None;
+            }
+        }
+    }
+
+    /// Keeps the previous configuration, if the new one changes hit detection. The oldest one is
+    /// kept if several changes are applied within the check window.
+    fn arm_rollback(&mut self, prev: DrumConfig, saved: bool) {
+        match &mut self.rollback {
+            Some(rollback) => {
+                rollback.saved |= saved;
+                rollback.since = None;
+                rollback.hits = 0;
+            },
+            None if prev.parse_cfg != self.cfg.parse_cfg => {
+                self.rollback = Some(Rollback { cfg: prev, saved, since: None, hits: 0 });
+            },
+            None => (),
+        }
+    }
+
+    /// Restores the configuration, which was used before the hit storm. The host is notified via
+    /// [`CfgStatus::RolledBack`] status.
+    fn roll_back(&mut self) {
+        let Some(rollback) = self.rollback.take() else { return };
+        crate::warn!("Hit storm after configuration change. Rolling back.");
+        let hits = self.cfg.hits;
+        self.cfg = rollback.cfg;
+        self.cfg.hits = hits;
+        if rollback.saved {
+            self.defer(Deferred::Save);
+        }
+        self.cfg_status = CfgStatus::RolledBack;
+        self.update_feature();
+    }
+
+    /// Saves hit counters once they are kept in RAM for [`HITS_FLUSH_S`]. Hits counted since the
+    /// last save are lost on power loss.
+    pub(crate) fn flush_hits(&mut self, secs: u32) {
+        let Some(age) = self.hits_age.as_mut() else { return };
+        *age += secs;
+        if *age >= HITS_FLUSH_S {
+            *age = 0;
+            self.defer(Deferred::Save);
+        }
+    }
+
+    /// Mirrors the active profile and modes into backup registers.
+    fn mirror(&mut self) {
+        self.backup.store(BackupState { profile: self.cfg.profile, menu: self.menu });
+    }
+
+    /// Erases all stored profiles and restarts the firmware with the default configuration.
+    ///
+    /// Used by the boot gesture as well, since a bad configuration might make the drum unusable.
+    pub(crate) fn factory_reset(&mut self) {
+        crate::warn!("Restoring factory configuration.");
+        self.defer(Deferred::EraseReset);
+        self.cfg = DrumConfig::default();
+        self.menu = false;
+        self.mirror();
+        self.update_feature();
+    }
+
+    /// Defers the flash write to [`super::app::CfgCommit`] task, so the flash is never written
+    /// within the parser or while USB interrupts are held off.
+    fn defer(&mut self, deferred: Deferred) {
+        self.deferred = self.deferred.max(Some(deferred));
+        // Full queue means the task is already woken up and takes this write as well.
+        self.commits.try_send(()).ok();
+    }
+
+    /// Takes the deferred flash write. The configuration is saved as it is at this moment, hits
+    /// counted afterwards are saved by the next write.
+    pub(crate) fn take_deferred(&mut self) -> Option<FlashJob> {
+        let Some(deferred) = self.deferred.take() else {
+            return core::mem::take(&mut self.crash_sync).then_some(FlashJob::SaveCrash);
+        };
+        self.reset_after = deferred != Deferred::Save;
+        Some(match deferred {
+            Deferred::EraseReset => FlashJob::Erase,
+            Deferred::Save | Deferred::SaveReset => {
+                self.hits_age = None;
+                FlashJob::Save(self.cfg)
+            },
+        })
+    }
+
+    /// Finishes the deferred flash write taken by [`Self::take_deferred`].
+    pub(crate) fn deferred_done(&mut self, job: &FlashJob, result: JobResult) {
+        self.track(job, &result);
+        match (job, result) {
+            (FlashJob::SaveCrash, JobResult::Saved(Err(err))) => crate::error!("Unable to save crash report: {:?}", err),
+            (_, JobResult::Saved(Err(err))) => {
+                crate::error!("Unable to save configuration: {:?}", err);
+                self.hits_age.get_or_insert(0);
+            },
+            _ => (),
+        }
+        if core::mem::take(&mut self.reset_after) {
+            super::app::FirmwareReset::spawn().ok();
+        }
+    }
+
+    /// Command parsing function, which runs within USB interrupts.
+    ///
+    /// Commands are accepted from CDC serial port, vendor HID and WebUSB interfaces and queued for
+    /// [`Self::execute_request`], so flash erases and writes never stall sampling. Commands are
+    /// only taken while the queue has room for them, otherwise serial packets and vendor HID
+    /// reports are NAKed until the programmer task catches up. Unexpected errors of the serial port are returned to be handled
+    /// by [`super::usb::UsbTaikoDrum::handle_error`].
+    pub(crate) fn program(&mut self, requests: &mut RequestSender, stats: &mut UsbStats) -> usb_device::Result<()> {
+        self.check_touch();
+
+        // Perform a non-blocking read, while frames queued before are written further.
+        self.transmit(stats)?;
+        self.receive(stats)?;
+        while !requests.is_full() && let Some(request) = self.take_request() {
+            requests.try_send(request).ok();
+        }
+        Ok(())
+    }
+
+    /// Takes the next command from any programmer interface.
+    fn take_request(&mut self) -> Option<Request> {
+        #[cfg(feature = "console")]
+        if let Some(line) = self.console_input() {
+            return Some(Request::new(Interface::Console, &line));
+        }
+        if let Some(body) = self.next_frame() {
+            return Some(Request { interface: Interface::Serial, body });
+        }
+
+        // Vendor HID interface obtains the whole command within a single output report.
+        let mut req = [0u8; BUFF_LEN];
+        match self.hid.pull_raw_output(&mut req) {
+            Ok(rsize) => if rsize > 0 {
+                return Some(Request::new(Interface::Hid, &req[..rsize]));
+            },
+            Err(UsbError::WouldBlock) => (),
+            Err(usb_err) => crate::warn!("Vendor HID interface error: {:?}", usb_err),
+        }
+
+        // WebUSB configurator reads the response with a separate control request, which is empty
+        // until the command is executed.
+        if let Some(req) = self.webusb.pull_command() {
+            return Some(Request::new(Interface::WebUsb, &req));
+        }
+
+        // Feature report obtained via SET_REPORT is a plain configuration stream without command byte.
+        self.hid.pull_feature().map(|report| Request::new(Interface::Feature, &report))
+    }
+
+    /// Executes the command taken by [`Self::program`] and sends the response back over the same
+    /// interface.
+    ///
+    /// Commands writing the flash are returned along with their write instead, which is performed
+    /// by the programmer task outside of the device lock. Their response is sent by
+    /// [`Self::complete`] afterwards.
+    #[inline(never)]
+    pub(crate) fn execute_request(&mut self, request: Request, stats: &mut UsbStats) -> Option<Pending> {
+        // Status byte and response of the command are written after the echoed command byte and
+        // swapped afterwards.
+        let mut frame = [0u8; RESP_LEN + 1];
+        let command = match (request.interface, &request.body) {
+            (Interface::Feature, _) => Command::Write as u8,
+            (_, body) => body.as_ref().ok().and_then(|body| body.first().copied()).unwrap_or(Command::Unknown as u8),
+        };
+        let resp = frame.last_chunk_mut().expect("Response shall fit into the frame.");
+        let wsize = match (request.interface, &request.body) {
+            (_, Err(err)) => {
+                crate::warn!("Corrupted serial frame is dropped, error {:#x}", *err as u8);
+                Self::nak(resp, *err as u8)
+            },
+            // Resync frame, while empty reports of other interfaces are never sent on purpose.
+            (Interface::Serial, Ok(req)) if req.is_empty() => {
+                resp[0] = ACK;
+                1
+            },
+            (_, Ok(req)) if req.is_empty() => Self::nak(resp, FrameError::Length as u8),
+            // Feature report rewrites the flash the same way as the write command, so it is locked
+            // and unlocked the same way.
+            (Interface::Feature, Ok(_)) if self.cfg.locked != 0 => {
+                crate::warn!("Configuration is locked.");
+                Self::nak(resp, CfgError::Locked as u8)
+            },
+            (Interface::Feature, Ok(_)) if !self.unlocked => {
+                crate::warn!("Command is rejected without the unlock sequence.");
+                Self::nak(resp, FrameError::Unlock as u8)
+            },
+            (Interface::Feature, Ok(report)) => match self.write_cfg(report) {
+                Ok(()) => {
+                    resp[0] = ACK;
+                    1
+                },
+                Err(err) => Self::nak(resp, err as u8),
+            },
+            #[cfg(feature = "console")]
+            (Interface::Console, Ok(line)) => {
+                if let Some(command) = ConsoleCommand::parse(line) {
+                    self.console_command(command, stats);
                 }
+                0
+            },
+            (_, Ok(req)) => self.execute(req, resp, stats),
+        };
+        if let Some(job) = self.job.take() {
+            return Some(Pending { job, interface: request.interface, command, resp: [resp[0], resp[1]], len: wsize });
+        }
+        self.respond(request.interface, command, &mut frame, wsize, stats);
+        None
+    }
+
+    /// Finishes the command returned by [`Self::execute_request`], once its flash write is done,
+    /// and sends the response. Failed writes turn the response into [`NAK`].
+    #[inline(never)]
+    pub(crate) fn complete(&mut self, pending: Pending, result: JobResult, stats: &mut UsbStats) {
+        let Pending { job, interface, command, resp: [status, arg], len } = pending;
+        self.track(&job, &result);
+        let mut frame = [0u8; RESP_LEN + 1];
+        let resp = frame.last_chunk_mut().expect("Response shall fit into the frame.");
+        (resp[0], resp[1]) = (status, arg);
+
+        let rejected = match (result, job) {
+            (JobResult::Saved(Ok(())), _) => {
+                crate::info!("New configuration was written to flash.");
+                None
+            },
+            (JobResult::Saved(Err(err)), _) => {
+                crate::error!("Unable to save configuration: {:?}", err);
+                self.hits_age.get_or_insert(0);
+                Some(CfgError::Flash as u8)
+            },
+            (JobResult::ImportPending, _) => None,
+            (JobResult::Imported(imported, _), _) => {
+                self.apply_import(imported);
+                resp[1] = 1;
+                None
+            },
+            (JobResult::ImportRejected, _) => {
+                crate::warn!("Malformed command is rejected.");
+                Some(FrameError::Malformed as u8)
+            },
+            (JobResult::Update(Ok(())), FlashJob::FwCommit(..)) => {
+                super::app::FirmwareReset::spawn().ok();
+                None
+            },
+            (JobResult::Update(Ok(())), _) => None,
+            (JobResult::Update(Err(err)), _) => {
+                crate::warn!("Firmware update is rejected: {:?}", err);
+                let code = if let UpdateError::NoSlot = err { FrameError::NoSlot } else { FrameError::Update };
+                Some(code as u8)
+            },
+        };
+        let wsize = match rejected {
+            Some(code) => Self::nak(resp, code),
+            None => len,
+        };
+
+        // The prompt of the text console follows the result of the save.
+        #[cfg(feature = "console")]
+        if let Interface::Console = interface {
+            use core::fmt::Write;
+            match rejected {
+                Some(_) => write!(Text(&mut self.tx), "Rejected: {:?}\r\n> ", CfgError::Flash).ok(),
+                None => Text(&mut self.tx).write_str("> ").ok(),
+            };
+        }
+        self.respond(interface, command, &mut frame, wsize, stats);
+    }
+
+    /// Keeps the names of stored profiles in line with the finished flash write.
+    fn track(&mut self, job: &FlashJob, result: &JobResult) {
+        match (job, result) {
+            (FlashJob::Save(cfg) | FlashJob::SaveProfile(cfg, _), JobResult::Saved(Ok(()))) => {
+                if let Some(name) = self.names.get_mut(cfg.profile as usize) {
+                    *name = Some(cfg.name);
+                }
+            },
+            (FlashJob::Erase, JobResult::Saved(Ok(()))) => self.names = [None; PROFILES],
+            (_, JobResult::Imported(_, names)) => self.names = *names,
+            _ => (),
+        }
+    }
+
+    /// Sends the response of the command, which is written after the first byte of the frame.
+    fn respond(&mut self, interface: Interface, command: u8, frame: &mut [u8; RESP_LEN + 1], wsize: usize, stats: &mut UsbStats) {
+        // Responses echo the command, so the host never takes a late response of a timed out
+        // command for the response of the next one.
+        let wsize = if wsize > 0 {
+            frame[0] = frame[1];
+            frame[1] = command;
+            wsize + 1
+        } else {
+            0
+        };
+        let sent = match interface {
+            Interface::Serial => self.send(&frame[..wsize], stats),
+            Interface::Hid | Interface::Feature if wsize > 0 => self.hid.push_raw_input(&frame[..VENDOR_REPORT_SIZE]).map(drop),
+            Interface::WebUsb => Ok(self.webusb.set_response(&frame[..wsize])),
+            _ => Ok(()),
+        };
+        if let Err(err) = sent {
+            crate::warn!("Unable to send the response: {:?}", err);
+        }
+    }
+
+    /// Reads pending serial packets into the RX buffer.
+    ///
+    /// Packets are only pulled from the endpoint while they fit into the buffer, otherwise the
+    /// endpoint keeps NAKing the host until frames are taken by [`Self::next_frame`].
+    fn receive(&mut self, stats: &mut UsbStats) -> usb_device::Result<()> {
+        let Some(serial) = self.serial.as_mut() else { return Ok(()) };
+        let mut packet = [0u8; BUFF_LEN];
+
+        while CDC_RX_LEN - self.rx.len() >= BUFF_LEN {
+            let size = match serial.read(&mut packet) {
+                Ok(size) => size,
+                Err(UsbError::WouldBlock | UsbError::Unsupported) => break,
+                Err(usb_err) => {
+                    // Partially obtained command is useless, so buffered data is dropped.
+                    UsbClass::<UsbBus>::reset(serial);
+                    self.rx.clear();
+                    return Err(usb_err);
+                },
+            };
+            stats.cdc_rx = stats.cdc_rx.wrapping_add(size as u32);
+            self.rx.extend_from_slice(&packet[..size]).ok();
+            self.rx_stamp = timing::now();
+        }
+        Ok(())
+    }
+
+    /// Takes the next whole frame from the RX buffer. Returns the command with its arguments or
+    /// the reason to reject a corrupted frame.
+    ///
+    /// Frame consists of [`SYNC`], length of the body, the body itself and big-endian CRC16 of the
+    /// length and body. Bytes preceding the sync byte are dropped. Corrupted frames are only dropped
+    /// up to their sync byte, since it might have come from line noise right before a proper frame.
+    /// The same is done with partial frames after [`FRAME_TIMEOUT_CYCLES`], so frames sent after a
+    /// lost packet are found again. An empty frame with a valid CRC is the resync frame, which
+    /// only takes the ACK.
+    fn next_frame(&mut self) -> Option<Result<Vec<u8, { u8::MAX as usize }>, FrameError>> {
+        let start = self.rx.iter().position(|&b| b == SYNC).unwrap_or(self.rx.len());
+        self.rx.drain(..start);
+
+        let len = self.rx.get(1).map_or(0, |&len| len as usize);
+        if self.rx.len() < len + FRAME_OVERHEAD {
+            if self.rx.is_empty() || timing::now().wrapping_sub(self.rx_stamp) < FRAME_TIMEOUT_CYCLES {
+                return None;
             }
+            self.rx.drain(..1);
+            return Some(Err(FrameError::Timeout));
+        }
+        let (body, crc) = self.rx[1..len + FRAME_OVERHEAD].split_at(len + 1);
+        let frame = if crc16(body) != u16::from_be_bytes([crc[0], crc[1]]) {
+            Err(FrameError::Crc)
+        } else {
+            Ok(Vec::from_slice(&body[1..]).unwrap_or_default())
+        };
+        self.rx.drain(..if frame.is_ok() { len + FRAME_OVERHEAD } else { 1 });
+        Some(frame)
+    }
+
+    /// Sends the response or stream frame over the serial port, framed the same way as commands.
+    ///
+    /// Frames are queued as a whole and written in chunks as the serial port buffer frees up, so
+    /// frames longer than a single packet are never cut. Frames, which do not fit into the queue,
+    /// are dropped with [`UsbError::WouldBlock`].
+    fn send(&mut self, resp: &[u8], stats: &mut UsbStats) -> usb_device::Result<()> {
+        if self.serial.is_none() || resp.is_empty() {
+            return Ok(());
+        }
+        let (start, len) = (self.tx.len(), resp.len().min(u8::MAX as usize));
+        if CDC_TX_LEN - start < len + FRAME_OVERHEAD {
+            return Err(UsbError::WouldBlock);
+        }
+        self.tx.extend_from_slice(&[SYNC, len as u8]).ok();
+        self.tx.extend_from_slice(&resp[..len]).ok();
+        let crc = crc16(&self.tx[start + 1..]);
+        self.tx.extend_from_slice(&crc.to_be_bytes()).ok();
+        self.transmit(stats)
+    }
+
+    /// Writes queued frames into the serial port buffer, as much as fits into it.
+    fn transmit(&mut self, stats: &mut UsbStats) -> usb_device::Result<()> {
+        let Some(serial) = self.serial.as_mut().filter(|_| !self.tx.is_empty()) else { return Ok(()) };
+        let wsize = match serial.write(self.tx) {
+            Ok(wsize) => wsize,
+            Err(UsbError::WouldBlock) => 0,
+            Err(usb_err) => {
+                // Partially written frame is useless, so queued frames are dropped.
+                self.tx.clear();
+                return Err(usb_err);
+            },
+        };
+        self.tx.drain(..wsize);
+        stats.cdc_tx = stats.cdc_tx.wrapping_add(wsize as u32);
+        serial.flush().ok();
+        Ok(())
+    }
+
+    /// Takes the text console input preceding the next serial frame up to the end of a line.
+    /// Returns the complete line.
+    #[cfg(feature = "console")]
+    #[inline(never)]
+    fn console_input(&mut self) -> Option<Vec<u8, LINE_LEN>> {
+        let start = self.rx.iter().position(|&b| b == SYNC).unwrap_or(self.rx.len());
+        let (mut taken, mut line) = (0, None);
+        while taken < start && line.is_none() {
+            if self.console.feed(self.rx[taken], &mut Text(&mut self.tx)) {
+                line = Some(self.console.take());
+            }
+            taken += 1;
+        }
+        self.rx.drain(..taken);
+        line
+    }
+
+    /// Executes the text console command and prints its result.
+    ///
+    /// Values are set and saved the same way as by [`Command::Tune`] and [`Command::Write`], but
+    /// saving does not require [`Command::Unlock`], since a typed command is never an accident.
+    #[cfg(feature = "console")]
+    #[inline(never)]
+    fn console_command(&mut self, command: ConsoleCommand, stats: &UsbStats) {
+        use core::fmt::Write;
+
+        let result = match command {
+            ConsoleCommand::Help => {
+                Text(&mut self.tx).write_str(
+                    "show, set <key> <value>, save, stats\r\nKeys are the same as of the utility, set values are applied until saved or reset.\r\n"
+                ).ok();
+                Ok(())
+            },
+            ConsoleCommand::Show => {
+                let mut buff = [0u8; RESP_LEN];
+                let len = self.cfg.serialize(&mut buff);
+                let (mut out, mut idx) = (Text(&mut self.tx), 0);
+                while let Some(&tag) = buff[..len].get(idx) {
+                    let width = if matches!(tag, SHARP | CONS_LEFTKAT..=CONS_RIGHTKAT) { 2 } else { 1 };
+                    let value = buff[idx + 1..idx + 1 + width].iter().fold(0u32, |value, &b| value << 8 | b as u32);
+                    let name = config_names().find(|&(_, key)| key == tag).map_or("?", |(name, _)| name);
+                    if tag != STREAM_VERSION {
+                        write!(out, "{} {}\r\n", name, value).ok();
+                    }
+                    idx += 1 + width;
+                }
+                Ok(())
+            },
+            ConsoleCommand::Set(..) | ConsoleCommand::Save if self.cfg.locked != 0 => Err(CfgError::Locked),
+            ConsoleCommand::Set(tag, value) => {
+                // Sensitivity is sent within four bytes, the same way as by the utility.
+                let len = match tag { SENS => 5, SHARP | CONS_LEFTKAT..=CONS_RIGHTKAT => 3, _ => 2 };
+                let mut data = [tag, 0, 0, 0, 0];
+                data[1..len].copy_from_slice(&(value as u32).to_be_bytes()[5 - len..]);
+                if len == 2 && value > u8::MAX as u16 {
+                    Err(CfgError::Keycode)
+                } else {
+                    self.tune(&data[..len])
+                }
+            },
+            ConsoleCommand::Save => self.write_cfg(&[]),
+            ConsoleCommand::Stats => {
+                let mut out = Text(&mut self.tx);
+                for (name, value) in STATS_NAMES.split_ascii_whitespace().zip(stats.usb().into_iter().chain(stats.runtime())) {
+                    write!(out, "{} {}\r\n", name, value).ok();
+                }
+                Ok(())
+            },
+            ConsoleCommand::Invalid => {
+                Text(&mut self.tx).write_str("Unknown command, see help.\r\n").ok();
+                Ok(())
+            },
+        };
+        match result {
+            // The prompt follows the save, once the flash is written.
+            Ok(()) if self.job.is_some() => None,
+            Ok(()) => Text(&mut self.tx).write_str("> ").ok(),
+            Err(err) => write!(Text(&mut self.tx), "Rejected: {:?}\r\n> ", err).ok(),
+        };
+    }
+
+    /// Performs the action requested by closing the serial port opened with a magic baud rate.
+    fn check_touch(&mut self) {
+        let Some(serial) = &self.serial else { return };
+        let dtr = serial.dtr();
+        if !core::mem::replace(&mut self.dtr, dtr) || dtr {
+            return;
+        }
+        // Nobody reads the stream after the port is closed.
+        self.scope = 0;
+        self.telemetry = false;
+        self.unlocked = false;
+        self.tx.clear();
+
+        match serial.line_coding().data_rate() {
+            TOUCH_BOOTLOADER_BAUD if !super::bootloader::available() => {
+                crate::warn!("Serial port touch is ignored, no bootloader is configured.");
+            },
+            TOUCH_BOOTLOADER_BAUD => {
+                crate::info!("Serial port touch requested the bootloader.");
+                super::app::BootloaderEntry::spawn().ok();
+            },
+            TOUCH_RESET_BAUD => {
+                super::app::FirmwareReset::spawn().ok();
+            },
+            _ => (),
+        }
+    }
+
+    /// Mirrors current configuration into the feature report of vendor HID interface.
+    ///
+    /// Second drum entries are left out, so the rest always fits. Those are kept unchanged when
+    /// the report is written back.
+    fn update_feature(&mut self) {
+        let mut buff = [0u8; VENDOR_REPORT_SIZE];
+        let len = serialize_entries(&self.cfg, &mut buff, false);
+        self.hid.set_feature(&buff[..len]);
+    }
+
+    /// Mutates current configuration based on obtained data and saves it to flash. Invalid
+    /// configurations are rejected as a whole.
+    fn write_cfg(&mut self, data: &[u8]) -> Result<(), CfgError> {
+        let (prev, new_cfg) = (self.cfg, self.cfg.deserialize(data)?);
+        self.store_cfg(new_cfg);
+        self.arm_rollback(prev, true);
+        Ok(())
+    }
+
+    /// Applies the configuration stream without saving it to flash, so values can be adjusted while
+    /// playing. Write command without payload saves the tuned configuration.
+    fn tune(&mut self, data: &[u8]) -> Result<(), CfgError> {
+        let (prev, new_cfg) = (self.cfg, self.cfg.deserialize(data)?);
+        self.cfg = new_cfg;
+        self.arm_rollback(prev, false);
+        self.update_feature();
+        Ok(())
+    }
+
+    /// Replaces current configuration with the provided one and saves it to flash. The
+    /// configuration is applied even if it could not be saved.
+    fn store_cfg(&mut self, new_cfg: DrumConfig) {
+        if new_cfg.hid_mode != self.cfg.hid_mode 
+            || new_cfg.midi_mode != self.cfg.midi_mode 
+            || new_cfg.poll_interval != self.cfg.poll_interval 
+            || new_cfg.velocity_axes != self.cfg.velocity_axes
+            || new_cfg.cdc_disabled != self.cfg.cdc_disabled
+            || new_cfg.max_power != self.cfg.max_power
+            || new_cfg.self_powered != self.cfg.self_powered
+        {
+            crate::info!("USB modes will be changed to {:?}, {:?}, {} ms after restart.", 
+                new_cfg.hid_mode, new_cfg.midi_mode, new_cfg.poll_interval
+            );
+        }
+        self.cfg = new_cfg;
+        self.save_cfg();
+        self.update_feature();
+    }
+
+    /// Saves current configuration to flash along with hit counters, once the command is executed.
+    /// The configuration is still applied until restart, so a failed write is only reported to
+    /// the host. Only used by commands, the programmer itself defers its writes.
+    fn save_cfg(&mut self) {
+        self.hits_age = None;
+        self.job = Some(FlashJob::Save(self.cfg));
+    }
+
+    /// Switches to the provided profile. Unsaved changes of the current one are dropped, while hit
+    /// counters are carried over.
+    fn select_profile(&mut self, profile: u8) {
+        if profile == self.cfg.profile {
+            return;
+        }
+        crate::info!("Switching to configuration profile {}.", profile);
+        self.store_cfg(self.load_profile(profile));
+        self.mirror();
+    }
+
+    /// Stored configuration of the provided profile. Hit counters and the lock are shared by all
+    /// profiles.
+    #[inline(never)]
+    fn load_profile(&self, profile: u8) -> DrumConfig {
+        let mut cfg = DrumConfig::profile(profile);
+        cfg.hits = self.cfg.hits;
+        cfg.locked = self.cfg.locked;
+        cfg.calibration = self.cfg.calibration;
+        cfg
+    }
+
+    /// Renames the provided profile without touching unsaved changes of the current one.
+    fn rename_profile(&mut self, profile: u8, name: &[u8]) {
+        let mut cfg = DrumConfig::profile(profile);
+        cfg.set_name(name);
+        self.job = Some(if profile == self.cfg.profile {
+            self.cfg.name = cfg.name;
+            FlashJob::Save(cfg)
+        } else {
+            FlashJob::SaveProfile(cfg, self.cfg.profile)
         });
     }
 
-    /// Sends an acknowledge signal with a small delay.
-    fn ack(&mut self) {
-        if let Err(err) = self.serial.write(&[ACK]) {
-            todo!()
+    /// Writes a configuration stream into the provided profile. The active one is written the same
+    /// way as by [`Command::Write`], others are saved without touching unsaved changes of it.
+    fn write_profile(&mut self, profile: u8, data: &[u8]) -> Result<(), CfgError> {
+        if profile == self.cfg.profile {
+            return self.write_cfg(data);
+        }
+        let cfg = self.load_profile(profile).deserialize(data)?;
+        self.job = Some(FlashJob::SaveProfile(cfg, self.cfg.profile));
+        Ok(())
+    }
+
+    /// Applies the active profile of the imported configuration blob.
+    fn apply_import(&mut self, imported: DrumConfig) {
+        // Counters and calibration of the drum, which exported the blob, are replaced by the local ones.
+        let (hits, calibration) = (self.cfg.hits, self.cfg.calibration);
+        self.cfg = imported;
+        self.cfg.hits = hits;
+        self.cfg.calibration = calibration;
+        self.defer(Deferred::Save);
+        self.mirror();
+        self.update_feature();
+        crate::info!("Configuration was imported, active profile: {}.", self.cfg.profile);
+    }
+
+    /// Response to the rejected command with [`CfgError`] or [`FrameError`] code.
+    fn nak(resp: &mut [u8; RESP_LEN], code: u8) -> usize {
+        resp[0] = NAK;
+        resp[1] = code;
+        2
+    }
+
+    /// Acknowledges the command with big-endian counters.
+    #[inline(never)]
+    fn counters(resp: &mut [u8; RESP_LEN], counters: &[u32]) -> usize {
+        for (bytes, count) in resp[1..].chunks_exact_mut(4).zip(counters) {
+            bytes.copy_from_slice(&count.to_be_bytes());
+        }
+        1 + 4 * counters.len()
+    }
+
+    /// Response to the command with missing or malformed arguments, or wrong [`COMMAND_KEY`].
+    #[inline(never)]
+    fn malformed(resp: &mut [u8; RESP_LEN]) -> usize {
+        crate::warn!("Malformed command is rejected.");
+        Self::nak(resp, FrameError::Malformed as u8)
+    }
+
+    /// Executes a single command and prepares the response.
+    ///
+    /// The response always starts from the acknowledge byte, rejected commands are answered with
+    /// [`NAK`] and the error code instead. Returns the length of the response.
+    #[inline(never)]
+    fn execute(&mut self, req: &[u8], resp: &mut [u8; RESP_LEN], stats: &UsbStats) -> usize {
+        // Performing only properly parsed CMDs.
+        let cmd = match req[0].try_into() {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                crate::warn!("Unknown command byte received: {:#x}, rejecting...", err);
+                return Self::nak(resp, FrameError::Command as u8);
+            }
+        };
+        resp[0] = ACK;
+
+        // Tournament setups are protected from other software opening the port.
+        if self.cfg.locked != 0 && matches!(cmd,
+            Command::Write | Command::Tune | Command::Identity | Command::Profile | Command::ProfileName | Command::ProfileWrite | Command::Import | Command::FactoryReset
+            | Command::FwWrite | Command::FwBoot | Command::Bootloader
+        ) {
+            crate::warn!("Configuration is locked.");
+            return Self::nak(resp, CfgError::Locked as u8);
+        }
+
+        // Commands rewriting the flash are allowed until any other command follows them.
+        if !matches!(cmd,
+            Command::Write | Command::Identity | Command::Profile | Command::ProfileName | Command::ProfileWrite
+            | Command::Import | Command::Lock | Command::FactoryReset | Command::FwWrite | Command::FwBoot
+        ) {
+            self.unlocked = false;
+        } else if !self.unlocked {
+            crate::warn!("Command is rejected without the unlock sequence.");
+            return Self::nak(resp, FrameError::Unlock as u8);
+        }
+
+        match cmd {
+            Command::Reset => {
+                // Repeated requests are acknowledged as well, while the reset is already pending.
+                super::app::FirmwareReset::spawn().ok();
+                1
+            },
+            Command::Bootloader => {
+                // The drum disappears until the bootloader is left, therefore the key is required.
+                if req.get(1..3) != Some(&COMMAND_KEY) {
+                    return Self::malformed(resp);
+                }
+                if !super::bootloader::available() {
+                    return Self::nak(resp, FrameError::NoBootloader as u8);
+                }
+                // The response is sent before the reboot, which is delayed by the task.
+                super::app::BootloaderEntry::spawn().ok();
+                1
+            },
+            Command::Read => {
+                // Sending current configuration back.
+                let len = self.cfg.serialize(&mut resp[1..]);
+                crate::info!("Current configuration was send [{}] bytes", len);
+                len + 1
+            }
+            Command::Write => match self.write_cfg(&req[1..]) {
+                Ok(()) => 1,
+                Err(err) => Self::nak(resp, err as u8),
+            }
+            Command::Haptic => {
+                // Feedback is best effort. Pulses obtained while the previous one is active are dropped.
+                match HapticPulse::from_bytes(&req[1..]) {
+                    Some(pulse) => if super::app::Haptic::spawn(pulse).is_err() {
+                        crate::debug!("Haptic actuator is busy.");
+                    },
+                    None => crate::warn!("Malformed haptic pulse request."),
+                }
+                1
+            }
+            Command::Sampler => {
+                // Restart is done by a separate task, since the sensor handler is shared with the
+                // sampling interrupt. Requested mode is echoed back.
+                let Some(mode) = PiezoSensorSampleMode::from_bytes(&req[1..]) else { return Self::malformed(resp) };
+                if super::app::SamplerControl::spawn(mode).is_err() {
+                    crate::debug!("Sampler restart is already pending.");
+                }
+                match mode {
+                    PiezoSensorSampleMode::HALT => { resp[1] = 0; 2 },
+                    PiezoSensorSampleMode::TIMER(period) => {
+                        resp[1] = 1;
+                        resp[2..4].copy_from_slice(&period.to_be_bytes());
+                        4
+                    },
+                }
+            }
+            Command::Menu => {
+                // Missing argument toggles the mode. Current mode is sent back.
+                self.set_menu(match req.get(1) {
+                    Some(0) => false,
+                    Some(1) => true,
+                    _ => !self.menu,
+                });
+                resp[1] = self.menu as u8;
+                2
+            }
+            Command::Tune => match self.tune(&req[1..]) {
+                Ok(()) => 1,
+                Err(err) => Self::nak(resp, err as u8),
+            }
+            Command::Unlock => {
+                if req.get(1..) != Some(&UNLOCK_MAGIC) {
+                    return Self::malformed(resp);
+                }
+                self.unlocked = true;
+                1
+            }
+            Command::Ping => {
+                // Host measures the round trip time including the wait for the programmer task.
+                // Timestamp in CPU cycles is followed by the echoed payload.
+                let payload = &req[1..req.len().min(RESP_LEN - 4)];
+                resp[1..5].copy_from_slice(&super::timing::now().to_be_bytes());
+                resp[5..5 + payload.len()].copy_from_slice(payload);
+                5 + payload.len()
+            }
+            Command::Hits => Self::counters(resp, &self.cfg.hits),
+            Command::Stats => match req.get(1) {
+                // USB counters are sent by default, sample processing ones are requested with 1, CPU
+                // load with 2 and pipeline health with 3.
+                Some(1) => Self::counters(resp, &stats.runtime()),
+                Some(2) => Self::counters(resp, &super::load::take()),
+                Some(3) => Self::counters(resp, &super::health::take()),
+                _ => Self::counters(resp, &stats.usb()),
+            },
+            Command::Status => {
+                // Configuration status followed by boot statistics.
+                let bytes = self.backup.stats().to_bytes();
+                resp[1] = self.cfg_status as u8;
+                resp[2..2 + bytes.len()].copy_from_slice(&bytes);
+                bytes.len() + 2
+            }
+            Command::Profiles => {
+                // Active profile, mask of stored profiles and their names.
+                let (head, names) = resp[1..3 + PROFILES * PROFILE_NAME_LEN].split_at_mut(2);
+                names.fill(0);
+                head[0] = self.cfg.profile;
+                head[1] = 0;
+                for ((profile, stored), name) in (0..PROFILES as u8).zip(self.names).zip(names.chunks_exact_mut(PROFILE_NAME_LEN)) {
+                    if profile == self.cfg.profile {
+                        name.copy_from_slice(&self.cfg.name);
+                    } else if let Some(stored) = stored {
+                        name.copy_from_slice(&stored);
+                    } else {
+                        continue;
+                    }
+                    head[1] |= 1 << profile;
+                }
+                3 + PROFILES * PROFILE_NAME_LEN
+            }
+            Command::Profile => match req.get(1) {
+                Some(&profile) if (profile as usize) < PROFILES => {
+                    self.select_profile(profile);
+                    resp[1] = profile;
+                    2
+                },
+                _ => Self::malformed(resp),
+            }
+            Command::ProfileRead => match req.get(1) {
+                Some(&profile) if (profile as usize) < PROFILES => {
+                    // Unsaved changes are only kept by the active profile.
+                    let cfg = if profile == self.cfg.profile { self.cfg } else { self.load_profile(profile) };
+                    cfg.serialize(&mut resp[1..]) + 1
+                },
+                _ => Self::malformed(resp),
+            }
+            Command::ProfileWrite => match &req[1..] {
+                [profile, data @ ..] if (*profile as usize) < PROFILES => match self.write_profile(*profile, data) {
+                    Ok(()) => 1,
+                    Err(err) => Self::nak(resp, err as u8),
+                },
+                _ => Self::malformed(resp),
+            }
+            Command::ProfileName => match &req[1..] {
+                [profile, name @ ..] if (*profile as usize) < PROFILES => {
+                    self.rename_profile(*profile, name);
+                    1
+                },
+                _ => Self::malformed(resp),
+            }
+            Command::FactoryReset => {
+                if req.get(1..3) != Some(&COMMAND_KEY) {
+                    return Self::malformed(resp);
+                }
+                self.factory_reset();
+                1
+            }
+            Command::Export => {
+                // Chunk of the blob at the requested offset. Shorter chunk ends the blob.
+                let Some(&[o0, o1]) = req.get(1..3) else {
+                    return Self::malformed(resp);
+                };
+                let mut blob = [0u8; BLOB_LEN];
+                let len = self.cfg.export(&mut blob);
+                let chunk = blob.get(u16::from_be_bytes([o0, o1]) as usize..len).unwrap_or(&[]);
+                let size = chunk.len().min(CHUNK_LEN);
+                resp[1..=size].copy_from_slice(&chunk[..size]);
+                size + 1
+            }
+            Command::Dump => {
+                // Raw pages are read the same way as the exported blob, e.g. to inspect layouts
+                // of older firmware, which fail to migrate.
+                let Some(&[o0, o1]) = req.get(1..3) else {
+                    return Self::malformed(resp);
+                };
+                let offset = u16::from_be_bytes([o0, o1]) as usize;
+                let size = CfgFlash::len().saturating_sub(offset).min(CHUNK_LEN);
+                CfgFlash::read(offset, &mut resp[1..=size]).ok();
+                size + 1
+            }
+            #[cfg(feature = "capture")]
+            Command::Capture => {
+                // Times before and after the hit in milliseconds arm a new capture, the state of
+                // the latest one is sent back, along with its samples and those preceding the peak.
+                if let Some(&[before, after]) = req.get(1..3) {
+                    self.capture.arm(before, after);
+                }
+                let (len, lead) = self.capture.len();
+                resp[1] = self.capture.state() as u8;
+                resp[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+                resp[4..6].copy_from_slice(&(lead as u16).to_be_bytes());
+                6
+            }
+            #[cfg(feature = "capture")]
+            Command::CaptureRead => {
+                // Same as the exported blob, shorter chunk ends the capture.
+                let Some(&[o0, o1]) = req.get(1..3) else {
+                    return Self::malformed(resp);
+                };
+                self.capture.read(u16::from_be_bytes([o0, o1]) as usize, &mut resp[1..=CHUNK_LEN]) + 1
+            }
+            Command::Crash => {
+                // Kind, registers and message of the last crash, nothing if there is none. Any
+                // argument clears the report once it is sent.
+                let len = super::crash::read(&mut resp[1..]);
+                if req.len() > 1 {
+                    super::crash::clear();
+                    self.crash_sync = true;
+                    self.commits.try_send(()).ok();
+                }
+                len + 1
+            }
+            Command::SelfTest => {
+                // Masks of done and failed checks.
+                resp[1..3].copy_from_slice(&super::selftest::results());
+                3
+            }
+            #[cfg(feature = "log-ring")]
+            Command::LogRead => {
+                // Same as the exported blob, shorter chunk ends the log.
+                let Some(&[o0, o1]) = req.get(1..3) else {
+                    return Self::malformed(resp);
+                };
+                super::logger::read_ring(u16::from_be_bytes([o0, o1]) as usize, &mut resp[1..=CHUNK_LEN]) + 1
+            }
+            Command::Import => {
+                // All profiles are overwritten, therefore the key is required.
+                match &req[1..] {
+                    [k0, k1, o0, o1, chunk @ ..] if [*k0, *k1] == COMMAND_KEY => {
+                        // The flag is set once the whole blob is imported.
+                        let chunk = Vec::from_slice(chunk).unwrap_or_default();
+                        self.job = Some(FlashJob::Import(u16::from_be_bytes([*o0, *o1]) as usize, chunk));
+                        resp[1] = 0;
+                        2
+                    },
+                    _ => Self::malformed(resp),
+                }
+            }
+            Command::Lock => match &req[1..] {
+                [k0, k1, locked, ..] if [*k0, *k1] == COMMAND_KEY => {
+                    self.cfg.locked = *locked;
+                    self.save_cfg();
+                    crate::info!("Configuration lock: {}", locked);
+                    resp[1] = *locked;
+                    2
+                },
+                _ => Self::malformed(resp),
+            }
+            Command::FwWrite | Command::FwBoot => {
+                // Firmware is replaced, therefore the key is required.
+                let [k0, k1, a0, a1, a2, a3, data @ ..] = &req[1..] else {
+                    return Self::malformed(resp);
+                };
+                // The firmware is restarted once the committed image is verified.
+                let arg = u32::from_be_bytes([*a0, *a1, *a2, *a3]);
+                self.job = Some(match (&cmd, data) {
+                    _ if [*k0, *k1] != COMMAND_KEY => return Self::malformed(resp),
+                    (Command::FwWrite, chunk) => FlashJob::FwWrite(arg as usize, Vec::from_slice(chunk).unwrap_or_default()),
+                    (_, &[c0, c1, c2, c3, ..]) => FlashJob::FwCommit(arg as usize, u32::from_be_bytes([c0, c1, c2, c3])),
+                    _ => return Self::malformed(resp),
+                });
+                1
+            }
+            Command::Scope => {
+                // Samples are only streamed over the serial port, until the port is closed.
+                self.scope = req.get(1).copied().unwrap_or(0);
+                self.scope_peak = Default::default();
+                self.scope_batch.clear();
+                resp[1] = self.scope;
+                2
+            }
+            Command::Telemetry => {
+                // Same as the scope, hits are only streamed over the serial port until it is closed.
+                self.telemetry = req.get(1).is_some_and(|&on| on != 0);
+                resp[1] = self.telemetry as u8;
+                2
+            }
+            Command::Calibrate => {
+                // Duration in 100 ms units starts a new measurement, the latest result is sent back.
+                if let Some(&duration @ 1..) = req.get(1) {
+                    self.noise.start(duration as u32 * BLOCKS_PER_100MS);
+                }
+                resp[1] = self.noise.is_running() as u8;
+                let calibration = self.cfg.calibration;
+                for i in 0..4 {
+                    resp[2 + 2 * i..4 + 2 * i].copy_from_slice(&calibration.sigma[i].to_be_bytes());
+                    resp[10 + 2 * i..12 + 2 * i].copy_from_slice(&calibration.offset[i].to_be_bytes());
+                }
+                18
+            }
+            Command::Identity => {
+                // Wrong identity might hide the drum from some hosts, therefore the key is required.
+                let id = &mut self.cfg.usb_identity;
+                match &req[1..] {
+                    [k0, k1, field, data @ ..] if [*k0, *k1] == COMMAND_KEY => match (*field, data) {
+                        (IDENTITY_DEFAULT, _) => *id = UsbIdentity::new(),
+                        (IDENTITY_VIDPID, [v0, v1, p0, p1, ..]) => {
+                            id.vid = u16::from_be_bytes([*v0, *v1]);
+                            id.pid = u16::from_be_bytes([*p0, *p1]);
+                        },
+                        (IDENTITY_MANUFACTURER, s) => id.manufacturer = UsbIdentity::to_field(s),
+                        (IDENTITY_PRODUCT, s) => id.product = UsbIdentity::to_field(s),
+                        _ => return Self::malformed(resp),
+                    },
+                    _ => return Self::malformed(resp),
+                }
+
+                self.save_cfg();
+                crate::info!("USB identity will be changed after restart.");
+                1
+            }
+            #[cfg(not(feature = "capture"))]
+            Command::Capture | Command::CaptureRead => Self::nak(resp, FrameError::Command as u8),
+            #[cfg(not(feature = "log-ring"))]
+            Command::LogRead => Self::nak(resp, FrameError::Command as u8),
+            Command::Unknown => Self::nak(resp, FrameError::Command as u8),
         }
-        cortex_m::asm::delay(720);
     }
 }
 
-/* Constant bytes are completely equal to those defined within the taiko drum control utility. */
-const LEFTKAT: u8 = 0x10;
-const LEFTDON: u8 = 0x11;
-const RIGHTDON: u8 = 0x12;
-const RIGHTKAT: u8 = 0x13; 
-const SENS: u8 = 0x20;
-const SHARP: u8 = 0x21;
+/// Names of configuration tags in the text console, equal to the keys of the utility, in the
+/// order of [`CONFIG_TAGS`].
+#[cfg(feature = "console")]
+const CONFIG_NAMES: &str = "left_kat left_don right_don right_kat mod_left_kat mod_left_don mod_right_don mod_right_kat \
+    p2_left_kat p2_left_don p2_right_don p2_right_kat p2_mod_left_kat p2_mod_left_don p2_mod_right_don p2_mod_right_kat \
+    cons_left_kat cons_left_don cons_right_don cons_right_kat sens sharp mode midi poll repeat_delay repeat_rate \
+    axes cdc_off power self_powered idle_sleep gesture_kats gesture_dons gesture_hold";
+#[cfg(feature = "console")]
+const CONFIG_TAGS: [u8; 35] = [
+    LEFTKAT, LEFTDON, RIGHTDON, RIGHTKAT, MOD_LEFTKAT, MOD_LEFTDON, MOD_RIGHTDON, MOD_RIGHTKAT,
+    P2_LEFTKAT, P2_LEFTDON, P2_RIGHTDON, P2_RIGHTKAT, P2_MOD_LEFTKAT, P2_MOD_LEFTDON, P2_MOD_RIGHTDON, P2_MOD_RIGHTKAT,
+    CONS_LEFTKAT, CONS_LEFTDON, CONS_RIGHTDON, CONS_RIGHTKAT, SENS, SHARP, HID_MODE, MIDI_MODE, POLL_INTERVAL, REPEAT_DELAY, REPEAT_RATE,
+    VELOCITY_AXES, CDC_DISABLED, MAX_POWER, SELF_POWERED, IDLE_SLEEP, GESTURE_KATS, GESTURE_DONS, GESTURE_HOLD,
+];
+/// Names of USB and sample processing counters in the text console, see [`UsbStats`].
+#[cfg(feature = "console")]
+const STATS_NAMES: &str = "reports naks resets suspends cdc_rx cdc_tx errors missed_sof frame_jitter \
+    sample_overflows report_overflows dropped_reports hits_left_kat hits_left_don hits_right_don hits_right_kat \
+    crosstalk max_latency";
+
+/// Text console names of configuration tags.
+#[cfg(feature = "console")]
+fn config_names() -> impl Iterator<Item = (&'static str, u8)> {
+    CONFIG_NAMES.split_ascii_whitespace().zip(CONFIG_TAGS)
+}
+
+/// Tag of the configuration value with the provided text console name.
+#[cfg(feature = "console")]
+pub(crate) fn config_key(name: &str) -> Option<u8> {
+    config_names().find(|&(key, _)| key == name).map(|(_, tag)| tag)
+}
 
 impl ProgrammerSerializer for DrumConfig {
-    type Error = u8;
-    fn serialize(&self, buff: &mut [u8; BUFF_LEN]) {
-        let hm = self.hit_mapping;
-        let pc = self.parse_cfg;
-        let s = pc.sensitivity;
-        let sh = pc.sharpness.to_be_bytes();
-
-        // Values scanned by utility are expected in big-endian format.
-        let data = [
-            LEFTKAT,    hm.left_kat as u8,
-            RIGHTDON,   hm.right_don as u8,
-            LEFTDON,    hm.left_don as u8,
-            RIGHTKAT,   hm.right_kat as u8,
-            SENS,       s,
-            SHARP,      sh[0], sh[1],
-        ];
-
-        buff[..data.len()].copy_from_slice(&data);
+    type Error = CfgError;
+    fn serialize(&self, buff: &mut [u8]) -> usize {
+        serialize_entries(self, buff, cfg!(feature = "two-player"))
     }
 
     fn deserialize(&self, buff: &[u8]) -> Result<Self, Self::Error> {
         let mut idx = 0;
         let mut s = self.clone();
+        // Streams without the version are written by the utility in the layout of version 1.
+        let mut version = 1;
 
         while idx < buff.len() {
-            log::info!("IDX: {}, BUFF(IDX): {}", idx, buff[idx]);
             match buff[idx] {
-                /* One byte is expected for keyboard mapping configuration. */
-                cmd if matches!(cmd, LEFTKAT | LEFTDON | RIGHTDON | RIGHTKAT) => {
+                /* One byte is expected for keyboard mapping configuration and its modifiers. */
+                cmd if matches!(cmd, 
+                    LEFTKAT | LEFTDON | RIGHTDON | RIGHTKAT | MOD_LEFTKAT | MOD_LEFTDON | MOD_RIGHTDON | MOD_RIGHTKAT |
+                    P2_LEFTKAT..=P2_MOD_RIGHTKAT
+                ) => {
                     idx += 1;
                     if let Some(&key) = buff.get(idx) {
                         match cmd {
-                            LEFTKAT => s.hit_mapping.left_kat = key.into(),
-                            LEFTDON => s.hit_mapping.left_don = key.into(),
-                            RIGHTDON => s.hit_mapping.right_don = key.into(),
-                            RIGHTKAT => s.hit_mapping.right_kat = key.into(),
+                            LEFTKAT => s.hit_mapping.left_kat.key = key.into(),
+                            LEFTDON => s.hit_mapping.left_don.key = key.into(),
+                            RIGHTDON => s.hit_mapping.right_don.key = key.into(),
+                            RIGHTKAT => s.hit_mapping.right_kat.key = key.into(),
+                            MOD_LEFTKAT => s.hit_mapping.left_kat.modifier = key,
+                            MOD_LEFTDON => s.hit_mapping.left_don.modifier = key,
+                            MOD_RIGHTDON => s.hit_mapping.right_don.modifier = key,
+                            MOD_RIGHTKAT => s.hit_mapping.right_kat.modifier = key,
+                            P2_LEFTKAT => s.p2_hit_mapping.left_kat.key = key.into(),
+                            P2_LEFTDON => s.p2_hit_mapping.left_don.key = key.into(),
+                            P2_RIGHTDON => s.p2_hit_mapping.right_don.key = key.into(),
+                            P2_RIGHTKAT => s.p2_hit_mapping.right_kat.key = key.into(),
+                            P2_MOD_LEFTKAT => s.p2_hit_mapping.left_kat.modifier = key,
+                            P2_MOD_LEFTDON => s.p2_hit_mapping.left_don.modifier = key,
+                            P2_MOD_RIGHTDON => s.p2_hit_mapping.right_don.modifier = key,
+                            P2_MOD_RIGHTKAT => s.p2_hit_mapping.right_kat.modifier = key,
                             _ => unreachable!(),
                         }
                     } else {
-                        log::error!("Desserialization error: Unexpected end of stream within the configuration command.");
-                        return Err(0);
+                        crate::error!("Desserialization error: Unexpected end of stream within the configuration command.");
+                        return Err(CfgError::Truncated);
                     } 
                 }, 
-                /* Four bytes is expected for sensitivity configuration. */
+                /* Four bytes is expected for sensitivity configuration, or a single one within
+                 * streams read back from the drum, which lead with their version. */
                 SENS => {
-                    if buff.get(idx+4).is_some() {
-                        s.parse_cfg.sensitivity = buff[idx].try_into().unwrap();
+                    let width = if version >= 2 { 1 } else { 4 };
+                    if let Some(&sensitivity) = buff.get(idx + width) {
+                        s.parse_cfg.sensitivity = sensitivity;
                     } else {
-                        log::error!("Desserialization error: Unexpected end of stream within the configuration command.");
-                        return Err(0);
+                        crate::error!("Desserialization error: Unexpected end of stream within the configuration command.");
+                        return Err(CfgError::Truncated);
                     }
-                    idx += 4;
+                    idx += width;
                 },
                 /* Two bytes are expected for sharpness configuration. */
                 SHARP => {
-                    if buff.get(idx+2).is_some() {
-                        s.parse_cfg.sharpness = u16::from_be_bytes(buff[idx..idx+2].try_into().unwrap());
+                    if let Some(bytes) = buff.get(idx + 1..idx + 3) {
+                        s.parse_cfg.sharpness = u16::from_be_bytes(bytes.try_into().unwrap());
                     } else {
-                        log::error!("Desserialization error: Unexpected end of stream within the configuration command.");
-                        return Err(0);
+                        crate::error!("Desserialization error: Unexpected end of stream within the configuration command.");
+                        return Err(CfgError::Truncated);
                     }
                     idx += 2;
                 },
+                /* Two bytes are expected for consumer usage mapping. */
+                cmd if matches!(cmd, CONS_LEFTKAT | CONS_LEFTDON | CONS_RIGHTDON | CONS_RIGHTKAT) => {
+                    if let Some(bytes) = buff.get(idx + 1..idx + 3) {
+                        let usage = u16::from_be_bytes(bytes.try_into().unwrap());
+                        match cmd {
+                            CONS_LEFTKAT => s.consumer_mapping.left_kat = usage,
+                            CONS_LEFTDON => s.consumer_mapping.left_don = usage,
+                            CONS_RIGHTDON => s.consumer_mapping.right_don = usage,
+                            CONS_RIGHTKAT => s.consumer_mapping.right_kat = usage,
+                            _ => unreachable!(),
+                        }
+                    } else {
+                        crate::error!("Desserialization error: Unexpected end of stream within the configuration command.");
+                        return Err(CfgError::Truncated);
+                    }
+                    idx += 2;
+                },
+                /* One byte is expected for HID and MIDI modes, polling interval, auto-repeat, velocity axes, CDC flag, power, idle sleep and gestures. */
+                cmd if matches!(cmd, 
+                    HID_MODE | MIDI_MODE | POLL_INTERVAL | REPEAT_DELAY | REPEAT_RATE | VELOCITY_AXES | CDC_DISABLED | MAX_POWER | SELF_POWERED | IDLE_SLEEP |
+                    GESTURE_KATS | GESTURE_DONS | GESTURE_HOLD
+                ) => {
+                    idx += 1;
+                    if let Some(&mode) = buff.get(idx) {
+                        match cmd {
+                            HID_MODE => s.hid_mode = mode.into(),
+                            MIDI_MODE => s.midi_mode = mode.into(),
+                            POLL_INTERVAL => s.poll_interval = mode,
+                            REPEAT_DELAY => s.repeat_delay = mode,
+                            REPEAT_RATE => s.repeat_rate = mode,
+                            VELOCITY_AXES => s.velocity_axes = mode,
+                            CDC_DISABLED => s.cdc_disabled = mode,
+                            MAX_POWER => s.max_power = mode,
+                            SELF_POWERED => s.self_powered = mode,
+                            IDLE_SLEEP => s.idle_sleep = mode,
+                            GESTURE_KATS => s.gesture_mapping.both_kats = mode,
+                            GESTURE_DONS => s.gesture_mapping.both_dons = mode,
+                            GESTURE_HOLD => s.gesture_mapping.hold_don = mode,
+                            _ => unreachable!(),
+                        }
+                    } else {
+                        crate::error!("Desserialization error: Unexpected end of stream within the configuration command.");
+                        return Err(CfgError::Truncated);
+                    }
+                },
+                /* Streams read back from the drum lead with their version, which is not a setting. */
+                STREAM_VERSION => {
+                    idx += 1;
+                    if let Some(&stream) = buff.get(idx) {
+                        version = stream;
+                    } else {
+                        crate::error!("Desserialization error: Unexpected end of stream within the configuration command.");
+                        return Err(CfgError::Truncated);
+                    }
+                },
+                /* Fixed-size HID reports are zero padded after the last command. */
+                0x00 => break,
                 bad @ _ => {
-                    log::error!("Deserialization error: Unable to properly parse upcoming configuration byte-stream from the utility software.");
-                    return Err(bad);
+                    crate::error!("Deserialization error: Unexpected byte value obtained: {}", bad);
+                    return Err(CfgError::UnknownTag);
                 }
             }
             idx += 1;
         }
 
+        s.validate().inspect_err(|err| crate::error!("Configuration is rejected: {:?}", err))?;
         Ok(s)
     }
 }
+
+/// Writes the configuration stream of [`Command::Read`] and returns its length. Entries, which do
+/// not fit, are left out as a whole.
+///
+/// Second drum entries are only written with `with_p2`, which two-player firmware sets except for
+/// the vendor HID feature report, where they do not fit next to the rest.
+fn serialize_entries(cfg: &DrumConfig, buff: &mut [u8], with_p2: bool) -> usize {
+    let hm = cfg.hit_mapping;
+    let p2 = cfg.p2_hit_mapping;
+    let cm = cfg.consumer_mapping;
+    let gm = cfg.gesture_mapping;
+    let pc = cfg.parse_cfg;
+
+    // Entries in (tag, value, width in bytes, omitted when zero) format.
+    let base = [
+        (STREAM_VERSION, CFG_STREAM_VERSION as u16, 1, false),
+        (LEFTKAT,       hm.left_kat.key as u16,     1, false),
+        (RIGHTDON,      hm.right_don.key as u16,    1, false),
+        (LEFTDON,       hm.left_don.key as u16,     1, false),
+        (RIGHTKAT,      hm.right_kat.key as u16,    1, false),
+        (SENS,          pc.sensitivity as u16,      1, false),
+        (SHARP,         pc.sharpness,               2, false),
+        (HID_MODE,      cfg.hid_mode as u16,        1, false),
+        (MIDI_MODE,     cfg.midi_mode as u16,       1, false),
+        (POLL_INTERVAL, cfg.poll_interval as u16,   1, false),
+        (REPEAT_DELAY,  cfg.repeat_delay as u16,    1, false),
+        (REPEAT_RATE,   cfg.repeat_rate as u16,     1, false),
+        (VELOCITY_AXES, cfg.velocity_axes as u16,   1, false),
+        (MOD_LEFTKAT,   hm.left_kat.modifier as u16,    1, true),
+        (MOD_LEFTDON,   hm.left_don.modifier as u16,    1, true),
+        (MOD_RIGHTDON,  hm.right_don.modifier as u16,   1, true),
+        (MOD_RIGHTKAT,  hm.right_kat.modifier as u16,   1, true),
+        (CONS_LEFTKAT,  cm.left_kat,    2, true),
+        (CONS_LEFTDON,  cm.left_don,    2, true),
+        (CONS_RIGHTDON, cm.right_don,   2, true),
+        (CONS_RIGHTKAT, cm.right_kat,   2, true),
+        (GESTURE_KATS,  gm.both_kats as u16,    1, true),
+        (GESTURE_DONS,  gm.both_dons as u16,    1, true),
+        (GESTURE_HOLD,  gm.hold_don as u16,     1, true),
+        (CDC_DISABLED,  cfg.cdc_disabled as u16,    1, true),
+        (MAX_POWER,     cfg.max_power as u16,       1, true),
+        (SELF_POWERED,  cfg.self_powered as u16,    1, true),
+        (IDLE_SLEEP,    cfg.idle_sleep as u16,      1, false),
+    ];
+    let p2 = [
+        (P2_LEFTKAT,        p2.left_kat.key as u16,         1, false),
+        (P2_LEFTDON,        p2.left_don.key as u16,         1, false),
+        (P2_RIGHTDON,       p2.right_don.key as u16,        1, false),
+        (P2_RIGHTKAT,       p2.right_kat.key as u16,        1, false),
+        (P2_MOD_LEFTKAT,    p2.left_kat.modifier as u16,    1, true),
+        (P2_MOD_LEFTDON,    p2.left_don.modifier as u16,    1, true),
+        (P2_MOD_RIGHTDON,   p2.right_don.modifier as u16,   1, true),
+        (P2_MOD_RIGHTKAT,   p2.right_kat.modifier as u16,   1, true),
+    ];
+
+    // Values scanned by utility are expected in big-endian format.
+    base.into_iter()
+        .chain(p2.into_iter().filter(|_| with_p2))
+        .filter(|&(_, value, _, optional)| !(optional && value == 0))
+        .try_fold(0, |idx, (tag, value, width, _)| {
+            let end = idx + 1 + width;
+            if end > buff.len() {
+                crate::warn!("Configuration does not fit into a single response. Truncating...");
+                return Err(idx);
+            }
+            buff[idx] = tag;
+            buff[idx + 1..end].copy_from_slice(&value.to_be_bytes()[2 - width..]);
+            Ok(end)
+        })
+        .unwrap_or_else(|idx| idx)
+}
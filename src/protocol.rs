@@ -0,0 +1,244 @@
+//! Definitions of the programmer protocol shared by all of its interfaces.
+//!
+//! Serial frames are `SYNC <length> <command and arguments> <CRC16>`, where the length counts the
+//! body and the big-endian [`crc16`] covers the length and body. Responses start with [`ACK`] or
+//! [`NAK`] followed by the echoed command, while stream frames start with the command, which
+//! requested them. Corrupted frames are answered with [`Command::Unknown`] in place of the command.
+//! An empty frame with a valid CRC resynchronizes the stream: it is acknowledged once all bytes
+//! preceding it are dropped, either as corrupted or as a partial frame, which timed out. Vendor
+//! HID reports and WebUSB requests carry the same bodies without framing.
+//!
+//! This module only depends on `core`, so host programs can include it as it is, e.g. with the
+//! `#[path]` attribute, instead of duplicating these values. The control utility is written in
+//! Tcl, therefore it still defines them on its own.
+
+/// Starts each serial frame, so line noise preceding it is skipped.
+pub(crate) const SYNC: u8 = 0xA5;
+/// Sync and length bytes preceding the frame body and CRC16 following it.
+pub(crate) const FRAME_OVERHEAD: usize = 4;
+/// Precedes the echoed command and the response of an executed command.
+pub(crate) const ACK: u8 = 0x06;
+/// Replaces [`ACK`] when the command is rejected, followed by the echoed command and the code of
+/// a configuration error or [`FrameError`].
+pub(crate) const NAK: u8 = 0x15;
+/// Key which must follow protected command bytes, so stray bytes never change USB identity or
+/// wipe the configuration.
+///
+/// The key is fixed and documented, so it only guards against accidents. Any program opening the
+/// port can send it, including the unlock of a locked configuration.
+pub(crate) const COMMAND_KEY: [u8; 2] = [0x55, 0xAA];
+/// Payload of [`Command::Unlock`], which must precede commands rewriting the configuration or
+/// firmware, so programs probing serial ports never rewrite the flash by accident.
+pub(crate) const UNLOCK_MAGIC: [u8; 4] = *b"TAIK";
+/* Fields of the identity command. */
+pub(crate) const IDENTITY_DEFAULT: u8 = 0x00;
+pub(crate) const IDENTITY_VIDPID: u8 = 0x01;
+pub(crate) const IDENTITY_MANUFACTURER: u8 = 0x02;
+pub(crate) const IDENTITY_PRODUCT: u8 = 0x03;
+/// Closing the serial port opened with this baud rate reboots into the bootloader, the same way
+/// as Arduino boards do, which is expected by many flashing tools.
+pub(crate) const TOUCH_BOOTLOADER_BAUD: u32 = 1200;
+/// Closing the serial port opened with this baud rate resets the firmware.
+pub(crate) const TOUCH_RESET_BAUD: u32 = 2400;
+
+/// Reasons to reject a serial frame or command. Sent back after [`NAK`], numbered after
+/// [`CfgError`](super::cfg::CfgError) codes.
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub(crate) enum FrameError {
+    /// CRC mismatch, e.g. the frame is corrupted by line noise.
+    Crc = 0x10,
+    /// Request without a command byte over an interface other than the serial port, where an
+    /// empty frame is the resync frame instead.
+    Length = 0x11,
+    /// Unknown command byte.
+    Command = 0x12,
+    /// Missing or malformed arguments, or wrong [`COMMAND_KEY`].
+    Malformed = 0x13,
+    /// Firmware update chunk is out of order, or the image is corrupted or not bootable.
+    Update = 0x14,
+    /// Chip has no flash for the firmware update slot.
+    NoSlot = 0x15,
+    /// Command rewriting the flash without the preceding [`Command::Unlock`].
+    Unlock = 0x16,
+    /// Partial frame was not completed in time, e.g. a packet of it was lost.
+    Timeout = 0x17,
+    /// Firmware is built without a bootloader address.
+    NoBootloader = 0x18,
+}
+
+/// Bitwise CRC16-CCITT (polynomial 0x1021, initial value 0xFFFF) of serial frames.
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u16) << 8), |crc, _| (crc << 1) ^ (0x1021 & (crc >> 15).wrapping_neg()))
+    })
+}
+
+/// First byte of each request, which is echoed by stream frames.
+#[repr(u8)]
+pub(crate) enum Command {
+    /// Unknown state.
+    Unknown = 0x00,
+    /// Read current configuration.
+    Read    = 0x01,
+    /// Write new configuration.
+    Write   = 0x02,
+    /// Pulse the haptic actuator.
+    Haptic  = 0x10,
+    /// Enter, leave or toggle menu navigation mode.
+    Menu    = 0x11,
+    /// Override USB identity (VID/PID and strings).
+    Identity = 0x12,
+    /// Apply configuration without saving it to flash.
+    Tune    = 0x13,
+    /// Read USB traffic and error counters.
+    Stats   = 0x14,
+    /// Read the state of the configuration found in flash at boot and boot statistics.
+    Status  = 0x15,
+    /// List configuration profiles.
+    Profiles = 0x16,
+    /// Select the active configuration profile.
+    Profile = 0x17,
+    /// Rename a configuration profile.
+    ProfileName = 0x18,
+    /// Erase all profiles and restart with the default configuration.
+    FactoryReset = 0x19,
+    /// Read a chunk of the exported configuration of all profiles.
+    Export  = 0x1A,
+    /// Write a chunk of the configuration blob to import.
+    Import  = 0x1B,
+    /// Read hit counters of each pad.
+    Hits    = 0x1C,
+    /// Lock or unlock configuration changes.
+    Lock    = 0x1D,
+    /// Write a chunk of the firmware update.
+    FwWrite = 0x1E,
+    /// Verify the firmware update and install it at the next boot.
+    FwBoot  = 0x1F,
+    /// Start or stop streaming raw samples.
+    Scope   = 0x20,
+    /// Measure the noise of idle pads or read the latest result.
+    Calibrate = 0x21,
+    /// Start or stop streaming detection details of each hit.
+    Telemetry = 0x22,
+    /// Read configuration of a profile without switching to it.
+    ProfileRead = 0x23,
+    /// Write configuration of a profile without switching to it.
+    ProfileWrite = 0x24,
+    /// Reboot into the bootloader.
+    Bootloader = 0x25,
+    /// Echo the payload back along with a timestamp.
+    Ping    = 0x26,
+    /// Allow commands rewriting the configuration or firmware.
+    Unlock  = 0x27,
+    /// Read a chunk of raw configuration pages.
+    Dump    = 0x28,
+    /// Arm a capture of raw samples around the next hit or read its state.
+    Capture = 0x29,
+    /// Read a chunk of captured samples. Both capture commands are unknown to builds without the
+    /// `capture` feature.
+    CaptureRead = 0x2A,
+    /// Switch the sampling mode and restart both ADCs.
+    Sampler = 0x2B,
+    /// Read a chunk of the latest log lines. Unknown to builds without the `log-ring` feature.
+    LogRead = 0x2C,
+    /// Read or clear the report of the last panic or hard fault.
+    Crash   = 0x2D,
+    /// Read results of the power-on self-test.
+    SelfTest = 0x2E,
+
+    /// Reset the firmware.
+    Reset   = 0xff,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use Command::*;
+        Ok(match value {
+            0x00 => Unknown,
+            0x01 => Read,
+            0x02 => Write,
+            0x10 => Haptic,
+            0x11 => Menu,
+            0x12 => Identity,
+            0x13 => Tune,
+            0x14 => Stats,
+            0x15 => Status,
+            0x16 => Profiles,
+            0x17 => Profile,
+            0x18 => ProfileName,
+            0x19 => FactoryReset,
+            0x1A => Export,
+            0x1B => Import,
+            0x1C => Hits,
+            0x1D => Lock,
+            0x1E => FwWrite,
+            0x1F => FwBoot,
+            0x20 => Scope,
+            0x21 => Calibrate,
+            0x22 => Telemetry,
+            0x23 => ProfileRead,
+            0x24 => ProfileWrite,
+            0x25 => Bootloader,
+            0x26 => Ping,
+            0x27 => Unlock,
+            0x28 => Dump,
+            0x29 => Capture,
+            0x2A => CaptureRead,
+            0x2B => Sampler,
+            0x2C => LogRead,
+            0x2D => Crash,
+            0x2E => SelfTest,
+
+            0xff => Reset,
+            _ => return Err(value)
+        })
+    }
+}
+
+/// Layout version of the [`Command::Read`] stream, which leads it as the value of [`STREAM_VERSION`]
+/// tag: 1 - every field in a fixed order, 2 - fields of optional features are omitted while zero
+/// and second drum fields are only sent by two-player builds. Sensitivity takes a single byte
+/// within version 2 streams, which are accepted back by [`Command::Write`], while streams without
+/// the version tag carry it in four bytes. Shall be bumped whenever the stream changes beyond
+/// appended fields.
+pub(crate) const CFG_STREAM_VERSION: u8 = 2;
+
+/* Tags of configuration fields within the streams of [`Command::Read`] and [`Command::Write`]. */
+pub(crate) const STREAM_VERSION: u8 = 0x0F;
+pub(crate) const LEFTKAT: u8 = 0x10;
+pub(crate) const LEFTDON: u8 = 0x11;
+pub(crate) const RIGHTDON: u8 = 0x12;
+pub(crate) const RIGHTKAT: u8 = 0x13; 
+pub(crate) const MOD_LEFTKAT: u8 = 0x18;
+pub(crate) const MOD_LEFTDON: u8 = 0x19;
+pub(crate) const MOD_RIGHTDON: u8 = 0x1A;
+pub(crate) const MOD_RIGHTKAT: u8 = 0x1B;
+pub(crate) const P2_LEFTKAT: u8 = 0x40;
+pub(crate) const P2_LEFTDON: u8 = 0x41;
+pub(crate) const P2_RIGHTDON: u8 = 0x42;
+pub(crate) const P2_RIGHTKAT: u8 = 0x43;
+pub(crate) const P2_MOD_LEFTKAT: u8 = 0x44;
+pub(crate) const P2_MOD_LEFTDON: u8 = 0x45;
+pub(crate) const P2_MOD_RIGHTDON: u8 = 0x46;
+pub(crate) const P2_MOD_RIGHTKAT: u8 = 0x47;
+pub(crate) const SENS: u8 = 0x20;
+pub(crate) const SHARP: u8 = 0x21;
+pub(crate) const HID_MODE: u8 = 0x30;
+pub(crate) const MIDI_MODE: u8 = 0x31;
+pub(crate) const POLL_INTERVAL: u8 = 0x32;
+pub(crate) const REPEAT_DELAY: u8 = 0x33;
+pub(crate) const REPEAT_RATE: u8 = 0x34;
+pub(crate) const VELOCITY_AXES: u8 = 0x35;
+pub(crate) const CDC_DISABLED: u8 = 0x36;
+pub(crate) const MAX_POWER: u8 = 0x37;
+pub(crate) const SELF_POWERED: u8 = 0x38;
+pub(crate) const IDLE_SLEEP: u8 = 0x39;
+pub(crate) const GESTURE_KATS: u8 = 0x50;
+pub(crate) const GESTURE_DONS: u8 = 0x51;
+pub(crate) const GESTURE_HOLD: u8 = 0x52;
+pub(crate) const CONS_LEFTKAT: u8 = 0x14;
+pub(crate) const CONS_LEFTDON: u8 = 0x15;
+pub(crate) const CONS_RIGHTDON: u8 = 0x16;
+pub(crate) const CONS_RIGHTKAT: u8 = 0x17;
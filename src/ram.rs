@@ -0,0 +1,72 @@
+//! Static RAM budget.
+//!
+//! All 20 KB of SRAM are shared by statics and the stack, which grows down towards them without
+//! any guard, so a stack overflow silently corrupts them. Sizes of the largest statics are summed
+//! here at compile time, and the build fails, if the stack would be left with less than
+//! [`STACK_RESERVE`]. New buffers of a few hundred bytes or more shall be listed in [`STATICS`].
+//! The budget is logged at boot as well. USB endpoint buffers are kept in the separate 512-byte
+//! packet memory, so those are not counted.
+
+use core::mem::size_of;
+
+use super::cfg::BLOB_LEN;
+use super::cross_correlation::XcorrScratch;
+use super::hid::DrumReport;
+use super::parser::Parser;
+use super::piezo::{PiezoSample, PIEZO_SENSOR_QUEUE_CAPACITY, PLAYERS};
+use super::prog::{ProgBuffers, Request, REQUEST_QUEUE_CAPACITY};
+use super::typematic::TYPEMATIC_QUEUE_CAPACITY;
+use super::usb::{UsbAllocator, UsbDescriptors, UsbTaikoDrum};
+
+/// Size of the SRAM.
+const SRAM: usize = 20 * 1024;
+/// Least stack size. The deepest path is the parser preempted by USB interrupts, the sampling
+/// interrupt and a fault handler.
+const STACK_RESERVE: usize = 4 * 1024;
+/// Statics, which are not listed: 1 KB RTT buffer, RTIC task storage and small counters.
+const UNLISTED: usize = 2 * 1024;
+
+/// Largest statics and their sizes in bytes.
+pub(crate) const STATICS: &[(&str, usize)] = &[
+    ("parsers", PLAYERS * size_of::<Parser>()),
+    ("xcorr scratch", size_of::<XcorrScratch>()),
+    ("usb device", size_of::<UsbTaikoDrum<'static>>()),
+    ("usb allocator", size_of::<Option<UsbAllocator>>()),
+    ("usb descriptors", size_of::<UsbDescriptors>()),
+    ("programmer buffers", size_of::<ProgBuffers>()),
+    ("import buffer", BLOB_LEN),
+    ("sample queue", PIEZO_SENSOR_QUEUE_CAPACITY * size_of::<PiezoSample>()),
+    ("typematic queue", TYPEMATIC_QUEUE_CAPACITY * size_of::<DrumReport>()),
+    ("request queue", REQUEST_QUEUE_CAPACITY * size_of::<Request>()),
+    ("crash report", super::crash::REPORT_LEN),
+    #[cfg(feature = "log-ring")]
+    ("log ring", super::logger::RING_LEN),
+    #[cfg(feature = "uart-log")]
+    ("uart queue", super::logger::UART_QUEUE_LEN),
+];
+
+/// Total size of [`STATICS`].
+const fn total() -> usize {
+    let (mut sum, mut idx) = (0, 0);
+    while idx < STATICS.len() {
+        sum += STATICS[idx].1;
+        idx += 1;
+    }
+    sum
+}
+
+/// RAM left for the stack.
+pub(crate) const STACK_FREE: usize = SRAM.saturating_sub(UNLISTED + total());
+
+const _: () = assert!(
+    total() + UNLISTED + STACK_RESERVE <= SRAM,
+    "Statics leave too little RAM for the stack. Disable some features or shrink buffers."
+);
+
+/// Logs the size of each listed static and the RAM left for the stack.
+pub(crate) fn report() {
+    for (name, size) in STATICS {
+        crate::debug!("RAM: {} - {} bytes", name, size);
+    }
+    crate::info!("RAM: {} bytes of statics, about {} bytes left for the stack.", total() + UNLISTED, STACK_FREE);
+}
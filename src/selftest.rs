@@ -0,0 +1,96 @@
+//! Power-on self-test.
+//!
+//! Helps builders to validate the soldering without any tools. The clock and the stored
+//! configuration are checked during initialization, while sensors are checked on the first
+//! samples, which are taken right after boot. Results are read with the programmer, and the first
+//! failed check is blinked on the status LED of boards, which have one (PC13 of Blue Pill boards):
+//! the LED blinks as many times as the number of the check and repeats that every two seconds.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::parser::MID_RANGE;
+use super::piezo::{PiezoSample, PLAYERS};
+use super::pins::{PinMode, StatusLedPin};
+
+/// Checks of the self-test, which are also bits of the results.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub(crate) enum Check {
+    /// Crystal, PLL and the 48 MHz USB clock are running.
+    Clock = 0,
+    /// Stored configuration passed the CRC check.
+    Config = 1,
+    /// Idle level of each sensor is close to the midpoint, so none is shorted or left open.
+    Sensors = 2,
+}
+
+/// Samples averaged by the sensor check, about 26 ms at 10 kHz.
+const SENSOR_CHECK_SAMPLES: u32 = 256;
+/// Largest deviation of the idle level from the midpoint in ADC counts. Shorted or open inputs
+/// sit at one of the rails.
+const SENSOR_OFFSET_LIMIT: u32 = 512;
+/// LED timings of blink codes in milliseconds.
+pub(crate) const BLINK_ON_MS: u32 = 200;
+pub(crate) const BLINK_OFF_MS: u32 = 300;
+pub(crate) const BLINK_PAUSE_MS: u32 = 2_000;
+
+/// Checks, which were done and which failed.
+static DONE: AtomicU8 = AtomicU8::new(0);
+static FAILED: AtomicU8 = AtomicU8::new(0);
+
+/// Records the result of the check.
+pub(crate) fn report(check: Check, passed: bool) {
+    if !passed {
+        crate::error!("Self-test failed: {:?}", check);
+        FAILED.fetch_or(1 << check as u8, Ordering::Relaxed);
+    }
+    DONE.fetch_or(1 << check as u8, Ordering::Relaxed);
+}
+
+/// Masks of done and failed checks.
+pub(crate) fn results() -> [u8; 2] {
+    [DONE.load(Ordering::Relaxed), FAILED.load(Ordering::Relaxed)]
+}
+
+/// Amount of blinks of the first failed check, which is its number counted from one.
+pub(crate) fn blinks() -> Option<u32> {
+    let failed = FAILED.load(Ordering::Relaxed);
+    (failed != 0).then(|| failed.trailing_zeros() + 1)
+}
+
+/// Configures the status LED, which is off unless a check fails.
+pub(crate) fn init_led(led: &mut StatusLedPin) {
+    led.set_high();
+    led.set_mode(PinMode::PushPull);
+}
+
+/// Averages the first samples of each sensor.
+pub(crate) struct SensorCheck {
+    count: u32,
+    sums: [[u32; 4]; PLAYERS],
+}
+
+impl SensorCheck {
+    pub(crate) const fn new() -> Self {
+        Self { count: 0, sums: [[0; 4]; PLAYERS] }
+    }
+
+    /// Accumulates the sample and reports the result once enough samples are taken.
+    pub(crate) fn update(&mut self, sample: &PiezoSample) {
+        if self.count >= SENSOR_CHECK_SAMPLES {
+            return;
+        }
+        for (sums, pads) in self.sums.iter_mut().zip(sample.0) {
+            for (sum, pad) in sums.iter_mut().zip(pads) {
+                *sum += pad as u32;
+            }
+        }
+        self.count += 1;
+
+        if self.count == SENSOR_CHECK_SAMPLES {
+            let biased = self.sums.iter().flatten()
+                .all(|&sum| (sum / SENSOR_CHECK_SAMPLES).abs_diff(MID_RANGE as u32) <= SENSOR_OFFSET_LIMIT);
+            report(Check::Sensors, biased);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Supply voltage monitoring.
+//!
+//! This line has no programmable brown-out reset, only the fixed power-down reset at about 1.9 V.
+//! The programmable voltage detector (PVD) raises an interrupt, once the supply drops below
+//! 2.9 V and once it recovers, so dips of flaky USB power are counted and a reset, which
+//! happens during a dip, is told apart from firmware crashes by the reset cause.
+
+use super::pac::{EXTI, PWR};
+
+/// EXTI line of the PVD output.
+const EXTI_PVD: u32 = 1 << 16;
+
+/// Enables the PVD and its interrupt on both edges. The backup domain shall be enabled before.
+///
+/// The highest threshold gives the most time to record the dip before the power-down reset.
+pub(crate) fn init(pwr: &mut PWR, exti: &mut EXTI) {
+    pwr.cr.modify(|_, w| w.pls().v2_9().pvde().set_bit());
+    exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_PVD) });
+    exti.rtsr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_PVD) });
+    exti.ftsr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_PVD) });
+}
+
+/// Records the new supply state on the PVD interrupt.
+pub(crate) fn on_pvd() {
+    let (pwr, exti) = unsafe { (&*PWR::ptr(), &*EXTI::ptr()) };
+    exti.pr.write(|w| unsafe { w.bits(EXTI_PVD) });
+
+    let low = pwr.csr.read().pvdo().bit_is_set();
+    super::backup::record_supply(low);
+    if low {
+        crate::warn!("Supply voltage dropped below the PVD threshold.");
+    } else {
+        crate::info!("Supply voltage recovered.");
+    }
+}
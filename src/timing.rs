@@ -0,0 +1,91 @@
+//! USB frame timing.
+//!
+//! The host starts each 1 ms frame with a Start-of-Frame packet. Its arrival is timestamped with
+//! the CPU cycle counter, so the firmware knows the phase of host polling frames, which allows to
+//! align report pushes with them, and how much the frame period jitters.
+
+use cortex_m::peripheral::{DCB, DWT};
+
+use super::clocks::SYSCLK_HZ;
+
+/// CPU cycles within a single full speed frame.
+pub(crate) const CYCLES_PER_FRAME: u32 = SYSCLK_HZ / 1_000;
+/// Frame numbers are 11 bits wide.
+const FRAME_NUMBER_MASK: u16 = 0x7FF;
+/// Amount of frames between jitter reports in the log.
+const REPORT_PERIOD_FRAMES: u32 = 1000;
+
+/// Enables the cycle counter used for timestamps.
+pub(crate) fn init(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+/// Current timestamp in CPU cycles.
+#[inline(always)]
+pub(crate) fn now() -> u32 {
+    DWT::cycle_count()
+}
+
+/// Timing of the host frames, measured from SOF events.
+pub(crate) struct FrameTiming {
+    /// Timestamp and frame number of the last SOF.
+    last: Option<(u32, u16)>,
+    /// Largest deviation of the frame period from 1 ms in CPU cycles within the current period.
+    max_jitter: u32,
+    /// Frames, which SOF events were missed (e.g. due to long critical sections).
+    missed: u32,
+    /// Frames since the last jitter report.
+    frames: u32,
+}
+
+impl FrameTiming {
+    pub(crate) const fn new() -> Self {
+        Self { last: None, max_jitter: 0, missed: 0, frames: 0 }
+    }
+
+    /// Records the SOF event of the provided frame.
+    pub(crate) fn on_sof(&mut self, now: u32, frame: u16) {
+        if let Some((last, last_frame)) = self.last.replace((now, frame)) {
+            let frames = frame.wrapping_sub(last_frame) & FRAME_NUMBER_MASK;
+            if frames == 0 {
+                return;
+            }
+
+            let expected = frames as u32 * CYCLES_PER_FRAME;
+            let jitter = now.wrapping_sub(last).abs_diff(expected);
+            self.max_jitter = self.max_jitter.max(jitter);
+            self.missed += frames as u32 - 1;
+            self.frames += frames as u32;
+        }
+
+        if self.frames >= REPORT_PERIOD_FRAMES {
+            crate::debug!("Frame jitter: {} cycles, missed SOF: {}", self.max_jitter, self.missed);
+            self.frames = 0;
+            self.max_jitter = 0;
+        }
+    }
+
+    /// Forgets the last SOF, e.g. when the bus is suspended and frames stop.
+    pub(crate) fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// CPU cycles passed since the start of the current frame. Returns [`None`] if no SOF was
+    /// obtained yet or it is older than a frame.
+    pub(crate) fn phase(&self, now: u32) -> Option<u32> {
+        self.last
+            .map(|(last, _)| now.wrapping_sub(last))
+            .filter(|&phase| phase < CYCLES_PER_FRAME)
+    }
+
+    /// Largest frame period deviation in CPU cycles within the current report period.
+    pub(crate) fn max_jitter(&self) -> u32 {
+        self.max_jitter
+    }
+
+    /// Total amount of missed SOF events.
+    pub(crate) fn missed(&self) -> u32 {
+        self.missed
+    }
+}
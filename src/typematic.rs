@@ -0,0 +1,22 @@
+//! Typematic (auto-repeat) of keyboard reports while pads are being held.
+//!
+//! Keyboard reports generated by the parser are forwarded to the [`super::app::Typematic`] task,
+//! which repeatedly releases and presses the held keys after the initial delay. This allows to
+//! navigate game menus with the drum alone.
+
+use super::hid::DrumReport;
+
+/// Communication queue capacity.
+pub(crate) const TYPEMATIC_QUEUE_CAPACITY: usize = 4;
+
+pub(crate) type TypematicSender = rtic_sync::channel::Sender<'static, DrumReport, TYPEMATIC_QUEUE_CAPACITY>;
+pub(crate) type TypematicReceiver = rtic_sync::channel::Receiver<'static, DrumReport, TYPEMATIC_QUEUE_CAPACITY>;
+
+/// Returns the report if it shall be repeated, which is only the case for keyboard reports with
+/// at least one pressed key.
+pub(crate) fn held(report: DrumReport) -> Option<DrumReport> {
+    match report {
+        DrumReport::Keyboard(keys, consumer) if !keys.is_empty() || consumer.usage_id != 0 => Some(report),
+        _ => None,
+    }
+}
@@ -0,0 +1,341 @@
+//! Firmware updates staged within the `UPDATE` flash region.
+//!
+//! New image is streamed into the update slot by the programmer in order, verified with CRC32 and
+//! marked for installation with a trailer within the last page of the slot. The image is verified
+//! again at the next boot and swapped with the running firmware page by page by [`swap`], which
+//! runs from RAM, since the flash it would execute from is erased. The previous firmware is kept
+//! within the slot, until the new one runs long enough to be confirmed by [`confirm`]. If the new
+//! firmware resets before that, e.g. after a crash, the previous one is swapped back at the next
+//! boot. Power loss during the swap itself (about two seconds) still leaves the drum without
+//! firmware, which is only recoverable with the system memory bootloader (BOOT0) or SWD.
+//!
+//! Updates are only accepted, if the `UPDATE` region of `memory.x` holds the largest image along
+//! with the trailer page. It lies beyond the documented 64K of STM32F103C8, which most of those
+//! parts have anyway. Images written to missing flash never pass the verification.
+
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use super::cfg::crc32;
+use super::chip::CLONE_FLASH_BSY_CYCLES;
+use super::flash::{CfgFlash, FlashError, PAGE_SIZE};
+
+unsafe extern "C" {
+    static __app_start: u8;
+    static __app_end: u8;
+    static __update_start: u8;
+}
+
+/* Flash controller registers, which are accessed directly by the installer. */
+const FLASH_KEYR: *mut u32 = 0x4002_2004 as *mut u32;
+const FLASH_SR: *const u32 = 0x4002_200C as *const u32;
+const FLASH_CR: *mut u32 = 0x4002_2010 as *mut u32;
+const FLASH_AR: *mut u32 = 0x4002_2014 as *mut u32;
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+const SR_BSY: u32 = 1 << 0;
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+/// Application interrupt and reset control register of the SCB.
+const AIRCR: *mut u32 = 0xE000_ED0C as *mut u32;
+/// System reset request along with the write key.
+const AIRCR_SYSRESET: u32 = 0x05FA_0004;
+/// Marks the staged image for installation. Unlikely to be found in erased or random flash.
+const UPDATE_MAGIC: u32 = 0x7A1C_0FE5;
+/// Marks the installed image, which is not confirmed yet, while the slot holds the previous one.
+const TRIAL_MAGIC: u32 = 0x7A1C_7E57;
+/// Length, CRC32 and magic of the image, followed by the half-word, which is programmed by the
+/// first boot of the installed image.
+const TRAILER_LEN: usize = 14;
+/// Offset of the boot mark within the trailer.
+const BOOT_MARK: usize = 12;
+/// Half-words within a single page.
+const PAGE_WORDS: usize = PAGE_SIZE / 2;
+
+/// Volatile load of the word at the provided address. Written as a single instruction, since
+/// `read_volatile` is not guaranteed to be inlined into the code running from RAM.
+macro_rules! load {
+    ($addr:expr) => {{
+        let value: u32;
+        core::arch::asm!("ldr {0}, [{1}]", out(reg) value, in(reg) $addr, options(nostack, preserves_flags, readonly));
+        value
+    }};
+}
+
+/// Volatile store of the word to the provided address, see `load!`.
+macro_rules! store {
+    ($addr:expr, $value:expr) => {
+        core::arch::asm!("str {0}, [{1}]", in(reg) $value as u32, in(reg) $addr, options(nostack, preserves_flags));
+    };
+}
+
+/// Volatile load of the half-word at the provided address, see `load!`.
+macro_rules! load_half {
+    ($addr:expr) => {{
+        let value: u32;
+        core::arch::asm!("ldrh {0}, [{1}]", out(reg) value, in(reg) $addr, options(nostack, preserves_flags, readonly));
+        value as u16
+    }};
+}
+
+/// Volatile store of the half-word to the provided address, see `load!`.
+macro_rules! store_half {
+    ($addr:expr, $value:expr) => {
+        core::arch::asm!("strh {0}, [{1}]", in(reg) $value as u32, in(reg) $addr, options(nostack, preserves_flags));
+    };
+}
+
+/// Waits for the flash controller. No functions can be called, since it is used from RAM, while
+/// the flash is erased.
+macro_rules! wait {
+    () => {
+        let mut i = 0;
+        while i < CLONE_FLASH_BSY_CYCLES {
+            load!(FLASH_SR);
+            i += 1;
+        }
+        while load!(FLASH_SR) & SR_BSY != 0 {}
+    };
+}
+
+/// Reasons to reject a firmware update.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum UpdateError {
+    /// Linker script leaves no room for the update slot.
+    NoSlot,
+    /// Chunk does not continue the image or exceeds the application region.
+    Order,
+    /// Length, CRC or vector table of the image is wrong.
+    Image,
+    /// Unable to program the update slot.
+    Flash,
+}
+
+impl From<FlashError> for UpdateError {
+    fn from(_: FlashError) -> Self {
+        Self::Flash
+    }
+}
+
+/// Firmware image, which is being streamed into the update slot.
+pub(crate) struct Staging {
+    /// Bytes of the image written so far.
+    written: usize,
+}
+
+impl Staging {
+    pub(crate) const fn new() -> Self {
+        Self { written: 0 }
+    }
+
+    /// Writes the chunk at the provided offset of the image.
+    ///
+    /// Chunks shall be written in order. The one at zero offset starts a new image and unmarks
+    /// the previously staged one. Only the last chunk may have an odd length.
+    pub(crate) fn write(&mut self, flash: &mut CfgFlash, offset: usize, chunk: &[u8]) -> Result<(), UpdateError> {
+        if !has_slot() {
+            return Err(UpdateError::NoSlot);
+        }
+        if offset == 0 {
+            self.written = 0;
+            flash.erase_update(trailer())?;
+        }
+        if offset != self.written || !offset.is_multiple_of(2) || offset + chunk.len() > app_len() {
+            return Err(UpdateError::Order);
+        }
+
+        for (i, pair) in chunk.chunks(2).enumerate() {
+            let dst = offset + 2 * i;
+            if dst.is_multiple_of(PAGE_SIZE) {
+                flash.erase_update(dst)?;
+            }
+            flash.program_update(dst, u16::from_le_bytes([pair[0], pair.get(1).copied().unwrap_or(0xFF)]))?;
+        }
+        self.written += chunk.len();
+        Ok(())
+    }
+
+    /// Verifies the whole image and marks it for installation at the next boot.
+    pub(crate) fn commit(&mut self, flash: &mut CfgFlash, len: usize, crc: u32) -> Result<(), UpdateError> {
+        if len != self.written || len < 8 {
+            return Err(UpdateError::Image);
+        }
+        let image = CfgFlash::update(0, len)?;
+        if crc32(image) != crc || !is_bootable(image) {
+            return Err(UpdateError::Image);
+        }
+
+        // Magic goes last, so the image is only marked once the whole trailer is written.
+        let trailer = trailer();
+        let words = [len as u32, crc, UPDATE_MAGIC];
+        for (i, word) in words.iter().flat_map(|w| [*w as u16, (*w >> 16) as u16]).enumerate() {
+            flash.program_update(trailer + 2 * i, word)?;
+        }
+        self.written = 0;
+        crate::info!("Firmware update of {} bytes is staged.", len);
+        Ok(())
+    }
+}
+
+/// Installs the staged firmware if it was marked for installation before the reset, or swaps the
+/// previous one back if the installed one reset before it was confirmed.
+///
+/// Shall be called before any peripheral is configured. The image is verified again, so a
+/// corrupted one is never installed.
+pub(crate) fn check() {
+    if !has_slot() {
+        return;
+    }
+    let Ok(tail) = CfgFlash::update(trailer(), TRAILER_LEN) else { return };
+    let word = |i: usize| u32::from_le_bytes([tail[i], tail[i + 1], tail[i + 2], tail[i + 3]]);
+    let (len, crc, magic) = (word(0) as usize, word(4), word(8));
+    let booted = tail[BOOT_MARK..] != [0xFF, 0xFF];
+    let slot = unsafe { &__update_start as *const u8 };
+
+    match magic {
+        UPDATE_MAGIC => match CfgFlash::update(0, len) {
+            Ok(image) if len <= app_len() && crc32(image) == crc && is_bootable(image) => unsafe {
+                let words = [len as u32, crc, TRIAL_MAGIC].map(|w| [w as u16, (w >> 16) as u16]);
+                cortex_m::interrupt::disable();
+                swap(slot.add(trailer()) as *mut u16, words.as_flattened().as_ptr(), words.as_flattened().len(), len.div_ceil(PAGE_SIZE))
+            },
+            _ => crate::error!("Staged firmware update is corrupted."),
+        },
+        TRIAL_MAGIC if booted => unsafe {
+            cortex_m::interrupt::disable();
+            swap(slot.add(trailer()) as *mut u16, ptr::null(), 0, len.div_ceil(PAGE_SIZE))
+        },
+        TRIAL_MAGIC => unsafe {
+            // Reset of this boot rolls the update back, unless it is confirmed first.
+            if FLASH_CR.read_volatile() & CR_LOCK != 0 {
+                FLASH_KEYR.write_volatile(KEY1);
+                FLASH_KEYR.write_volatile(KEY2);
+            }
+            FLASH_CR.write_volatile(CR_PG);
+            (slot.add(trailer() + BOOT_MARK) as *mut u16).write_volatile(0);
+            wait!();
+            FLASH_CR.write_volatile(CR_LOCK);
+            crate::info!("Running the installed firmware update until it is confirmed.");
+        },
+        _ => (),
+    }
+}
+
+/// Keeps the installed firmware, so it is no longer rolled back at the next boot. Called once the
+/// firmware runs long enough after the boot, which proves it is usable.
+pub(crate) fn confirm(flash: &mut CfgFlash) {
+    if !has_slot() || CfgFlash::update(trailer() + 8, 4) != Ok(&TRIAL_MAGIC.to_le_bytes()[..]) {
+        return;
+    }
+    match flash.erase_update(trailer()) {
+        Ok(()) => crate::info!("Installed firmware update is confirmed."),
+        Err(err) => crate::error!("Unable to confirm the firmware update: {:?}", err),
+    }
+}
+
+/// Swaps the provided amount of pages of the running firmware with the ones of the update slot,
+/// writes the provided half-words into the erased trailer and resets the system.
+///
+/// Runs from RAM and only accesses the registers directly, since the flash it would otherwise
+/// call into is erased. The same swap installs the image and rolls it back.
+#[inline(never)]
+#[unsafe(link_section = ".data")]
+unsafe fn swap(trailer: *mut u16, words: *const u16, count: usize, pages: usize) -> ! {
+    // No functions can be called, including the iterators, pointer methods and volatile accesses,
+    // so addresses are plain integers and accesses are plain instructions.
+    macro_rules! erase {
+        ($page:expr) => {
+            store!(FLASH_CR, CR_PER);
+            store!(FLASH_AR, $page);
+            store!(FLASH_CR, CR_PER | CR_STRT);
+            wait!();
+        };
+    }
+    macro_rules! program {
+        ($dst:expr, $word:expr) => {
+            // Erased half-words are skipped.
+            let word: u16 = $word;
+            if word != 0xFFFF {
+                store!(FLASH_CR, CR_PG);
+                store_half!($dst, word);
+                wait!();
+            }
+        };
+    }
+
+    // Page of the running firmware, while its slot page is rewritten.
+    let mut buff = MaybeUninit::<[u16; PAGE_WORDS]>::uninit();
+    let buff = ptr::addr_of_mut!(buff) as usize;
+    let (trailer, words) = (trailer as usize, words as usize);
+    unsafe {
+        let (app, slot) = (&__app_start as *const u8 as usize, &__update_start as *const u8 as usize);
+        if load!(FLASH_CR) & CR_LOCK != 0 {
+            store!(FLASH_KEYR, KEY1);
+            store!(FLASH_KEYR, KEY2);
+        }
+
+        let mut page = 0;
+        while page < pages {
+            let (app, slot) = (app + page * PAGE_SIZE, slot + page * PAGE_SIZE);
+            let mut i = 0;
+            while i < PAGE_SIZE {
+                store_half!(buff + i, load_half!(app + i));
+                i += 2;
+            }
+            erase!(app);
+            i = 0;
+            while i < PAGE_SIZE {
+                program!(app + i, load_half!(slot + i));
+                i += 2;
+            }
+            erase!(slot);
+            i = 0;
+            while i < PAGE_SIZE {
+                program!(slot + i, load_half!(buff + i));
+                i += 2;
+            }
+            page += 1;
+        }
+
+        erase!(trailer);
+        let mut i = 0;
+        while i < count {
+            program!(trailer + 2 * i, load_half!(words + 2 * i));
+            i += 1;
+        }
+        store!(FLASH_CR, 0);
+        core::arch::asm!("dsb");
+        store!(AIRCR, AIRCR_SYSRESET);
+        loop {
+            core::arch::asm!("wfi");
+        }
+    }
+}
+
+/// Update region of the linker script holds the largest image along with the trailer page, so the
+/// whole running firmware fits into it during the swap.
+fn has_slot() -> bool {
+    CfgFlash::update_len() >= app_len() + PAGE_SIZE
+}
+
+/// Size of the application region, which limits the image.
+fn app_len() -> usize {
+    unsafe { &__app_end as *const u8 as usize - &__app_start as *const u8 as usize }
+}
+
+/// Offset of the page holding the trailer, right after the largest image.
+fn trailer() -> usize {
+    CfgFlash::update_len() - PAGE_SIZE
+}
+
+/// Vector table of the image points into RAM and the application region, so a binary built for
+/// another address or chip is never installed.
+fn is_bootable(image: &[u8]) -> bool {
+    let sp = u32::from_le_bytes([image[0], image[1], image[2], image[3]]);
+    let reset = u32::from_le_bytes([image[4], image[5], image[6], image[7]]) as usize;
+    let app = unsafe { &__app_start as *const u8 as usize };
+    sp & 0xFFF0_0000 == 0x2000_0000 && (app..app + app_len()).contains(&reset)
+}
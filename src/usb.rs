@@ -3,33 +3,128 @@
 use usbd_hid::hid_class::HIDClass;
 use usb_device::{
     bus::UsbBusAllocator, 
+    class::UsbClass,
     device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid}, 
-    LangID
+    LangID, UsbError
 };
 
 use core::marker::PhantomData;
-use super::pac::{RCC, USB, GPIOA};
-use lhash::md5;
+use super::pac::{RCC, USB};
+use super::pins::{PinMode, UsbDpPin};
+use heapless::{Deque, Vec};
 
 use super::hid::*;
-use super::prog::Programmer;
+use super::descriptor::{ReportDescriptor, ReportLayout};
+use super::piezo::PLAYERS;
+use super::midi::{MidiClass, MidiMode};
+use super::dfu::DfuRuntimeClass;
+use super::prog::{JobResult, Pending, Programmer, Request, RequestSender};
+use super::cfg::UsbIdentity;
+use super::timing::{self, FrameTiming};
+use super::health::{self, Lag};
+use super::chip::{Chip, CLONE_USB_STARTUP_CYCLES};
 
 /* Constant USB definitions. See: https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt */
 const USB_VID: u16 = 0x16c0;
 const USB_PID: u16 = 0x27db;
 const USB_MANUFACTURER: &'static str = "Serhii Shkliaiev [not-forest]";
 const USB_PRODUCT: &'static str = "Taiko Drum Controller";
-const USB_SERIAL_NUMBER: &'static str = 
-    unsafe { 
-        core::str::from_utf8_unchecked(
-            // USB serial number is generates on each build in form of md5 hash.
-            md5(crate::version::TAIKO_HID_FIRMWARE_VERSION.as_bytes()).as_slice()
-        )
-    }; 
-const USB_HID_CLASS_POLLING_MS: u8 = 60;
+/// Address of the 96-bit unique device ID.
+const UID_ADDRESS: usize = 0x1FFF_F7E8;
+/// Serial number is the unique device ID in hexadecimal form.
+const SERIAL_NUMBER_LEN: usize = 24;
+/// Maximal amount of USB classes in the composite device.
+const USB_MAX_CLASSES: usize = 7;
+/// Amount of reports waiting for busy endpoints. Covers a few polling intervals of fast hits.
+const REPORT_QUEUE_CAPACITY: usize = 8;
+/// Amount of unexpected USB errors in a row, after which the device is re-enumerated.
+const USB_ERROR_LIMIT: u8 = 8;
 
 /// Usb VID-PID Pair
 const TAIKO_DRUM_VIDPID: UsbVidPid  = UsbVidPid(USB_VID, USB_PID);
+/// HORIPAD VID-PID pair, which is required by consoles to accept the HORI report layout.
+const HORI_VIDPID: UsbVidPid = UsbVidPid(0x0f0d, 0x0092);
+
+/// Descriptor data generated during initialization, which must outlive the USB device.
+pub(crate) struct UsbDescriptors {
+    report: ReportDescriptor,
+    identity: UsbIdentity,
+    serial: [u8; SERIAL_NUMBER_LEN],
+}
+
+impl UsbDescriptors {
+    pub(crate) const fn new() -> Self {
+        Self { report: ReportDescriptor::new(), identity: UsbIdentity::new(), serial: [0; SERIAL_NUMBER_LEN] }
+    }
+
+    /// Generates the serial number from the unique device ID, so several drums connected to the
+    /// same host are distinguished.
+    fn serial_number(buff: &mut [u8; SERIAL_NUMBER_LEN]) -> &str {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let uid = unsafe { core::ptr::read_volatile(UID_ADDRESS as *const [u32; 3]) };
+
+        for (chunk, word) in buff.chunks_exact_mut(8).zip(uid) {
+            for (i, c) in chunk.iter_mut().enumerate() {
+                *c = HEX[(word >> (28 - i * 4)) as usize & 0xF];
+            }
+        }
+        // Only ASCII hexadecimal digits are written.
+        unsafe { core::str::from_utf8_unchecked(buff) }
+    }
+}
+
+/// USB traffic, error and sample processing counters for diagnosing latency and throughput
+/// problems in the field.
+///
+/// All counters wrap around.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct UsbStats {
+    /// Reports accepted by the endpoints.
+    pub(crate) reports: u32,
+    /// Report pushes, which were refused by busy endpoints.
+    pub(crate) naks: u32,
+    /// Bus resets issued by the host.
+    pub(crate) resets: u32,
+    /// Bus suspend events.
+    pub(crate) suspends: u32,
+    /// Bytes obtained and sent over the CDC serial programmer interface.
+    pub(crate) cdc_rx: u32,
+    pub(crate) cdc_tx: u32,
+    /// Unexpected USB errors.
+    pub(crate) errors: u32,
+    /// SOF events missed since startup.
+    pub(crate) missed_sof: u32,
+    /// Largest frame period deviation in CPU cycles within the last second.
+    pub(crate) frame_jitter: u32,
+    /// Sampling pauses, because the parser did not keep up with the ADC.
+    pub(crate) sample_overflows: u32,
+    /// Reports, which had to wait for a free place in the report queue.
+    pub(crate) report_overflows: u32,
+    /// Reports dropped, because the host was unable to receive them.
+    pub(crate) dropped: u32,
+    /// Hits of each pad of the first drum since boot in LK, LD, RD, RK order.
+    pub(crate) hits: [u32; 4],
+    /// Hits rejected as cross-talk of another pad.
+    pub(crate) crosstalk: u32,
+    /// Longest processing of a single sample by the parser task in CPU cycles.
+    pub(crate) latency: u32,
+}
+
+impl UsbStats {
+    /// USB counters in the order of declaration.
+    pub(crate) fn usb(&self) -> [u32; 9] {
+        [
+            self.reports, self.naks, self.resets, self.suspends, self.cdc_rx, self.cdc_tx,
+            self.errors, self.missed_sof, self.frame_jitter,
+        ]
+    }
+
+    /// Sample processing counters in the order of declaration.
+    pub(crate) fn runtime(&self) -> [u32; 9] {
+        let [lk, ld, rd, rk] = self.hits;
+        [self.sample_overflows, self.report_overflows, self.dropped, lk, ld, rd, rk, self.crosstalk, self.latency]
+    }
+}
 
 pub(crate) type UsbBus = stm32_usbd::UsbBus<UsbControllerSTM32F103>;
 pub(crate) type UsbAllocator = UsbBusAllocator<UsbBus>;
@@ -41,10 +136,36 @@ pub(crate) type UsbAllocator = UsbBusAllocator<UsbBus>;
 pub struct UsbTaikoDrum<'a> {
     /// Physical USB device wrapper.
     pub(crate) dev: UsbDevice<'a, UsbBus>,
-    /// HID Class for simulating a USB keyboard clicks.
-    pub(crate) hid_keyboard: HIDClass<'a, UsbBus>,
+    /// HID Class for simulating a USB keyboard clicks or gamepad button presses.
+    pub(crate) hid_keyboard: DrumHidClass<'a, UsbBus>,
+    /// HID Class for consumer control usages. Only present in keyboard mode.
+    pub(crate) hid_consumer: Option<HIDClass<'a, UsbBus>>,
+    /// HID report layout the device was enumerated with.
+    pub(crate) layout: ReportLayout,
+    /// Optional MIDI class, which replaces HID reports with percussion notes.
+    pub(crate) midi: Option<MidiClass<'a, UsbBus>>,
     /// Serial interface programmer.
     pub(crate) programmer: Programmer<'a>,
+    /// DFU run-time interface for switching into the bootloader.
+    pub(crate) dfu: DfuRuntimeClass,
+    /// Host frame timing measured from SOF events.
+    pub(crate) timing: FrameTiming,
+    /// Traffic and error counters.
+    pub(crate) stats: UsbStats,
+    /// Device state after the last poll, used to count bus resets.
+    state: UsbDeviceState,
+    /// Reports, which were not accepted by busy endpoints yet, in the order of generation.
+    pending: Deque<DrumReport, REPORT_QUEUE_CAPACITY>,
+    /// Consumer part of the latest keyboard report, which was not accepted by the busy endpoint yet.
+    consumer: Option<DrumConsumerHidReport>,
+    /// Bus was suspended at the time of the last check.
+    suspended: bool,
+    /// Unexpected USB errors since the last successful report.
+    errors: u8,
+    /// Re-enumeration was requested due to persistent errors and not performed yet.
+    reenumerate: bool,
+    /// Cable is detached, which is only known with VBUS sensing.
+    detached: bool,
     _phantom: PhantomData<USB>,
 }
 
@@ -52,72 +173,396 @@ impl<'a> UsbTaikoDrum<'a> {
     /// Initializes a new instance of [`UsbTaikoDrum`].
     pub(crate) fn new(
         alloc: &'a Option<UsbAllocator>, 
+        descriptors: &'static mut UsbDescriptors,
         programmer: Programmer<'a>,
         usb: USB, 
-        gpioa: &mut GPIOA, 
-        rcc: &mut RCC
+        usb_dp: &mut UsbDpPin,
     ) -> Self {
         drop(usb);
-        /* Configuring USB lines. */
-        rcc.apb2enr.modify(|_, w| w.iopaen().set_bit());
-        rcc.cfgr.modify(|_, w|
-            w.ppre1().div4()        // Clock prescaler for low-freq area (18 MHz). 
-             .usbpre().clear_bit()  // Divides SYSCLK by 1.5 to obtain 48 MHz.
-            /* USB peripheral requires PCLK1 frequency to be greater than 8MHz. */
-        );
-
-        Self::reset(gpioa);
-
-        log::info!("Preparing HID descriptor with polling speed of {} ms.", USB_HID_CLASS_POLLING_MS);
-        /* Building HID classes for communication with host machine. */
-        let hid_keyboard = HIDClass::new(
+        // USB clock and APB1 prescalers are set along with the system clock.
+        Self::reset(usb_dp);
+
+        let UsbDescriptors { report, identity, serial } = descriptors;
+        let layout = ReportLayout {
+            mode: programmer.cfg.hid_mode,
+            players: PLAYERS,
+            pads: 4,
+            velocity_axes: programmer.cfg.velocity_axes != 0,
+        };
+        let hid_mode = layout.mode;
+        // Zero is not a valid interval for interrupt endpoints.
+        let poll_ms = programmer.cfg.poll_interval.max(1);
+        crate::info!("Preparing {:?} HID descriptor with polling speed of {} ms.", hid_mode, poll_ms);
+        /* 
+         * Building HID classes for communication with host machine. 
+         *
+         * Only IN endpoints are allocated, since USB packet memory is quite limited. Output reports
+         * are still received via SET_REPORT requests on the control pipe.
+         * */
+        let mut hid_keyboard = DrumHidClass::new_ep_in(
+            alloc.as_ref().expect("Won't panic if this function is only called once."), 
+            report.build(layout), 
+            poll_ms
+        )
+            .with_report_ids(layout.report_ids())
+            .with_idle(if hid_mode == HidMode::Keyboard { KEYBOARD_DEFAULT_IDLE } else { 0 });
+        // Released state is returned on GET_REPORT requests until the first hit.
+        match hid_mode {
+            HidMode::Keyboard if layout.report_ids() => {
+                hid_keyboard.set_input(&DrumHitStrokeHidReport::empty().to_bytes_with_id(PLAYER1_REPORT_ID));
+                hid_keyboard.set_input(&DrumHitStrokeHidReport::empty().to_bytes_with_id(PLAYER2_REPORT_ID));
+            },
+            HidMode::Keyboard => hid_keyboard.set_input(&DrumHitStrokeHidReport::empty().to_bytes()),
+            HidMode::Gamepad => hid_keyboard.set_input(
+                &DrumGamepadHidReport::new([false; 4], [0; 4]).to_bytes()[..layout.gamepad_report_len()]
+            ),
+            HidMode::Hori => hid_keyboard.set_input(&DrumHoriHidReport::new([false; 4]).to_bytes()),
+        }
+        let hid_consumer = (hid_mode == HidMode::Keyboard).then(|| HIDClass::new_ep_in(
             alloc.as_ref().expect("Won't panic if this function is only called once."), 
-            DrumHitStrokeHidReport::desc(), 
-            USB_HID_CLASS_POLLING_MS
-        );
+            DrumConsumerHidReport::desc(), 
+            poll_ms
+        ));
+
+        let midi = match programmer.cfg.midi_mode {
+            MidiMode::Off => None,
+            MidiMode::Percussion => {
+                crate::debug!("Preparing USB MIDI interface.");
+                Some(MidiClass::new(alloc.as_ref().expect("Won't panic if this function is only called once.")))
+            }
+        };
+
+        let dfu = DfuRuntimeClass::new(alloc.as_ref().expect("Won't panic if this function is only called once."));
+
+        /* 
+         * Identity overrides are copied out of the programmer, since descriptors must outlive the
+         * device. Consoles only accept HORI report layout with the HORIPAD IDs.
+         * */
+        *identity = programmer.cfg.usb_identity;
+        let identity: &'static UsbIdentity = identity;
+        let serial_number = UsbDescriptors::serial_number(serial);
+        let vid_pid = match (hid_mode, identity.vid_pid()) {
+            (HidMode::Hori, _) => HORI_VIDPID,
+            (_, Some((vid, pid))) => UsbVidPid(vid, pid),
+            _ => TAIKO_DRUM_VIDPID,
+        };
 
         /* Initializing the USB device. */
         let dev = UsbDeviceBuilder::new(
             alloc.as_ref().expect("Won't panic if this function is only called once."),
-            TAIKO_DRUM_VIDPID
+            vid_pid
         )
             .strings(&[
                 StringDescriptors::new(LangID::EN)
-                    .manufacturer(USB_MANUFACTURER)
-                    .product(USB_PRODUCT)
-                    .serial_number(USB_SERIAL_NUMBER)
+                    .manufacturer(identity.manufacturer().unwrap_or(USB_MANUFACTURER))
+                    .product(identity.product().unwrap_or(USB_PRODUCT))
+                    .serial_number(serial_number)
             ]).expect("Shall not panic as long as data type is correct.")
             .supports_remote_wakeup(false)
+            .self_powered(programmer.cfg.self_powered != 0)
+            .max_power(programmer.cfg.max_power_ma()).expect("Bus current is limited to 500 mA by the configuration.")
             .device_release(crate::version::TAIKO_HID_FIRMWARE_VERSION_BCD)
-            .device_class(0x03)
+            // CDC and MIDI functions span two interfaces each and are grouped by their IADs, which
+            // require the miscellaneous device class. Class of other interfaces is set per interface.
+            .composite_with_iads()
             .build();
+        // SOF events are not used by the bus driver, therefore only enabled for frame timing.
+        Self::regs().cntr.modify(|_, w| w.sofm().set_bit());
 
-        Self { dev, hid_keyboard, programmer, _phantom: PhantomData }
+        Self { dev, hid_keyboard, hid_consumer, layout, midi, programmer, dfu, timing: FrameTiming::new(), stats: UsbStats::default(), state: UsbDeviceState::Default, pending: Deque::new(), consumer: None, suspended: false, errors: 0, reenumerate: false, detached: false, _phantom: PhantomData }
     }
 
     /// Simulates a USB disconnection by pulling down the D+ line.
-    pub(crate) fn reset(gpioa: &mut GPIOA) {
+    pub(crate) fn reset(usb_dp: &mut UsbDpPin) {
         /* Setting USB reset condition on D+ line. */
-        gpioa.crh.write(|w| 
-            w      /* Pulling the line LOW, which simulates disconnection */
-             .mode12().output()
-             .cnf12().push_pull()
-        );
-        gpioa.odr.write(|w| w.odr12().clear_bit());
+        usb_dp.set_low();
+        usb_dp.set_mode(PinMode::PushPull);
         cortex_m::asm::delay(720_000);
 
-        gpioa.crh.write(|w| 
-            w      /* Sets to floating input. */
-             .mode11().input()
-             .mode12().input()
-             .cnf11().open_drain()
-             .cnf12().open_drain()
-        );
+        /* Releasing the line back to the transceiver. */
+        usb_dp.set_mode(PinMode::FloatingInput);
+    }
+
+    /// Drops the device off the bus from the panic handler, so the host releases all held keys.
+    ///
+    /// Release reports are not sent, since the panic might have happened while the device was in
+    /// use. The transceiver is powered down and D+ is held low until the system reset.
+    pub(crate) fn disconnect() {
+        Self::regs().cntr.write(|w| w.pdwn().set_bit().fres().set_bit());
+        let mut usb_dp = unsafe { UsbDpPin::steal() };
+        usb_dp.set_low();
+        usb_dp.set_mode(PinMode::PushPull);
     }
 
     /// Polling function wrapper.
     pub(crate) fn poll(&mut self) {
-        self.dev.poll(&mut [&mut self.hid_keyboard, &mut self.programmer.serial]);
+        self.take_sof();
+        let mut classes: Vec<&mut dyn UsbClass<UsbBus>, USB_MAX_CLASSES> = Vec::new();
+
+        classes.push(&mut self.hid_keyboard).ok();
+        if let Some(consumer) = self.hid_consumer.as_mut() {
+            classes.push(consumer).ok();
+        }
+        if let Some(serial) = self.programmer.serial.as_mut() {
+            classes.push(serial).ok();
+        }
+        classes.push(&mut self.programmer.hid).ok();
+        classes.push(&mut self.programmer.webusb).ok();
+        if let Some(midi) = self.midi.as_mut() {
+            classes.push(midi).ok();
+        }
+        classes.push(&mut self.dfu).ok();
+
+        self.dev.poll(&mut classes);
+        drop(classes);
+
+        let state = self.dev.state();
+        if state == UsbDeviceState::Default && self.state != state {
+            self.stats.resets = self.stats.resets.wrapping_add(1);
+        }
+        if state == UsbDeviceState::Configured && self.state != state {
+            self.release_all();
+        }
+        self.state = state;
+    }
+
+    /// Replaces stale reports with the released state, so the host never sees keys held before
+    /// the bus was suspended or the device was reconfigured.
+    fn release_all(&mut self) {
+        self.pending.clear();
+        self.consumer = None;
+        let released = match self.layout.mode {
+            HidMode::Keyboard => DrumReport::released(),
+            HidMode::Gamepad => DrumReport::Gamepad(DrumGamepadHidReport::new([false; 4], [0; 4])),
+            HidMode::Hori => DrumReport::Hori(DrumHoriHidReport::new([false; 4])),
+        };
+        if self.midi.is_none() {
+            self.pending.push_back(released).ok();
+        }
+        if self.layout.report_ids() {
+            self.pending.push_back(DrumReport::Player2(DrumHitStrokeHidReport::empty())).ok();
+        }
+    }
+
+    /// Takes programmer commands, which are queued for the programmer task.
+    pub(crate) fn program(&mut self, requests: &mut RequestSender) -> usb_device::Result<()> {
+        self.programmer.program(requests, &mut self.stats)
+    }
+
+    /// Executes the queued programmer command. Frame timing is copied into the counters beforehand.
+    /// Commands writing the flash are returned to be completed once it is written.
+    pub(crate) fn execute(&mut self, request: Request) -> Option<Pending> {
+        self.stats.missed_sof = self.timing.missed();
+        self.stats.frame_jitter = self.timing.max_jitter();
+        self.programmer.execute_request(request, &mut self.stats)
+    }
+
+    /// Sends the response of the programmer command, once its flash write is done.
+    pub(crate) fn complete(&mut self, pending: Pending, result: JobResult) {
+        self.programmer.complete(pending, result, &mut self.stats);
+    }
+
+    /// Records the SOF event, if one happened since the last call.
+    ///
+    /// SOF flag is not cleared by the bus driver, so it must be cleared here. Otherwise the
+    /// interrupt would fire again right away.
+    fn take_sof(&mut self) {
+        let usb = Self::regs();
+        if usb.istr.read().sof().bit_is_set() {
+            usb.istr.write(|w| unsafe { w.bits(0xffff) }.sof().clear_bit());
+            self.timing.on_sof(timing::now(), usb.fnr.read().fn_().bits());
+        }
+    }
+
+    /// USB peripheral registers, which are owned by the bus driver otherwise.
+    fn regs() -> &'static super::pac::usb::RegisterBlock {
+        unsafe { &*USB::ptr() }
+    }
+
+    /// Advances idle timers of HID interfaces, which repeat the last reports when required.
+    pub(crate) fn tick_idle(&mut self, elapsed_ms: u16) {
+        if self.dev.state() == UsbDeviceState::Configured {
+            self.hid_keyboard.tick_idle(elapsed_ms);
+        }
+    }
+
+    /// Returns the new bus suspend state, if it was changed since the last call. Detached cable
+    /// is treated as a suspended bus.
+    pub(crate) fn suspend_changed(&mut self) -> Option<bool> {
+        let suspended = self.detached || self.dev.state() == UsbDeviceState::Suspend;
+        if suspended {
+            // Frames stop while suspended, so the gap is not a jitter.
+            self.timing.reset();
+        }
+        let changed = core::mem::replace(&mut self.suspended, suspended) != suspended;
+        if changed && suspended {
+            self.stats.suspends = self.stats.suspends.wrapping_add(1);
+        }
+        changed.then_some(suspended)
+    }
+
+    /// Handles the cable detach. Pending reports are dropped.
+    pub(crate) fn detach(&mut self) {
+        crate::info!("USB cable was detached.");
+        self.detached = true;
+        self.pending.clear();
+        self.consumer = None;
+    }
+
+    /// Handles the cable re-attach by running the connect sequence again, so the host enumerates
+    /// the device from scratch.
+    pub(crate) fn attach(&mut self, usb_dp: &mut UsbDpPin) {
+        crate::info!("USB cable was attached.");
+        self.detached = false;
+        self.reenumerate(usb_dp);
+    }
+
+    /// MIDI mode the device was enumerated with.
+    pub(crate) fn midi_mode(&self) -> MidiMode {
+        if self.midi.is_some() { MidiMode::Percussion } else { MidiMode::Off }
+    }
+
+    /// Input reports are only accepted while the device is configured and the bus is not suspended.
+    pub(crate) fn accepts_input(&self) -> bool {
+        !self.detached && self.dev.state() == UsbDeviceState::Configured
+    }
+
+    /// Queues the report and sends as many pending reports as endpoints accept.
+    ///
+    /// Returns the report back if the queue is full. Reports are silently dropped while the device
+    /// is not configured, since there is no host to deliver them to.
+    pub(crate) fn queue_report(&mut self, report: DrumReport) -> Result<(), DrumReport> {
+        if !self.accepts_input() {
+            self.stats.dropped = self.stats.dropped.wrapping_add(self.pending.len() as u32 + 1);
+            self.pending.clear();
+            self.consumer = None;
+            return Ok(());
+        }
+
+        if let Err(report) = self.pending.push_back(report) {
+            self.stats.report_overflows = self.stats.report_overflows.wrapping_add(1);
+            health::record(Lag::SendFailure);
+            return Err(report);
+        }
+        health::reports_queued(self.pending.len());
+        self.flush_reports();
+        Ok(())
+    }
+
+    /// Sends pending reports in order, until one of the endpoints is busy.
+    ///
+    /// Consumer part of a keyboard report, which was left behind by its busy endpoint, is sent
+    /// first, so the next keyboard report never overtakes it.
+    pub(crate) fn flush_reports(&mut self) {
+        match self.push_consumer() {
+            Ok(()) => (),
+            Err(UsbError::WouldBlock) => {
+                self.stats.naks = self.stats.naks.wrapping_add(1);
+                return;
+            },
+            Err(usb_err) => {
+                self.stats.dropped = self.stats.dropped.wrapping_add(1);
+                health::record(Lag::SendFailure);
+                self.handle_error(usb_err);
+            },
+        }
+        while let Some(&report) = self.pending.front() {
+            match self.push_report(&report) {
+                Ok(report_length) => {
+                    self.stats.reports = self.stats.reports.wrapping_add(1);
+                    crate::debug!("Bytes send: {}, frame phase: {} cycles", report_length, self.timing.phase(timing::now()).unwrap_or(0));
+                    self.errors = 0;
+                },
+                Err(UsbError::WouldBlock) => {
+                    self.stats.naks = self.stats.naks.wrapping_add(1);
+                    break;
+                },
+                Err(UsbError::Unsupported) => (),
+                // Report is dropped, so a single broken report never blocks the queue.
+                Err(usb_err) => {
+                    self.stats.dropped = self.stats.dropped.wrapping_add(1);
+                    health::record(Lag::SendFailure);
+                    self.handle_error(usb_err);
+                },
+            }
+            self.pending.pop_front();
+        }
+    }
+
+    /// Handles an unexpected USB error without stopping the firmware.
+    ///
+    /// Transient bus errors are only logged. Persistent ones request re-enumeration, which is
+    /// performed by [`super::app::UsbReenumeration`] task.
+    pub(crate) fn handle_error(&mut self, err: UsbError) {
+        crate::warn!("Unexpected USB error: {:?}", err);
+        self.stats.errors = self.stats.errors.wrapping_add(1);
+        self.errors = self.errors.saturating_add(1);
+        if self.errors == USB_ERROR_LIMIT {
+            crate::error!("USB errors persist. Re-enumerating the device...");
+            self.reenumerate = true;
+        }
+    }
+
+    /// Takes the re-enumeration request.
+    pub(crate) fn take_reenumerate(&mut self) -> bool {
+        core::mem::take(&mut self.reenumerate)
+    }
+
+    /// Disconnects the device from the bus and connects it back, so the host enumerates it again.
+    ///
+    /// The transceiver is powered down meanwhile, since it owns D+ line while enabled. Sampling is
+    /// not stopped, pending reports are dropped.
+    pub(crate) fn reenumerate(&mut self, usb_dp: &mut UsbDpPin) {
+        self.pending.clear();
+        self.consumer = None;
+        self.errors = 0;
+        self.dev.bus().force_reenumeration(|| Self::reset(usb_dp));
+    }
+
+    /// Pushes the report to the corresponding interface.
+    ///
+    /// Keyboard part of keyboard reports is pushed first, so hits are never delayed by the consumer
+    /// endpoint. If only the consumer endpoint is busy, its part is kept for [`Self::push_consumer`].
+    fn push_report(&mut self, report: &DrumReport) -> usb_device::Result<usize> {
+        match report {
+            DrumReport::Keyboard(report, consumer) => {
+                let len = if self.layout.report_ids() {
+                    self.hid_keyboard.push_input(&report.to_bytes_with_id(PLAYER1_REPORT_ID))?
+                } else {
+                    self.hid_keyboard.push_input(&report.to_bytes())?
+                };
+                self.consumer = Some(*consumer);
+                // Keyboard part is sent already, so the report is not dropped.
+                match self.push_consumer() {
+                    Ok(()) | Err(UsbError::WouldBlock) => (),
+                    Err(usb_err) => self.handle_error(usb_err),
+                }
+                Ok(len)
+            },
+            DrumReport::Player2(report) => self.hid_keyboard.push_input(&report.to_bytes_with_id(PLAYER2_REPORT_ID)),
+            DrumReport::Gamepad(report) => self.hid_keyboard.push_input(
+                &report.to_bytes()[..self.layout.gamepad_report_len()]
+            ),
+            DrumReport::Hori(report) => self.hid_keyboard.push_input(&report.to_bytes()),
+            DrumReport::Midi(events) => match &self.midi {
+                Some(midi) => midi.send(events),
+                None => Err(UsbError::InvalidState),
+            },
+        }
+    }
+
+    /// Pushes the consumer part of the latest keyboard report, if it is not sent yet.
+    ///
+    /// Only the busy endpoint keeps it, so a broken report never blocks the queue.
+    fn push_consumer(&mut self) -> usb_device::Result<()> {
+        let result = match (&self.hid_consumer, &self.consumer) {
+            (Some(hid_consumer), Some(consumer)) => hid_consumer.push_input(consumer).map(drop),
+            _ => Ok(()),
+        };
+        if !matches!(result, Err(UsbError::WouldBlock)) {
+            self.consumer = None;
+        }
+        result
     }
 
     /// First long poll that must be performed during enumeration.
@@ -128,9 +573,9 @@ impl<'a> UsbTaikoDrum<'a> {
         if self.dev.state() == UsbDeviceState::Default {
             rtic::export::interrupt::free(|_| {
                 while self.dev.state() != UsbDeviceState::Addressed { self.poll() }
-                log::info!("USB device obtained it's address.");
+                crate::debug!("USB device obtained it's address.");
                 while self.dev.state() != UsbDeviceState::Configured { self.poll() }
-                log::info!("USB device is fully configured by the host machine.");
+                crate::info!("USB device is fully configured by the host machine.");
             });
         }
     }
@@ -163,7 +608,7 @@ unsafe impl stm32_usbd::UsbPeripheral for UsbControllerSTM32F103 {
 
     fn startup_delay() {
         // There is a chip specific startup delay. For STM32F103xx it's 1µs and this should wait for
-        // at least that long.
-        cortex_m::asm::delay(72);
+        // at least that long. Clones need more.
+        cortex_m::asm::delay(if Chip::detect().is_clone() { CLONE_USB_STARTUP_CYCLES } else { 72 });
     }
 }
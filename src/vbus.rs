@@ -0,0 +1,47 @@
+//! VBUS sensing for self-powered builds.
+//!
+//! Bus powered drums lose power together with the cable, while self-powered ones keep running
+//! after it is pulled. VBUS is sensed on PB10 via a resistor divider (PB10 is 5V tolerant), so
+//! the firmware knows when the cable is detached and re-attached.
+
+use super::pins::{PinMode, VbusPin};
+
+/// Interval between VBUS pin reads.
+pub(crate) const VBUS_POLL_MS: u32 = 10;
+/// Amount of equal reads in a row required to accept the new state.
+const VBUS_DEBOUNCE_READS: u8 = 3;
+
+/// Debounced VBUS state.
+#[derive(Debug)]
+pub(crate) struct VbusSense {
+    pin: VbusPin,
+    attached: bool,
+    /// Reads in a row, which differ from the current state.
+    changes: u8,
+}
+
+impl VbusSense {
+    /// Configures PB10 as floating input. The divider shall pull it down when VBUS is absent.
+    pub(crate) fn new(mut pin: VbusPin) -> Self {
+        pin.set_mode(PinMode::FloatingInput);
+
+        let attached = pin.is_high();
+        crate::info!("VBUS sensing is enabled. Cable attached: {}", attached);
+        Self { pin, attached, changes: 0 }
+    }
+
+    /// Reads the pin and returns the new state if it was changed.
+    pub(crate) fn changed(&mut self) -> Option<bool> {
+        if self.pin.is_high() == self.attached {
+            self.changes = 0;
+            return None;
+        }
+
+        self.changes += 1;
+        (self.changes >= VBUS_DEBOUNCE_READS).then(|| {
+            self.changes = 0;
+            self.attached = !self.attached;
+            self.attached
+        })
+    }
+}
@@ -0,0 +1,216 @@
+//! WebUSB support for a browser-based configurator.
+//!
+//! Advertises the WebUSB platform capability within the BOS descriptor, so browsers offer the
+//! configurator landing page when the drum is connected. The configurator talks to the runtime
+//! programmer over a vendor-specific interface without endpoints: commands are sent within the
+//! data stage of a control OUT request and the response is read back with a control IN request.
+//! Unlike HID and CDC interfaces, this one is never claimed by the OS drivers.
+//!
+//! Microsoft OS 2.0 descriptors are provided as well, so Windows binds WinUSB to the vendor
+//! interface automatically without any INF files.
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+use heapless::Vec;
+
+const USB_CLASS_VENDOR: u8 = 0xFF;
+const CAPABILITY_PLATFORM: u8 = 0x05;
+/// WebUSB platform capability UUID {3408b638-09a9-47a0-8bfd-a0768815b665} in the wire format.
+const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47,
+    0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15, 0xB6, 0x65,
+];
+/// Vendor request code used by the browser to read URL descriptors.
+const WEBUSB_VENDOR_CODE: u8 = 0x01;
+const WEBUSB_GET_URL: u16 = 0x02;
+const WEBUSB_URL_DESC_TYPE: u8 = 0x03;
+const WEBUSB_SCHEME_HTTPS: u8 = 0x01;
+const LANDING_PAGE_INDEX: u8 = 0x01;
+/// Configurator landing page without the scheme prefix.
+const LANDING_PAGE_URL: &str = "github.com/not-forest/osu-taiko-drum";
+
+const MS_OS_20_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C,
+    0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+/// Vendor request code used by Windows to read MS OS 2.0 descriptor set.
+const MS_OS_20_VENDOR_CODE: u8 = 0x02;
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+/// Windows 8.1, which is the first one supporting MS OS 2.0 descriptors.
+const MS_OS_20_WINDOWS_VERSION: [u8; 4] = 0x0603_0000u32.to_le_bytes();
+/// Device interface GUID under which the configurator finds the drum via WinUSB.
+const DEVICE_INTERFACE_GUID: &str = "{8A5B0C3E-6D2F-4E71-9B3A-2C5D7E9F1A40}";
+const DEVICE_INTERFACE_GUIDS_NAME: &str = "DeviceInterfaceGUIDs";
+/* Lengths of the registry property parts, which are UTF-16 encoded with terminators. */
+const PROPERTY_NAME_LEN: usize = (DEVICE_INTERFACE_GUIDS_NAME.len() + 1) * 2;
+const PROPERTY_DATA_LEN: usize = (DEVICE_INTERFACE_GUID.len() + 2) * 2;
+const REGISTRY_PROPERTY_LEN: usize = 10 + PROPERTY_NAME_LEN + PROPERTY_DATA_LEN;
+/// Whole descriptor set: set header, configuration and function subsets, compatible ID and
+/// registry property.
+const MS_OS_20_SET_LEN: usize = 10 + 8 + 8 + 20 + REGISTRY_PROPERTY_LEN;
+/// Offset of the first interface number within the function subset header.
+const MS_OS_20_FIRST_INTERFACE: usize = 10 + 8 + 4;
+
+/// MS OS 2.0 descriptor set template. Interface number is patched on request.
+const MS_OS_20_SET: [u8; MS_OS_20_SET_LEN] = {
+    const fn put(buff: &mut [u8; MS_OS_20_SET_LEN], mut idx: usize, data: &[u8]) -> usize {
+        let mut i = 0;
+        while i < data.len() {
+            buff[idx] = data[i];
+            idx += 1;
+            i += 1;
+        }
+        idx
+    }
+
+    // Writes ASCII string as UTF-16 with the provided amount of zero terminators.
+    const fn put_utf16(buff: &mut [u8; MS_OS_20_SET_LEN], mut idx: usize, s: &str, terminators: usize) -> usize {
+        let s = s.as_bytes();
+        let mut i = 0;
+        while i < s.len() {
+            idx = put(buff, idx, &[s[i], 0]);
+            i += 1;
+        }
+        idx + terminators * 2
+    }
+
+    const fn le(value: usize) -> [u8; 2] {
+        (value as u16).to_le_bytes()
+    }
+
+    let mut buff = [0u8; MS_OS_20_SET_LEN];
+    let [w0, w1, w2, w3] = MS_OS_20_WINDOWS_VERSION;
+    let [set0, set1] = le(MS_OS_20_SET_LEN);
+    let [cfg0, cfg1] = le(MS_OS_20_SET_LEN - 10);
+    let [fun0, fun1] = le(MS_OS_20_SET_LEN - 18);
+    let [reg0, reg1] = le(REGISTRY_PROPERTY_LEN);
+    let [name0, name1] = le(PROPERTY_NAME_LEN);
+    let [data0, data1] = le(PROPERTY_DATA_LEN);
+
+    let mut idx = put(&mut buff, 0, &[0x0A, 0x00, 0x00, 0x00, w0, w1, w2, w3, set0, set1]);   // Set header
+    idx = put(&mut buff, idx, &[0x08, 0x00, 0x01, 0x00, 0x00, 0x00, cfg0, cfg1]);               // Configuration subset
+    idx = put(&mut buff, idx, &[0x08, 0x00, 0x02, 0x00, 0x00, 0x00, fun0, fun1]);               // Function subset
+    idx = put(&mut buff, idx, &[0x14, 0x00, 0x03, 0x00]);                                       // Compatible ID
+    idx = put(&mut buff, idx, b"WINUSB\0\0\0\0\0\0\0\0\0\0");
+    idx = put(&mut buff, idx, &[reg0, reg1, 0x04, 0x00, 0x07, 0x00, name0, name1]);             // Registry property (REG_MULTI_SZ)
+    idx = put_utf16(&mut buff, idx, DEVICE_INTERFACE_GUIDS_NAME, 1);
+    idx = put(&mut buff, idx, &[data0, data1]);
+    idx = put_utf16(&mut buff, idx, DEVICE_INTERFACE_GUID, 2);
+    assert!(idx == MS_OS_20_SET_LEN);
+    buff
+};
+
+/* Vendor requests of the configurator interface. */
+const REQ_COMMAND: u8 = 0x01;
+const REQ_RESPONSE: u8 = 0x02;
+/// Largest command and response, which is the same as for other programmer interfaces.
+const WEBUSB_BUFF_LEN: usize = 64;
+
+/// WebUSB capability and vendor configurator interface.
+pub(crate) struct WebUsbClass {
+    if_num: InterfaceNumber,
+    /// Command obtained from the configurator, which was not executed yet.
+    command: Option<Vec<u8, WEBUSB_BUFF_LEN>>,
+    /// Response to the last executed command.
+    response: Vec<u8, WEBUSB_BUFF_LEN>,
+}
+
+impl WebUsbClass {
+    /// Allocates the vendor interface. No endpoints are required.
+    pub(crate) fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>) -> Self {
+        Self { if_num: alloc.interface(), command: None, response: Vec::new() }
+    }
+
+    /// Takes the command obtained from the configurator, if any.
+    pub(crate) fn pull_command(&mut self) -> Option<Vec<u8, WEBUSB_BUFF_LEN>> {
+        self.command.take()
+    }
+
+    /// Stores the response, which is returned on the next response request.
+    pub(crate) fn set_response(&mut self, data: &[u8]) {
+        self.response.clear();
+        self.response.extend_from_slice(&data[..data.len().min(WEBUSB_BUFF_LEN)]).ok();
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for WebUsbClass {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        let mut data = [0u8; 21];
+        data[1..17].copy_from_slice(&WEBUSB_UUID);
+        data[17..].copy_from_slice(&[
+            0x00, 0x01,     // bcdVersion 1.0
+            WEBUSB_VENDOR_CODE,
+            LANDING_PAGE_INDEX,
+        ]);
+        writer.capability(CAPABILITY_PLATFORM, &data)?;
+
+        let mut data = [0u8; 25];
+        data[1..17].copy_from_slice(&MS_OS_20_UUID);
+        data[17..21].copy_from_slice(&MS_OS_20_WINDOWS_VERSION);
+        data[21..23].copy_from_slice(&(MS_OS_20_SET_LEN as u16).to_le_bytes());
+        data[23] = MS_OS_20_VENDOR_CODE;
+        writer.capability(CAPABILITY_PLATFORM, &data)
+    }
+
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.if_num, USB_CLASS_VENDOR, 0x00, 0x00)
+    }
+
+    fn reset(&mut self) {
+        self.command = None;
+        self.response.clear();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type != control::RequestType::Vendor {
+            return;
+        }
+
+        match req.recipient {
+            control::Recipient::Device if req.request == WEBUSB_VENDOR_CODE && req.index == WEBUSB_GET_URL => {
+                if req.value as u8 != LANDING_PAGE_INDEX {
+                    xfer.reject().ok();
+                    return;
+                }
+
+                let url = LANDING_PAGE_URL.as_bytes();
+                let mut desc: Vec<u8, { LANDING_PAGE_URL.len() + 3 }> = Vec::new();
+                desc.extend_from_slice(&[(url.len() + 3) as u8, WEBUSB_URL_DESC_TYPE, WEBUSB_SCHEME_HTTPS]).ok();
+                desc.extend_from_slice(url).ok();
+                xfer.accept_with(&desc).ok();
+            },
+            control::Recipient::Device if req.request == MS_OS_20_VENDOR_CODE && req.index == MS_OS_20_DESCRIPTOR_INDEX => {
+                let mut set = MS_OS_20_SET;
+                set[MS_OS_20_FIRST_INTERFACE] = self.if_num.into();
+                xfer.accept_with(&set).ok();
+            },
+            control::Recipient::Interface if req.index == u8::from(self.if_num) as u16 => {
+                match req.request {
+                    REQ_RESPONSE => xfer.accept_with(&self.response).ok(),
+                    _ => xfer.reject().ok(),
+                };
+            },
+            _ => (),
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if req.request_type != control::RequestType::Vendor
+            || req.recipient != control::Recipient::Interface
+            || req.index != u8::from(self.if_num) as u16
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_COMMAND if !xfer.data().is_empty() => {
+                let data = xfer.data();
+                self.command = Vec::from_slice(&data[..data.len().min(WEBUSB_BUFF_LEN)]).ok();
+                xfer.accept().ok();
+            },
+            _ => { xfer.reject().ok(); },
+        }
+    }
+}
@@ -2,7 +2,7 @@
 ###
 ### Taiko Drum Controller configuration utility.
 ###
-### Communicates with drum's firmware via USB serial interface by sending raw commands, which are afterwards parsed 
+### Communicates with drum's firmware via USB serial interface by sending framed commands, which are afterwards parsed 
 ### by the microcontroller. This utility does not checks the endpoint device for proper VID,PID values, therefore
 ### higher level software is required to find a proper file created by the OS (e.g. ttyACM0, COM1, etc.) while enumerating 
 ### the USB device.
@@ -11,6 +11,8 @@ set version "0.1.0"
 array set config {}
 set port ""
 set cmd ""
+# Profile read or written instead of the active one.
+set slot ""
 
 # Utility help message.
 proc help {} {
@@ -27,7 +29,41 @@ proc help {} {
     puts "                    e.g. \"left_don=X right_kat=V\""
     puts "  Valid configuration values:"
     puts "  left_don, right_don, left_kat, right_kat"
+    puts "  mod_left_don, mod_right_don, mod_left_kat, mod_right_kat (modifier bits: 1 - ctrl, 2 - shift, 4 - alt, 8 - gui; 16, 32, 64, 128 - the same for right ones)"
+    puts "  p2_left_don, p2_right_don, p2_left_kat, p2_right_kat, p2_mod_* (second drum in two-player firmware)"
+    puts "  cons_left_don, cons_right_don, cons_left_kat, cons_right_kat (consumer usage, e.g. 233 - volume up, 0 - off)"
+    puts "  mode (0 - keyboard, 1 - gamepad, 2 - HORI/Switch; applied after --reset)"
+    puts "  midi (0 - off, 1 - percussion notes instead of HID reports; applied after --reset)"
+    puts "  poll (HID polling interval in milliseconds, 1-255; applied after --reset)"
+    puts "  axes (1 - report hit strength as gamepad axes, 0 - buttons only; applied after --reset)"
+    puts "  gesture_kats, gesture_dons, gesture_hold (keycode sent on both kats, both dons or don held for 2 s, e.g. 41 - Escape, 40 - Enter, 255 - toggle menu mode, 254 - enable serial interface and reset, 0 - off)"
+    puts "  cdc_off (1 - hide this serial interface, only vendor HID and WebUSB remain; applied after --reset)"
+    puts "  power (bus current in 2 mA units, e.g. 150 - 300 mA for builds with solenoids, 0 - default 100 mA), self_powered (1 - own power supply); applied after --reset"
+    puts "  idle_sleep (seconds without hits before the drum stops flash and RAM clocks while sleeping, 0 - never)"
+    puts "  repeat_delay (delay before held pads repeat keys in 10 ms units, 0 - off), repeat_rate (repeats per second)"
     puts "  --reset            Resets the firmware."
+    puts "  --bootloader       Reboots the drum into the bootloader to flash new firmware."
+    puts "  --factory-reset    Erases all profiles and restarts the drum with the default configuration."
+    puts "  --profile <0-3>    Switches to another configuration profile, e.g. separate setups for osu! and TnT."
+    puts "  --profiles         Lists stored configuration profiles and their names."
+    puts "  --slot <0-3>       Reads or writes another profile with --read or --configure without switching to it."
+    puts "  --export <file>    Saves all profiles into a file, which can be imported later or by another drum."
+    puts "  --import <file>    Replaces all profiles with the ones exported into a file."
+    puts "  --update <file>    Installs new firmware from a raw binary (objcopy -O binary), requires a chip with 128K of flash."
+    puts "  --lock, --unlock   Rejects or allows configuration changes, e.g. to protect tournament setups from accidental changes by other software."
+    puts "  --scope <1-255>    Shows live peak levels of each pad, streaming each N-th of 20000 samples per second. Stopped by Ctrl+C."
+    puts "  --telemetry        Shows why each hit is accepted or rejected, e.g. while tuning sensitivity and sharpness. Stopped by Ctrl+C."
+    puts "  --hits             Shows hit counters of each pad, e.g. to track pad wear."
+    puts "  --capture <ms:ms>  Waits for the next hit and shows raw samples of the given time before and after its peak, e.g. 2:4 to diagnose ghost hits. Requires firmware built with the capture feature."
+    puts "  --log              Shows the latest log lines kept by the drum, e.g. to attach to bug reports. Requires firmware built with the log-ring feature."
+    puts "  --sampler <halt|period> Restarts the ADCs in halt mode or timer mode with the given period in 36 MHz timer ticks (1800-65535, 3600 by default), e.g. to experiment with sampling settings. Kept until reset."
+    puts "  --crash            Shows and clears the report of the last panic, hard fault or crystal failure, e.g. after the drum stopped responding and was reset."
+    puts "  --selftest         Shows results of the power-on self-test, e.g. to validate the soldering of a new build."
+    puts "  --dump             Shows raw configuration pages of the flash, e.g. to inspect layouts that fail to migrate."
+    puts "  --calibrate        Measures noise of idle pads for 3 seconds, do not hit the drum meanwhile."
+    puts "  --ping             Measures the round trip time of commands between the host and the drum."
+    puts "  --stats            Shows USB, sample processing and pipeline health counters since boot, e.g. to attach to lag reports."
+    puts "  --load             Shows the CPU load and the time taken by each task within a second, e.g. to compare parser cost between builds."
     puts "  --read -r          Read current configuration from the device. This option is set as default if no configuration is provided."
     puts "  --help, -h         Show this help message"
     puts "  --version, -v      Shows current version of this utility. The version will always match the current firmware version."
@@ -53,6 +89,14 @@ while {$i < [llength $argv]} {
 
     switch -- $key {
         --port -
+        --profile -
+        --slot -
+        --scope -
+        --capture -
+        --sampler -
+        --export -
+        --import -
+        --update -
         --configure {
             if {$i >= [llength $argv]} {
                 puts stderr "Missing value for $key"
@@ -72,6 +116,39 @@ while {$i < [llength $argv]} {
             continue
         }
 
+        --lock -
+        --unlock -
+        --bootloader -
+        --hits -
+        --log -
+        --crash -
+        --selftest -
+        --dump -
+        --profiles -
+        --telemetry -
+        --calibrate -
+        --ping -
+        --load -
+        --stats {
+            if {$cmd eq ""} {
+                set cmd [string range $key 2 end]
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+            continue
+        }
+
+        --factory-reset {
+            if {$cmd eq ""} {
+                set cmd factory_reset
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+            continue
+        }
+
         --reset {
             if {$cmd eq ""} {
                 set cmd reset
@@ -101,11 +178,62 @@ while {$i < [llength $argv]} {
 
     switch -- $key {
         --port      { set port $val }
+        --slot      { set slot $val }
+        --scope     {
+            if {$cmd eq ""} {
+                set cmd scope
+                set decimation $val
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+        }
+        --capture   {
+            if {$cmd eq ""} {
+                set cmd capture
+                lassign [split $val ":"] before after
+                if {$after eq ""} {
+                    set after $before
+                }
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+        }
+        --sampler   {
+            if {$cmd eq ""} {
+                set cmd sampler
+                set sampler $val
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+        }
+        --profile   {
+            if {$cmd eq ""} {
+                set cmd profile
+                set profile $val
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+        }
+        --export -
+        --import -
+        --update {
+            if {$cmd eq ""} {
+                set cmd [string range $key 2 end]
+                set file $val
+            } else {
+                puts "Invalid command merging. Some arguments collide with each other."
+                exit 1
+            }
+        }
         --configure {
             # Parses the configuration string into an array
             if {[string length $val] > 0} {
                 set cmd write
-                set keys "left_kat right_kat left_don right_don sens sharp"
+                set keys "left_kat right_kat left_don right_don sens sharp mode midi poll axes cdc_off power self_powered idle_sleep repeat_delay repeat_rate gesture_kats gesture_dons gesture_hold mod_left_kat mod_left_don mod_right_don mod_right_kat p2_left_kat p2_left_don p2_right_don p2_right_kat p2_mod_left_kat p2_mod_left_don p2_mod_right_don p2_mod_right_kat cons_left_kat cons_left_don cons_right_don cons_right_kat"
                 foreach pair [split $val " "] {
                     set split_pair [split $pair "="]
                     set key [lindex $split_pair 0]
@@ -133,22 +261,131 @@ if {$port eq ""} {
     exit 1
 }
 
-# Command bytes definition. Those are equal to the ones defined within the drum's firmware.
+# Command bytes definition. Those are equal to the ones defined within src/protocol.rs of the firmware.
 set CMD_READ    0x01
 set CMD_WRITE   0x02
 set RESERVED    0x03
 
+set CMD_PROFILES 0x16
+set CMD_PROFILE 0x17
+set CMD_FACTORY_RESET 0x19
+set CMD_EXPORT  0x1A
+set CMD_IMPORT  0x1B
+set CMD_HITS    0x1C
+set CMD_STATS   0x14
+set CMD_LOCK    0x1D
+set CMD_FW_WRITE 0x1E
+set CMD_FW_BOOT 0x1F
+# Firmware update chunks, which fit into a single frame along with the command, key and offset.
+set FW_CHUNK    128
+set CMD_SCOPE   0x20
+set CMD_CALIBRATE 0x21
+set CMD_TELEMETRY 0x22
+set CMD_PROFILE_READ 0x23
+set CMD_PROFILE_WRITE 0x24
+set CMD_BOOTLOADER 0x25
+set CMD_PING    0x26
+set CMD_UNLOCK  0x27
+set CMD_DUMP    0x28
+set CMD_CAPTURE 0x29
+set CMD_CAPTURE_READ 0x2A
+set CMD_SAMPLER 0x2B
+set CMD_LOG_READ 0x2C
+set CMD_CRASH 0x2D
+set CMD_SELFTEST 0x2E
+# Samples within a millisecond of the capture.
+set CAPTURE_RATE 20
+# Payload of the unlock command, which must precede commands rewriting the flash.
+set UNLOCK_MAGIC "TAIK"
+# Pings sent to measure the round trip time.
+set PING_COUNT  20
+# Window of the CPU load measurement in milliseconds.
+set LOAD_WINDOW 1000
+# Length of profile names.
+set PROFILE_NAME_LEN 12
+# Noise measurement duration in 100 ms units.
+set CALIBRATE_DURATION 30
+# Exported chunks fill the whole response after ACK and the echoed command, the shorter one ends the blob.
+set EXPORT_CHUNK 62
+# Imported chunks, which fit into a single vendor HID report along with the command, key and offset.
+set IMPORT_CHUNK 48
+# Key required by protected commands.
+set COMMAND_KEY "\x55\xAA"
+# Tag leading the configuration stream with its layout version, and the latest version known here.
+set STREAM_VERSION 0x0F
+set CFG_STREAM_VERSION 2
 set CMD_RESET   0xFF
+# Responses start with ACK or NAK followed by the echoed command byte.
 set ACK         0x06
+# Rejected commands are answered with NAK followed by the echoed command and the error code.
+set NAK         0x15
+# Starts each frame sent over the serial port.
+set SYNC        0xA5
+# Bytes received after the latest frame.
+set rx ""
+array set nak_errors {
+    1 "command stream is truncated"
+    2 "unknown configuration key"
+    3 "sensitivity shall be within 1-100"
+    4 "sharpness gives a hit threshold outside of 16-2000 ADC counts at this sensitivity"
+    5 "reserved keycode"
+    6 "consumer usage above 0x514"
+    7 "configuration is locked, see --unlock"
+    8 "unable to save to flash, changes are lost after reset"
+    16 "frame CRC mismatch"
+    17 "empty request"
+    18 "unknown command, firmware might be outdated"
+    19 "malformed command"
+    20 "firmware image is rejected, it shall be a raw binary built for this drum"
+    21 "chip has no flash for the firmware update"
+    22 "command is not unlocked"
+    23 "frame timed out, a packet was lost"
+    24 "firmware is built without a bootloader, see BOOTLOADER_ADDRESS"
+}
 
+# Configuration tags, the same as within src/protocol.rs of the firmware.
 array set key_to_cmd {
     left_kat  0x10
     left_don  0x11
     right_don 0x12
     right_kat 0x13
 
+    mod_left_kat   0x18
+    mod_left_don   0x19
+    mod_right_don  0x1A
+    mod_right_kat  0x1B
+
+    p2_left_kat      0x40
+    p2_left_don      0x41
+    p2_right_don     0x42
+    p2_right_kat     0x43
+    p2_mod_left_kat  0x44
+    p2_mod_left_don  0x45
+    p2_mod_right_don 0x46
+    p2_mod_right_kat 0x47
+
+    cons_left_kat  0x14
+    cons_left_don  0x15
+    cons_right_don 0x16
+    cons_right_kat 0x17
+
     sens      0x20
     sharp     0x21
+
+    mode      0x30
+    midi      0x31
+    poll      0x32
+    repeat_delay 0x33
+    repeat_rate  0x34
+    axes      0x35
+    cdc_off   0x36
+    power     0x37
+    self_powered 0x38
+    idle_sleep   0x39
+
+    gesture_kats 0x50
+    gesture_dons 0x51
+    gesture_hold 0x52
 }
 
 # Opens and configures the requested serial port.
@@ -166,65 +403,161 @@ proc serial {port} {
     return $serial
 }
 
-# Waits for a specific byte from the serial port.
+proc byte {val} { binary format c $val }
+
+# CRC16-CCITT of the frame length and body, equal to the one computed by the firmware.
+proc crc16 {data} {
+    set crc 0xFFFF
+    binary scan $data cu* bytes
+    foreach b $bytes {
+        set crc [expr {$crc ^ ($b << 8)}]
+        for {set i 0} {$i < 8} {incr i} {
+            set crc [expr {(($crc << 1) ^ (($crc & 0x8000) ? 0x1021 : 0)) & 0xFFFF}]
+        }
+    }
+    return $crc
+}
+
+# Reads the next frame from the serial port with timeout.
 #
-# @param serial
-#       Opened and configured serial from which we expect the required byte.
-# @param expected_byte
-#       The expected byte value.
+# Frames consist of the sync byte, length, body and big-endian CRC16 of the length and body. Line
+# noise and corrupted frames are skipped. Bytes following the frame are kept for the next call.
+#
+# @param conn
+#       Opened and configured serial port.
 # @param timeout
 #       Amount in seconds, after which the script shall give up the connection.
-proc wait_for {serial expected_byte timeout} {
+# @return
+#       Frame body, which starts with ACK, NAK or the tag of the stream frame.
+proc read_frame {conn timeout} {
+    global SYNC rx
+
     set start_time [clock seconds]
+    while {[clock seconds] - $start_time < $timeout} {
+        append rx [read $conn]
+        set sync [string first [byte $SYNC] $rx]
+        if {$sync < 0} {
+            set rx ""
+        } else {
+            set rx [string range $rx $sync end]
+        }
+
+        if {![binary scan $rx x1cu len] || [string length $rx] < $len + 4} {
+            after 1
+            continue
+        }
+        set body [string range $rx 1 [expr {$len + 1}]]
+        binary scan $rx x[expr {$len + 2}]Su crc
+        if {$len == 0 || $crc != [crc16 $body]} {
+            # Corrupted frame, searching for the next one.
+            set rx [string range $rx 1 end]
+            continue
+        }
+        set rx [string range $rx [expr {$len + 4}] end]
+        return [string range $body 1 end]
+    }
+    puts stderr "Did not receive ACK from device (timeout)."
+    exit 1
+}
+
+# Sends the command and waits for its response with timeout.
+#
+# Commands are framed the same way as responses, see read_frame. Rejected commands are reported
+# along with the error code following NAK, including corrupted frames reported with the echoed
+# command 0. Stream frames and late responses of other commands are skipped.
+#
+# @param conn
+#       Opened and configured serial port.
+# @param msg
+#       Command byte followed by its arguments.
+# @param timeout
+#       Amount in seconds, after which the script shall give up the connection.
+# @return
+#       Response bytes following ACK and the echoed command.
+proc request {conn msg timeout} {
+    global SYNC ACK NAK nak_errors
+
+    set body "[byte [string length $msg]]${msg}"
+    puts -nonewline $conn "[byte $SYNC]${body}[binary format S [crc16 $body]]"
+    flush $conn
+
+    binary scan $msg cu cmd
     while {1} {
-        set byte [read $serial 1]
-        if {[binary scan $byte c received] && $received == $expected_byte} {
-            return 1
+        set resp [read_frame $conn $timeout]
+        if {[binary scan $resp cucu status echo] != 2 || ($echo != $cmd && $echo != 0)} {
+            continue
         }
-        
-        set current_time [clock seconds]
-        if {($current_time - $start_time) >= $timeout} {
-            return 0
+        if {$status == $NAK} {
+            binary scan $resp x2cu code
+            set reason "error $code"
+            if {[info exists nak_errors($code)]} {
+                set reason $nak_errors($code)
+            }
+            puts stderr "Command is rejected by device: $reason."
+            exit 1
+        } elseif {$status == $ACK} {
+            return [string range $resp 2 end]
         }
-        
-        after 10
     }
 }
 
-proc byte {val} { binary format c $val }
+# Drops bytes left by a previous session, e.g. a frame cut by a killed utility.
+#
+# The empty resync frame is acknowledged once the drum drops everything preceding it, which takes
+# up to 100 ms after a partial frame. Corrupted and timed out frames are reported meanwhile, while
+# firmware without resync reports the empty frame itself.
+proc resync {conn timeout} {
+    global SYNC ACK NAK
+
+    set body [byte 0]
+    puts -nonewline $conn "[byte $SYNC]${body}[binary format S [crc16 $body]]"
+    flush $conn
 
-# Waiting for ACK from the microcontroller's side with timeout of 5 seconds.
-proc until_ack {conn ack timeout} {
-    if {![wait_for $conn $ack $timeout]} {
-        puts stderr "Did not receive ACK from device (timeout)."
-        exit 1
+    while {1} {
+        set resp [read_frame $conn $timeout]
+        if {[binary scan $resp cucu status echo] == 2 && $echo == 0
+            && ($status == $ACK || ($status == $NAK && [binary scan $resp x2cu code] == 1 && $code == 17))} {
+            return
+        }
     }
 }
 
+# Allows commands rewriting the configuration or firmware, until any other command is sent.
+proc unlock {conn timeout} {
+    global CMD_UNLOCK UNLOCK_MAGIC
+    request $conn "[byte $CMD_UNLOCK]${UNLOCK_MAGIC}" $timeout
+}
+
 # Main
 
 set conn [serial $port]
 set timeout 5
+resync $conn $timeout
 
 if {$cmd eq "read"} {
-    puts -nonewline $conn [byte $CMD_READ]
-    flush $conn
-
-    until_ack $conn $ACK $timeout
+    if {$slot eq ""} {
+        set resp [request $conn [byte $CMD_READ] $timeout]
+    } else {
+        set resp [request $conn "[byte $CMD_PROFILE_READ][byte $slot]" $timeout]
+    }
 
-    # Read each configuration entry: 2 bytes per item
+    # Read each configuration entry: key followed by its value.
     set received_config ""
-    while {[eof $conn] eq 0} {
-        set cmd_byte [read $conn 1]
-
-        if {[string length $cmd_byte] < 1} {
-            break
+    set idx 0
+    while {[binary scan $resp x${idx}cu cmd_id]} {
+        incr idx
+
+        if {$cmd_id == $STREAM_VERSION} {
+            binary scan $resp x${idx}cu version
+            incr idx
+            if {$version > $CFG_STREAM_VERSION} {
+                puts stderr "Configuration stream v$version is newer than this utility, some values may be wrong."
+            }
+            continue
         }
-        binary scan $cmd_byte c cmd_id
 
         # Backward keyname unparsing.
         set key "UNKNOWN"
-        set raw_value 0xFF
         foreach k [array names key_to_cmd] {
             if {$key_to_cmd($k) == $cmd_id} {
                 set key $k
@@ -233,17 +566,17 @@ if {$cmd eq "read"} {
         }
 
         switch $key {
-            "sens" {
-                set raw_value [read $conn 4]
-                binary scan $raw_value I val
-            }
-            "sharp" {
-                set raw_value [read $conn 2]
-                binary scan $raw_value S val
+            "sharp" -
+            "cons_left_kat" -
+            "cons_left_don" -
+            "cons_right_don" -
+            "cons_right_kat" {
+                binary scan $resp x${idx}Su val
+                incr idx 2
             }
             default {
-                set raw_value [read $conn 1]
-                binary scan $raw_value cu val
+                binary scan $resp x${idx}cu val
+                incr idx
             }
         }
 
@@ -254,9 +587,6 @@ if {$cmd eq "read"} {
 
 } elseif {$cmd eq "write"} {
     set len 0
-    puts -nonewline $conn [byte $CMD_WRITE]
-    flush $conn
-    until_ack $conn $ACK $timeout 
 
     foreach key [array names config] {
         if {![info exists key_to_cmd($key)]} {
@@ -276,7 +606,11 @@ if {$cmd eq "read"} {
                 set val_bytes [binary format I $value]
                 incr len 5
             }
-            "sharp" {
+            "sharp" -
+            "cons_left_kat" -
+            "cons_left_don" -
+            "cons_right_don" -
+            "cons_right_kat" {
                 set val_bytes [binary format S $value]
                 incr len 3
             }
@@ -288,12 +622,298 @@ if {$cmd eq "read"} {
 
         append msg "${cmd_byte}${val_bytes}"
     } 
-    puts -nonewline $conn msg
-    flush $conn
+    unlock $conn $timeout
+    if {$slot eq ""} {
+        request $conn "[byte $CMD_WRITE]${msg}" $timeout
+    } else {
+        request $conn "[byte $CMD_PROFILE_WRITE][byte $slot]${msg}" $timeout
+    }
 
     puts "Configuration of ${len} bytes is sent."
+} elseif {$cmd eq "profile"} {
+    unlock $conn $timeout
+    request $conn "[byte $CMD_PROFILE][byte $profile]" $timeout
+
+    puts "Switched to profile ${profile}."
+} elseif {$cmd eq "profiles"} {
+    set resp [request $conn [byte $CMD_PROFILES] $timeout]
+    binary scan $resp cucu active stored
+    for {set profile 0} {$profile < 4} {incr profile} {
+        set name [string trimright [string range $resp [expr {2 + $profile * $PROFILE_NAME_LEN}] [expr {1 + ($profile + 1) * $PROFILE_NAME_LEN}]] "\0"]
+        if {!($stored & (1 << $profile))} {
+            set name "(empty)"
+        }
+        puts "[expr {$profile == $active ? "*" : " "}] ${profile}: ${name}"
+    }
+} elseif {$cmd eq "factory_reset"} {
+    unlock $conn $timeout
+    request $conn "[byte $CMD_FACTORY_RESET]${COMMAND_KEY}" $timeout
+
+    puts "Factory configuration is restored."
+} elseif {$cmd eq "export"} {
+    set blob ""
+    while {1} {
+        set chunk [request $conn "[byte $CMD_EXPORT][binary format S [string length $blob]]" $timeout]
+        append blob $chunk
+        if {[string length $chunk] < $EXPORT_CHUNK} {
+            break
+        }
+    }
+
+    set fd [open $file w]
+    chan configure $fd -translation binary
+    puts -nonewline $fd $blob
+    close $fd
+    puts "Configuration of [string length $blob] bytes is exported to ${file}."
+} elseif {$cmd eq "dump"} {
+    # Pages are read in chunks of the exported blob size, the shorter one ends the region.
+    set raw ""
+    while {1} {
+        set chunk [request $conn "[byte $CMD_DUMP][binary format S [string length $raw]]" $timeout]
+        append raw $chunk
+        if {[string length $chunk] < $EXPORT_CHUNK} {
+            break
+        }
+    }
+
+    for {set offset 0} {$offset < [string length $raw]} {incr offset 16} {
+        set line [string range $raw $offset [expr {$offset + 15}]]
+        binary scan $line H* hex
+        regsub -all {..} $hex {& } hex
+        puts [format "%04x: %-48s %s" $offset $hex [regsub -all {[^\x20-\x7e]} $line .]]
+    }
+} elseif {$cmd eq "import"} {
+    set fd [open $file r]
+    chan configure $fd -translation binary
+    set blob [read $fd]
+    close $fd
+
+    # Each chunk is acknowledged along with a flag, which is set once the whole blob is imported.
+    set imported 0
+    unlock $conn $timeout
+    for {set offset 0} {$offset < [string length $blob]} {incr offset $IMPORT_CHUNK} {
+        set chunk [string range $blob $offset [expr {$offset + $IMPORT_CHUNK - 1}]]
+        set resp [request $conn "[byte $CMD_IMPORT]${COMMAND_KEY}[binary format S $offset]${chunk}" $timeout]
+        binary scan $resp cu imported
+    }
+
+    if {$imported != 1} {
+        puts stderr "Configuration blob was not accepted by the device."
+        exit 1
+    }
+    puts "Configuration is imported from ${file}."
+} elseif {$cmd eq "update"} {
+    set fd [open $file r]
+    chan configure $fd -translation binary
+    set image [read $fd]
+    close $fd
+
+    unlock $conn $timeout
+    for {set offset 0} {$offset < [string length $image]} {incr offset $FW_CHUNK} {
+        set chunk [string range $image $offset [expr {$offset + $FW_CHUNK - 1}]]
+        request $conn "[byte $CMD_FW_WRITE]${COMMAND_KEY}[binary format I $offset]${chunk}" $timeout
+        puts -nonewline "\rWritten [expr {$offset + [string length $chunk]}] of [string length $image] bytes."
+        flush stdout
+    }
+    puts ""
+
+    # Image is verified by the drum and installed while it restarts.
+    request $conn "[byte $CMD_FW_BOOT]${COMMAND_KEY}[binary format II [string length $image] [zlib crc32 $image]]" $timeout
+    puts "Firmware is staged. The drum installs it after restart, do not unplug it until it reconnects."
+} elseif {$cmd eq "scope"} {
+    request $conn "[byte $CMD_SCOPE][byte $decimation]" $timeout
+
+    # Each frame carries a batch of big-endian samples of 4 pads per drum. The peak of each pad
+    # within the frame is drawn as a bar of 12-bit ADC range.
+    set pads {LK LD RD RK}
+    while {1} {
+        set frame [read_frame $conn $timeout]
+        if {![binary scan $frame cuSu* tag samples] || $tag != $CMD_SCOPE} {
+            continue
+        }
+        set peaks {}
+        foreach pad $pads { lappend peaks 0 }
+        for {set i 0} {$i < [llength $samples]} {incr i} {
+            set pad [expr {$i % 4}]
+            lset peaks $pad [expr {max([lindex $peaks $pad], [lindex $samples $i])}]
+        }
+        set line ""
+        foreach pad $pads peak $peaks {
+            set bar [string repeat "#" [expr {$peak * 16 / 4096}]]
+            append line [format "%s %4d %-16s " $pad $peak $bar]
+        }
+        puts $line
+    }
+} elseif {$cmd eq "capture"} {
+    # State is polled until the capture is frozen: 1 - armed, 2 - recording after the hit.
+    set resp [request $conn "[byte $CMD_CAPTURE][byte $before][byte $after]" $timeout]
+    puts "Waiting for a hit..."
+    while {[binary scan $resp cuSuSu state len lead] == 3 && $state != 3} {
+        after 100
+        set resp [request $conn [byte $CMD_CAPTURE] $timeout]
+    }
+
+    # Samples are read the same way as the exported blob, the shorter chunk ends the capture.
+    set raw ""
+    while {1} {
+        set chunk [request $conn "[byte $CMD_CAPTURE_READ][binary format S [string length $raw]]" $timeout]
+        append raw $chunk
+        if {[string length $chunk] < $EXPORT_CHUNK} {
+            break
+        }
+    }
+
+    # Each sample carries big-endian values of 4 pads, timed relative to the peak of the hit.
+    binary scan $raw Su* samples
+    for {set i 0} {$i < $len} {incr i} {
+        set time [expr {double($i - $lead) / $CAPTURE_RATE}]
+        puts [format "%+7.2f ms: LK %4d LD %4d RD %4d RK %4d" $time {*}[lrange $samples [expr {$i * 4}] [expr {$i * 4 + 3}]]]
+    }
+} elseif {$cmd eq "log"} {
+    # Lines are read the same way as the exported blob, the shorter chunk ends the log.
+    set raw ""
+    while {1} {
+        set chunk [request $conn "[byte $CMD_LOG_READ][binary format S [string length $raw]]" $timeout]
+        append raw $chunk
+        if {[string length $chunk] < $EXPORT_CHUNK} {
+            break
+        }
+    }
+    puts -nonewline $raw
+} elseif {$cmd eq "crash"} {
+    # Kind, big-endian PC, LR, xPSR, CFSR, HFSR and BFAR, followed by the message.
+    set resp [request $conn "[byte $CMD_CRASH][byte 0]" $timeout]
+    if {[binary scan $resp cuIuIuIuIuIuIu kind pc lr xpsr cfsr hfsr bfar] != 7} {
+        puts "No crash was reported."
+    } else {
+        puts [lindex {"" "Panic:" "Hard fault:" "Clock failure:"} $kind]
+        puts [format "  PC 0x%08X LR 0x%08X xPSR 0x%08X" $pc $lr $xpsr]
+        puts [format "  CFSR 0x%08X HFSR 0x%08X BFAR 0x%08X" $cfsr $hfsr $bfar]
+        puts "  [string range $resp 25 end]"
+    }
+} elseif {$cmd eq "selftest"} {
+    # Masks of done and failed checks, bits are the numbers blinked by the LED minus one.
+    set resp [request $conn [byte $CMD_SELFTEST] $timeout]
+    binary scan $resp cucu done failed
+    foreach check {clock config sensors} bit {0 1 2} {
+        if {!($done & (1 << $bit))} {
+            set result "not done"
+        } elseif {$failed & (1 << $bit)} {
+            set result "FAILED ([expr {$bit + 1}] blinks)"
+        } else {
+            set result "passed"
+        }
+        puts "${check}: ${result}"
+    }
+} elseif {$cmd eq "sampler"} {
+    # Halt mode waits for the analog watchdog, timer mode takes a big-endian u16 sampling period.
+    if {$sampler eq "halt"} {
+        set resp [request $conn "[byte $CMD_SAMPLER][byte 0]" $timeout]
+    } else {
+        set resp [request $conn "[byte $CMD_SAMPLER][byte 1][binary format S $sampler]" $timeout]
+    }
+    if {[binary scan $resp cuSu mode period] == 2} {
+        puts "Sampling restarted in timer mode every ${period} ticks ([expr {36000000 / $period}] Hz)."
+    } else {
+        puts "Sampling restarted in halt mode."
+    }
+} elseif {$cmd eq "telemetry"} {
+    request $conn "[byte $CMD_TELEMETRY][byte 1]" $timeout
+
+    # Each frame carries records of hits detected within a single sample: pad, verdict, correlated
+    # pad, peak and median in ADC counts relative to the midpoint, their distance and the distance
+    # required for a hit.
+    set pads {LK LD RD RK}
+    set verdicts {accepted simultaneous crosstalk}
+    while {1} {
+        # The drum might not be hit for a long time.
+        set frame [read_frame $conn 86400]
+        if {![binary scan $frame cu tag] || $tag != $CMD_TELEMETRY} {
+            continue
+        }
+        for {set i 1} {[binary scan $frame "x${i}cucucuSSSuSu" pad verdict other peak median deviation limit] == 7} {incr i 11} {
+            set line [format "%s peak %5d median %5d deviation %4d/%-4d %s" \
+                [lindex $pads $pad] $peak $median $deviation $limit [lindex $verdicts $verdict]]
+            if {$verdict != 0} {
+                append line " with [lindex $pads $other]"
+            }
+            puts $line
+        }
+    }
+} elseif {$cmd eq "lock" || $cmd eq "unlock"} {
+    unlock $conn $timeout
+    request $conn "[byte $CMD_LOCK]${COMMAND_KEY}[byte [expr {$cmd eq "lock"}]]" $timeout
+
+    puts "Configuration is ${cmd}ed."
+} elseif {$cmd eq "hits"} {
+    set resp [request $conn [byte $CMD_HITS] $timeout]
+    binary scan $resp IuIuIuIu left_kat left_don right_don right_kat
+    foreach pad {left_kat left_don right_don right_kat} {
+        puts "${pad}: [set $pad]"
+    }
+} elseif {$cmd eq "calibrate"} {
+    set resp [request $conn "[byte $CMD_CALIBRATE][byte $CALIBRATE_DURATION]" $timeout]
+    puts "Measuring noise, do not hit the drum..."
+    while {[binary scan $resp cu running] && $running} {
+        after 200
+        set resp [request $conn [byte $CMD_CALIBRATE] $timeout]
+    }
+    binary scan $resp cuSuSuSuSuSSSS running s0 s1 s2 s3 o0 o1 o2 o3
+    foreach pad {left_kat left_don right_don right_kat} sigma [list $s0 $s1 $s2 $s3] offset [list $o0 $o1 $o2 $o3] {
+        puts "${pad}: sigma ${sigma}, offset ${offset}"
+    }
+} elseif {$cmd eq "stats"} {
+    # Latencies are measured in CPU cycles at 72 MHz.
+    set usb [request $conn [byte $CMD_STATS] $timeout]
+    binary scan $usb Iu* counters
+    foreach name {reports naks resets suspends cdc_rx cdc_tx errors missed_sof frame_jitter} value $counters {
+        puts "${name}: ${value}"
+    }
+    set runtime [request $conn "[byte $CMD_STATS][byte 1]" $timeout]
+    binary scan $runtime Iu* counters
+    foreach name {sample_overflows report_overflows dropped_reports hits_left_kat hits_left_don hits_right_don hits_right_kat crosstalk max_latency} value $counters {
+        puts "${name}: ${value}"
+    }
+    # Reading the health clears its warning.
+    set health [request $conn "[byte $CMD_STATS][byte 3]" $timeout]
+    binary scan $health Iu* counters
+    foreach name {pipeline_warning sample_queue_peak report_queue_peak parser_overruns sampling_pauses send_failures} value $counters {
+        puts "${name}: ${value}"
+    }
+} elseif {$cmd eq "load"} {
+    # The first request starts a new window. Shares are in 0.01% units, runs in CPU cycles at 72 MHz.
+    request $conn "[byte $CMD_STATS][byte 2]" $timeout
+    after $LOAD_WINDOW
+    set resp [request $conn "[byte $CMD_STATS][byte 2]" $timeout]
+    binary scan $resp IuIuIu* window idle tasks
+    puts [format "idle: %.2f%% of %u ms" [expr {$idle / 100.0}] $window]
+    foreach name {sampling parser usb_tx usb_rx programmer} {share longest} $tasks {
+        puts [format "%s: %.2f%%, longest run %u cycles" $name [expr {$share / 100.0}] $longest]
+    }
+} elseif {$cmd eq "ping"} {
+    # Each ping carries its sequence number, which is echoed back after the drum timestamp.
+    set rtts {}
+    for {set seq 0} {$seq < $PING_COUNT} {incr seq} {
+        set start [clock microseconds]
+        set resp [request $conn "[byte $CMD_PING][binary format I $seq]" $timeout]
+        set rtt [expr {[clock microseconds] - $start}]
+        if {![binary scan $resp IuIu stamp echo] || $echo != $seq} {
+            puts stderr "Ping ${seq} is lost."
+            continue
+        }
+        lappend rtts $rtt
+        puts [format "seq %2d: %6.2f ms (drum cycle counter %u)" $seq [expr {$rtt / 1000.0}] $stamp]
+        after 50
+    }
+    if {[llength $rtts] > 0} {
+        set rtts [lsort -integer $rtts]
+        puts [format "min %.2f ms, median %.2f ms, max %.2f ms" [expr {[lindex $rtts 0] / 1000.0}] \
+            [expr {[lindex $rtts [expr {[llength $rtts] / 2}]] / 1000.0}] [expr {[lindex $rtts end] / 1000.0}]]
+    }
+} elseif {$cmd eq "bootloader"} {
+    request $conn "[byte $CMD_BOOTLOADER]${COMMAND_KEY}" $timeout
+
+    puts "The drum reboots into the bootloader."
 } elseif {$cmd eq "reset"} {
-    puts -nonewline $conn [byte $CMD_RESET]
-    flush $conn
-    until_ack $conn $ACK $timeout
+    request $conn [byte $CMD_RESET] $timeout
 }